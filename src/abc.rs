@@ -0,0 +1,1417 @@
+//! ABC notation - ABC記譜法(2.1)をサクラのMMLに変換する
+//!
+//! 情報フィールド(X/T/M/L/Q/K/V)と、音符・臨時記号・タイ・連符・和音・繰り返しを読み、
+//! 声部(V:)ごとのトラックにしたMMLを作る。繰り返しは展開して書き出す。
+//! ファイルに複数の曲があるときは最初の曲だけを変換する。
+
+use crate::diagnostic::Severity;
+use crate::span::SourceText;
+use std::collections::HashMap;
+
+/// 変換したMMLのタイムベース (3連符・5連符が割り切れる値)
+pub const ABC_TIMEBASE: i64 = 480;
+/// 全音符のステップ数
+const WHOLE: i64 = ABC_TIMEBASE * 4;
+
+/// 音名(c〜b)の半音の位置
+const SEMITONES: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// 調号の#と♭が付く順番
+const SHARP_ORDER: [char; 7] = ['f', 'c', 'g', 'd', 'a', 'e', 'b'];
+const FLAT_ORDER: [char; 7] = ['b', 'e', 'a', 'd', 'g', 'c', 'f'];
+
+/// 変換中のエラー・警告
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AbcMessage {
+    pub severity: Severity,
+    /// 行番号 (0始まり)
+    pub line: isize,
+    /// 列番号 (0始まり)
+    pub column: isize,
+    pub text: String,
+}
+
+/// 変換結果
+#[derive(Debug, Clone)]
+pub struct AbcMml {
+    pub mml: String,
+    /// MMLの位置を元のABCの位置へ戻す対応表
+    pub source_text: SourceText,
+    pub messages: Vec<AbcMessage>,
+}
+
+/// 分数 (全音符=1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frac {
+    num: i64,
+    den: i64,
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a.abs().max(1)
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+impl Frac {
+    fn new(num: i64, den: i64) -> Self {
+        let den = if den == 0 { 1 } else { den };
+        let g = gcd(num, den) * den.signum();
+        let f = Frac {
+            num: num / g,
+            den: den / g,
+        };
+        // 連符を重ねて分母が大きくなりすぎたらステップ単位に丸める
+        if f.den > 1_000_000 {
+            return Frac::new(f.ticks(), WHOLE);
+        }
+        f
+    }
+    fn int(v: i64) -> Self {
+        Frac { num: v, den: 1 }
+    }
+    fn mul(self, o: Frac) -> Frac {
+        Frac::new(self.num * o.num, self.den * o.den)
+    }
+    fn add(self, o: Frac) -> Frac {
+        Frac::new(self.num * o.den + o.num * self.den, self.den * o.den)
+    }
+    /// ステップ数 (四捨五入)
+    fn ticks(self) -> i64 {
+        (self.num * WHOLE * 2 + self.den).div_euclid(self.den * 2)
+    }
+}
+
+/// 音符1つ分の音高
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AbcNote {
+    /// 音名 (c〜b)
+    letter: char,
+    /// 臨時記号 (+1=#, -1=♭)
+    acc: i32,
+    /// サクラのオクターブ (o5c=60)
+    octave: i32,
+}
+
+impl AbcNote {
+    fn pitch(&self) -> i32 {
+        self.octave * 12 + SEMITONES[letter_index(self.letter)] + self.acc
+    }
+    /// MMLの音名 (ex) c+ / b-
+    fn name(&self) -> String {
+        let mut s = self.letter.to_string();
+        let mark = if self.acc > 0 { "+" } else { "-" };
+        s.push_str(&mark.repeat(self.acc.unsigned_abs() as usize));
+        s
+    }
+}
+
+fn letter_index(letter: char) -> usize {
+    "cdefgab".find(letter).unwrap_or(0)
+}
+
+/// 小節線の種類
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Bar {
+    Plain,
+    /// 複縦線・終止線 (繰り返しの区切り)
+    Section,
+    RepeatStart,
+    RepeatEnd,
+    /// n番カッコ
+    Ending(Vec<u32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ItemKind {
+    /// 単音か和音
+    Note {
+        notes: Vec<AbcNote>,
+        len: Frac,
+        tie: bool,
+    },
+    Rest(Frac),
+    /// そのまま書き出すMML (ex) Tempo(120)
+    Command(String),
+    Bar(Bar),
+}
+
+/// 声部の中の要素 (pos/sizeは元のABCでの文字位置)
+#[derive(Debug, Clone, PartialEq)]
+struct Item {
+    kind: ItemKind,
+    pos: usize,
+    size: usize,
+    line: isize,
+}
+
+/// 声部ごとの設定と読み込んだ要素
+#[derive(Debug, Clone)]
+struct Voice {
+    id: String,
+    name: String,
+    channel: Option<u8>,
+    unit: Frac,
+    key: [i32; 7],
+    meter: Option<(i64, i64)>,
+    items: Vec<Item>,
+    /// 小節内で付けた臨時記号 (音名, オクターブ) => 臨時記号
+    bar_acc: HashMap<(char, i32), i32>,
+    /// 連符の残りの音符数と長さの倍率
+    tuplet: Option<(usize, Frac)>,
+    /// 付点リズム(>)で次の音符に掛ける倍率
+    broken: Option<Frac>,
+}
+
+/// 曲のヘッダで決まる初期値
+struct Defaults {
+    unit: Option<Frac>,
+    key: [i32; 7],
+    meter: Option<(i64, i64)>,
+    program: Option<i64>,
+}
+
+impl Defaults {
+    /// L: がないときは拍子から決める (3/4未満なら16分音符、それ以外は8分音符)
+    fn unit(&self) -> Frac {
+        if let Some(unit) = self.unit {
+            return unit;
+        }
+        match self.meter {
+            Some((n, d)) if n * 4 < d * 3 => Frac::new(1, 16),
+            _ => Frac::new(1, 8),
+        }
+    }
+}
+
+/// 調号を読む (ex) G / Dm / A mix / Bb dor / D ^g =c / none
+fn parse_key(value: &str, current: [i32; 7]) -> Result<[i32; 7], String> {
+    let tokens: Vec<&str> = value
+        .split_whitespace()
+        .filter(|t| !t.contains('=') || t.starts_with('='))
+        .collect();
+    let mut key = current;
+    let mut rest: &[&str] = &tokens;
+    match tokens.first().copied() {
+        None => return Ok(current),
+        Some(t) if t.eq_ignore_ascii_case("none") || t == "HP" => {
+            key = [0; 7];
+            rest = &tokens[1..];
+        }
+        Some("Hp") => {
+            key = fifths_to_key(2);
+            rest = &tokens[1..];
+        }
+        Some(t) if t.starts_with(['A', 'B', 'C', 'D', 'E', 'F', 'G']) => {
+            let tonic = t.chars().next().unwrap_or('C');
+            let mut fifths = [0, 2, 4, -1, 1, 3, 5][letter_index(tonic.to_ascii_lowercase())];
+            let mut mode = &t[1..];
+            if let Some(m) = mode.strip_prefix('#') {
+                fifths += 7;
+                mode = m;
+            } else if let Some(m) = mode.strip_prefix('b') {
+                fifths -= 7;
+                mode = m;
+            }
+            rest = &tokens[1..];
+            // 旋法は続けて書くことも、空白で区切ることもできる
+            if mode.is_empty() {
+                if let Some(t) = rest.first() {
+                    if t.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                        mode = t;
+                        rest = &rest[1..];
+                    }
+                }
+            }
+            let mode = mode.to_ascii_lowercase();
+            let shift = match mode.get(0..3).unwrap_or(&mode) {
+                "" | "maj" | "ion" | "exp" => 0,
+                "m" | "min" | "aeo" => -3,
+                "mix" => -1,
+                "dor" => -2,
+                "phr" => -4,
+                "lyd" => 1,
+                "loc" => -5,
+                _ => return Err(format!("unknown mode \"{}\"", mode)),
+            };
+            key = fifths_to_key((fifths + shift).clamp(-7, 7));
+        }
+        Some(_) => {}
+    }
+    // 個別に指定した臨時記号 (ex) ^f _b =c
+    for t in rest {
+        let acc = match t.chars().next() {
+            Some('^') => t.chars().take_while(|c| *c == '^').count() as i32,
+            Some('_') => -(t.chars().take_while(|c| *c == '_').count() as i32),
+            Some('=') => 0,
+            _ => continue,
+        };
+        match t.chars().last().map(|c| c.to_ascii_lowercase()) {
+            Some(c @ 'a'..='g') => key[letter_index(c)] = acc,
+            _ => return Err(format!("invalid accidental \"{}\"", t)),
+        }
+    }
+    Ok(key)
+}
+
+fn fifths_to_key(fifths: i32) -> [i32; 7] {
+    let mut key = [0; 7];
+    for i in 0..fifths.unsigned_abs() as usize {
+        if fifths > 0 {
+            key[letter_index(SHARP_ORDER[i])] = 1;
+        } else {
+            key[letter_index(FLAT_ORDER[i])] = -1;
+        }
+    }
+    key
+}
+
+/// 拍子を読む (ex) 6/8 / C / C| / 2+3/8 / none
+fn parse_meter(value: &str) -> Result<Option<(i64, i64)>, String> {
+    let value = value.trim();
+    match value {
+        "" | "none" => return Ok(None),
+        "C" => return Ok(Some((4, 4))),
+        "C|" => return Ok(Some((2, 2))),
+        _ => {}
+    }
+    let err = || format!("invalid meter \"{}\"", value);
+    let (num, den) = value.split_once('/').ok_or_else(err)?;
+    let mut n = 0;
+    for part in num.trim_matches(|c| c == '(' || c == ')').split('+') {
+        n += part.trim().parse::<i64>().map_err(|_| err())?;
+    }
+    let d = den.trim().parse::<i64>().map_err(|_| err())?;
+    if n <= 0 || d <= 0 || d > 64 || n > 64 {
+        return Err(err());
+    }
+    Ok(Some((n, d)))
+}
+
+/// 分数を読む (ex) 1/8 / 3/4 / 1
+fn parse_frac(value: &str) -> Option<Frac> {
+    let value = value.trim();
+    let (n, d) = value.split_once('/').unwrap_or((value, "1"));
+    let n = n.trim().parse::<i64>().ok()?;
+    let d = d.trim().parse::<i64>().ok()?;
+    if n <= 0 || d <= 0 || n > 1000 || d > 1000 {
+        return None;
+    }
+    Some(Frac::new(n, d))
+}
+
+/// Q: から四分音符のテンポを求める (ex) 1/4=120 / 3/8=80 / "Allegro" 1/4=120 / 120
+fn parse_tempo(value: &str, unit: Frac) -> Result<Option<i64>, String> {
+    // 引用符の中は表示用の文字列
+    let mut text = String::new();
+    for (i, part) in value.split('"').enumerate() {
+        if i % 2 == 0 {
+            text.push_str(part);
+            text.push(' ');
+        }
+    }
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let err = || format!("invalid tempo \"{}\"", value.trim());
+    let (beat, bpm) = match text.split_once('=') {
+        Some((beats, bpm)) => {
+            let mut beat = Frac::int(0);
+            for b in beats.split_whitespace() {
+                let f = match b {
+                    "C" | "L" => unit,
+                    _ => parse_frac(b).ok_or_else(err)?,
+                };
+                beat = beat.add(f);
+            }
+            if beat.num == 0 {
+                beat = unit;
+            }
+            (beat, bpm.trim())
+        }
+        None => (unit, text),
+    };
+    let bpm = bpm.parse::<i64>().map_err(|_| err())?;
+    let tempo = Frac::int(bpm).mul(beat).mul(Frac::int(4));
+    let tempo = (tempo.num * 2 + tempo.den).div_euclid(tempo.den * 2);
+    if !(1..=10000).contains(&tempo) {
+        return Err(err());
+    }
+    Ok(Some(tempo))
+}
+
+/// 強弱記号のベロシティ
+pub(crate) fn dynamics_velocity(name: &str) -> Option<isize> {
+    let v = match name {
+        "pppp" => 15,
+        "ppp" => 30,
+        "pp" => 45,
+        "p" => 60,
+        "mp" => 75,
+        "mf" => 90,
+        "f" => 105,
+        "ff" => 115,
+        "fff" | "ffff" => 127,
+        _ => return None,
+    };
+    Some(v)
+}
+
+/// ABCの読み込み
+struct Parser {
+    chars: Vec<char>,
+    voices: Vec<Voice>,
+    cur: Option<usize>,
+    defaults: Defaults,
+    /// TR(0) に書くヘッダの命令
+    header_items: Vec<Item>,
+    title: Option<String>,
+    tune_started: bool,
+    in_body: bool,
+    messages: Vec<AbcMessage>,
+    /// 読み込み中の行 (行番号, 行頭の位置)
+    line: (isize, usize),
+}
+
+impl Parser {
+    fn new(src: &str) -> Self {
+        Self {
+            chars: src.chars().collect(),
+            voices: vec![],
+            cur: None,
+            defaults: Defaults {
+                unit: None,
+                key: [0; 7],
+                meter: None,
+                program: None,
+            },
+            header_items: vec![],
+            title: None,
+            tune_started: false,
+            in_body: false,
+            messages: vec![],
+            line: (0, 0),
+        }
+    }
+
+    fn message(&mut self, severity: Severity, pos: usize, text: String) {
+        self.messages.push(AbcMessage {
+            severity,
+            line: self.line.0,
+            column: pos.saturating_sub(self.line.1) as isize,
+            text,
+        });
+    }
+
+    fn error(&mut self, pos: usize, text: String) {
+        self.message(Severity::Error, pos, text);
+    }
+
+    fn text(&self, start: usize, end: usize) -> String {
+        self.chars[start..end].iter().collect()
+    }
+
+    fn find(&self, from: usize, end: usize, c: char) -> Option<usize> {
+        (from..end).find(|&i| self.chars[i] == c)
+    }
+
+    fn peek(&self, i: usize, end: usize) -> char {
+        if i < end {
+            self.chars[i]
+        } else {
+            '\0'
+        }
+    }
+
+    fn read_number(&self, i: &mut usize, end: usize) -> Option<i64> {
+        let start = *i;
+        let mut v: i64 = 0;
+        while *i < end && self.chars[*i].is_ascii_digit() {
+            v = (v * 10 + self.chars[*i].to_digit(10).unwrap_or(0) as i64).min(100_000);
+            *i += 1;
+        }
+        if *i == start {
+            None
+        } else {
+            Some(v)
+        }
+    }
+
+    /// 現在の声部 (V: がなければ最初の声部を作る)
+    fn voice(&mut self) -> &mut Voice {
+        let no = match self.cur {
+            Some(no) => no,
+            None => self.add_voice(""),
+        };
+        self.cur = Some(no);
+        &mut self.voices[no]
+    }
+
+    fn add_voice(&mut self, id: &str) -> usize {
+        let mut items = vec![];
+        if let Some(program) = self.defaults.program {
+            items.push(Item {
+                kind: ItemKind::Command(format!("Voice({})", program + 1)),
+                pos: self.line.1,
+                size: 0,
+                line: self.line.0,
+            });
+        }
+        self.voices.push(Voice {
+            id: id.to_string(),
+            name: String::new(),
+            channel: None,
+            unit: self.defaults.unit(),
+            key: self.defaults.key,
+            meter: self.defaults.meter,
+            items,
+            bar_acc: HashMap::new(),
+            tuplet: None,
+            broken: None,
+        });
+        self.voices.len() - 1
+    }
+
+    fn push_item(&mut self, kind: ItemKind, pos: usize, size: usize) {
+        let line = self.line.0;
+        let item = Item {
+            kind,
+            pos,
+            size,
+            line,
+        };
+        if self.in_body {
+            self.voice().items.push(item);
+        } else {
+            self.header_items.push(item);
+        }
+    }
+
+    /// 本体を読み始める (K: の後)
+    fn start_body(&mut self) {
+        if self.in_body {
+            return;
+        }
+        self.in_body = true;
+        // ヘッダで宣言した声部は、ヘッダの最後の値を使う
+        let unit = self.defaults.unit();
+        for v in self.voices.iter_mut() {
+            v.unit = unit;
+            v.key = self.defaults.key;
+            v.meter = self.defaults.meter;
+        }
+        if !self.voices.is_empty() {
+            self.cur = Some(0);
+        }
+    }
+
+    fn parse(&mut self) {
+        let len = self.chars.len();
+        let mut start = 0;
+        let mut lineno = 0;
+        while start <= len {
+            let mut end = self.find(start, len, '\n').unwrap_or(len);
+            let next = end + 1;
+            if end > start && self.chars[end - 1] == '\r' {
+                end -= 1;
+            }
+            self.line = (lineno, start);
+            if !self.parse_line(start, end) {
+                return;
+            }
+            start = next;
+            lineno += 1;
+        }
+    }
+
+    /// 1行を読む。次の曲が始まったらfalse
+    fn parse_line(&mut self, start: usize, end: usize) -> bool {
+        // 指示行 (ex) %%MIDI program 40
+        if self.text(start, end.min(start + 2)) == "%%" {
+            self.directive(start, end);
+            return true;
+        }
+        // コメントを取り除く (\% はそのまま)
+        let mut end = end;
+        if let Some(p) = (start..end)
+            .find(|&i| self.chars[i] == '%' && (i == start || self.chars[i - 1] != '\\'))
+        {
+            end = p;
+        }
+        if end - start >= 2
+            && self.chars[start + 1] == ':'
+            && (self.chars[start].is_ascii_alphabetic() || self.chars[start] == '+')
+        {
+            let field = self.chars[start];
+            if field == 'X' {
+                if self.tune_started && (self.in_body || !self.voices.is_empty()) {
+                    let msg = "only the first tune is converted".to_string();
+                    self.message(Severity::Warning, start, msg);
+                    return false;
+                }
+                self.tune_started = true;
+                return true;
+            }
+            let value = self.text(start + 2, end);
+            self.field(field, value.trim(), start, end - start);
+            return true;
+        }
+        if self.text(start, end).trim().is_empty() {
+            return true;
+        }
+        // K: がなくても音符があれば本体とみなす
+        self.tune_started = true;
+        self.start_body();
+        self.music(start, end);
+        true
+    }
+
+    fn directive(&mut self, start: usize, end: usize) {
+        let text = self.text(start + 2, end);
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.first() != Some(&"MIDI") {
+            return;
+        }
+        let nums: Vec<i64> = words
+            .iter()
+            .skip(2)
+            .filter_map(|w| w.parse().ok())
+            .collect();
+        match (words.get(1).copied(), nums.last()) {
+            (Some("program"), Some(&program)) if (0..128).contains(&program) => {
+                if self.in_body {
+                    let cmd = format!("Voice({})", program + 1);
+                    self.push_item(ItemKind::Command(cmd), start, end - start);
+                } else {
+                    self.defaults.program = Some(program);
+                }
+            }
+            (Some("channel"), Some(&ch)) if (1..=16).contains(&ch) => self.set_channel(ch as u8),
+            (Some("program" | "channel"), _) => {
+                self.error(start, format!("invalid directive \"{}\"", text.trim()));
+            }
+            _ => {}
+        }
+    }
+
+    /// 声部のチャンネルを変える (ヘッダでは声部が決まらないので無視する)
+    fn set_channel(&mut self, ch: u8) {
+        if self.in_body {
+            self.voice().channel = Some(ch);
+        }
+    }
+
+    /// 情報フィールド (行頭の X: または本体中の [K:...])
+    fn field(&mut self, field: char, value: &str, pos: usize, size: usize) {
+        match field {
+            'T' if self.title.is_none() && !self.in_body => self.title = Some(value.to_string()),
+            'M' => match parse_meter(value) {
+                Ok(meter) => {
+                    if self.in_body {
+                        self.voice().meter = meter;
+                    } else {
+                        self.defaults.meter = meter;
+                    }
+                    if let Some((n, d)) = meter {
+                        let cmd = format!("TimeSignature({},{})", n, d);
+                        self.push_item(ItemKind::Command(cmd), pos, size);
+                    }
+                }
+                Err(msg) => self.error(pos, msg),
+            },
+            'L' => match parse_frac(value) {
+                Some(unit) => {
+                    if self.in_body {
+                        self.voice().unit = unit;
+                    } else {
+                        self.defaults.unit = Some(unit);
+                    }
+                }
+                None => self.error(pos, format!("invalid unit note length \"{}\"", value)),
+            },
+            'Q' => {
+                let unit = if self.in_body {
+                    self.voice().unit
+                } else {
+                    self.defaults.unit()
+                };
+                match parse_tempo(value, unit) {
+                    Ok(Some(tempo)) => {
+                        let cmd = format!("Tempo({})", tempo);
+                        self.push_item(ItemKind::Command(cmd), pos, size);
+                    }
+                    Ok(None) => {}
+                    Err(msg) => self.error(pos, msg),
+                }
+            }
+            'K' => {
+                let current = if self.in_body {
+                    self.voice().key
+                } else {
+                    self.defaults.key
+                };
+                match parse_key(value, current) {
+                    Ok(key) => {
+                        if self.in_body {
+                            self.voice().key = key;
+                        } else {
+                            self.defaults.key = key;
+                        }
+                    }
+                    Err(msg) => self.error(pos, msg),
+                }
+                self.start_body();
+            }
+            'V' => self.select_voice(value),
+            _ => {}
+        }
+    }
+
+    /// V:id name="..." で声部を切り替える
+    fn select_voice(&mut self, value: &str) {
+        let id = value.split_whitespace().next().unwrap_or("").to_string();
+        let no = match self.voices.iter().position(|v| v.id == id) {
+            Some(no) => no,
+            None => self.add_voice(&id),
+        };
+        for key in ["name=", "nm="] {
+            if let Some(p) = value.find(key) {
+                let rest = &value[p + key.len()..];
+                let name = match rest.strip_prefix('"') {
+                    Some(r) => r.split('"').next().unwrap_or(""),
+                    None => rest.split_whitespace().next().unwrap_or(""),
+                };
+                self.voices[no].name = name.to_string();
+                break;
+            }
+        }
+        if self.in_body {
+            self.cur = Some(no);
+        }
+    }
+
+    /// 音符や小節線の並んだ行を読む
+    fn music(&mut self, start: usize, end: usize) {
+        let mut i = start;
+        while i < end {
+            let c = self.chars[i];
+            let begin = i;
+            match c {
+                ' ' | '\t' | '`' | 'y' | '\\' | ')' => i += 1,
+                // 装飾記号は読み飛ばす
+                '.' | '~' | 'H' | 'L' | 'M' | 'O' | 'P' | 'S' | 'T' | 'u' | 'v' => i += 1,
+                // コードネーム・注釈
+                '"' => match self.find(i + 1, end, '"') {
+                    Some(j) => i = j + 1,
+                    None => {
+                        self.error(i, "unterminated string".to_string());
+                        i = end;
+                    }
+                },
+                '!' | '+' => match self.find(i + 1, end, c) {
+                    Some(j) => {
+                        let name = self.text(i + 1, j);
+                        if let Some(v) = dynamics_velocity(&name) {
+                            self.push_item(ItemKind::Command(format!("v{}", v)), i, j + 1 - i);
+                        }
+                        i = j + 1;
+                    }
+                    None => {
+                        self.error(i, "unterminated decoration".to_string());
+                        i = end;
+                    }
+                },
+                // 装飾音は鳴らさない
+                '{' => match self.find(i + 1, end, '}') {
+                    Some(j) => i = j + 1,
+                    None => {
+                        self.error(i, "unterminated grace notes".to_string());
+                        i = end;
+                    }
+                },
+                '(' => {
+                    if self.peek(i + 1, end).is_ascii_digit() {
+                        self.tuplet(&mut i, end);
+                    } else {
+                        i += 1; // スラー
+                    }
+                }
+                '[' => {
+                    let next = self.peek(i + 1, end);
+                    if next.is_ascii_digit() {
+                        i += 1;
+                        let list = self.read_ending(&mut i, end);
+                        self.push_item(ItemKind::Bar(Bar::Ending(list)), begin, i - begin);
+                    } else if next == '|' {
+                        self.bar(&mut i, end);
+                    } else if next.is_ascii_alphabetic() && self.peek(i + 2, end) == ':' {
+                        match self.find(i + 3, end, ']') {
+                            Some(j) => {
+                                let value = self.text(i + 3, j);
+                                self.field(next, value.trim(), i, j + 1 - i);
+                                i = j + 1;
+                            }
+                            None => {
+                                self.error(i, "unterminated inline field".to_string());
+                                i = end;
+                            }
+                        }
+                    } else {
+                        self.chord(&mut i, end);
+                    }
+                }
+                '|' | ':' => self.bar(&mut i, end),
+                '-' => {
+                    i += 1;
+                    match self.voice().items.last_mut() {
+                        Some(Item {
+                            kind: ItemKind::Note { tie, .. },
+                            ..
+                        }) => *tie = true,
+                        _ => self.error(begin, "tie without a note".to_string()),
+                    }
+                }
+                '>' | '<' => self.broken_rhythm(&mut i, end),
+                'z' | 'x' => {
+                    i += 1;
+                    let len = self.read_length(&mut i, end);
+                    let len = self.timed_length(len);
+                    self.push_item(ItemKind::Rest(len), begin, i - begin);
+                }
+                'Z' | 'X' => {
+                    i += 1;
+                    let bars = self.read_number(&mut i, end).unwrap_or(1);
+                    let (n, d) = self.voice().meter.unwrap_or((4, 4));
+                    let len = Frac::new(n * bars, d);
+                    self.push_item(ItemKind::Rest(len), begin, i - begin);
+                }
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    if let Some(note) = self.read_note(&mut i, end) {
+                        let len = self.read_length(&mut i, end);
+                        let len = self.timed_length(len);
+                        let kind = ItemKind::Note {
+                            notes: vec![note],
+                            len,
+                            tie: false,
+                        };
+                        self.push_item(kind, begin, i - begin);
+                    }
+                }
+                _ => {
+                    self.error(i, format!("unexpected character \"{}\"", c));
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// 音高を読む (ex) ^c' _B, =e
+    fn read_note(&mut self, i: &mut usize, end: usize) -> Option<AbcNote> {
+        let begin = *i;
+        let mut acc: Option<i32> = None;
+        while *i < end {
+            match self.chars[*i] {
+                '^' => acc = Some(acc.unwrap_or(0) + 1),
+                '_' => acc = Some(acc.unwrap_or(0) - 1),
+                '=' => acc = Some(0),
+                _ => break,
+            }
+            *i += 1;
+        }
+        let c = self.peek(*i, end);
+        if !matches!(c, 'A'..='G' | 'a'..='g') {
+            self.error(begin, "accidental without a note".to_string());
+            return None;
+        }
+        *i += 1;
+        let mut octave = if c.is_ascii_uppercase() { 5 } else { 6 };
+        while *i < end {
+            match self.chars[*i] {
+                '\'' => octave += 1,
+                ',' => octave -= 1,
+                _ => break,
+            }
+            *i += 1;
+        }
+        let letter = c.to_ascii_lowercase();
+        let v = self.voice();
+        let acc = match acc {
+            Some(acc) => {
+                v.bar_acc.insert((letter, octave), acc);
+                acc
+            }
+            None => match v.bar_acc.get(&(letter, octave)) {
+                Some(acc) => *acc,
+                None => v.key[letter_index(letter)],
+            },
+        };
+        Some(AbcNote {
+            letter,
+            acc,
+            octave,
+        })
+    }
+
+    /// 音長の倍率を読む (ex) 2 / 3/2 / / / // / 3/
+    fn read_length(&mut self, i: &mut usize, end: usize) -> Frac {
+        let num = self.read_number(i, end).unwrap_or(1);
+        let mut slashes = 0;
+        while self.peek(*i, end) == '/' {
+            slashes += 1;
+            *i += 1;
+        }
+        if slashes == 0 {
+            return Frac::int(num.max(1));
+        }
+        let den = match self.read_number(i, end) {
+            Some(d) if slashes == 1 => d.max(1),
+            _ => 1 << slashes.min(6),
+        };
+        Frac::new(num.max(1), den)
+    }
+
+    /// 単位音長・付点リズム・連符を反映した長さ
+    fn timed_length(&mut self, mult: Frac) -> Frac {
+        let v = self.voice();
+        let mut len = v.unit.mul(mult);
+        if let Some(f) = v.broken.take() {
+            len = len.mul(f);
+        }
+        if let Some((n, f)) = v.tuplet {
+            len = len.mul(f);
+            v.tuplet = if n > 1 { Some((n - 1, f)) } else { None };
+        }
+        len
+    }
+
+    /// 和音 (ex) [CEG]2 / [C2E2]
+    fn chord(&mut self, i: &mut usize, end: usize) {
+        let begin = *i;
+        *i += 1;
+        let mut notes = vec![];
+        let mut inner: Option<Frac> = None;
+        let mut tie = false;
+        loop {
+            match self.peek(*i, end) {
+                ']' => {
+                    *i += 1;
+                    break;
+                }
+                ' ' => *i += 1,
+                '-' => {
+                    tie = true;
+                    *i += 1;
+                }
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    if let Some(note) = self.read_note(i, end) {
+                        notes.push(note);
+                    }
+                    let len = self.read_length(i, end);
+                    inner.get_or_insert(len);
+                }
+                _ => {
+                    self.error(begin, "unterminated chord".to_string());
+                    return;
+                }
+            }
+        }
+        if notes.is_empty() {
+            self.error(begin, "empty chord".to_string());
+            return;
+        }
+        let outer = self.read_length(i, end);
+        let len = self.timed_length(inner.unwrap_or(Frac::int(1)).mul(outer));
+        self.push_item(ItemKind::Note { notes, len, tie }, begin, *i - begin);
+    }
+
+    /// 連符 (ex) (3abc / (3:2:3 / (5::5
+    fn tuplet(&mut self, i: &mut usize, end: usize) {
+        let begin = *i;
+        *i += 1;
+        let p = self.read_number(i, end).unwrap_or(0);
+        let mut q = None;
+        let mut r = None;
+        if self.peek(*i, end) == ':' {
+            *i += 1;
+            q = self.read_number(i, end);
+            if self.peek(*i, end) == ':' {
+                *i += 1;
+                r = self.read_number(i, end);
+            }
+        }
+        if !(2..=9).contains(&p) {
+            self.error(begin, format!("invalid tuplet ({}", p));
+            return;
+        }
+        let compound = matches!(self.voice().meter, Some((n, _)) if n % 3 == 0 && n > 3);
+        let q = q.unwrap_or(match p {
+            3 | 6 => 2,
+            2 | 4 | 8 => 3,
+            _ if compound => 3,
+            _ => 2,
+        });
+        let r = r.unwrap_or(p).max(1);
+        self.voice().tuplet = Some((r as usize, Frac::new(q.max(1), p)));
+    }
+
+    /// 付点リズム (ex) a>b / a<<b
+    fn broken_rhythm(&mut self, i: &mut usize, end: usize) {
+        let begin = *i;
+        let c = self.chars[*i];
+        let mut n = 0;
+        while self.peek(*i, end) == c {
+            n += 1;
+            *i += 1;
+        }
+        let short = Frac::new(1, 1 << n.min(4));
+        let long = Frac::int(2).add(Frac::new(-1, 1 << n.min(4)));
+        let (prev, next) = if c == '>' {
+            (long, short)
+        } else {
+            (short, long)
+        };
+        let v = self.voice();
+        match v.items.last_mut() {
+            Some(Item {
+                kind: ItemKind::Note { len, .. } | ItemKind::Rest(len),
+                ..
+            }) => {
+                *len = len.mul(prev);
+                v.broken = Some(next);
+            }
+            _ => self.error(begin, "broken rhythm without a note".to_string()),
+        }
+    }
+
+    /// n番カッコの番号 (ex) 1 / 1,2 / 1-3
+    fn read_ending(&mut self, i: &mut usize, end: usize) -> Vec<u32> {
+        let mut list = vec![];
+        while let Some(n) = self.read_number(i, end) {
+            let n = n as u32;
+            if self.peek(*i, end) == '-' && self.peek(*i + 1, end).is_ascii_digit() {
+                *i += 1;
+                let to = self.read_number(i, end).unwrap_or(0) as u32;
+                list.extend(n..=to.min(n + 16));
+            } else {
+                list.push(n);
+            }
+            if self.peek(*i, end) != ',' {
+                break;
+            }
+            *i += 1;
+        }
+        list
+    }
+
+    /// 小節線 (ex) | || |] [| |: :| :: |1 :|2
+    fn bar(&mut self, i: &mut usize, end: usize) {
+        let begin = *i;
+        let mut s = String::new();
+        while *i < end {
+            let c = self.chars[*i];
+            let is_bar = matches!(c, '|' | ':' | ']') || (c == '[' && s.is_empty());
+            if !is_bar {
+                break;
+            }
+            s.push(c);
+            *i += 1;
+        }
+        let size = *i - begin;
+        self.voice().bar_acc.clear();
+        let mut bars = vec![];
+        if !s.contains('|') {
+            if s.len() < 2 || s.contains(['[', ']']) {
+                self.error(begin, format!("unexpected bar line \"{}\"", s));
+                return;
+            }
+            bars.push(Bar::RepeatEnd);
+            bars.push(Bar::RepeatStart);
+        } else {
+            let end_repeat = s.starts_with(':');
+            let start_repeat = s.ends_with(':');
+            if end_repeat {
+                bars.push(Bar::RepeatEnd);
+            }
+            if s.contains("||") || s.contains(['[', ']']) {
+                bars.push(Bar::Section);
+            }
+            if start_repeat {
+                bars.push(Bar::RepeatStart);
+            }
+            if bars.is_empty() {
+                bars.push(Bar::Plain);
+            }
+        }
+        for bar in bars {
+            self.push_item(ItemKind::Bar(bar), begin, size);
+        }
+        // |1 :|2 のように続けて番号を書いたn番カッコ
+        if self.peek(*i, end).is_ascii_digit() {
+            let begin = *i;
+            let list = self.read_ending(i, end);
+            self.push_item(ItemKind::Bar(Bar::Ending(list)), begin, *i - begin);
+        }
+    }
+}
+
+/// 繰り返しを展開する
+fn unroll(items: &[Item]) -> Vec<Item> {
+    let mut res = vec![];
+    let mut start = 0;
+    let mut pass = 1;
+    let mut skipping = false;
+    let mut jumped: HashMap<usize, u32> = HashMap::new();
+    let mut i = 0;
+    while i < items.len() {
+        let item = &items[i];
+        match &item.kind {
+            ItemKind::Bar(Bar::RepeatStart) => {
+                start = i + 1;
+                pass = 1;
+                skipping = false;
+            }
+            ItemKind::Bar(Bar::Section) => {
+                start = i + 1;
+                pass = 1;
+                skipping = false;
+            }
+            ItemKind::Bar(Bar::Ending(list)) => skipping = !list.contains(&pass),
+            ItemKind::Bar(Bar::RepeatEnd) if !skipping => {
+                // 後ろのn番カッコの番号だけ繰り返す (ex) |1,2 ... :|3
+                let times = match items.get(i + 1).map(|n| &n.kind) {
+                    Some(ItemKind::Bar(Bar::Ending(list))) => {
+                        list.iter().max().copied().unwrap_or(2).max(2) - 1
+                    }
+                    _ => 1,
+                };
+                let count = jumped.entry(i).or_insert(0);
+                if *count < times {
+                    *count += 1;
+                    pass += 1;
+                    i = start;
+                    continue;
+                }
+                start = i + 1;
+            }
+            ItemKind::Bar(_) => {}
+            _ if skipping => {}
+            _ => res.push(item.clone()),
+        }
+        i += 1;
+    }
+    res
+}
+
+/// タイでつないだ同じ音をまとめる
+fn merge_ties(items: Vec<Item>) -> Vec<Item> {
+    let mut res: Vec<Item> = vec![];
+    for item in items {
+        if let (
+            Some(Item {
+                kind:
+                    ItemKind::Note {
+                        notes: prev_notes,
+                        len: prev_len,
+                        tie: prev_tie,
+                    },
+                ..
+            }),
+            ItemKind::Note { notes, len, tie },
+        ) = (res.last_mut(), &item.kind)
+        {
+            let same = prev_notes.len() == notes.len()
+                && prev_notes
+                    .iter()
+                    .zip(notes.iter())
+                    .all(|(a, b)| a.pitch() == b.pitch());
+            if *prev_tie && same {
+                *prev_len = prev_len.add(*len);
+                *prev_tie = *tie;
+                continue;
+            }
+        }
+        res.push(item);
+    }
+    res
+}
+
+/// 文字数を数えながら書き出すMML
+struct Output {
+    text: String,
+    chars: usize,
+    source_text: SourceText,
+}
+
+impl Output {
+    fn push(&mut self, s: &str) {
+        self.text.push_str(s);
+        self.chars += s.chars().count();
+    }
+    /// ここから書く内容が元のABCのどこに対応するか
+    fn mark(&mut self, item: &Item) {
+        self.source_text
+            .push_replace(self.chars, item.pos, item.size);
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "'"))
+}
+
+/// 声部の要素をMMLにする
+fn write_items(out: &mut Output, items: &[Item]) {
+    // 最も多い音長を基本の音長にする
+    let mut pos = Frac::int(0);
+    let mut steps: Vec<i64> = vec![];
+    let mut counts: HashMap<i64, usize> = HashMap::new();
+    for item in items {
+        if let ItemKind::Note { len, .. } | ItemKind::Rest(len) = &item.kind {
+            let end = pos.add(*len);
+            let step = end.ticks() - pos.ticks();
+            *counts.entry(step).or_default() += 1;
+            steps.push(step);
+            pos = end;
+        } else {
+            steps.push(0);
+        }
+    }
+    let mut length = counts
+        .iter()
+        .max_by_key(|(step, count)| (**count, **step))
+        .map(|(step, _)| *step)
+        .unwrap_or(ABC_TIMEBASE);
+    out.push(&format!("l%{}", length));
+    let mut octave = 0;
+    let mut line = items.first().map(|i| i.line).unwrap_or(0);
+    for (item, &step) in items.iter().zip(steps.iter()) {
+        out.push(if item.line != line { "\n" } else { " " });
+        line = item.line;
+        out.mark(item);
+        let len_str = |length: i64| {
+            if step == length {
+                String::new()
+            } else {
+                format!("%{}", step)
+            }
+        };
+        match &item.kind {
+            ItemKind::Note { notes, .. } if step > 0 => {
+                let mut s = String::new();
+                if notes.len() > 1 && step != length {
+                    s.push_str(&format!("l%{} ", step));
+                    length = step;
+                }
+                if notes.len() > 1 {
+                    s.push('\'');
+                }
+                for (i, n) in notes.iter().enumerate() {
+                    if i > 0 {
+                        s.push(' ');
+                    }
+                    if n.octave != octave {
+                        s.push_str(&format!("o{}", n.octave));
+                        octave = n.octave;
+                    }
+                    s.push_str(&n.name());
+                }
+                if notes.len() > 1 {
+                    s.push('\'');
+                } else {
+                    s.push_str(&len_str(length));
+                }
+                out.push(&s);
+            }
+            ItemKind::Rest(_) if step > 0 => out.push(&format!("r{}", len_str(length))),
+            ItemKind::Command(cmd) => out.push(cmd),
+            _ => {}
+        }
+    }
+    out.push("\n");
+}
+
+/// ABCをサクラのMMLに変換する
+pub fn convert(src: &str) -> AbcMml {
+    let mut p = Parser::new(src);
+    p.parse();
+    let mut out = Output {
+        text: String::new(),
+        chars: 0,
+        source_text: SourceText::with_original(src),
+    };
+    out.source_text.push_replace(0, 0, 0);
+    out.push(&format!(
+        "// Converted from ABC\nTimeBase({})\n",
+        ABC_TIMEBASE
+    ));
+    if p.title.is_some() || !p.header_items.is_empty() {
+        out.push("TR(0)");
+        if let Some(title) = &p.title {
+            out.push(&format!(" TrackName({})", quote(title)));
+        }
+        for item in p.header_items.iter() {
+            out.push(" ");
+            out.mark(item);
+            if let ItemKind::Command(cmd) = &item.kind {
+                out.push(cmd);
+            }
+        }
+        out.push("\n");
+    }
+    let mut channel = 0;
+    for (no, v) in p.voices.iter().enumerate() {
+        // 10チャンネル(ドラム)は使わない
+        channel = if channel == 9 { 11 } else { channel + 1 };
+        let ch = v
+            .channel
+            .map(|c| c as i64)
+            .unwrap_or(((channel - 1) % 16) + 1);
+        out.push(&format!("\nTR({}) CH({})", no + 1, ch));
+        if !v.name.is_empty() {
+            out.push(&format!(" TrackName({})", quote(&v.name)));
+        }
+        out.push("\n");
+        let items = merge_ties(unroll(&v.items));
+        write_items(&mut out, &items);
+    }
+    AbcMml {
+        mml: out.text,
+        source_text: out.source_text,
+        messages: p.messages,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 変換したMMLの本体 (ヘッダの2行を除く)
+    fn body(src: &str) -> String {
+        let res = convert(src);
+        assert_eq!(res.messages, vec![], "{}", res.mml);
+        let lines: Vec<&str> = res.mml.lines().skip(2).collect();
+        lines.join("\n").trim().to_string()
+    }
+
+    /// トラックごとの (時間, ノート番号, 長さ) の一覧
+    fn notes(src: &str) -> Vec<(usize, isize, isize, isize)> {
+        let res = convert(src);
+        let song = crate::compile(&res.mml, 0);
+        let smf = crate::smf::parse(&song.bin).unwrap();
+        let mut list = vec![];
+        for (tr, track) in smf.tracks.iter().enumerate() {
+            let mut on: HashMap<u8, usize> = HashMap::new();
+            for e in track.events.iter() {
+                match e.kind {
+                    crate::smf::SmfEventKind::NoteOn { key, velocity, .. } if velocity > 0 => {
+                        on.insert(key, e.tick);
+                    }
+                    crate::smf::SmfEventKind::NoteOff { key, .. } => {
+                        let start = on.remove(&key).unwrap();
+                        list.push((tr, start as isize, key as isize, (e.tick - start) as isize));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        list.sort();
+        list
+    }
+
+    #[test]
+    fn converts_header_and_notes() {
+        let src = "X:1\nT:Scale\nM:3/4\nL:1/8\nQ:1/4=100\nK:G\nGABc d2|e/f/g z2 g>f|\n";
+        assert_eq!(
+            body(src),
+            "TR(0) TrackName(\"Scale\") TimeSignature(3,4) Tempo(100)\n\nTR(1) CH(1)\nl%240 o5g a b o6c d%480 e%120 f+%120 g r%480 g%360 f+%120"
+        );
+    }
+
+    #[test]
+    fn key_signatures_and_accidentals() {
+        assert_eq!(parse_key("D", [0; 7]).unwrap(), [1, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(parse_key("Bb", [0; 7]).unwrap(), [0, 0, -1, 0, 0, 0, -1]);
+        assert_eq!(parse_key("Ador", [0; 7]).unwrap(), fifths_to_key(1));
+        assert_eq!(parse_key("E min", [0; 7]).unwrap(), fifths_to_key(1));
+        assert_eq!(
+            parse_key("C ^f _b", [0; 7]).unwrap(),
+            [0, 0, 0, 1, 0, 0, -1]
+        );
+        assert!(parse_key("Cxyz", [0; 7]).is_err());
+        // 小節内の臨時記号は小節線まで続く
+        assert_eq!(
+            body("K:F\nB ^c c =B B | c B,"),
+            "TR(1) CH(1)\nl%240 o5b- o6c+ c+ o5b b o6c o4b-"
+        );
+    }
+
+    #[test]
+    fn lengths_tuplets_and_ties() {
+        let list = notes("L:1/4\nK:C\n(3CDE F- F G/G/ [CE]2-[CE] A>B|");
+        let starts: Vec<(isize, isize)> = list.iter().map(|n| (n.1, n.3)).collect();
+        assert_eq!(
+            starts,
+            vec![
+                (0, 288),
+                (320, 288),
+                (640, 288),
+                (960, 864),
+                (1920, 216),
+                (2160, 216),
+                (2400, 1296),
+                (2400, 1296),
+                (3840, 648),
+                (4560, 216),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_are_expanded() {
+        let pitches = |src: &str| -> Vec<isize> { notes(src).iter().map(|n| n.2).collect() };
+        assert_eq!(pitches("K:C\n|:C D:|E|"), vec![60, 62, 60, 62, 64]);
+        assert_eq!(pitches("K:C\n|:C|1D:|2E|]F"), vec![60, 62, 60, 64, 65]);
+        assert_eq!(pitches("K:C\nC2::D2:|"), vec![60, 60, 62, 62]);
+        assert_eq!(pitches("K:C\n|:C|1,2D:|3E|"), vec![60, 62, 60, 62, 60, 64]);
+    }
+
+    #[test]
+    fn voices_become_tracks() {
+        let src = "X:1\nL:1/4\nV:1 name=\"Melody\"\nV:2\nK:C\nV:1\nc d\nV:2\nC,2\n%%MIDI program 32\nV:1\ne\n";
+        let res = convert(src);
+        assert_eq!(
+            res.mml.lines().skip(2).collect::<Vec<_>>().join("\n"),
+            "\nTR(1) CH(1) TrackName(\"Melody\")\nl%480 o6c d\ne\n\nTR(2) CH(2)\nl%960 o4c\nVoice(33)"
+        );
+        let list = notes(src);
+        assert_eq!(list[2], (1, 960, 76, 432));
+        assert_eq!(list[3], (2, 0, 48, 864));
+    }
+
+    #[test]
+    fn reports_errors_with_positions() {
+        let res = convert("K:C\nC D\nE ? [CE\n");
+        assert_eq!(res.messages.len(), 2);
+        assert_eq!((res.messages[0].line, res.messages[0].column), (2, 2));
+        assert_eq!(res.messages[0].text, "unexpected character \"?\"");
+        assert_eq!(res.messages[1].text, "unterminated chord");
+        let res = convert("X:1\nK:C\nC\n\nX:2\nK:D\nD\n");
+        assert_eq!(res.messages[0].severity, Severity::Warning);
+        assert_eq!(res.messages[0].line, 4);
+    }
+
+    #[test]
+    fn notes_map_back_to_the_abc_source() {
+        let res = convert("K:C\nC D [EG]2\n");
+        let span = |text: &str| {
+            let p = res.mml.rfind(text).unwrap();
+            let conv = res.mml[..p].chars().count();
+            res.source_text.span(0, conv, conv + text.chars().count())
+        };
+        let d = span("d");
+        assert_eq!((d.start, d.end, d.line, d.column), (6, 7, 1, 2));
+        let chord = span("'e g'");
+        assert_eq!((chord.start, chord.end), (8, 13));
+    }
+}
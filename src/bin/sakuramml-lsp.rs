@@ -0,0 +1,19 @@
+//! sakuramml-lsp - MMLの言語サーバー
+//!
+//! 標準入出力でJSON-RPC (Language Server Protocol) を話す。エディタからこのコマンドを起動して使う。
+#[cfg(target_arch = "wasm32")]
+fn main() {}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let code = match sakuramml::lsp::run(stdin.lock(), stdout.lock()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("[ERROR](0): {}", e);
+            1
+        }
+    };
+    std::process::exit(code);
+}
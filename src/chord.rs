@@ -0,0 +1,375 @@
+//! chord - コードネームの解析と和音の配置
+//!
+//! C Am7 F/A G7sus4 のようなコードネームを構成音にし、転回・広げ方・前の和音からの
+//! つながりを考えて音符番号を並べ、MMLにする。
+
+/// コードの出し方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChordStyle {
+    /// 和音 (ex) 'ceg'
+    Harmony,
+    /// 根音だけ
+    Root,
+    /// ベース音(分数コードの分母、なければ根音)だけ
+    Bass,
+    /// 構成音を下から順に弾く (音長をコードの音数で分ける)
+    Arpeggio,
+}
+
+impl ChordStyle {
+    /// 名前から作る (harmony / root / bass / arp)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "harmony" | "chord" => Some(ChordStyle::Harmony),
+            "root" => Some(ChordStyle::Root),
+            "bass" => Some(ChordStyle::Bass),
+            "arp" | "arpeggio" => Some(ChordStyle::Arpeggio),
+            _ => None,
+        }
+    }
+}
+
+/// コードの配置の設定 (トラックごと)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChordOptions {
+    pub style: ChordStyle,
+    /// 転回 (下の音を何音オクターブ上げるか)
+    pub inversion: isize,
+    /// 根音のオクターブ (-1ならトラックのオクターブ)
+    pub octave: isize,
+    /// 下から2番目・4番目…の音を何オクターブ上げるか (0なら密集配置)
+    pub spread: isize,
+    /// 前の和音からの動きが少ない転回を選ぶか
+    pub voice_leading: bool,
+}
+
+impl Default for ChordOptions {
+    fn default() -> Self {
+        Self {
+            style: ChordStyle::Harmony,
+            inversion: 0,
+            octave: -1,
+            spread: 0,
+            voice_leading: false,
+        }
+    }
+}
+
+/// コードネーム
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chord {
+    /// 根音 (0=C〜11=B)
+    pub root: isize,
+    /// 根音からの音程 (半音・昇順)
+    pub intervals: Vec<isize>,
+    /// 分数コードのベース音 (0=C〜11=B)
+    pub bass: Option<isize>,
+}
+
+/// 音名を読む (ex) C / F# / Bb --- (音高 0〜11, 残り)
+fn parse_pitch(s: &str) -> Option<(isize, &str)> {
+    let mut pitch: isize = match s.chars().next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let mut rest = &s[1..];
+    loop {
+        if let Some(r) = rest.strip_prefix('#') {
+            pitch += 1;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('b') {
+            pitch -= 1;
+            rest = r;
+        } else {
+            break;
+        }
+    }
+    Some((pitch.rem_euclid(12), rest))
+}
+
+/// 先頭が候補のどれかなら読み進める
+fn eat(s: &mut &str, words: &[&str]) -> bool {
+    for w in words {
+        if let Some(r) = s.strip_prefix(w) {
+            *s = r;
+            return true;
+        }
+    }
+    false
+}
+
+impl Chord {
+    /// コードネームを読む (ex) C / Am7 / F/A / G7sus4 / Bbmaj7 / Cm7b5 / C7(b9)
+    pub fn parse(name: &str) -> Result<Self, String> {
+        let err = || format!("unknown chord: {}", name);
+        // 分母が音名なら分数コード (6/9 は分数コードではない)
+        let (body, bass) = match name.rfind('/') {
+            Some(i) => match parse_pitch(&name[i + 1..]) {
+                Some((bass, "")) => (&name[..i], Some(bass)),
+                _ => (name, None),
+            },
+            None => (name, None),
+        };
+        let (root, mut s) = parse_pitch(body).ok_or_else(err)?;
+        let mut third = Some(4);
+        let mut fifth = Some(7);
+        let mut seventh: Option<isize> = None;
+        let mut tensions: Vec<isize> = vec![];
+        let mut major7 = false;
+        let mut dim = false;
+        // 種類
+        if eat(&mut s, &["maj", "Maj", "M", "△"]) {
+            major7 = true;
+        } else if eat(&mut s, &["min", "m", "-"]) {
+            third = Some(3);
+            // マイナー・メジャーセブンス (ex) CmM7
+            major7 = eat(&mut s, &["maj", "Maj", "M", "△"]);
+        } else if eat(&mut s, &["dim", "o"]) {
+            third = Some(3);
+            fifth = Some(6);
+            dim = true;
+        } else if eat(&mut s, &["aug", "+"]) {
+            fifth = Some(8);
+        } else if eat(&mut s, &["ø"]) {
+            third = Some(3);
+            fifth = Some(6);
+            seventh = Some(10);
+        }
+        let seventh_of = |major7: bool| {
+            if major7 {
+                11
+            } else if dim {
+                9
+            } else {
+                10
+            }
+        };
+        // 数字
+        if eat(&mut s, &["13"]) {
+            seventh = Some(seventh_of(major7));
+            tensions.extend([14, 21]);
+        } else if eat(&mut s, &["11"]) {
+            seventh = Some(seventh_of(major7));
+            tensions.extend([14, 17]);
+        } else if eat(&mut s, &["9"]) {
+            seventh = Some(seventh_of(major7));
+            tensions.push(14);
+        } else if eat(&mut s, &["7"]) {
+            seventh = Some(seventh_of(major7));
+        } else if eat(&mut s, &["6/9", "69"]) {
+            tensions.extend([9, 14]);
+        } else if eat(&mut s, &["6"]) {
+            tensions.push(9);
+        } else if eat(&mut s, &["5"]) {
+            third = None;
+        }
+        // 変化・追加
+        while !s.is_empty() {
+            if eat(&mut s, &["(", ")", ",", " "]) {
+                continue;
+            }
+            if eat(&mut s, &["sus2"]) {
+                third = Some(2);
+            } else if eat(&mut s, &["sus4", "sus"]) {
+                third = Some(5);
+            } else if eat(&mut s, &["add9", "add2"]) {
+                tensions.push(14);
+            } else if eat(&mut s, &["add11", "add4"]) {
+                tensions.push(17);
+            } else if eat(&mut s, &["add13", "add6"]) {
+                tensions.push(21);
+            } else if eat(&mut s, &["omit3", "no3"]) {
+                third = None;
+            } else if eat(&mut s, &["omit5", "no5"]) {
+                fifth = None;
+            } else if eat(&mut s, &["b5", "-5"]) {
+                fifth = Some(6);
+            } else if eat(&mut s, &["#5", "+5"]) {
+                fifth = Some(8);
+            } else if eat(&mut s, &["b9", "-9"]) {
+                tensions.push(13);
+            } else if eat(&mut s, &["#9", "+9"]) {
+                tensions.push(15);
+            } else if eat(&mut s, &["#11", "+11"]) {
+                tensions.push(18);
+            } else if eat(&mut s, &["b13", "-13"]) {
+                tensions.push(20);
+            } else if eat(&mut s, &["13"]) {
+                tensions.push(21);
+            } else if eat(&mut s, &["11"]) {
+                tensions.push(17);
+            } else if eat(&mut s, &["9"]) {
+                tensions.push(14);
+            } else {
+                return Err(err());
+            }
+        }
+        let mut intervals = vec![0];
+        intervals.extend(third);
+        intervals.extend(fifth);
+        intervals.extend(seventh);
+        intervals.extend(tensions);
+        intervals.sort();
+        intervals.dedup();
+        Ok(Self {
+            root,
+            intervals,
+            bass,
+        })
+    }
+
+    /// 和音の音符番号 (ベース音は含まない)
+    /// base はオクターブの先頭(ド)の音符番号、prev は前の和音
+    pub fn voicing(&self, base: isize, opt: &ChordOptions, prev: &[isize]) -> Vec<isize> {
+        let closed: Vec<isize> = self
+            .intervals
+            .iter()
+            .map(|i| base + self.root + i)
+            .collect();
+        let arrange = |inversion: isize, shift: isize| {
+            let mut notes = closed.clone();
+            for i in 0..inversion.max(0) as usize {
+                let n = i % notes.len();
+                notes[n] += 12;
+            }
+            notes.sort();
+            for (i, n) in notes.iter_mut().enumerate() {
+                *n += shift * 12;
+                if i % 2 == 1 {
+                    *n += opt.spread.max(0) * 12;
+                }
+            }
+            notes.sort();
+            notes
+        };
+        let notes = if opt.voice_leading && !prev.is_empty() {
+            // 前の和音の近くの音との距離の合計がいちばん小さい配置
+            let nearest =
+                |n: isize, list: &[isize]| list.iter().map(|p| (n - p).abs()).min().unwrap_or(0);
+            let mut best: Option<(isize, Vec<isize>)> = None;
+            for shift in [0, -1, 1] {
+                for inversion in 0..closed.len() as isize {
+                    let notes = arrange(inversion, shift);
+                    let cost = notes.iter().map(|n| nearest(*n, prev)).sum::<isize>()
+                        + prev.iter().map(|p| nearest(*p, &notes)).sum::<isize>();
+                    if best.as_ref().map(|b| cost < b.0).unwrap_or(true) {
+                        best = Some((cost, notes));
+                    }
+                }
+            }
+            best.map(|b| b.1).unwrap_or_default()
+        } else {
+            arrange(opt.inversion, 0)
+        };
+        notes
+            .into_iter()
+            .filter(|n| (0..=127).contains(n))
+            .collect()
+    }
+
+    /// 和音の下に置くベース音 (和音のいちばん下の音より低い、ベース音の高さの音)
+    pub fn bass_below(&self, notes: &[isize]) -> Option<isize> {
+        let pitch = self.bass?;
+        let low = *notes.iter().min()?;
+        let mut n = low - (low - pitch).rem_euclid(12);
+        if n >= low {
+            n -= 12;
+        }
+        Some(n).filter(|n| *n >= 0)
+    }
+}
+
+/// 音符番号をMMLの音名にする (オクターブが変わるときだけ o を書く)
+/// KeyFlagで臨時記号が付く音名には * を付けて打ち消す
+pub fn note_to_mml(no: isize, octave: &mut isize, key_flag: &[isize]) -> String {
+    const NAMES: [(&str, usize); 12] = [
+        ("c", 0),
+        ("c+", 0),
+        ("d", 2),
+        ("d+", 2),
+        ("e", 4),
+        ("f", 5),
+        ("f+", 5),
+        ("g", 7),
+        ("g+", 7),
+        ("a", 9),
+        ("a+", 9),
+        ("b", 11),
+    ];
+    let mut s = String::new();
+    let o = no.div_euclid(12);
+    if o != *octave {
+        s.push_str(&format!("o{}", o));
+        *octave = o;
+    }
+    let (name, natural) = NAMES[no.rem_euclid(12) as usize];
+    s.push_str(name);
+    if key_flag.get(natural).copied().unwrap_or(0) != 0 {
+        s.push('*');
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intervals(name: &str) -> Vec<isize> {
+        Chord::parse(name).unwrap().intervals
+    }
+
+    #[test]
+    fn parse_chord_symbols() {
+        assert_eq!(intervals("C"), vec![0, 4, 7]);
+        assert_eq!(intervals("Am7"), vec![0, 3, 7, 10]);
+        assert_eq!(intervals("G7sus4"), vec![0, 5, 7, 10]);
+        assert_eq!(intervals("Bbmaj7"), vec![0, 4, 7, 11]);
+        assert_eq!(intervals("Cm7b5"), vec![0, 3, 6, 10]);
+        assert_eq!(intervals("Cdim7"), vec![0, 3, 6, 9]);
+        assert_eq!(intervals("CmM7"), vec![0, 3, 7, 11]);
+        assert_eq!(intervals("C7(b9,#11)"), vec![0, 4, 7, 10, 13, 18]);
+        assert_eq!(intervals("C6/9"), vec![0, 4, 7, 9, 14]);
+        assert_eq!(intervals("C5"), vec![0, 7]);
+        let c = Chord::parse("F#m/A").unwrap();
+        assert_eq!((c.root, c.bass), (6, Some(9)));
+        assert!(Chord::parse("H7").is_err());
+        assert!(Chord::parse("Cxyz").is_err());
+    }
+
+    #[test]
+    fn voicing_with_inversion_spread_and_voice_leading() {
+        let c = Chord::parse("C").unwrap();
+        let mut opt = ChordOptions::default();
+        assert_eq!(c.voicing(60, &opt, &[]), vec![60, 64, 67]);
+        opt.inversion = 1;
+        assert_eq!(c.voicing(60, &opt, &[]), vec![64, 67, 72]);
+        opt.inversion = 0;
+        opt.spread = 1;
+        assert_eq!(c.voicing(60, &opt, &[]), vec![60, 67, 76]);
+        // C→F は F/C の形 (c f a) が近い
+        opt.spread = 0;
+        opt.voice_leading = true;
+        let f = Chord::parse("F").unwrap();
+        assert_eq!(f.voicing(60, &opt, &[60, 64, 67]), vec![60, 65, 69]);
+        // 分数コードのベース音は和音の下
+        let fa = Chord::parse("F/A").unwrap();
+        assert_eq!(fa.bass_below(&[65, 69, 72]), Some(57));
+    }
+
+    #[test]
+    fn note_names_cancel_key_flag() {
+        let mut octave = 5;
+        assert_eq!(note_to_mml(60, &mut octave, &[0; 12]), "c");
+        assert_eq!(note_to_mml(54, &mut octave, &[0; 12]), "o4f+");
+        assert_eq!(octave, 4);
+        let mut key_flag = [0; 12];
+        key_flag[5] = 1;
+        assert_eq!(note_to_mml(53, &mut octave, &key_flag), "f*");
+    }
+}
@@ -0,0 +1,133 @@
+//! Diagnostic - コンパイル時のエラー・警告
+//!
+//! ログ文字列 `[ERROR](12) ...` と同じ内容を、エディタなどから扱いやすい形で保持する。
+use crate::sakura_message::MessageKind;
+
+/// 重要度
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    /// Print命令などの情報
+    Info,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        }
+    }
+}
+
+/// エラー・警告の1件分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: MessageKind,
+    /// 行番号 (ログと同じく0始まり)
+    pub line: isize,
+    /// 列番号 (0始まり)
+    pub column: isize,
+    /// ソースの名前 (メインのソースは空文字列 / Includeしたファイルはその名前)
+    pub source: String,
+    /// 設定した言語のメッセージ
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(
+        severity: Severity,
+        kind: MessageKind,
+        line: isize,
+        source: &str,
+        message: String,
+    ) -> Self {
+        Self {
+            severity,
+            kind,
+            line,
+            column: 0,
+            source: source.to_string(),
+            message,
+        }
+    }
+    /// ログに書く位置 (ex) 12 / drums.mml:3
+    pub fn pos(&self) -> String {
+        if self.source.is_empty() {
+            return self.line.to_string();
+        }
+        format!("{}:{}", self.source, self.line)
+    }
+    /// 従来のログ形式の文字列 (ex) [ERROR](12) Runtime Error: ...
+    pub fn to_log(&self) -> String {
+        let label = match (self.severity, self.kind) {
+            (_, MessageKind::Print) => "PRINT",
+            (Severity::Error, _) => "ERROR",
+            (Severity::Warning, _) => "WARN",
+            (Severity::Info, _) => "INFO",
+        };
+        format!("[{}]({}) {}", label, self.pos(), self.message)
+    }
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"severity\":\"{}\",\"kind\":\"{:?}\",\"line\":{},\"column\":{},\"source\":{},\"message\":{}}}",
+            self.severity.as_str(),
+            self.kind,
+            self.line,
+            self.column,
+            json_str(&self.source),
+            json_str(&self.message),
+        )
+    }
+}
+
+/// Diagnosticの一覧をJSONの配列にする
+pub fn diagnostics_to_json(list: &[Diagnostic]) -> String {
+    let items: Vec<String> = list.iter().map(|d| d.to_json()).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn json_str(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostic_formats_log_and_json() {
+        let mut d = Diagnostic::new(
+            Severity::Error,
+            MessageKind::UnknownChar,
+            3,
+            "drums.mml",
+            "Unknown Character: \"!\"".to_string(),
+        );
+        d.column = 5;
+        assert_eq!(d.to_log(), "[ERROR](drums.mml:3) Unknown Character: \"!\"");
+        assert_eq!(
+            d.to_json(),
+            "{\"severity\":\"error\",\"kind\":\"UnknownChar\",\"line\":3,\"column\":5,\"source\":\"drums.mml\",\"message\":\"Unknown Character: \\\"!\\\"\"}"
+        );
+        let p = Diagnostic::new(Severity::Info, MessageKind::Print, 0, "", "hi".to_string());
+        assert_eq!(p.to_log(), "[PRINT](0) hi");
+        assert_eq!(diagnostics_to_json(&[]), "[]");
+    }
+}
@@ -0,0 +1,442 @@
+//! Formatter - MMLソースの整形
+//!
+//! lexer::lex_lossless のトークンを並べ直し、空白・インデント・トラックの桁揃えだけを整える。
+//! 行の数とコメントは変えず、整形の前後でMIDIが同じになることを確かめてから結果を返す。
+use crate::lexer::{lex_lossless, lex_source, SourceToken, SourceTokenKind};
+use crate::midi;
+use crate::runner::exec;
+use crate::song::{EventType, Song};
+use crate::tempo_map::TempoMap;
+use crate::token::{Token, TokenType};
+use std::collections::HashMap;
+
+/// 中身を整形するブロックの直前の命令 (ex) Sub{ ... } / IF(...){ ... } ELSE { ... }
+const CODE_BLOCK_WORDS: [&str; 6] = ["Sub", "SUB", "Div", "DIV", "Else", "ELSE"];
+/// 桁を揃えるトラック指定
+const TRACK_WORDS: [&str; 3] = ["Track", "TRACK", "TR"];
+/// 小節の先頭の音符の直前にあれば、小節線をその前に入れる命令 (ex) テンポ130;ドー → | テンポ130;ドー
+const BAR_LEAD_TOKENS: [TokenType; 13] = [
+    TokenType::Tempo,
+    TokenType::TempoChange,
+    TokenType::TimeSignature,
+    TokenType::KeySignature,
+    TokenType::Voice,
+    TokenType::Length,
+    TokenType::Octave,
+    TokenType::OctaveRel,
+    TokenType::QLen,
+    TokenType::Velocity,
+    TokenType::Timing,
+    TokenType::ControlChange,
+    TokenType::PitchBend,
+];
+
+/// 整形の設定
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// インデントの空白の数
+    pub indent: usize,
+    /// 小節の区切りに `|` を入れる
+    pub bar_lines: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            indent: 2,
+            bar_lines: false,
+        }
+    }
+}
+
+/// ブロック `{...}` の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    /// MMLとして整形する
+    Code,
+    /// 文字列やマクロの定義など、そのまま残す
+    Verbatim,
+}
+
+/// 整形後の1行
+#[derive(Debug, Clone, Default)]
+struct Line {
+    text: String,
+    /// インデントの深さ
+    depth: usize,
+    /// インデントを付け直す行か (falseなら元の行のまま)
+    code: bool,
+    /// 改行 ("\n" / "\r\n" / 最後の行は空)
+    eol: String,
+}
+
+/// ソースを整形する。整形でMIDIが変わる場合はエラーを返す
+pub fn format_mml(src: &str, options: &FormatOptions) -> Result<String, String> {
+    let source = if options.bar_lines {
+        insert_bar_lines(src)
+    } else {
+        src.to_string()
+    };
+    let res = layout(&lex_lossless(&source), options.indent);
+    if compile(src) != compile(&res) {
+        return Err("Formatting would change the MIDI output".to_string());
+    }
+    Ok(res)
+}
+
+fn compile(src: &str) -> Vec<u8> {
+    let mut song = Song::new();
+    let tokens = lex_source(&mut song, src);
+    exec(&mut song, &tokens);
+    midi::generate(&mut song)
+}
+
+/// `{` の直前のトークンから、ブロックの種類を決める
+fn block_kind(prev: Option<&SourceToken>) -> Block {
+    let t = match prev {
+        Some(t) => t,
+        None => return Block::Code,
+    };
+    match t.kind {
+        // IF(...){ / Function Name(...){ / Sub{ / {ceg}4 (連符)
+        SourceTokenKind::Text if t.text.ends_with('=') => Block::Verbatim,
+        SourceTokenKind::Word if !CODE_BLOCK_WORDS.contains(&t.text.as_str()) => Block::Verbatim,
+        SourceTokenKind::Str => Block::Verbatim,
+        _ => Block::Code,
+    }
+}
+
+/// 行末までの空白以外の直前のトークン
+fn prev_token(tokens: &[SourceToken], index: usize) -> Option<&SourceToken> {
+    tokens[..index]
+        .iter()
+        .rev()
+        .find(|t| t.kind != SourceTokenKind::Space)
+        .filter(|t| t.kind != SourceTokenKind::Newline)
+}
+
+fn layout(tokens: &[SourceToken], indent: usize) -> String {
+    let mut lines = vec![Line {
+        code: true,
+        ..Default::default()
+    }];
+    let mut stack: Vec<Block> = vec![];
+    let mut ended = false; // End命令以降はそのまま残す
+    let mut space = false; // 次のトークンの前に空白を1つ入れる
+    for (i, t) in tokens.iter().enumerate() {
+        let verbatim = ended || stack.last() == Some(&Block::Verbatim);
+        let depth = stack.iter().filter(|b| **b == Block::Code).count();
+        let line = lines.last_mut().unwrap();
+        match t.kind {
+            SourceTokenKind::Newline => {
+                line.eol = t.text.clone();
+                lines.push(Line {
+                    depth,
+                    code: !verbatim,
+                    ..Default::default()
+                });
+                space = false;
+                continue;
+            }
+            SourceTokenKind::Space if verbatim => line.text.push_str(&t.text),
+            SourceTokenKind::Space => space = !line.text.is_empty() || !line.code,
+            _ => {
+                if space {
+                    line.text.push(' ');
+                    space = false;
+                }
+                if !verbatim {
+                    match t.kind {
+                        SourceTokenKind::Open => stack.push(block_kind(prev_token(tokens, i))),
+                        SourceTokenKind::Close => {
+                            stack.pop();
+                            if line.text.is_empty() {
+                                line.depth = depth.saturating_sub(1);
+                            }
+                        }
+                        SourceTokenKind::Word if stack.is_empty() => {
+                            ended = t.text == "End" || t.text == "END";
+                        }
+                        _ => {}
+                    }
+                } else if t.kind == SourceTokenKind::Open {
+                    stack.push(Block::Verbatim);
+                } else if t.kind == SourceTokenKind::Close {
+                    stack.pop();
+                }
+                push_text(&mut lines, &t.text);
+            }
+        }
+    }
+    align_tracks(&mut lines);
+    let mut res = String::new();
+    for line in lines.iter() {
+        if line.code && !line.text.is_empty() {
+            res.push_str(&" ".repeat(line.depth * indent));
+        }
+        res.push_str(&line.text);
+        res.push_str(&line.eol);
+    }
+    res
+}
+
+/// トークンの文字列を追加する (複数行のコメントなどは、続きの行をそのまま残す)
+fn push_text(lines: &mut Vec<Line>, text: &str) {
+    let mut parts = text.split('\n').peekable();
+    while let Some(part) = parts.next() {
+        let line = lines.last_mut().unwrap();
+        if parts.peek().is_none() {
+            line.text.push_str(part);
+            break;
+        }
+        match part.strip_suffix('\r') {
+            Some(part) => {
+                line.text.push_str(part);
+                line.eol = "\r\n".to_string();
+            }
+            None => {
+                line.text.push_str(part);
+                line.eol = "\n".to_string();
+            }
+        }
+        lines.push(Line::default());
+    }
+}
+
+/// 行頭のトラック指定 (ex) TR(1) の長さ
+fn track_header_len(text: &str) -> Option<usize> {
+    let word = TRACK_WORDS
+        .iter()
+        .find(|w| text.starts_with(&format!("{}(", w)))?;
+    let rest = &text[word.len() + 1..];
+    let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 || !rest[digits..].starts_with(')') {
+        return None;
+    }
+    let len = word.len() + digits + 2;
+    // トラック指定だけの行は揃えない
+    if text[len..].trim().is_empty() {
+        return None;
+    }
+    Some(len)
+}
+
+/// 続けて書いたトラック指定の後ろの桁を揃える
+fn align_tracks(lines: &mut [Line]) {
+    let mut i = 0;
+    while i < lines.len() {
+        let mut j = i;
+        while j < lines.len()
+            && lines[j].code
+            && lines[j].depth == lines[i].depth
+            && track_header_len(&lines[j].text).is_some()
+        {
+            j += 1;
+        }
+        if j - i >= 2 {
+            let width = lines[i..j]
+                .iter()
+                .filter_map(|l| track_header_len(&l.text))
+                .max()
+                .unwrap_or(0);
+            for line in lines[i..j].iter_mut() {
+                let len = track_header_len(&line.text).unwrap_or(0);
+                let rest = line.text[len..].trim_start().to_string();
+                line.text = format!("{:width$} {}", &line.text[..len], rest, width = width);
+            }
+        }
+        i = j.max(i + 1);
+    }
+}
+
+/// 音符の先頭の位置から、小節線を入れる位置への対応を作る
+///
+/// 入れるのは本体に並んだトークンの先頭だけで、命令の引数やブロックの中には入れない。
+/// 和音 'ceg' / 「ドミソ」 の中の音符は和音の前に入れる。
+fn bar_heads(tokens: &[Token]) -> HashMap<usize, usize> {
+    let mut res = HashMap::new();
+    let mut harmony: Option<usize> = None; // 和音の前の位置
+    let mut lead: Option<usize> = None; // 音符の直前に続く命令の先頭
+    for t in tokens.iter() {
+        let span = match t.span.filter(|s| s.source_no == 0) {
+            Some(span) => span,
+            None => {
+                lead = None;
+                continue;
+            }
+        };
+        match t.ttype {
+            TokenType::HarmonyBegin => {
+                harmony = Some(lead.take().unwrap_or(span.start));
+            }
+            TokenType::HarmonyEnd => harmony = None,
+            TokenType::Note | TokenType::NoteN => {
+                let head = harmony.or(lead.take()).unwrap_or(span.start);
+                res.entry(span.start).or_insert(head);
+            }
+            _ if harmony.is_some() => {}
+            ttype if BAR_LEAD_TOKENS.contains(&ttype) => {
+                lead.get_or_insert(span.start);
+            }
+            _ => lead = None,
+        }
+    }
+    res
+}
+
+/// 小節の先頭で始まる音符の前に `|` を入れる
+///
+/// 時間は実際に実行して求める。ループやマクロなどで何度も演奏される音符は位置が決まらないので除く。
+fn insert_bar_lines(src: &str) -> String {
+    let mut song = Song::new();
+    let tokens = lex_source(&mut song, src);
+    exec(&mut song, &tokens);
+    let heads = bar_heads(&tokens);
+    let map = TempoMap::new(&song);
+    let notes = |events: &[crate::song::Event]| -> Vec<(usize, usize, isize)> {
+        events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOn)
+            .filter_map(|e| e.span.map(|s| (s, e.time)))
+            .filter(|(s, _)| s.source_no == 0)
+            .map(|(s, time)| (s.start, s.end, time))
+            .collect()
+    };
+    let mut count: HashMap<(usize, usize), usize> = HashMap::new();
+    for trk in song.tracks.iter() {
+        for (start, end, _) in notes(&trk.events) {
+            *count.entry((start, end)).or_insert(0) += 1;
+        }
+    }
+    let chars: Vec<char> = src.chars().collect();
+    let mut points = vec![];
+    for trk in song.tracks.iter() {
+        let mut list: Vec<(usize, usize, isize)> = notes(&trk.events)
+            .into_iter()
+            .filter(|(start, end, _)| count[&(*start, *end)] == 1 && *end <= chars.len())
+            .collect();
+        list.sort();
+        let mut prev: Option<(isize, usize)> = None; // (小節, 音符の先頭)
+        for (start, _end, time) in list {
+            let pos = map.tick_to_bar(time);
+            // 音符の範囲は後ろの空白や `|` を含むので、前の音符の先頭から調べる
+            if let (Some((measure, prev_start)), Some(&head)) = (prev, heads.get(&start)) {
+                if pos.measure > measure
+                    && pos.beat == 1
+                    && pos.step == 0
+                    && prev_start < head
+                    && !chars[prev_start..head].contains(&'|')
+                {
+                    points.push(head);
+                }
+            }
+            let measure = prev.map(|(m, _)| m.max(pos.measure)).unwrap_or(pos.measure);
+            prev = Some((measure, start));
+        }
+    }
+    points.sort();
+    points.dedup();
+    let mut res = String::new();
+    let mut last = 0;
+    for p in points {
+        if p < last {
+            continue;
+        }
+        res.extend(chars[last..p].iter());
+        if p > 0 && !chars[p - 1].is_whitespace() {
+            res.push(' ');
+        }
+        res.push_str("| ");
+        last = p;
+    }
+    res.extend(chars[last..].iter());
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(src: &str) -> String {
+        format_mml(src, &FormatOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn lossless_tokens_restore_the_source() {
+        let src = "TR(1)  l8 cde // comment  \r\n/* a\n b */ Str A = {c  d}\n#A={e}\nEnd\n  rest";
+        let text: String = lex_lossless(src).iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(text, src);
+    }
+
+    #[test]
+    fn normalize_spaces_and_indent_blocks() {
+        let src = "Function Foo(){\nc  d\t e   // keep  this\n}\nIF (1) {\n  Sub {\ncde\n}\n}\n/// debug   \n";
+        assert_eq!(
+            fmt(src),
+            "Function Foo(){\n  c d e // keep  this\n}\nIF (1) {\n  Sub {\n    cde\n  }\n}\n/// debug   \n"
+        );
+        // 2回目の整形では変わらない
+        assert_eq!(fmt(&fmt(src)), fmt(src));
+    }
+
+    #[test]
+    fn keep_strings_and_text_after_end() {
+        let src = "TrackName={Piano   1}\nStr A = {\n c  d\n}\nA\nEnd\n  c   d\n";
+        assert_eq!(fmt(src), src);
+    }
+
+    #[test]
+    fn align_track_sections() {
+        let src = "TR(1) l8 cde\nTR(10)   @1 cde\nTR(2)\n";
+        assert_eq!(fmt(src), "TR(1)  l8 cde\nTR(10) @1 cde\nTR(2)\n");
+    }
+
+    #[test]
+    fn insert_bar_lines_at_measure_boundaries() {
+        let options = FormatOptions {
+            bar_lines: true,
+            ..Default::default()
+        };
+        let src = "TimeSignature(3,4) l4 cde fg r c2.\nTR(2) c2. 'ceg'2.";
+        let res = format_mml(src, &options).unwrap();
+        assert_eq!(
+            res,
+            "TimeSignature(3,4) l4 cde | fg r | c2.\nTR(2) c2. | 'ceg'2."
+        );
+        assert_eq!(format_mml(&res, &options).unwrap(), res);
+        // 和音の中や命令の引数には入れず、小節の先頭の命令の前に入れる
+        let src = "l4 Tempo(120) c d e f Tempo(100) 'g>c'1 c1";
+        assert_eq!(
+            format_mml(src, &options).unwrap(),
+            "l4 Tempo(120) c d e f | Tempo(100) 'g>c'1 | c1"
+        );
+    }
+
+    #[test]
+    fn insert_bar_lines_into_sutoton_sample() {
+        let options = FormatOptions {
+            bar_lines: true,
+            ..Default::default()
+        };
+        let src = include_str!("../samples/seija.mml");
+        let res = format_mml(src, &options).unwrap();
+        assert_eq!(res.lines().count(), src.lines().count());
+        assert!(res.contains("| テンポ130;ドー;テンポ120;ソー | テンポ100;ドーーー"));
+        assert!(res.contains("| 「ずぱ」ー「ずぱ」ー　| 「ずぱ」ーーー"));
+        assert!(!res.contains("「 |"));
+        assert_eq!(format_mml(&res, &options).unwrap(), res);
+    }
+
+    #[test]
+    fn format_samples_without_changing_midi() {
+        for src in [
+            include_str!("../samples/candy_of_kujirahand.mml"),
+            include_str!("../samples/sakura2.mml"),
+            include_str!("../samples/seija.mml"),
+        ] {
+            let res = fmt(src);
+            assert_eq!(res.lines().count(), src.lines().count());
+            assert_eq!(fmt(&res), res);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Include file resolver
+//!
+//! `Include(ファイル名)` で読み込むファイルを探す仕組み。
+//! CLIではディスク上のファイルを、wasmではメモリ上のファイル一覧を使う。
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+
+/// 読み込んだインクルードファイル
+#[derive(Debug, Clone)]
+pub struct IncludeFile {
+    /// 二重読み込み・循環参照の判定に使う正規化した名前
+    pub name: String,
+    /// ファイルの内容
+    pub source: String,
+}
+
+/// Include命令のファイルを解決する
+pub trait IncludeResolver: Debug {
+    /// `name` を読み込む。`from` はInclude命令を書いたファイルの名前(メインのソースは空文字列)
+    fn resolve(&self, name: &str, from: &str) -> Option<IncludeFile>;
+}
+
+/// ディスク上のファイルを読み込む (CLI用)
+///
+/// Include命令を書いたファイルのディレクトリ、検索パスの順に探す。
+#[derive(Debug, Clone, Default)]
+pub struct FileIncludeResolver {
+    pub search_paths: Vec<PathBuf>,
+}
+
+impl FileIncludeResolver {
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        Self { search_paths }
+    }
+    fn candidates(&self, name: &str, from: &str) -> Vec<PathBuf> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return vec![path.to_path_buf()];
+        }
+        let mut res = vec![];
+        match Path::new(from).parent() {
+            Some(dir) if !from.is_empty() => res.push(dir.join(path)),
+            _ => res.push(path.to_path_buf()),
+        }
+        for dir in self.search_paths.iter() {
+            res.push(dir.join(path));
+        }
+        res
+    }
+}
+
+impl IncludeResolver for FileIncludeResolver {
+    fn resolve(&self, name: &str, from: &str) -> Option<IncludeFile> {
+        for path in self.candidates(name, from) {
+            if !path.is_file() {
+                continue;
+            }
+            let source = match std::fs::read_to_string(&path) {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let name = std::fs::canonicalize(&path)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .to_string();
+            return Some(IncludeFile { name, source });
+        }
+        None
+    }
+}
+
+/// メモリ上のファイル一覧から読み込む (wasm用)
+#[derive(Debug, Clone, Default)]
+pub struct MemoryIncludeResolver {
+    pub files: HashMap<String, String>,
+}
+
+impl MemoryIncludeResolver {
+    pub fn new(files: HashMap<String, String>) -> Self {
+        Self { files }
+    }
+    pub fn add_file(&mut self, name: &str, source: &str) {
+        self.files.insert(name.to_string(), source.to_string());
+    }
+}
+
+impl IncludeResolver for MemoryIncludeResolver {
+    fn resolve(&self, name: &str, _from: &str) -> Option<IncludeFile> {
+        self.files.get(name).map(|source| IncludeFile {
+            name: name.to_string(),
+            source: source.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_resolver_returns_registered_files() {
+        let mut resolver = MemoryIncludeResolver::default();
+        resolver.add_file("drums.mml", "Rhythm{b}");
+        let file = resolver.resolve("drums.mml", "").unwrap();
+        assert_eq!(file.name, "drums.mml");
+        assert_eq!(file.source, "Rhythm{b}");
+        assert!(resolver.resolve("bass.mml", "").is_none());
+    }
+
+    #[test]
+    fn file_resolver_searches_the_including_directory_first() {
+        let resolver = FileIncludeResolver::new(vec![PathBuf::from("lib")]);
+        let candidates = resolver.candidates("a.mml", "songs/main.mml");
+        assert_eq!(
+            candidates,
+            vec![PathBuf::from("songs/a.mml"), PathBuf::from("lib/a.mml")]
+        );
+        let candidates = resolver.candidates("a.mml", "");
+        assert_eq!(candidates[0], PathBuf::from("a.mml"));
+    }
+}
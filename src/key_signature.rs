@@ -0,0 +1,203 @@
+//! KeySignature - 調号 (#・♭の数と長調・短調)
+//!
+//! SMFの調号イベント(FF 59)と、KeyFlag(音名ごとの臨時記号)の相互変換を行う。
+
+/// #が付く順の音 (key_flagの添字: f c g d a e b)
+const SHARP_ORDER: [usize; 7] = [5, 0, 7, 2, 9, 4, 11];
+/// ♭が付く順の音 (key_flagの添字: b e a d g c f)
+const FLAT_ORDER: [usize; 7] = [11, 4, 9, 2, 7, 0, 5];
+
+/// 調号
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySignature {
+    /// #の数 (♭なら負数) -7..=7
+    pub fifths: isize,
+    /// 短調か
+    pub minor: bool,
+}
+
+impl KeySignature {
+    /// 主音の名前から作る (ex) D / Bb / F# / C#m
+    pub fn from_tonic(name: &str, minor: bool) -> Result<Self, String> {
+        let mut chars = name.chars();
+        let letter = chars.next().map(|c| c.to_ascii_lowercase());
+        // 長調のときの#の数 (c d e f g a b)
+        let mut fifths = match letter {
+            Some('c') => 0,
+            Some('d') => 2,
+            Some('e') => 4,
+            Some('f') => -1,
+            Some('g') => 1,
+            Some('a') => 3,
+            Some('b') => 5,
+            _ => return Err(format!("unknown key name: {}", name)),
+        };
+        let mut minor = minor;
+        for c in chars {
+            match c {
+                '#' | '+' | 's' => fifths += 7,
+                'b' | '-' => fifths -= 7,
+                'm' => minor = true,
+                _ => return Err(format!("unknown key name: {}", name)),
+            }
+        }
+        if minor {
+            fifths -= 3;
+        }
+        Self::new(fifths, minor)
+    }
+
+    /// #の数から作る
+    pub fn new(fifths: isize, minor: bool) -> Result<Self, String> {
+        if !(-7..=7).contains(&fifths) {
+            return Err(format!("too many sharps or flats: {}", fifths));
+        }
+        Ok(Self { fifths, minor })
+    }
+
+    /// KeyFlagから求める (順番どおりに並んだ#か♭の数、長調とする)
+    pub fn from_key_flag(key_flag: &[isize]) -> Self {
+        let count = |order: &[usize], flag: isize| {
+            order
+                .iter()
+                .take_while(|&&i| key_flag.get(i) == Some(&flag))
+                .count() as isize
+        };
+        let sharps = count(&SHARP_ORDER, 1);
+        let fifths = if sharps > 0 {
+            sharps
+        } else {
+            -count(&FLAT_ORDER, -1)
+        };
+        Self {
+            fifths,
+            minor: false,
+        }
+    }
+
+    /// 半音単位で移調した調 (#と♭の数が少ない方の書き方にする)
+    pub fn transpose(&self, semitones: isize) -> Self {
+        if semitones.rem_euclid(12) == 0 {
+            return *self;
+        }
+        let fifths = (self.fifths + 7 * semitones).rem_euclid(12);
+        Self {
+            fifths: if fifths > 6 { fifths - 12 } else { fifths },
+            minor: self.minor,
+        }
+    }
+
+    /// KeyFlagの値 (order: [c,c#,d,d#,e,f,f#,g,g#,a,a#,b])
+    pub fn to_key_flag(&self) -> Vec<isize> {
+        let mut key_flag = vec![0; 12];
+        let (order, flag) = if self.fifths >= 0 {
+            (&SHARP_ORDER, 1)
+        } else {
+            (&FLAT_ORDER, -1)
+        };
+        for &i in order.iter().take(self.fifths.unsigned_abs()) {
+            key_flag[i] = flag;
+        }
+        key_flag
+    }
+
+    /// FF 59 のデータ (sf, mi)
+    pub fn to_meta_data(&self) -> Vec<u8> {
+        vec![self.fifths as i8 as u8, self.minor as u8]
+    }
+
+    /// FF 59 のデータから作る
+    pub fn from_meta_data(data: &[u8]) -> Option<Self> {
+        if data.len() < 2 || data[1] > 1 {
+            return None;
+        }
+        Self::new(data[0] as i8 as isize, data[1] == 1).ok()
+    }
+
+    /// 主音の名前 (ex) Bb / F#
+    pub fn tonic(&self) -> &'static str {
+        // 5度ずつ並べた音名 (短調の主音は長調の3つ先)
+        const NAMES: [&str; 18] = [
+            "Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#",
+            "G#", "D#", "A#",
+        ];
+        let i = self.fifths + if self.minor { 3 } else { 0 } + 7;
+        NAMES[i as usize]
+    }
+}
+
+impl std::fmt::Display for KeySignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mode = if self.minor { "minor" } else { "major" };
+        write!(f, "{} {}", self.tonic(), mode)
+    }
+}
+
+/// 引数でそのまま読む言葉か (調の名前とオプション) --- それ以外は式として読む
+pub fn is_arg_word(s: &str) -> bool {
+    const OPTIONS: [&str; 7] = ["major", "maj", "minor", "min", "on", "keyflag", "off"];
+    OPTIONS.contains(&s.to_ascii_lowercase().as_str()) || KeySignature::from_tonic(s, false).is_ok()
+}
+
+/// KeySignature命令の引数を読む (ex) "D, major" / "Bbm, on" / "-2"
+/// 戻り値は (調号, KeyFlagも設定するか)。引数がなければ None
+pub fn parse_args(args: &str) -> Result<Option<(KeySignature, bool)>, String> {
+    let parts: Vec<&str> = args
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let first = match parts.first() {
+        Some(s) => *s,
+        None => return Ok(None),
+    };
+    let mut minor = false;
+    let mut set_flag = false;
+    for opt in parts[1..].iter() {
+        match opt.to_ascii_lowercase().as_str() {
+            "major" | "maj" => minor = false,
+            "minor" | "min" => minor = true,
+            "on" | "keyflag" => set_flag = true,
+            "off" => set_flag = false,
+            _ => return Err(format!("unknown option: {}", opt)),
+        }
+    }
+    let key = match first.parse::<isize>() {
+        Ok(fifths) => KeySignature::new(fifths, minor)?,
+        Err(_) => KeySignature::from_tonic(first, minor)?,
+    };
+    Ok(Some((key, set_flag)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_key_names() {
+        let key = |s: &str| parse_args(s).unwrap().unwrap().0;
+        assert_eq!(key("D, major").fifths, 2);
+        assert_eq!(key("Bb").fifths, -2);
+        assert_eq!(key("F#m").fifths, 3);
+        assert_eq!(key("c, minor").to_string(), "C minor");
+        assert_eq!(key("-3, minor").to_string(), "C minor");
+        assert_eq!(key("G#m").to_string(), "G# minor");
+        assert!(parse_args("A, on").unwrap().unwrap().1);
+        assert_eq!(parse_args("").unwrap(), None);
+        assert!(parse_args("H").is_err());
+        assert!(parse_args("B#").is_err());
+    }
+
+    #[test]
+    fn convert_key_flag_and_meta_data() {
+        let key = KeySignature::new(-2, false).unwrap();
+        let flag = key.to_key_flag();
+        assert_eq!(flag, vec![0, 0, 0, 0, -1, 0, 0, 0, 0, 0, 0, -1]);
+        assert_eq!(KeySignature::from_key_flag(&flag), key);
+        assert_eq!(key.to_meta_data(), vec![0xFE, 0]);
+        assert_eq!(KeySignature::from_meta_data(&[0xFE, 0]), Some(key));
+        // Cから長2度上げるとD
+        assert_eq!(KeySignature::new(0, false).unwrap().transpose(2).fifths, 2);
+        assert_eq!(key.transpose(-12), key);
+    }
+}
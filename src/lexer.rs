@@ -1,8 +1,12 @@
 //! lexer
+use crate::abc;
+use crate::diagnostic::{Diagnostic, Severity};
 use crate::note_length::calc_length;
 use crate::sakura_message::MessageKind;
 use crate::song::{SFunction, Song};
 use crate::source_cursor::SourceCursor;
+use crate::span::{SourceText, Span, SpanOrigin};
+use crate::sutoton;
 use crate::svalue::SValue;
 use crate::token::{
     zen2han, Token, TokenType, TokenValueType, COMMENT_DEBUG, COMMENT_NORMAL, NOTE_PARAM_L,
@@ -15,6 +19,7 @@ mod calc;
 mod cc;
 mod command;
 mod error;
+mod lossless;
 mod note;
 mod variable;
 
@@ -26,6 +31,8 @@ use error::*;
 use note::*;
 use variable::*;
 
+pub use lossless::{lex_lossless, SourceToken, SourceTokenKind};
+
 /// カーソル位置が End/END 命令かどうかを調べる
 /// 「EndPoint」のように単語が続く場合はEnd命令ではない
 fn is_end_command(cur: &SourceCursor) -> bool {
@@ -72,7 +79,7 @@ fn lex_preprocess(song: &mut Song, cur: &mut SourceCursor) -> bool {
                         song.get_message(MessageKind::ErrorDefineVariableIsReserved),
                         func_name
                     );
-                    read_error(cur, song, &msg);
+                    read_error(cur, song, MessageKind::ErrorDefineVariableIsReserved, &msg);
                 }
                 // register function name
                 let func_id = song.functions.len();
@@ -100,24 +107,65 @@ fn lex_preprocess(song: &mut Song, cur: &mut SourceCursor) -> bool {
 
 /// split source code to tokens
 pub fn lex(song: &mut Song, src: &str, lineno: isize) -> Vec<Token> {
+    // 対応表がなければ、この文字列を元のソースとみなす
+    if song.source_texts[song.source_no].is_empty() {
+        song.source_texts[song.source_no] = SourceText::new(src);
+    }
+    lex_at(song, src, lineno, SpanOrigin::Offset(0))
+}
+
+/// ストトン表記を変換して字句解析する。トークンの範囲は変換前のソースでの位置になる
+pub fn lex_source(song: &mut Song, src: &str) -> Vec<Token> {
+    let (mml, source_text) = sutoton::convert_with_map(src);
+    // 変換で先頭の空行が消えるので、その分だけ行番号をずらす
+    let (lineno, _) = source_text.line_column(source_text.to_original(0));
+    song.source_texts[song.source_no] = source_text;
+    lex_at(song, &mml, lineno, SpanOrigin::Offset(0))
+}
+
+/// ABC記譜法のソースをMMLに変換して字句解析する。トークンの範囲は元のABCでの位置になる
+pub fn lex_abc_source(song: &mut Song, src: &str) -> Vec<Token> {
+    let res = abc::convert(src);
+    for m in res.messages.iter() {
+        let kind = match m.severity {
+            Severity::Error => MessageKind::ErrorAbcSyntax,
+            _ => MessageKind::WarningAbcIgnored,
+        };
+        let msg = format!("{}: {}", song.get_message(kind), m.text);
+        let mut d = song.new_diagnostic(m.severity, kind, m.line, msg);
+        d.column = m.column;
+        song.add_diagnostic(d);
+    }
+    song.source_texts[song.source_no] = res.source_text;
+    lex_at(song, &res.mml, 0, SpanOrigin::Offset(0))
+}
+
+/// 元のソースでの位置を指定して字句解析する
+pub fn lex_at(song: &mut Song, src: &str, lineno: isize, origin: SpanOrigin) -> Vec<Token> {
     let mut result: Vec<Token> = vec![
         Token::new_lineno(lineno), // init lineno
     ];
     let mut cur = SourceCursor::from(src);
     cur.line = lineno;
+    cur.origin = origin;
     // preprocess
     let _pre = lex_preprocess(song, &mut cur);
     // read
     let mut flag_harmony = false;
+    let mut last = (0, cur.index); // 前の命令の (先頭のトークン, 開始位置)
     while !cur.is_eos() {
+        set_spans(song, &cur, &mut result, last);
+        last = (result.len(), cur.index);
         let ch = zen2han(cur.get_char());
         // println!("lex: ch = {}", ch);
         match ch {
             // <CHAR_COMMANDS>
             /*
-            SPACE TAB CR LF ; CHR(0x7C) => // @ space - 空白文字 / ';'や'|'も読み飛ばす
+            SPACE TAB CR LF ; => // @ space - 空白文字 / ';'も読み飛ばす
+            CHR(0x7C) => // @ bar line - 小節線 / BarCheck(on)のとき小節の頭にあるか確かめる
             */
-            ' ' | '\t' | '\r' | '|' | ';' => {}
+            ' ' | '\t' | '\r' | ';' => {}
+            '|' => result.push(Token::new(TokenType::BarLine, 0, vec![])),
             // ret
             '\n' => {
                 cur.line += 1;
@@ -242,9 +290,41 @@ pub fn lex(song: &mut Song, src: &str, lineno: isize) -> Vec<Token> {
             }
         }
     }
+    set_spans(song, &cur, &mut result, last);
+    // 行番号にソースの番号を付ける (Includeしたファイルの行番号と区別するため)
+    for t in result.iter_mut() {
+        if t.ttype == TokenType::LineNo {
+            t.value_i = song.source_no as isize;
+        }
+    }
     normalize_tokens(result)
 }
 
+/// 命令を読んで追加したトークンに範囲を設定する
+fn set_spans(song: &Song, cur: &SourceCursor, result: &mut [Token], last: (usize, usize)) {
+    let (first, start) = last;
+    if first >= result.len() {
+        return;
+    }
+    let span = song.span_of(cur.origin, start, cur.index);
+    for t in result[first..].iter_mut() {
+        fill_span(t, span);
+    }
+}
+
+/// 範囲が未設定のトークンに範囲を設定する (子のトークンも同様)
+fn fill_span(t: &mut Token, span: Option<Span>) {
+    if t.span.is_none() {
+        t.span = span;
+    }
+    let span = t.span;
+    if let Some(children) = t.children.as_mut() {
+        for c in children.iter_mut() {
+            fill_span(c, span);
+        }
+    }
+}
+
 // Emptyを削除し、Tokensを展開して返す。ただし、Div/Subは実行時にならないと展開結果が分からないため、それは展開しない
 fn normalize_tokens(tokens: Vec<Token>) -> Vec<Token> {
     let mut res = vec![];
@@ -146,16 +146,73 @@ pub(super) fn read_args_tokens(cur: &mut SourceCursor, song: &mut Song) -> Vec<T
         if cur.eq_char(')') {
             cur.next(); // skip ')'
         } else {
-            song.add_log(format!(
-                "[ERROR]({}) {}",
-                cur.line,
-                song.get_message(MessageKind::MissingParenthesis)
-            ));
+            let msg = song
+                .get_message(MessageKind::MissingParenthesis)
+                .to_string();
+            add_lex_error(cur, song, MessageKind::MissingParenthesis, msg);
         }
     }
     tokens
 }
 
+/// 引数を式として読み取る。ただし is_word に合う引数は文字列のまま読む
+/// (ex) KeySignature(D, minor) / KeySignature(K) --- D と minor は文字列、K は変数
+pub(super) fn read_args_tokens_with_words(
+    cur: &mut SourceCursor,
+    song: &mut Song,
+    is_word: fn(&str) -> bool,
+) -> Vec<Token> {
+    cur.skip_space();
+    if !cur.eq_char('(') {
+        return vec![];
+    }
+    cur.next(); // skip '('
+    let mut tokens = vec![];
+    loop {
+        cur.skip_space();
+        // 次の ',' か ')' までをそのまま見る
+        let start = cur.index;
+        let mut word = String::new();
+        while !cur.is_eos() && !cur.eq_char(',') && !cur.eq_char(')') {
+            word.push(cur.peek_n(0));
+            cur.next();
+        }
+        let word = word.trim();
+        if word.is_empty() {
+            // 省略した引数 (ex) KeySignature()
+        } else if is_word(word) {
+            let mut tok = Token::new_const(
+                TokenType::ConstStr,
+                word.len() as isize,
+                Some(word.to_string()),
+                TokenValueType::STR,
+            );
+            tok.span = song.span_of(cur.origin, start, cur.index);
+            tokens.push(tok);
+        } else {
+            cur.index = start;
+            let sub_tokens = read_calc_tokens(cur, song).unwrap_or(vec![]);
+            tokens.push(Token::new_tokens(TokenType::Tokens, 0, sub_tokens));
+        }
+        cur.skip_space();
+        if cur.eq_char(',') {
+            cur.next(); // skip ','
+        } else {
+            break;
+        }
+    }
+    cur.skip_space();
+    if cur.eq_char(')') {
+        cur.next(); // skip ')'
+    } else {
+        let msg = song
+            .get_message(MessageKind::MissingParenthesis)
+            .to_string();
+        add_lex_error(cur, song, MessageKind::MissingParenthesis, msg);
+    }
+    tokens
+}
+
 /// 数値列を受け取る命令の引数を式として読み取る。
 /// 従来の`Command=1,2,3`形式も互換性のため受け付ける。
 pub(super) fn read_int_args_tokens(cur: &mut SourceCursor, song: &mut Song) -> Vec<Token> {
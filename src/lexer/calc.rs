@@ -37,7 +37,7 @@ pub(super) fn read_value(cur: &mut SourceCursor, song: &mut Song) -> Option<Toke
                     ));
                 }
                 let msg = song.get_message(MessageKind::MissingParenthesis);
-                read_error(cur, song, msg);
+                read_error(cur, song, MessageKind::MissingParenthesis, msg);
                 return Some(Token::new_const0());
             }
             // value or array
@@ -64,7 +64,7 @@ pub(super) fn read_value(cur: &mut SourceCursor, song: &mut Song) -> Option<Toke
                     cur.next();
                 } else {
                     let msg = song.get_message(MessageKind::MissingParenthesis);
-                    read_error(cur, song, msg);
+                    read_error(cur, song, MessageKind::MissingParenthesis, msg);
                 }
                 return Some(Token::new_data_tokens(
                     TokenType::MakeArray,
@@ -77,7 +77,7 @@ pub(super) fn read_value(cur: &mut SourceCursor, song: &mut Song) -> Option<Toke
                 cur.next();
             } else {
                 let msg = song.get_message(MessageKind::MissingParenthesis);
-                read_error(cur, song, msg);
+                read_error(cur, song, MessageKind::MissingParenthesis, msg);
             }
             // ( calc )
             let token = Token::new_calc_token('(', LEX_PAREN, vec![token]);
@@ -147,23 +147,29 @@ pub(super) fn read_value(cur: &mut SourceCursor, song: &mut Song) -> Option<Toke
             ));
         }
         '{' => {
+            let start = cur.index;
             let str = cur.get_token_nest('{', '}');
-            return Some(Token::new_const(
+            let mut tok = Token::new_const(
                 TokenType::ConstStr,
                 str.len() as isize,
                 Some(str),
                 TokenValueType::STR,
-            ));
+            );
+            tok.span = song.span_of(cur.origin, start, cur.index);
+            return Some(tok);
         }
         '"' => {
+            let start = cur.index;
             cur.next();
             let str = cur.get_token_ch('"');
-            return Some(Token::new_const(
+            let mut tok = Token::new_const(
                 TokenType::ConstStr,
                 str.len() as isize,
                 Some(str),
                 TokenValueType::STR,
-            ));
+            );
+            tok.span = song.span_of(cur.origin, start, cur.index);
+            return Some(tok);
         }
         'A'..='Z' | '_' | '#' | 'a'..='z' => {
             return Some(read_value_word(cur, song));
@@ -181,6 +187,7 @@ pub(super) fn read_value_word(cur: &mut SourceCursor, song: &mut Song) -> Token
     if cur.eq_char('(') {
         // function call or array or macro_expand
         let arg_lineno = cur.line;
+        let arg_origin = cur.origin_of_nest('(');
         let arg_str = cur.get_token_nest('(', ')');
         // println!("read_calc_args={:?}", arg_str);
         // MML(l) などの引数は値ではなく、参照するMML命令名として渡す。
@@ -202,7 +209,7 @@ pub(super) fn read_value_word(cur: &mut SourceCursor, song: &mut Song) -> Token
                 TokenValueType::STR,
             )]
         } else {
-            lex_calc(song, &arg_str, arg_lineno)
+            lex_calc(song, &arg_str, arg_lineno, arg_origin)
         };
         tok.children = Some(arg_tokens);
         tok.tag = 1; // FUNCTION
@@ -367,7 +374,7 @@ fn read_calc_priority(
         let right_val_o = read_calc_priority(cur, song, operator_priority - 1);
         if right_val_o.is_none() {
             let msg = song.get_message(MessageKind::ErrorMissingValue);
-            read_error(cur, song, msg);
+            read_error(cur, song, MessageKind::ErrorMissingValue, msg);
         }
         let right_val = right_val_o.unwrap_or(Token::new_empty("ERROR", cur.line));
         left_val = Token::new_calc_token(operator_ch, operator_priority, vec![left_val, right_val]);
@@ -376,13 +383,23 @@ fn read_calc_priority(
 }
 
 /// lex calc script
-pub(super) fn lex_calc(song: &mut Song, src: &str, lineno: isize) -> Vec<Token> {
+pub(super) fn lex_calc(
+    song: &mut Song,
+    src: &str,
+    lineno: isize,
+    origin: SpanOrigin,
+) -> Vec<Token> {
     let mut cur = SourceCursor::from(src);
     cur.line = lineno;
+    cur.origin = origin;
     let mut result = vec![];
     while !cur.is_eos() {
         let lastpos = cur.index;
-        let tokens = read_calc_tokens(&mut cur, song).unwrap_or(vec![]);
+        let mut tokens = read_calc_tokens(&mut cur, song).unwrap_or(vec![]);
+        let span = song.span_of(cur.origin, lastpos, cur.index);
+        for t in tokens.iter_mut() {
+            fill_span(t, span);
+        }
         result.extend(tokens);
         if cur.peek().unwrap_or('\0') == ',' {
             cur.next();
@@ -410,12 +427,15 @@ pub(super) fn read_while(cur: &mut SourceCursor, song: &mut Song) -> Token {
         read_error_cmd(cur, song, "WHILE");
         return Token::new_empty("ERROR:WHILE", cur.line);
     }
+    let cond_origin = cur.origin_of_nest('(');
     let cond_s = cur.get_token_nest('(', ')');
-    let cond_tok = lex_calc(song, &cond_s, lineno);
+    let cond_tok = lex_calc(song, &cond_s, lineno, cond_origin);
     cur.skip_space();
     // read body
+    let body_lineno = cur.line;
+    let body_origin = cur.origin_of_nest('{');
     let body_s = cur.get_token_nest('{', '}');
-    let body_tok = lex(song, &body_s, lineno);
+    let body_tok = lex_at(song, &body_s, body_lineno, body_origin);
     // while
     let while_tok = Token::new_tokens_lineno(
         TokenType::While,
@@ -438,8 +458,15 @@ pub(super) fn read_for(cur: &mut SourceCursor, song: &mut Song) -> Token {
     }
     // read init
     cur.next(); // skip '('
-    let init_s = cur.get_token_ch(';').trim().to_string();
+    let init_start = cur.index;
+    let init_raw = cur.get_token_ch(';');
+    let init_head = init_raw.chars().count() - init_raw.trim_start().chars().count();
+    let init_origin = cur.origin.at(init_start + init_head);
+    let init_span = song.span_of(cur.origin, init_start, cur.index);
+    let init_s = init_raw.trim().to_string();
+    let cond_origin = cur.origin.at(cur.index);
     let cond_s = cur.get_token_ch(';');
+    let inc_origin = cur.origin.at(cur.index);
     let inc_s = cur.get_token_ch(')');
     println!("---");
     cur.skip_space();
@@ -447,17 +474,20 @@ pub(super) fn read_for(cur: &mut SourceCursor, song: &mut Song) -> Token {
         read_error_cmd(cur, song, "FOR");
         return Token::new_empty("ERROR:FOR", cur.line);
     }
+    let body_lineno = cur.line;
+    let body_origin = cur.origin_of_nest('{');
     let body_s = cur.get_token_nest('{', '}');
     // もし、String型のinit_sが"Int "から始まっていなければ"Int "を足す
-    let init_s = if init_s == "" || (init_s.starts_with("Int ") || init_s.starts_with("INT ")) {
-        init_s
-    } else {
-        format!("Int {}", init_s)
-    };
-    let init_tok = lex(song, &init_s, lineno);
-    let cond_tok = lex_calc(song, &cond_s, lineno);
-    let inc_tok = lex(song, &inc_s, lineno);
-    let body_tok = lex(song, &body_s, lineno);
+    let (init_s, init_origin) =
+        if init_s == "" || (init_s.starts_with("Int ") || init_s.starts_with("INT ")) {
+            (init_s, init_origin)
+        } else {
+            (format!("Int {}", init_s), SpanOrigin::Expand(init_span))
+        };
+    let init_tok = lex_at(song, &init_s, lineno, init_origin);
+    let cond_tok = lex_calc(song, &cond_s, lineno, cond_origin);
+    let inc_tok = lex_at(song, &inc_s, lineno, inc_origin);
+    let body_tok = lex_at(song, &body_s, body_lineno, body_origin);
     let for_tok = Token::new_tokens_lineno(
         TokenType::For,
         0,
@@ -480,16 +510,19 @@ pub(super) fn read_if(cur: &mut SourceCursor, song: &mut Song) -> Token {
         read_error_cmd(cur, song, "IF");
         return Token::new_empty("ERROR:IF", cur.line);
     }
+    let cond_origin = cur.origin_of_nest('(');
     let cond = cur.get_token_nest('(', ')');
-    let cond_tok = lex_calc(song, &cond, cur.line);
+    let cond_tok = lex_calc(song, &cond, cur.line, cond_origin);
     cur.skip_space();
     if !cur.eq_char('{') {
         read_error_cmd(cur, song, "IF");
         return Token::new_empty("ERROR:IF", cur.line);
     }
     // read then block
+    let then_lineno = cur.line;
+    let then_origin = cur.origin_of_nest('{');
     let then_s = cur.get_token_nest('{', '}');
-    let then_tok = lex(song, &then_s, cur.line);
+    let then_tok = lex_at(song, &then_s, then_lineno, then_origin);
     let mut else_tok = vec![];
     cur.skip_space_ret();
     // read else block
@@ -501,8 +534,9 @@ pub(super) fn read_if(cur: &mut SourceCursor, song: &mut Song) -> Token {
             read_error_cmd(cur, song, "IF");
             return Token::new_empty("ERROR:IF:ELSE", else_lineno);
         }
+        let else_origin = cur.origin_of_nest('{');
         let else_s = cur.get_token_nest('{', '}');
-        else_tok = lex(song, &else_s, else_lineno);
+        else_tok = lex_at(song, &else_s, else_lineno, else_origin);
     }
     // println!("cond: {:?}", cond_tok);
     // token
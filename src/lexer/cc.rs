@@ -238,8 +238,13 @@ pub(super) fn read_cc_unknown_option(
     cmd: &str,
 ) -> Token {
     let _ = read_int_args_tokens(cur, song); // 引数を読み飛ばす
-    let msg = format!("not supported : {}.{}", target.name(), cmd);
-    song.add_log(format!("[ERROR]({}) {}", cur.line, msg));
+    let msg = format!(
+        "{} : {}.{}",
+        song.get_message(MessageKind::ErrorNotSupported),
+        target.name(),
+        cmd
+    );
+    add_lex_error(cur, song, MessageKind::ErrorNotSupported, msg.clone());
     Token::new_empty(&msg, cur.line)
 }
 
@@ -380,7 +385,7 @@ pub(super) fn read_cc(cur: &mut SourceCursor, song: &mut Song, ch: char) -> Toke
         Some(v) => v,
         None => {
             let msg = song.get_message(MessageKind::ScriptSyntaxError);
-            read_error(cur, song, msg);
+            read_error(cur, song, MessageKind::ScriptSyntaxError, msg);
             return Token::new_empty("ERROR", cur.line);
         }
     };
@@ -49,6 +49,10 @@ pub(super) fn read_upper_command(cur: &mut SourceCursor, song: &mut Song) -> Tok
                     TokenType::Div => return read_command_div(cur, song, false),
                     TokenType::Sub => return read_command_sub(cur, song),
                     TokenType::KeyFlag => return read_key_flag(cur, song),
+                    TokenType::KeySignature => return read_key_signature(cur, song),
+                    TokenType::Strum | TokenType::Arp => {
+                        return read_harmony_option(cur, song, token_t)
+                    }
                     TokenType::DefInt => return read_def_var(cur, song, TokenValueType::INT),
                     TokenType::DefStr => return read_def_var(cur, song, TokenValueType::STR),
                     TokenType::DefArray => return read_def_var(cur, song, TokenValueType::ARRAY),
@@ -66,7 +70,9 @@ pub(super) fn read_upper_command(cur: &mut SourceCursor, song: &mut Song) -> Tok
                     TokenType::For => return read_for(cur, song),
                     TokenType::While => return read_while(cur, song),
                     TokenType::SysEx => return read_sysex(cur, song),
-                    TokenType::UseKeyShift => return read_use_key_shift(cur, song),
+                    TokenType::UseKeyShift
+                    | TokenType::UseBarCheck
+                    | TokenType::ChordVoiceLeading => return read_on_off(cur, song, token_t),
                     TokenType::Return => {
                         cur.skip_space();
                         let values = if cur.eq_char('(') {
@@ -90,7 +96,8 @@ pub(super) fn read_upper_command(cur: &mut SourceCursor, song: &mut Song) -> Tok
                     }
                     TokenType::DefUserFunction => return read_def_user_function(cur, song),
                     _ => {
-                        song.add_log(format!("[SYSTEM_ERROR] FUNCTION NOT SET : {}", cmd));
+                        let msg = format!("[SYSTEM_ERROR] FUNCTION NOT SET : {}", cmd);
+                        add_lex_error(cur, song, MessageKind::UnknownError, msg);
                     }
                 }
             }
@@ -107,15 +114,65 @@ pub(super) fn read_upper_command(cur: &mut SourceCursor, song: &mut Song) -> Tok
     return Token::new_empty(&cmd, cur.line);
 }
 
-pub(super) fn read_include(cur: &mut SourceCursor, _song: &mut Song) -> Token {
-    // @ 未実装
+/// Include命令 - ファイルを読み込み、字句解析した結果をその場所に展開する
+/// 同じファイルは一度だけ読み込み、循環したIncludeはエラーにする
+pub(super) fn read_include(cur: &mut SourceCursor, song: &mut Song) -> Token {
     cur.skip_space();
     let filename = if cur.eq_char('(') {
         cur.get_token_nest('(', ')')
+    } else if cur.eq_char('{') {
+        cur.get_token_nest('{', '}')
     } else {
         "".to_string()
     };
-    return Token::new_empty(&format!("Unimplemented Include({})", filename), cur.line);
+    let filename = filename.trim().trim_matches('"').trim().to_string();
+    if filename.is_empty() {
+        lex_error_missing_arg(cur, song, "Include");
+        return Token::new_empty("ERROR", cur.line);
+    }
+    // ファイルを探す
+    let from = song.source_names[song.source_no].clone();
+    let file = song
+        .include_resolver
+        .as_ref()
+        .and_then(|resolver| resolver.resolve(&filename, &from));
+    let file = match file {
+        Some(file) => file,
+        None => {
+            let msg = format!(
+                "{}: \"{}\"",
+                song.get_message(MessageKind::ErrorIncludeNotFound),
+                filename
+            );
+            return read_error(cur, song, MessageKind::ErrorIncludeNotFound, &msg);
+        }
+    };
+    // 読み込み済みのファイルか
+    if let Some(no) = song.source_names.iter().position(|n| *n == file.name) {
+        if no == song.source_no || song.include_stack.contains(&no) {
+            let msg = format!(
+                "{}: \"{}\"",
+                song.get_message(MessageKind::ErrorIncludeCircular),
+                filename
+            );
+            return read_error(cur, song, MessageKind::ErrorIncludeCircular, &msg);
+        }
+        // 二度目以降は何もしない (インクルードガード)
+        return Token::new_empty(&format!("Include({})", filename), cur.line);
+    }
+    // 読み込んだファイルを字句解析する
+    let source_no = song.source_names.len();
+    song.source_names.push(file.name);
+    song.source_texts.push(SourceText::default());
+    song.include_stack.push(song.source_no);
+    song.source_no = source_no;
+    let mut tokens = lex_source(song, &file.source);
+    song.source_no = song.include_stack.pop().unwrap_or(0);
+    // 読み込み元の行番号に戻す
+    let mut lineno_tok = Token::new_lineno(cur.line);
+    lineno_tok.value_i = song.source_no as isize;
+    tokens.push(lineno_tok);
+    Token::new_tokens_lineno(TokenType::Tokens, 0, tokens, cur.line)
 }
 
 pub(super) fn read_timebase(cur: &mut SourceCursor, song: &mut Song) -> Token {
@@ -127,7 +184,7 @@ pub(super) fn read_timebase(cur: &mut SourceCursor, song: &mut Song) -> Token {
     let v_opt = read_arg_const_int(cur);
     if v_opt.is_none() {
         let msg = song.get_message(MessageKind::ShouldBeConstant);
-        return read_error(cur, song, msg);
+        return read_error(cur, song, MessageKind::ShouldBeConstant, msg);
     }
     song.timebase = v_opt.unwrap_or(96);
     if song.timebase <= 48 {
@@ -137,6 +194,49 @@ pub(super) fn read_timebase(cur: &mut SourceCursor, song: &mut Song) -> Token {
     Token::new_comment(&format!("TIMEBASE={}", song.timebase), cur.line)
 }
 
+/// 調号の引数は調の名前とオプションをそのまま読み、数値や変数は式として読む
+/// (ex) KeySignature(D, major) / KeySignature(K, minor)
+pub(super) fn read_key_signature(cur: &mut SourceCursor, song: &mut Song) -> Token {
+    cur.skip_space();
+    if cur.eq_char('=') {
+        cur.next();
+        cur.skip_space();
+    }
+    let args = read_args_tokens_with_words(cur, song, crate::key_signature::is_arg_word);
+    Token::new_tokens(TokenType::KeySignature, 0, args)
+}
+
+/// 和音のストローク・アルペジオ (ex) Strum(up, 3) / Arp(updown, 16){ 'ceg'1 }
+/// 向き・音型と音長はそのまま読み、ほかの引数は式として読む。続けて { } を書くとその中だけに効く
+pub(super) fn read_harmony_option(
+    cur: &mut SourceCursor,
+    song: &mut Song,
+    ttype: TokenType,
+) -> Token {
+    let args = read_args_tokens_with_words(cur, song, is_harmony_option_word);
+    let mut children = vec![Token::new_tokens(TokenType::Tokens, 0, args)];
+    cur.skip_space();
+    if cur.eq_char('{') {
+        let lineno = cur.line;
+        let origin = cur.origin_of_nest('{');
+        let block = cur.get_token_nest('{', '}');
+        let tokens = lex_at(song, &block, lineno, origin);
+        children.push(Token::new_tokens(TokenType::Tokens, 0, tokens));
+    }
+    Token::new_tokens(ttype, 0, children)
+}
+
+/// Strum/Arpの引数でそのまま読む言葉か (ex) up / off / 8. / %24
+fn is_harmony_option_word(s: &str) -> bool {
+    let is_length = s
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | '^' | '%'));
+    matches!(
+        s.to_ascii_lowercase().as_str(),
+        "up" | "down" | "updown" | "random" | "on" | "off"
+    ) || (is_length && !s.chars().all(|c| c.is_ascii_digit()))
+}
+
 pub(super) fn read_key_flag(cur: &mut SourceCursor, _song: &mut Song) -> Token {
     let mut flag = 1;
     let mut key_flag = vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]; // c, c#,d, d#,e, f, f#,g, g#,a, a#,b
@@ -249,7 +349,8 @@ pub(super) fn read_play(cur: &mut SourceCursor, song: &mut Song) -> Token {
     play_tok
 }
 
-pub(super) fn read_use_key_shift(cur: &mut SourceCursor, song: &mut Song) -> Token {
+/// on/off を引数にとる命令 (ex) UseKeyShift(on)
+pub(super) fn read_on_off(cur: &mut SourceCursor, song: &mut Song, ttype: TokenType) -> Token {
     cur.skip_space();
     if cur.eq_char('=') || cur.eq_char('(') {
         cur.next();
@@ -267,14 +368,15 @@ pub(super) fn read_use_key_shift(cur: &mut SourceCursor, song: &mut Song) -> Tok
     if cur.eq_char(')') {
         cur.next();
     }
-    Token::new(TokenType::UseKeyShift, 0, vec![v])
+    Token::new(ttype, 0, vec![v])
 }
 
 pub(super) fn read_command_sub(cur: &mut SourceCursor, song: &mut Song) -> Token {
     cur.skip_space();
     let lineno = cur.line; // ブロックを読む前の行番号が本体の先頭行
+    let origin = cur.origin_of_nest('{');
     let block = cur.get_token_nest('{', '}');
-    let tokens = lex(song, &block, lineno);
+    let tokens = lex_at(song, &block, lineno, origin);
     let mut tok = Token::new(TokenType::Sub, 0, vec![]);
     tok.children = Some(tokens);
     tok
@@ -292,9 +394,10 @@ pub(super) fn read_command_div(cur: &mut SourceCursor, song: &mut Song, need2bac
         cur.skip_space();
     }
     let lineno = cur.line; // ブロックを読む前の行番号が本体の先頭行
+    let origin = cur.origin_of_nest('{');
     let block = cur.get_token_nest('{', '}');
     let len_s = cur.get_note_length();
-    let tokens = lex(song, &block, lineno);
+    let tokens = lex_at(song, &block, lineno, origin);
     // count note
     let mut cnt = 0;
     for t in tokens.iter() {
@@ -327,7 +430,10 @@ pub(super) fn read_command_rhythm(cur: &mut SourceCursor, song: &mut Song) -> To
     let mut result = String::new();
     cur.skip_space();
     let line_start = cur.line;
+    let block_start = cur.index;
     let block = cur.get_token_nest('{', '}');
+    // マクロを展開した文字列なので、トークンの範囲はブロック全体にする
+    let origin = SpanOrigin::Expand(song.span_of(cur.origin, block_start, cur.index));
     // extract macro
     let mut macro_cur = SourceCursor::from(&block);
     macro_cur.line = line_start;
@@ -359,7 +465,7 @@ pub(super) fn read_command_rhythm(cur: &mut SourceCursor, song: &mut Song) -> To
         }
     }
     let mut t = Token::new_value(TokenType::Tokens, 0);
-    t.children = Some(lex(song, &result, cur.line));
+    t.children = Some(lex_at(song, &result, cur.line, origin));
     t
 }
 
@@ -375,9 +481,11 @@ pub(super) fn read_def_rhythm_macro(cur: &mut SourceCursor, song: &mut Song) {
     if 0x40 <= ch as u8 && ch as u8 <= 0x7F {
         song.rhthm_macro[ch as usize - 0x40] = s;
     } else {
-        song.add_log(format!(
-            "[ERROR]({}) could not define Rhythm macro '{}' ",
-            cur.line, ch
-        ));
+        let msg = format!(
+            "{} '{}' ",
+            song.get_message(MessageKind::ErrorRhythmMacro),
+            ch
+        );
+        add_lex_error(cur, song, MessageKind::ErrorRhythmMacro, msg);
     }
 }
@@ -3,6 +3,28 @@ use super::*;
 
 pub(super) const LEX_MAX_ERROR: usize = 30;
 
+/// カーソル位置のエラー・警告を作る (行と列は元のソースでの位置)
+pub(super) fn lex_diagnostic(
+    cur: &SourceCursor,
+    song: &Song,
+    severity: Severity,
+    kind: MessageKind,
+    msg: String,
+) -> Diagnostic {
+    let mut d = song.new_diagnostic(severity, kind, cur.line, msg);
+    if let Some(span) = song.span_of(cur.origin, cur.index, cur.index) {
+        d.line = span.line;
+        d.column = span.column;
+    }
+    d
+}
+
+/// カーソル位置のエラーを記録する
+pub(super) fn add_lex_error(cur: &SourceCursor, song: &mut Song, kind: MessageKind, msg: String) {
+    let d = lex_diagnostic(cur, song, Severity::Error, kind, msg);
+    song.add_diagnostic(d);
+}
+
 /// append error log for lex
 pub(super) fn lex_error(cur: &mut SourceCursor, song: &mut Song, msg: &str) {
     // make error log
@@ -11,54 +33,70 @@ pub(super) fn lex_error(cur: &mut SourceCursor, song: &mut Song, msg: &str) {
         near = "[EOS]".to_string();
     }
     let log = format!(
-        "[ERROR]({}) {}: \"{}\" {} \"{}\"",
-        cur.line,
+        "{}: \"{}\" {} \"{}\"",
         song.get_message(MessageKind::UnknownChar),
         msg,
         song.get_message(MessageKind::Near),
         near
     );
+    let mut d = song.new_diagnostic(Severity::Error, MessageKind::UnknownChar, cur.line, log);
+    // 読み飛ばした文字の位置
+    let pos = cur.index.saturating_sub(1);
+    if let Some(span) = song.span_of(cur.origin, pos, cur.index) {
+        d.line = span.line;
+        d.column = span.column;
+    }
     if song.debug {
-        println!("{}", log);
+        println!("{}", d.to_log());
     }
     // add to logs
     if song.get_logs_len() == LEX_MAX_ERROR {
-        song.add_log(format!(
-            "[ERROR]({}) {}",
-            cur.line,
-            song.get_message(MessageKind::TooManyErrorsInLexer)
-        ));
+        let msg = song
+            .get_message(MessageKind::TooManyErrorsInLexer)
+            .to_string();
+        add_lex_error(cur, song, MessageKind::TooManyErrorsInLexer, msg);
     } else if song.get_logs_len() < LEX_MAX_ERROR {
-        song.add_log(log);
+        song.add_diagnostic(d);
     }
 }
 
 pub(super) fn read_error_cmd(cur: &mut SourceCursor, song: &mut Song, cmd: &str) -> Token {
     let near = cur.peek_str_n(8).replace('\n', "↵");
     let error_log = format!(
-        "[ERROR]({}) {} \"{}\" {} \"{}\"",
-        cur.line,
+        "{} \"{}\" {} \"{}\"",
         song.get_message(MessageKind::ScriptSyntaxError),
         cmd,
         song.get_message(MessageKind::Near),
         near,
     );
+    let d = lex_diagnostic(
+        cur,
+        song,
+        Severity::Error,
+        MessageKind::ScriptSyntaxError,
+        error_log,
+    );
     if song.debug {
-        println!("{}", error_log);
+        println!("{}", d.to_log());
     }
-    song.add_log(error_log);
+    song.add_diagnostic(d);
     return Token::new_empty("ERROR", cur.line);
 }
 
-pub(super) fn read_error(cur: &mut SourceCursor, song: &mut Song, msg: &str) -> Token {
+pub(super) fn read_error(
+    cur: &mut SourceCursor,
+    song: &mut Song,
+    kind: MessageKind,
+    msg: &str,
+) -> Token {
     let near = cur.peek_str_n(8).replace('\n', "↵");
-    song.add_log(format!(
-        "[ERROR]({}) {} {} \"{}\"",
-        cur.line,
+    let log = format!(
+        "{} {} \"{}\"",
         msg,
         song.get_message(MessageKind::Near),
-        near,
-    ));
+        near
+    );
+    add_lex_error(cur, song, kind, log);
     return Token::new_empty("ERROR", cur.line);
 }
 
@@ -70,17 +108,23 @@ pub(super) fn lex_error_missing_arg(cur: &mut SourceCursor, song: &mut Song, cmd
         near = "[EOS]".to_string();
     }
     let log = format!(
-        "[ERROR]({}) {}: \"{}\" {} \"{}\"",
-        cur.line,
+        "{}: \"{}\" {} \"{}\"",
         song.get_message(MessageKind::ErrorMissingArgument),
         cmd,
         song.get_message(MessageKind::Near),
         near,
     );
+    let d = lex_diagnostic(
+        cur,
+        song,
+        Severity::Error,
+        MessageKind::ErrorMissingArgument,
+        log,
+    );
     if song.debug {
-        println!("{}", log);
+        println!("{}", d.to_log());
     }
-    song.add_log(log);
+    song.add_diagnostic(d);
 }
 
 pub(super) fn read_warning(
@@ -90,15 +134,22 @@ pub(super) fn read_warning(
     reason: &str,
 ) -> Token {
     let near = cur.peek_str_n(8).replace('\n', "↵");
-    song.add_log(format!(
-        "[WARN]({}) {} \"{}\" {} : {} \"{}\"",
-        cur.line,
+    let log = format!(
+        "{} \"{}\" {} : {} \"{}\"",
         song.get_message(MessageKind::ScriptSyntaxWarning),
         cmd,
         reason,
         song.get_message(MessageKind::Near),
         near,
-    ));
+    );
+    let d = lex_diagnostic(
+        cur,
+        song,
+        Severity::Warning,
+        MessageKind::ScriptSyntaxWarning,
+        log,
+    );
+    song.add_diagnostic(d);
     return Token::new_empty("ERROR", cur.line);
 }
 
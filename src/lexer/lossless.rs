@@ -0,0 +1,130 @@
+//! lexer: 元のソースを失わずに区切る字句解析 (整形用)
+//!
+//! 全てのトークンの文字列をつなげると元のソースに戻る。空白・改行・コメントもトークンにする。
+//!
+//! lex_source からは作れない。lex_source はストトン表記をMMLに変換し、マクロを展開して、
+//! 空白やコメントを捨ててから読むので、トークンの範囲をつなげても元のソースには戻らない。
+//! 整形に要るのは空白・改行・コメント・ブロックの区切りだけなので、ここでは文字で区切る。
+//! 音符や命令の位置が要るとき (小節線を入れる位置など) は lex_source のトークンの範囲を使う。
+
+/// 区切ったトークンの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceTokenKind {
+    /// 空白・タブ
+    Space,
+    /// 改行 ("\n" または "\r\n")
+    Newline,
+    /// 一行コメント `//` `///` `##` `# ` `#-` (改行は含まない)
+    Comment,
+    /// 範囲コメント `/* ... */`
+    BlockComment,
+    /// 引数の文字列 "..."
+    Str,
+    /// `{`
+    Open,
+    /// `}`
+    Close,
+    /// 大文字から始まる命令名・変数名 (ex) Track / System.TimeBase
+    Word,
+    /// それ以外 (音符・数値・記号など)
+    Text,
+}
+
+/// 元のソースの一部分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceToken {
+    pub kind: SourceTokenKind,
+    pub text: String,
+    /// 行番号 (0始まり)
+    pub line: usize,
+}
+
+fn is_word_start(c: char) -> bool {
+    c.is_ascii_uppercase() || c == '_'
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// トークンの区切りになる文字か
+fn is_break(chars: &[char], i: usize) -> bool {
+    let c = chars[i];
+    let next = chars.get(i + 1).copied();
+    matches!(c, ' ' | '\t' | '\r' | '\n' | '{' | '}')
+        || is_word_start(c)
+        || (c == '/' && matches!(next, Some('/') | Some('*')))
+}
+
+/// ソースを失わずにトークンへ区切る
+pub fn lex_lossless(src: &str) -> Vec<SourceToken> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut res: Vec<SourceToken> = vec![];
+    let mut line = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        // 直前の空白以外の文字 (引数の文字列か、1度だけオクターブを下げる `"` かの判定用)
+        let prev = res
+            .iter()
+            .rev()
+            .find(|t| t.kind != SourceTokenKind::Space)
+            .and_then(|t| t.text.chars().last());
+        let kind = if c == '\n' || (c == '\r' && next == Some('\n')) {
+            i += if c == '\r' { 2 } else { 1 };
+            SourceTokenKind::Newline
+        } else if matches!(c, ' ' | '\t' | '\r') {
+            while i < chars.len() && matches!(chars[i], ' ' | '\t')
+                || (i < chars.len() && chars[i] == '\r' && chars.get(i + 1) != Some(&'\n'))
+            {
+                i += 1;
+            }
+            SourceTokenKind::Space
+        } else if (c == '/' && next == Some('/'))
+            || (c == '#' && matches!(next, Some('#') | Some(' ') | Some('-')))
+        {
+            while i < chars.len() && chars[i] != '\n' && chars[i] != '\r' {
+                i += 1;
+            }
+            SourceTokenKind::Comment
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i = (i + 2).min(chars.len());
+            SourceTokenKind::BlockComment
+        } else if c == '"' && matches!(prev, Some('(') | Some(',') | Some('=') | Some('{')) {
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            i = (i + 1).min(chars.len());
+            SourceTokenKind::Str
+        } else if c == '{' {
+            i += 1;
+            SourceTokenKind::Open
+        } else if c == '}' {
+            i += 1;
+            SourceTokenKind::Close
+        } else if is_word_start(c) {
+            while i < chars.len() && is_word_char(chars[i]) {
+                i += 1;
+            }
+            SourceTokenKind::Word
+        } else {
+            i += 1;
+            while i < chars.len() && !is_break(&chars, i) && chars[i] != '"' {
+                i += 1;
+            }
+            SourceTokenKind::Text
+        };
+        let text: String = chars[start..i].iter().collect();
+        let lines = text.matches('\n').count();
+        res.push(SourceToken { kind, text, line });
+        line += lines;
+    }
+    res
+}
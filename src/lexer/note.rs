@@ -239,7 +239,12 @@ pub(super) fn read_unknown_on_command(
         return None;
     }
     let _ = read_int_args_tokens(cur, song); // 引数を読み飛ばす
-    let mut msg = format!("not supported : {}.{}", target, cmd);
+    let mut msg = format!(
+        "{} : {}.{}",
+        song.get_message(MessageKind::ErrorNotSupported),
+        target,
+        cmd
+    );
     // 音符の中で音量を波形状に変化させたい場合は、ベロシティではなく
     // エクスプレッション(CC#11)を使う必要があるので、代替手段を案内する
     if cmd == "onNoteWave" || cmd == "W" {
@@ -249,7 +254,7 @@ pub(super) fn read_unknown_on_command(
             msg = format!("{} (hint: CC.onNoteWave / PB.onNoteWave)", msg);
         }
     }
-    song.add_log(format!("[ERROR]({}) {}", cur.line, msg));
+    add_lex_error(cur, song, MessageKind::ErrorNotSupported, msg.clone());
     Some(Token::new_empty(&msg, cur.line))
 }
 
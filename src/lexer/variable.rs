@@ -77,8 +77,9 @@ pub(super) fn read_def_user_function(cur: &mut SourceCursor, song: &mut Song) ->
         return read_error_cmd(cur, song, "FUNCTION");
     }
     let lineno = cur.line;
+    let body_origin = cur.origin_of_nest('{');
     let body_s = cur.get_token_nest('{', '}');
-    let body_tok = lex(song, &body_s, lineno);
+    let body_tok = lex_at(song, &body_s, lineno, body_origin);
     song.variables_stack_pop(); // destroy local variables
                                 // register variables
     let func_val = song
@@ -142,7 +143,12 @@ pub(super) fn check_variables(
                 song.get_message(MessageKind::ErrorDefineVariableIsReserved),
                 cmd
             );
-            return Some(read_error(cur, song, &msg));
+            return Some(read_error(
+                cur,
+                song,
+                MessageKind::ErrorDefineVariableIsReserved,
+                &msg,
+            ));
         }
         // let str
         if cur.eq_char('{') {
@@ -247,10 +253,8 @@ pub(super) fn read_def_var(
     cur.skip_space();
     let var_name = cur.get_word();
     if var_name == "" {
-        song.add_log(format!(
-            "[ERROR]({}): Variable's name should be Upper case like \"Test\".",
-            cur.line
-        ));
+        let msg = song.get_message(MessageKind::ErrorVariableName).to_string();
+        add_lex_error(cur, song, MessageKind::ErrorVariableName, msg);
         return Token::new_empty("Failed to def INT", cur.line);
     }
     // check reserved words
@@ -260,7 +264,7 @@ pub(super) fn read_def_var(
             song.get_message(MessageKind::ErrorDefineVariableIsReserved),
             var_name
         );
-        read_error(cur, song, &msg);
+        read_error(cur, song, MessageKind::ErrorDefineVariableIsReserved, &msg);
         return Token::new_empty("Failed to def INT", cur.line);
     }
     cur.skip_space();
@@ -304,7 +308,10 @@ pub(super) fn read_def_var(
             Token::new_variable(TokenType::DefArray, var_name, val_tokens)
         }
         _ => {
-            song.add_log(format!("[ERROR]({}): Invalid value type.", cur.line));
+            let msg = song
+                .get_message(MessageKind::ErrorInvalidValueType)
+                .to_string();
+            add_lex_error(cur, song, MessageKind::ErrorInvalidValueType, msg);
             return Token::new_empty("Failed to def INT", cur.line);
         }
     };
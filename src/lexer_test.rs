@@ -165,4 +165,141 @@ mod tests {
         println!("{:?}", tokens);
         assert_eq!(&tokens_to_str(&tokens), "[Comment#TIMEBASE=48]");
     }
+
+    fn song_with_files(files: &[(&str, &str)]) -> Song {
+        use crate::include_resolver::MemoryIncludeResolver;
+        let mut resolver = MemoryIncludeResolver::default();
+        for (name, src) in files {
+            resolver.add_file(name, src);
+        }
+        let mut song = Song::new();
+        song.set_include_resolver(Box::new(resolver));
+        song
+    }
+
+    #[test]
+    fn test_include() {
+        // 読み込んだファイルをその場所に展開する
+        let mut song = song_with_files(&[("a.mml", "de")]);
+        let tokens = lex(&mut song, "c Include(a.mml) f", 0);
+        assert_eq!(&tokens_to_str(&tokens), "[Note,0][Note,2][Note,4][Note,5]");
+        // 書式の違い・ストトン表記
+        let mut song = song_with_files(&[("a.mml", "ドレ"), ("b.mml", "ミ")]);
+        let src = crate::sutoton::convert("Include(\"a.mml\") 読む(b.mml)");
+        let tokens = lex(&mut song, &src, 0);
+        assert_eq!(&tokens_to_str(&tokens), "[Note,0][Note,2][Note,4]");
+        // 二度目以降は読み込まない (インクルードガード)
+        let mut song = song_with_files(&[("a.mml", "c"), ("b.mml", "Include(a.mml) d")]);
+        let tokens = lex(&mut song, "Include(a.mml) Include(b.mml)", 0);
+        assert_eq!(&tokens_to_str(&tokens), "[Note,0][Note,2]");
+        assert_eq!(song.get_logs_str(), "");
+    }
+
+    #[test]
+    fn test_include_errors() {
+        // 見つからない
+        let mut song = Song::new();
+        lex(&mut song, "Include(none.mml)", 0);
+        assert!(song
+            .get_logs_str()
+            .contains("Include file not found: \"none.mml\""));
+        // 循環参照
+        let mut song =
+            song_with_files(&[("a.mml", "Include(b.mml)"), ("b.mml", "\nInclude(a.mml)")]);
+        lex(&mut song, "Include(a.mml)", 0);
+        let log = song.get_logs_str();
+        assert!(
+            log.starts_with("[ERROR](b.mml:1) Circular Include: \"a.mml\""),
+            "{}",
+            log
+        );
+    }
+
+    #[test]
+    fn test_include_lineno() {
+        use crate::token::TokenType;
+        // 読み込んだファイルのエラーは、そのファイルの行番号で報告する
+        let mut song = song_with_files(&[("a.mml", "c\n\nc!")]);
+        let tokens = lex(&mut song, "c\nInclude(a.mml)\n!", 0);
+        let log = song.get_logs_str();
+        assert!(
+            log.contains("[ERROR](a.mml:2) Unknown Character"),
+            "{}",
+            log
+        );
+        assert!(log.contains("[ERROR](2) Unknown Character"), "{}", log);
+        // LineNoトークンにソースの番号が入る
+        let lines: Vec<(isize, isize)> = tokens
+            .iter()
+            .filter(|t| t.ttype == TokenType::LineNo)
+            .map(|t| (t.value_i, t.lineno))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![(0, 0), (0, 1), (1, 0), (1, 1), (1, 2), (0, 1), (0, 2)]
+        );
+    }
+
+    fn note_spans(tokens: &[crate::token::Token]) -> Vec<(isize, isize, usize, usize)> {
+        use crate::token::TokenType;
+        let mut res = vec![];
+        for t in tokens {
+            if t.ttype == TokenType::Note {
+                let s = t.span.unwrap();
+                res.push((s.line, s.column, s.start, s.end));
+            }
+            if let Some(children) = &t.children {
+                res.extend(note_spans(children));
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_span() {
+        // トークンに元のソースでの位置が入る
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, "cd\n  e");
+        assert_eq!(
+            note_spans(&tokens),
+            vec![(0, 0, 0, 1), (0, 1, 1, 2), (1, 2, 5, 6)]
+        );
+        // 入れ子のトークン
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, "[2 c\n {de}f]");
+        assert_eq!(
+            note_spans(&tokens),
+            vec![(0, 3, 3, 4), (1, 2, 7, 8), (1, 3, 8, 9), (1, 5, 10, 11)]
+        );
+        // ストトン表記は変換前の位置になる
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, "ドレ\n ミ");
+        assert_eq!(
+            note_spans(&tokens),
+            vec![(0, 0, 0, 1), (0, 1, 1, 2), (1, 1, 4, 5)]
+        );
+    }
+
+    #[test]
+    fn test_span_diagnostic_column() {
+        // エラーの列番号
+        let mut song = Song::new();
+        crate::lexer::lex_source(&mut song, "c\n  !");
+        let d = &song.get_diagnostics()[0];
+        assert_eq!((d.line, d.column), (1, 2));
+        // 実行時に字句解析する文字列も元の位置で報告する
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, "c\nPlay({cd!e})");
+        crate::runner::exec(&mut song, &tokens);
+        let d = &song.get_diagnostics()[0];
+        assert_eq!((d.line, d.column), (1, 8));
+        // マクロや変数を展開した先の実行時エラーは、展開した位置で報告する
+        for src in ["#A={TimeSig(1)}\n  #A", "Str MM={TimeSig(1)}\n  Play(MM)"] {
+            let mut song = Song::new();
+            let tokens = crate::lexer::lex_source(&mut song, src);
+            crate::runner::exec(&mut song, &tokens);
+            let d = &song.get_diagnostics()[0];
+            assert_eq!((d.line, d.column), (1, 2), "{}", src);
+        }
+    }
 }
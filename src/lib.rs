@@ -2,20 +2,38 @@
 //! This compiler that converts the text of "cde" into MIDI files.
 //! It is a tool that allows you to easily create music.
 
+pub mod abc;
+pub mod chord;
+pub mod diagnostic;
+pub mod formatter;
+pub mod include_resolver;
+pub mod key_signature;
 pub mod lexer;
+pub mod lint;
+// 言語サーバーはコマンドの説明(mml_def)を埋め込むので、WASMには入れない
+#[cfg(not(target_arch = "wasm32"))]
+pub mod lsp;
 pub mod midi;
+pub mod midi_to_mml;
 pub mod mml_def;
+pub mod musicxml;
+pub mod musicxml_to_mml;
 pub mod note_length;
 pub mod runner;
 pub mod sakura_functions;
 pub mod sakura_message;
 pub mod sakura_version;
+pub mod smf;
 pub mod song;
 pub mod song_test;
+pub mod soundfont;
 pub mod source_cursor;
+pub mod span;
 pub mod sutoton;
 pub mod svalue;
+pub mod tempo_map;
 pub mod token;
+pub mod wav;
 
 #[cfg(test)]
 mod lexer_test;
@@ -24,6 +42,9 @@ mod lexer_test;
 mod runner_test;
 
 extern crate wasm_bindgen;
+use diagnostic::Diagnostic;
+use sakura_message::MessageKind;
+use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
 
 /// Debug level - no info
@@ -68,6 +89,12 @@ pub struct SakuraCompiler {
     lang: String,
     debug_level: u32,
     max_input_size: usize,
+    include_files: HashMap<String, String>,
+    source_map: Vec<midi::SourceMapEntry>,
+    input_format: String,
+    output_options: midi::MidiOutputOptions,
+    tempo_map: tempo_map::TempoMap,
+    soundfont: Option<soundfont::SoundFont>,
 }
 #[wasm_bindgen]
 impl SakuraCompiler {
@@ -79,6 +106,12 @@ impl SakuraCompiler {
             debug_level: 0,
             lang: "en".to_string(),
             max_input_size: SAKURA_MAX_INPUT_SIZE,
+            include_files: HashMap::new(),
+            source_map: vec![],
+            input_format: "mml".to_string(),
+            output_options: midi::MidiOutputOptions::default(),
+            tempo_map: tempo_map::TempoMap::default(),
+            soundfont: None,
         }
     }
     /// compile to MIDI data
@@ -86,34 +119,73 @@ impl SakuraCompiler {
         // 同じコンパイラを再利用しても、前回の曲やログを引き継がない。
         self.song = song::Song::new();
         self.log_str.clear();
+        self.source_map.clear();
         if self.debug_level > 0 {
             self.song.debug = true;
         }
         self.song.set_language(&self.lang);
+        self.song
+            .set_include_resolver(Box::new(include_resolver::MemoryIncludeResolver::new(
+                self.include_files.clone(),
+            )));
         if source.len() > self.max_input_size {
             let msg = format!(
-                "[ERROR](0) Input size exceeds max_input_size ({} > {})",
+                "{} ({} > {})",
+                self.song.get_message(MessageKind::ErrorInputSize),
                 source.len(),
                 self.max_input_size
             );
-            self.song.add_log(msg);
+            self.song.add_error(MessageKind::ErrorInputSize, 0, msg);
             let log_text = self.song.get_logs_str();
             self.log_str.push_str(&log_text);
             return vec![];
         }
-        // convert sutoton
-        let source_mml = sutoton::convert(source);
-        // parse MML
-        let tokens = lexer::lex(&mut self.song, &source_mml, 0);
+        // convert sutoton (or ABC) & parse MML
+        let tokens = if self.input_format == "abc" {
+            lexer::lex_abc_source(&mut self.song, source)
+        } else {
+            lexer::lex_source(&mut self.song, source)
+        };
         // run Tokens
         runner::exec(&mut self.song, &tokens);
         // generate MIDI
-        let bin = midi::generate(&mut self.song);
+        let (bin, source_map) = midi::generate_with_options(&mut self.song, &self.output_options);
+        self.source_map = source_map;
+        let tempo_map = tempo_map::TempoMap::new(&self.song);
+        self.tempo_map = match self.output_options.ppq {
+            Some(ppq) => tempo_map.rescale(ppq as isize),
+            None => tempo_map,
+        };
         // get log text
         let log_text = self.song.get_logs_str();
         self.log_str.push_str(&log_text);
         bin
     }
+    /// add a file for Include (ex) Include(drums.mml)
+    pub fn add_include_file(&mut self, name: &str, source: &str) {
+        self.include_files
+            .insert(name.to_string(), source.to_string());
+    }
+    /// remove all files for Include
+    pub fn clear_include_files(&mut self) {
+        self.include_files.clear();
+    }
+    /// set input format "mml" or "abc"
+    pub fn set_input_format(&mut self, format: &str) {
+        self.input_format = format.to_ascii_lowercase();
+    }
+    /// set SMF format 0 (single track) or 1
+    pub fn set_smf_format(&mut self, format: u16) {
+        self.output_options.format = if format == 0 { 0 } else { 1 };
+    }
+    /// set output PPQ (0: use TimeBase)
+    pub fn set_ppq(&mut self, ppq: u16) {
+        self.output_options.ppq = if ppq == 0 || ppq > 0x7FFF {
+            None
+        } else {
+            Some(ppq)
+        };
+    }
     /// set message language
     pub fn set_language(&mut self, code: &str) {
         self.lang = code.to_string();
@@ -122,6 +194,82 @@ impl SakuraCompiler {
     pub fn get_log(&self) -> String {
         self.log_str.to_string()
     }
+    /// get errors and warnings as JSON
+    /// (ex) [{"severity":"error","kind":"UnknownChar","line":0,"column":0,"source":"","message":"..."}]
+    pub fn get_diagnostics_json(&self) -> String {
+        diagnostic::diagnostics_to_json(self.song.get_diagnostics())
+    }
+    /// check MML source and get lint warnings as JSON (same format as get_diagnostics_json)
+    pub fn lint(&self, source: &str) -> String {
+        let mut song = song::Song::new();
+        song.set_language(&self.lang);
+        song.set_include_resolver(Box::new(include_resolver::MemoryIncludeResolver::new(
+            self.include_files.clone(),
+        )));
+        diagnostic::diagnostics_to_json(&lint::lint(&mut song, source))
+    }
+    /// get the source map of the last compiled MIDI as JSON
+    /// (ex) [{"track":1,"tick":0,"offset":34,"size":4,"span":{"source":0,"start":0,"end":1,"line":0,"column":0}}]
+    pub fn get_source_map_json(&self) -> String {
+        midi::source_map_to_json(&self.source_map)
+    }
+    /// get tempo and time signature changes of the last compiled MIDI as JSON
+    /// (ex) {"timebase":96,"tempos":[{"tick":0,"bpm":120,"seconds":0}],"timeSignatures":[{"tick":0,"measure":1,"numerator":4,"denominator":4}]}
+    pub fn get_tempo_map_json(&self) -> String {
+        self.tempo_map.to_json()
+    }
+    /// convert MIDI tick to seconds
+    pub fn tick_to_seconds(&self, tick: i32) -> f64 {
+        self.tempo_map.tick_to_seconds(tick as isize)
+    }
+    /// convert seconds to MIDI tick
+    pub fn seconds_to_tick(&self, seconds: f64) -> i32 {
+        self.tempo_map.seconds_to_tick(seconds) as i32
+    }
+    /// convert MIDI tick to "measure:beat:step" (ex) 001:001:000
+    pub fn tick_to_bar(&self, tick: i32) -> String {
+        self.tempo_map.tick_to_bar(tick as isize).to_string()
+    }
+    /// convert measure:beat:step to MIDI tick
+    pub fn bar_to_tick(&self, measure: i32, beat: i32, step: i32) -> i32 {
+        let pos = tempo_map::BarPos {
+            measure: measure as isize,
+            beat: beat as isize,
+            step: step as isize,
+        };
+        self.tempo_map.bar_to_tick(pos) as i32
+    }
+    /// load a SoundFont (SF2) for render_audio (false on error, see get_log)
+    pub fn load_soundfont(&mut self, bin: &[u8]) -> bool {
+        match soundfont::parse(bin) {
+            Ok(font) => {
+                self.soundfont = Some(font);
+                true
+            }
+            Err(e) => {
+                self.log_str.push_str(&format!("[ERROR](0) {}\n", e));
+                false
+            }
+        }
+    }
+    /// unload the SoundFont (render_audio uses simple oscillators)
+    pub fn clear_soundfont(&mut self) {
+        self.soundfont = None;
+    }
+    /// render MIDI data to audio samples (stereo, interleaved L,R / -1.0 to 1.0)
+    pub fn render_audio(&mut self, bin: &[u8], sample_rate: u32) -> Vec<f32> {
+        let options = wav::RenderOptions {
+            sample_rate,
+            soundfont: self.soundfont.as_ref(),
+        };
+        match wav::render_smf(bin, &options) {
+            Ok(pcm) => pcm,
+            Err(msg) => {
+                self.log_str.push_str(&format!("[ERROR](0) {}\n", msg));
+                vec![]
+            }
+        }
+    }
     /// set debug level
     pub fn set_debug_level(&mut self, level: u32) {
         self.debug_level = level;
@@ -149,13 +297,39 @@ pub fn compile_to_midi(source: &str, debug_level: u32) -> Vec<u8> {
     if debug_level >= 1 {
         song.debug = true;
     }
-    let source_mml = sutoton::convert(source);
-    let tokens = lexer::lex(&mut song, &source_mml, 0);
+    let tokens = lexer::lex_source(&mut song, source);
     runner::exec(&mut song, &tokens);
     let bin = midi::generate(&mut song);
     bin
 }
 
+/// compile source to MusicXML (partwise)
+#[wasm_bindgen]
+pub fn compile_to_musicxml(source: &str) -> String {
+    let mut song = song::Song::new();
+    let tokens = lexer::lex_source(&mut song, source);
+    runner::exec(&mut song, &tokens);
+    musicxml::song_to_musicxml(&song)
+}
+
+/// convert MIDI data to MML (ex) TR(1) CH(1) l4 o5 cde
+#[wasm_bindgen]
+pub fn convert_midi_to_mml(bin: &[u8]) -> String {
+    match midi_to_mml::midi_to_mml(bin) {
+        Ok(mml) => mml,
+        Err(msg) => format!("// [ERROR] {}", msg),
+    }
+}
+
+/// convert MusicXML (partwise) to MML (ex) TR(1) CH(1) KeyFlag+(f) l4 cde
+#[wasm_bindgen]
+pub fn convert_musicxml_to_mml(source: &str) -> String {
+    match musicxml_to_mml::musicxml_to_mml(source) {
+        Ok(mml) => mml,
+        Err(msg) => format!("// [ERROR] {}", msg),
+    }
+}
+
 // ------------------------------------------
 // Functions for Rust Native
 // ------------------------------------------
@@ -164,8 +338,12 @@ pub fn compile_to_midi(source: &str, debug_level: u32) -> Vec<u8> {
 pub struct SakuraResult {
     /// MIDI binary data
     pub bin: Vec<u8>,
-    /// MIDI binary data
+    /// log text
     pub log: String,
+    /// errors and warnings
+    pub diagnostics: Vec<Diagnostic>,
+    /// tempo and time signature changes
+    pub tempo_map: tempo_map::TempoMap,
 }
 
 /// compile source to MIDI data
@@ -174,12 +352,16 @@ pub fn compile(source: &str, debug_level: u32) -> SakuraResult {
     if debug_level >= 1 {
         song.debug = true;
     }
-    let source_mml = sutoton::convert(source);
-    let tokens = lexer::lex(&mut song, &source_mml, 0);
+    let tokens = lexer::lex_source(&mut song, source);
     runner::exec(&mut song, &tokens);
     let bin = midi::generate(&mut song);
     let log_text = song.get_logs_str();
-    SakuraResult { bin, log: log_text }
+    SakuraResult {
+        bin,
+        log: log_text,
+        diagnostics: song.get_diagnostics().to_vec(),
+        tempo_map: tempo_map::TempoMap::new(&song),
+    }
 }
 
 #[cfg(test)]
@@ -218,6 +400,108 @@ mod public_api_tests {
             .contains("Input size exceeds max_input_size (3 > 2)"));
     }
 
+    #[test]
+    fn compile_returns_diagnostics() {
+        let result = compile("c\n!", SAKURA_DEBUG_NONE);
+        assert_eq!(result.diagnostics.len(), 1);
+        let d = &result.diagnostics[0];
+        assert_eq!(d.severity, diagnostic::Severity::Error);
+        assert_eq!(d.kind, MessageKind::UnknownChar);
+        assert_eq!(d.line, 1);
+        assert_eq!(d.source, "");
+        assert_eq!(result.log, d.to_log());
+    }
+
+    #[test]
+    fn compiler_returns_diagnostics_as_json() {
+        let mut compiler = SakuraCompiler::new();
+        compiler.set_language("ja");
+        compiler.compile("Print(1)");
+        assert_eq!(
+            compiler.get_diagnostics_json(),
+            "[{\"severity\":\"info\",\"kind\":\"Print\",\"line\":0,\"column\":0,\"source\":\"\",\"message\":\"1\"}]"
+        );
+        compiler.compile("o4c");
+        assert_eq!(compiler.get_diagnostics_json(), "[]");
+    }
+
+    #[test]
+    fn compiler_converts_ticks_with_the_tempo_map() {
+        let mut compiler = SakuraCompiler::new();
+        compiler.set_ppq(480);
+        compiler.compile("Tempo(60) TimeSignature(3,4) c2. Tempo(120) c");
+        assert_eq!(compiler.tick_to_seconds(480 * 3), 3.0);
+        assert_eq!(compiler.seconds_to_tick(3.5), 480 * 4);
+        assert_eq!(compiler.tick_to_bar(480 * 4), "002:002:000");
+        assert_eq!(compiler.bar_to_tick(2, 2, 0), 480 * 4);
+        assert!(compiler
+            .get_tempo_map_json()
+            .starts_with("{\"timebase\":480,\"tempos\":[{\"tick\":0,\"bpm\":60,\"seconds\":0},"));
+    }
+
+    #[test]
+    fn compiler_renders_audio_samples() {
+        let mut compiler = SakuraCompiler::new();
+        let bin = compiler.compile("P(0) c");
+        let pcm = compiler.render_audio(&bin, 8000);
+        assert!(pcm.len() > 8000);
+        assert!(pcm.iter().step_by(2).any(|s| s.abs() > 0.01));
+        assert!(!compiler.load_soundfont(b"RIFF"));
+        assert!(compiler.render_audio(b"MThd", 8000).is_empty());
+        assert!(compiler
+            .get_log()
+            .contains("[ERROR](0) Not SoundFont (SF2) file"));
+    }
+
+    #[test]
+    fn compiler_returns_source_map_as_json() {
+        let mut compiler = SakuraCompiler::new();
+        let bin = compiler.compile("TR=1 c");
+        let json = compiler.get_source_map_json();
+        // 空のトラック0の後、トラック1のNoteOn
+        assert_eq!(&bin[34..38], &[0x00, 0x90, 60, 100]);
+        assert!(
+            json.contains("\"tick\":0,\"offset\":34,\"size\":4,\"span\":{\"source\":0,\"start\":5,\"end\":6,\"line\":0,\"column\":5}"),
+            "{}",
+            json
+        );
+        compiler.compile("");
+        assert_eq!(compiler.get_source_map_json(), "[]");
+    }
+
+    #[test]
+    fn compiler_compiles_abc_when_selected() {
+        let mut compiler = SakuraCompiler::new();
+        compiler.set_input_format("abc");
+        let bin = compiler.compile("X:1\nK:D\nL:1/4\nF A d2|]\n");
+        let dump = compiler.dump_midi(bin);
+        assert_eq!(compiler.get_log(), "");
+        assert!(dump.contains("NoteOn($42"), "{}", dump);
+        assert!(compiler
+            .get_source_map_json()
+            .contains("\"start\":14,\"end\":15,\"line\":3,\"column\":0"));
+        compiler.compile("K:C\nC ?");
+        assert!(compiler
+            .get_diagnostics_json()
+            .contains("\"kind\":\"ErrorAbcSyntax\",\"line\":1,\"column\":2"));
+    }
+
+    #[test]
+    fn compiler_includes_files_from_the_memory_map() {
+        let mut compiler = SakuraCompiler::new();
+        compiler.add_include_file("drums.mml", "Str KICK={n36}");
+        compiler.add_include_file("part.mml", "Include(drums.mml) o4d");
+        let bin = compiler.compile("Include(part.mml) Include(drums.mml) KICK");
+        let dump = compiler.dump_midi(bin);
+        assert_eq!(compiler.get_log(), "");
+        assert!(dump.contains("NoteOn($32"));
+        assert!(dump.contains("NoteOn($24"));
+
+        compiler.clear_include_files();
+        compiler.compile("Include(drums.mml)");
+        assert!(compiler.get_log().contains("Include file not found"));
+    }
+
     #[test]
     fn malformed_mml_does_not_panic() {
         for source in ["Tempo()", "TimeSig()", "SysEx()", "[", "Function F(){"] {
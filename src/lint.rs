@@ -0,0 +1,372 @@
+//! Lint - MMLの静的チェック
+//!
+//! コンパイルは通るが間違いの可能性が高い書き方を、ルールID付きの警告として返す。
+//! 行に `// lint-ignore` (全ルール) や `// lint-ignore unused-variable` と書くと、その行の警告を出さない。
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::lexer::{lex_lossless, lex_source, SourceToken, SourceTokenKind};
+use crate::runner::exec;
+use crate::sakura_message::MessageKind;
+use crate::song::{Event, EventType, Song};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// 行ごとの警告を消すコメント
+const IGNORE_MARK: &str = "lint-ignore";
+
+/// 警告の種類のルールID
+pub fn rule_id(kind: MessageKind) -> Option<&'static str> {
+    match kind {
+        MessageKind::LintUnusedVariable => Some("unused-variable"),
+        MessageKind::LintCodeAfterEnd => Some("code-after-end"),
+        MessageKind::LintSharedChannel => Some("shared-channel"),
+        MessageKind::LintNoteOutOfRange => Some("note-out-of-range"),
+        MessageKind::LintVoiceAfterNote => Some("voice-after-note"),
+        MessageKind::WarningLoopCountZero => Some("loop-count-zero"),
+        MessageKind::LintShadowedFunction => Some("shadowed-function"),
+        _ => None,
+    }
+}
+
+/// ソースを実行してチェックし、警告を行の順に返す
+///
+/// `song` は言語やIncludeの設定を済ませた新しい曲を渡す。
+pub fn lint(song: &mut Song, src: &str) -> Vec<Diagnostic> {
+    let tokens = lex_source(song, src);
+    exec(song, &tokens);
+    let source = lex_lossless(src);
+    let mut res = vec![];
+    check_unused_variables(song, &source, &mut res);
+    check_code_after_end(song, &source, &mut res);
+    check_shadowed_functions(song, &source, &mut res);
+    check_shared_channels(song, &mut res);
+    check_note_range(song, &mut res);
+    check_voice_after_note(song, &mut res);
+    check_loop_count(song, &mut res);
+    let ignored = ignored_rules(&source);
+    let main = song.source_names[0].clone();
+    res.retain(|d| {
+        let rules = match ignored.get(&d.line) {
+            Some(rules) if d.source == main => rules,
+            _ => return true,
+        };
+        let id = rule_id(d.kind).unwrap_or("");
+        !rules.is_empty() && !rules.iter().any(|r| r == id)
+    });
+    res.sort_by_key(|d| d.line);
+    res
+}
+
+/// 新しい曲でソースをチェックする
+pub fn lint_source(src: &str) -> Vec<Diagnostic> {
+    lint(&mut Song::new(), src)
+}
+
+/// 警告を作る (ex) Variable is defined but never used: "A" [unused-variable]
+fn warning(
+    song: &Song,
+    kind: MessageKind,
+    source_no: usize,
+    line: usize,
+    detail: &str,
+) -> Diagnostic {
+    let mut msg = song.get_message(kind).to_string();
+    if !detail.is_empty() {
+        msg.push_str(": ");
+        msg.push_str(detail);
+    }
+    if let Some(id) = rule_id(kind) {
+        msg.push_str(&format!(" [{}]", id));
+    }
+    let source = song
+        .source_names
+        .get(source_no)
+        .cloned()
+        .unwrap_or_default();
+    Diagnostic::new(Severity::Warning, kind, line as isize, &source, msg)
+}
+
+/// 空白以外の次のトークン
+fn next_token(tokens: &[SourceToken], index: usize) -> Option<&SourceToken> {
+    tokens[index + 1..]
+        .iter()
+        .find(|t| t.kind != SourceTokenKind::Space)
+}
+
+/// `Int A` `Function Foo` のように、定義の命令に続く名前の一覧 (名前, 行)
+fn definitions<'a>(tokens: &'a [SourceToken], words: &[&str]) -> Vec<(&'a str, usize)> {
+    let mut res = vec![];
+    for (i, t) in tokens.iter().enumerate() {
+        if t.kind != SourceTokenKind::Word || !words.contains(&t.text.as_str()) {
+            continue;
+        }
+        if let Some(name) = next_token(tokens, i).filter(|n| n.kind == SourceTokenKind::Word) {
+            res.push((name.text.as_str(), name.line));
+        }
+    }
+    res
+}
+
+/// 定義したのに一度も使っていない変数
+fn check_unused_variables(song: &Song, tokens: &[SourceToken], res: &mut Vec<Diagnostic>) {
+    let mut count: HashMap<&str, usize> = HashMap::new();
+    for t in tokens.iter() {
+        if !matches!(
+            t.kind,
+            SourceTokenKind::Word | SourceTokenKind::Text | SourceTokenKind::Str
+        ) {
+            continue;
+        }
+        for word in t
+            .text
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        {
+            *count.entry(word).or_insert(0) += 1;
+        }
+    }
+    let words = ["Int", "INT", "Str", "STR", "Array", "ARRAY"];
+    for (name, line) in definitions(tokens, &words) {
+        if count.get(name).copied().unwrap_or(0) <= 1 {
+            let detail = format!("\"{}\"", name);
+            res.push(warning(
+                song,
+                MessageKind::LintUnusedVariable,
+                0,
+                line,
+                &detail,
+            ));
+        }
+    }
+}
+
+/// End命令の後ろに書いたコード (コメントだけなら警告しない)
+fn check_code_after_end(song: &Song, tokens: &[SourceToken], res: &mut Vec<Diagnostic>) {
+    let mut depth = 0;
+    let mut iter = tokens.iter();
+    for t in iter.by_ref() {
+        match t.kind {
+            SourceTokenKind::Open => depth += 1,
+            SourceTokenKind::Close => depth -= 1,
+            SourceTokenKind::Word if depth == 0 && (t.text == "End" || t.text == "END") => break,
+            _ => {}
+        }
+    }
+    let code = iter.find(|t| {
+        !matches!(
+            t.kind,
+            SourceTokenKind::Space
+                | SourceTokenKind::Newline
+                | SourceTokenKind::Comment
+                | SourceTokenKind::BlockComment
+        )
+    });
+    if let Some(t) = code {
+        res.push(warning(song, MessageKind::LintCodeAfterEnd, 0, t.line, ""));
+    }
+}
+
+/// システム関数と同じ名前のユーザー関数
+fn check_shadowed_functions(song: &Song, tokens: &[SourceToken], res: &mut Vec<Diagnostic>) {
+    for (name, line) in definitions(tokens, &["Function", "FUNCTION"]) {
+        if song.system_functions.contains_key(name) || song.calc_functions.contains_key(name) {
+            let detail = format!("\"{}\"", name);
+            res.push(warning(
+                song,
+                MessageKind::LintShadowedFunction,
+                0,
+                line,
+                &detail,
+            ));
+        }
+    }
+}
+
+/// 音符のイベントの (トラック, イベント) の一覧
+fn note_events(song: &Song) -> impl Iterator<Item = (usize, &Event)> {
+    song.tracks.iter().enumerate().flat_map(|(no, trk)| {
+        trk.events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOn)
+            .map(move |e| (no, e))
+    })
+}
+
+/// 複数のトラックの音符が同じチャンネルで鳴る (CH()で指定したトラック同士なら警告しない)
+fn check_shared_channels(song: &Song, res: &mut Vec<Diagnostic>) {
+    // チャンネルごと(ポートも区別する)の、音符を鳴らしたトラックと最初の音符
+    let mut channels: BTreeMap<isize, BTreeMap<usize, &Event>> = BTreeMap::new();
+    for (no, e) in note_events(song) {
+        let first = channels
+            .entry(song.tracks[no].port * 16 + e.channel)
+            .or_default()
+            .entry(no)
+            .or_insert(e);
+        if e.time < first.time {
+            *first = e;
+        }
+    }
+    for (ch, tracks) in channels.iter() {
+        if tracks.len() < 2 {
+            continue;
+        }
+        let names: Vec<String> = tracks.keys().map(|no| format!("TR({})", no)).collect();
+        for (no, e) in tracks.iter() {
+            if song.tracks[*no].channel_specified {
+                continue;
+            }
+            let span = e.span.unwrap_or_default();
+            let detail = format!("CH({}) {}", ch + 1, names.join(", "));
+            res.push(warning(
+                song,
+                MessageKind::LintSharedChannel,
+                span.source_no,
+                span.line.max(0) as usize,
+                &detail,
+            ));
+        }
+    }
+}
+
+/// KeyShift や TrackKey で範囲外になった音符 (同じ行は1回だけ)
+fn check_note_range(song: &Song, res: &mut Vec<Diagnostic>) {
+    let mut lines = HashSet::new();
+    for (_, e) in note_events(song) {
+        if (0..=127).contains(&e.v1) {
+            continue;
+        }
+        let span = e.span.unwrap_or_default();
+        if lines.insert((span.source_no, span.line)) {
+            res.push(warning(
+                song,
+                MessageKind::LintNoteOutOfRange,
+                span.source_no,
+                span.line.max(0) as usize,
+                &e.v1.to_string(),
+            ));
+        }
+    }
+}
+
+/// トラックの最初の音色指定が、最初の音符より後にある
+fn check_voice_after_note(song: &Song, res: &mut Vec<Diagnostic>) {
+    for (no, trk) in song.tracks.iter().enumerate() {
+        let first_note = trk
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOn)
+            .map(|e| e.time)
+            .min();
+        let first_voice = trk
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::Voice)
+            .min_by_key(|e| e.time);
+        if let (Some(note), Some(voice)) = (first_note, first_voice) {
+            if voice.time > note {
+                let span = voice.span.unwrap_or_default();
+                res.push(warning(
+                    song,
+                    MessageKind::LintVoiceAfterNote,
+                    span.source_no,
+                    span.line.max(0) as usize,
+                    &format!("TR({})", no),
+                ));
+            }
+        }
+    }
+}
+
+/// 回数が0のループ (実行時の警告をルールID付きにする)
+fn check_loop_count(song: &Song, res: &mut Vec<Diagnostic>) {
+    for d in song.get_diagnostics() {
+        if d.kind != MessageKind::WarningLoopCountZero {
+            continue;
+        }
+        let mut d = d.clone();
+        d.message = format!("{} [{}]", d.message, rule_id(d.kind).unwrap_or(""));
+        res.push(d);
+    }
+}
+
+/// 行ごとの `lint-ignore` で消すルール (空なら全てのルール)
+fn ignored_rules(tokens: &[SourceToken]) -> HashMap<isize, Vec<String>> {
+    let mut res = HashMap::new();
+    for t in tokens.iter() {
+        if !matches!(
+            t.kind,
+            SourceTokenKind::Comment | SourceTokenKind::BlockComment
+        ) {
+            continue;
+        }
+        let rest = match t.text.find(IGNORE_MARK) {
+            Some(i) => &t.text[i + IGNORE_MARK.len()..],
+            None => continue,
+        };
+        let rules = rest
+            .trim_end_matches("*/")
+            .split(|c: char| c == ',' || c.is_whitespace() || c == ':')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect();
+        res.insert(t.line as isize, rules);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(src: &str) -> Vec<(String, isize)> {
+        lint_source(src)
+            .iter()
+            .map(|d| (rule_id(d.kind).unwrap_or("").to_string(), d.line))
+            .collect()
+    }
+
+    #[test]
+    fn report_unused_variables_and_code_after_end() {
+        let src = "Int A = 1\nInt B = 2\nTR(1) l4 c(B)\nEnd\n// memo\ncde";
+        assert_eq!(
+            rules(src),
+            vec![
+                ("unused-variable".to_string(), 0),
+                ("code-after-end".to_string(), 5)
+            ]
+        );
+        assert!(lint_source(src)[0]
+            .message
+            .ends_with("\"A\" [unused-variable]"));
+        // コメントだけなら警告しない
+        assert!(rules("cde\nEnd\n// memo").is_empty());
+    }
+
+    #[test]
+    fn report_channels_notes_voices_and_loops() {
+        // TR(2)を先に使うとTR(1)もCH(2)になる
+        let src = "TR(2) c\nTR(1) c\nTR(3) CH(10) c\nTR(4) CH(10) c";
+        assert_eq!(
+            rules(src),
+            vec![
+                ("shared-channel".to_string(), 0),
+                ("shared-channel".to_string(), 1)
+            ]
+        );
+        // ポートが違えば同じチャンネル番号でも別のチャンネル
+        assert!(rules("TR(1) CH(1) c\nTR(2) CH(17) c").is_empty());
+        let src = "KeyShift(24) o9 b\nTR(2) cd @10 e\n[0 c]\nFunction Tempo(){ c }";
+        assert_eq!(
+            rules(src),
+            vec![
+                ("note-out-of-range".to_string(), 0),
+                ("voice-after-note".to_string(), 1),
+                ("loop-count-zero".to_string(), 2),
+                ("shadowed-function".to_string(), 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn suppress_warnings_per_line() {
+        let src = "Int A = 1 // lint-ignore\nInt B = 2 // lint-ignore: code-after-end\nStr C = {c} // lint-ignore unused-variable";
+        assert_eq!(rules(src), vec![("unused-variable".to_string(), 1)]);
+    }
+}
@@ -0,0 +1,765 @@
+//! Lsp - MMLの言語サーバー (Language Server Protocol)
+//!
+//! 標準入出力でJSON-RPCを話し、診断・補完・ホバー・定義へのジャンプを提供する。
+//! 診断は lexer と runner をそのまま使い、コンパイル時と同じエラー・警告を返す。
+pub mod command_doc;
+pub mod json;
+
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::include_resolver::FileIncludeResolver;
+use crate::lexer::{lex_abc_source, lex_source};
+use crate::runner::exec;
+use crate::sakura_version::SAKURA_VERSION;
+use crate::song::Song;
+use command_doc::CommandDocs;
+use json::Json;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+
+// LSPの定数
+const SYNC_FULL: f64 = 1.0;
+const KIND_FUNCTION: f64 = 3.0;
+const KIND_VARIABLE: f64 = 6.0;
+const KIND_KEYWORD: f64 = 14.0;
+const KIND_CONSTANT: f64 = 21.0;
+const ERROR_PARSE: f64 = -32700.0;
+const ERROR_METHOD_NOT_FOUND: f64 = -32601.0;
+
+/// 補完候補の1件分
+#[derive(Debug, Clone)]
+struct CompletionItem {
+    label: String,
+    kind: f64,
+    doc: String,
+}
+
+/// ユーザー定義の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefKind {
+    /// Function Name(...) {...}
+    Function,
+    /// Str Name = {...}
+    Str,
+}
+
+/// ソースの中の Function / Str の定義
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub kind: DefKind,
+    pub name: String,
+    /// 行番号 (0始まり)
+    pub line: usize,
+    /// 名前の先頭と末尾の列 (UTF-16単位)
+    pub start: usize,
+    pub end: usize,
+    /// 定義した行の内容
+    pub text: String,
+}
+
+/// 言語サーバーの状態
+pub struct LanguageServer {
+    /// URIごとの開いている文書
+    documents: HashMap<String, String>,
+    docs: CommandDocs,
+    /// ユーザー定義以外の補完候補
+    completions: Vec<CompletionItem>,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl Default for LanguageServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LanguageServer {
+    pub fn new() -> Self {
+        let docs = CommandDocs::load();
+        let song = Song::new();
+        let mut completions = vec![];
+        let groups: [(Vec<&String>, f64, &HashMap<String, String>); 4] = [
+            (
+                song.system_functions.keys().collect(),
+                KIND_FUNCTION,
+                &docs.system_functions,
+            ),
+            (
+                song.calc_functions.keys().collect(),
+                KIND_FUNCTION,
+                &docs.calc_functions,
+            ),
+            (
+                song.reserved_words.keys().collect(),
+                KIND_KEYWORD,
+                &docs.reserved_words,
+            ),
+            (
+                song.variables_stack[0].keys().collect(),
+                KIND_CONSTANT,
+                &docs.variables,
+            ),
+        ];
+        let mut used = HashSet::new();
+        for (names, kind, doc) in groups.iter() {
+            let mut names = names.clone();
+            names.sort();
+            for name in names {
+                if !used.insert(name.clone()) {
+                    continue;
+                }
+                completions.push(CompletionItem {
+                    label: name.clone(),
+                    kind: *kind,
+                    doc: doc.get(name).cloned().unwrap_or_default(),
+                });
+            }
+        }
+        Self {
+            documents: HashMap::new(),
+            docs,
+            completions,
+            shutdown: false,
+            exited: false,
+        }
+    }
+    /// exit通知を受け取ったか
+    pub fn is_exited(&self) -> bool {
+        self.exited
+    }
+    /// 終了コード (shutdownの後にexitしたら0)
+    pub fn exit_code(&self) -> i32 {
+        if self.shutdown {
+            0
+        } else {
+            1
+        }
+    }
+    /// メッセージを1件処理して、送り返すメッセージの一覧を返す
+    pub fn handle(&mut self, message: &str) -> Vec<Json> {
+        let msg = match json::parse(message) {
+            Ok(msg) => msg,
+            Err(e) => return vec![error_response(Json::Null, ERROR_PARSE, &e)],
+        };
+        let method = match msg.get("method").and_then(|m| m.as_str()) {
+            Some(method) => method.to_string(),
+            None => return vec![], // クライアントからの応答は使わない
+        };
+        let id = msg.get("id").cloned();
+        let params = msg.get("params").cloned().unwrap_or(Json::Null);
+        let result = match method.as_str() {
+            "initialize" => initialize_result(),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "exit" => {
+                self.exited = true;
+                return vec![];
+            }
+            "textDocument/didOpen" => {
+                let uri = text_str(&params, &["textDocument", "uri"]);
+                let text = text_str(&params, &["textDocument", "text"]);
+                self.documents.insert(uri.clone(), text);
+                return vec![self.publish_diagnostics(&uri)];
+            }
+            "textDocument/didChange" => {
+                let uri = text_str(&params, &["textDocument", "uri"]);
+                // 全文の同期なので最後の変更が文書全体になる
+                let changes = params.get("contentChanges").and_then(|c| c.as_array());
+                if let Some(text) = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c.get("text"))
+                    .and_then(|t| t.as_str())
+                {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                return vec![self.publish_diagnostics(&uri)];
+            }
+            "textDocument/didClose" => {
+                let uri = text_str(&params, &["textDocument", "uri"]);
+                self.documents.remove(&uri);
+                return vec![diagnostics_notification(&uri, vec![])];
+            }
+            "textDocument/completion" => self.completion(&params),
+            "textDocument/hover" => self.hover(&params),
+            "textDocument/definition" => self.definition(&params),
+            _ => match id {
+                Some(id) => {
+                    let reason = format!("Method not found: {}", method);
+                    return vec![error_response(id, ERROR_METHOD_NOT_FOUND, &reason)];
+                }
+                None => return vec![],
+            },
+        };
+        match id {
+            Some(id) => vec![Json::object(vec![
+                ("jsonrpc", Json::str("2.0")),
+                ("id", id),
+                ("result", result),
+            ])],
+            None => vec![],
+        }
+    }
+    /// 文書をコンパイルして診断を通知する
+    fn publish_diagnostics(&self, uri: &str) -> Json {
+        let text = self.documents.get(uri).map(|s| s.as_str()).unwrap_or("");
+        let items = check(uri, text)
+            .iter()
+            .map(|d| diagnostic_to_json(d, text))
+            .collect();
+        diagnostics_notification(uri, items)
+    }
+    fn completion(&self, params: &Json) -> Json {
+        let mut items: Vec<Json> = vec![];
+        let mut used = HashSet::new();
+        if let Some(text) = self.document(params) {
+            for def in find_definitions(text) {
+                if !used.insert(def.name.clone()) {
+                    continue;
+                }
+                let kind = match def.kind {
+                    DefKind::Function => KIND_FUNCTION,
+                    DefKind::Str => KIND_VARIABLE,
+                };
+                items.push(completion_item(&def.name, kind, &def.text));
+            }
+        }
+        for item in self.completions.iter() {
+            if used.contains(&item.label) {
+                continue;
+            }
+            items.push(completion_item(&item.label, item.kind, &item.doc));
+        }
+        Json::object(vec![
+            ("isIncomplete", Json::Bool(false)),
+            ("items", Json::Array(items)),
+        ])
+    }
+    fn hover(&self, params: &Json) -> Json {
+        let text = match self.document(params) {
+            Some(text) => text,
+            None => return Json::Null,
+        };
+        let (line, character) = position(params);
+        let (word, start, end) = match word_at(text, line, character) {
+            Some(w) => w,
+            None => return Json::Null,
+        };
+        let doc = match self.describe(text, &word) {
+            Some(doc) => doc,
+            None => return Json::Null,
+        };
+        Json::object(vec![
+            (
+                "contents",
+                Json::object(vec![
+                    ("kind", Json::str("plaintext")),
+                    ("value", Json::Str(format!("{}\n\n{}", word, doc))),
+                ]),
+            ),
+            ("range", range_json(line, start, line, end)),
+        ])
+    }
+    /// 単語の説明 (ユーザー定義、システム関数、計算式の関数、予約語、変数の順に探す)
+    fn describe(&self, text: &str, word: &str) -> Option<String> {
+        if let Some(def) = find_definitions(text).into_iter().find(|d| d.name == word) {
+            return Some(def.text);
+        }
+        let docs = &self.docs;
+        [
+            &docs.system_functions,
+            &docs.calc_functions,
+            &docs.reserved_words,
+            &docs.variables,
+        ]
+        .iter()
+        .find_map(|m| m.get(word).cloned())
+    }
+    fn definition(&self, params: &Json) -> Json {
+        let text = match self.document(params) {
+            Some(text) => text,
+            None => return Json::Null,
+        };
+        let (line, character) = position(params);
+        let word = match word_at(text, line, character) {
+            Some((word, _, _)) => word,
+            None => return Json::Null,
+        };
+        match find_definitions(text).into_iter().find(|d| d.name == word) {
+            Some(def) => Json::object(vec![
+                ("uri", Json::Str(text_str(params, &["textDocument", "uri"]))),
+                ("range", range_json(def.line, def.start, def.line, def.end)),
+            ]),
+            None => Json::Null,
+        }
+    }
+    fn document(&self, params: &Json) -> Option<&str> {
+        let uri = text_str(params, &["textDocument", "uri"]);
+        self.documents.get(&uri).map(|s| s.as_str())
+    }
+}
+
+fn initialize_result() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                ("textDocumentSync", Json::Number(SYNC_FULL)),
+                ("completionProvider", Json::object(vec![])),
+                ("hoverProvider", Json::Bool(true)),
+                ("definitionProvider", Json::Bool(true)),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object(vec![
+                ("name", Json::str("sakuramml-lsp")),
+                ("version", Json::str(SAKURA_VERSION)),
+            ]),
+        ),
+    ])
+}
+
+fn error_response(id: Json, code: f64, message: &str) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::str("2.0")),
+        ("id", id),
+        (
+            "error",
+            Json::object(vec![
+                ("code", Json::Number(code)),
+                ("message", Json::str(message)),
+            ]),
+        ),
+    ])
+}
+
+fn diagnostics_notification(uri: &str, items: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::str("2.0")),
+        ("method", Json::str("textDocument/publishDiagnostics")),
+        (
+            "params",
+            Json::object(vec![
+                ("uri", Json::str(uri)),
+                ("diagnostics", Json::Array(items)),
+            ]),
+        ),
+    ])
+}
+
+fn completion_item(label: &str, kind: f64, doc: &str) -> Json {
+    let mut item = vec![("label", Json::str(label)), ("kind", Json::Number(kind))];
+    if !doc.is_empty() {
+        item.push(("detail", Json::str(doc)));
+    }
+    Json::object(item)
+}
+
+fn range_json(line: usize, start: usize, end_line: usize, end: usize) -> Json {
+    let pos = |line: usize, character: usize| {
+        Json::object(vec![
+            ("line", Json::Number(line as f64)),
+            ("character", Json::Number(character as f64)),
+        ])
+    };
+    Json::object(vec![
+        ("start", pos(line, start)),
+        ("end", pos(end_line, end)),
+    ])
+}
+
+fn text_str(params: &Json, keys: &[&str]) -> String {
+    params
+        .path(keys)
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string()
+}
+
+fn position(params: &Json) -> (usize, usize) {
+    let get = |key: &str| {
+        params
+            .path(&["position", key])
+            .and_then(|v| v.as_i64())
+            .unwrap_or(0)
+            .max(0) as usize
+    };
+    (get("line"), get("character"))
+}
+
+/// 文書をコンパイルして、その文書についての診断を得る
+pub fn check(uri: &str, text: &str) -> Vec<Diagnostic> {
+    let mut song = Song::new();
+    if let Some(path) = uri_to_path(uri) {
+        // Include命令はこのファイルのディレクトリから探す
+        song.source_names[0] = path;
+        song.set_include_resolver(Box::new(FileIncludeResolver::new(vec![])));
+    }
+    let tokens = if song.source_names[0].to_ascii_lowercase().ends_with(".abc") {
+        lex_abc_source(&mut song, text)
+    } else {
+        lex_source(&mut song, text)
+    };
+    exec(&mut song, &tokens);
+    let main = song.source_names[0].clone();
+    song.get_diagnostics()
+        .iter()
+        .filter(|d| d.source == main)
+        .cloned()
+        .collect()
+}
+
+fn diagnostic_to_json(d: &Diagnostic, text: &str) -> Json {
+    let line = d.line.max(0) as usize;
+    let line_text = text.lines().nth(line).unwrap_or("");
+    let start = utf16_len_chars(line_text, d.column.max(0) as usize);
+    let end = line_text.encode_utf16().count().max(start);
+    let severity = match d.severity {
+        Severity::Error => 1.0,
+        Severity::Warning => 2.0,
+        Severity::Info => 3.0,
+    };
+    Json::object(vec![
+        ("range", range_json(line, start, line, end)),
+        ("severity", Json::Number(severity)),
+        ("source", Json::str("sakuramml")),
+        ("message", Json::str(&d.message)),
+    ])
+}
+
+/// file:// のURIをパスにする
+fn uri_to_path(uri: &str) -> Option<String> {
+    let rest = uri.strip_prefix("file://")?;
+    let mut bytes = vec![];
+    let src = rest.as_bytes();
+    let mut i = 0;
+    while i < src.len() {
+        let hex = src
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (src[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                bytes.push(b);
+                i += 3;
+            }
+            (c, _) => {
+                bytes.push(c);
+                i += 1;
+            }
+        }
+    }
+    let path = String::from_utf8_lossy(&bytes).to_string();
+    // Windowsのドライブ (ex) /C:/music/a.mml
+    let b = path.as_bytes();
+    if b.len() > 2 && b[0] == b'/' && b[2] == b':' {
+        return Some(path[1..].to_string());
+    }
+    Some(path)
+}
+
+/// 先頭からn文字分のUTF-16での長さ
+fn utf16_len_chars(s: &str, n: usize) -> usize {
+    s.chars().take(n).map(|c| c.len_utf16()).sum()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// 位置にある命令名・変数名と、その範囲(UTF-16単位)
+///
+/// 小文字の音符に続けて書いた命令 (ex) cdeTrack(1) は大文字から先を単語とする。
+pub fn word_at(text: &str, line: usize, character: usize) -> Option<(String, usize, usize)> {
+    let chars: Vec<char> = text.lines().nth(line)?.chars().collect();
+    // UTF-16の位置を文字の位置にする
+    let mut index = 0;
+    let mut units = 0;
+    while index < chars.len() && units + chars[index].len_utf16() <= character {
+        units += chars[index].len_utf16();
+        index += 1;
+    }
+    let mut start = index;
+    while start > 0 && is_word_char(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = index;
+    while end < chars.len() && is_word_char(chars[end]) {
+        end += 1;
+    }
+    let upper = (start..end).find(|&i| chars[i].is_ascii_uppercase())?;
+    if index < upper && index != end {
+        return None;
+    }
+    let word: String = chars[upper..end].iter().collect();
+    let word = word.trim_end_matches('.').to_string();
+    if word.is_empty() {
+        return None;
+    }
+    let offset = |i: usize| chars[..i].iter().map(|c| c.len_utf16()).sum::<usize>();
+    Some((
+        word.clone(),
+        offset(upper),
+        offset(upper) + word.encode_utf16().count(),
+    ))
+}
+
+/// ソースの中の Function / Str の定義を探す (コメントの中は除く)
+pub fn find_definitions(text: &str) -> Vec<Definition> {
+    let mut res = vec![];
+    let mut in_comment = false;
+    for (lineno, line) in text.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            if in_comment {
+                if chars[i] == '*' && chars.get(i + 1) == Some(&'/') {
+                    in_comment = false;
+                    i += 1;
+                }
+                i += 1;
+                continue;
+            }
+            if chars[i] == '/' && chars.get(i + 1) == Some(&'/') {
+                break;
+            }
+            if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+                in_comment = true;
+                i += 2;
+                continue;
+            }
+            if !chars[i].is_ascii_alphanumeric() {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let kind = match word.as_str() {
+                "Function" | "FUNCTION" => DefKind::Function,
+                "Str" | "STR" => DefKind::Str,
+                _ => continue,
+            };
+            while i < chars.len() && (chars[i] == ' ' || chars[i] == '\t') {
+                i += 1;
+            }
+            let name_start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            if name_start == i {
+                continue;
+            }
+            res.push(Definition {
+                kind,
+                name: chars[name_start..i].iter().collect(),
+                line: lineno,
+                start: utf16_len_chars(line, name_start),
+                end: utf16_len_chars(line, i),
+                text: line.trim().to_string(),
+            });
+        }
+    }
+    res
+}
+
+/// Content-Lengthのヘッダー付きのメッセージを1件読む (入力の終わりならNone)
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((key, value)) = header.split_once(':') {
+            if key.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).to_string()))
+}
+
+/// Content-Lengthのヘッダーを付けてメッセージを書く
+pub fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// exit通知か入力の終わりまでメッセージを処理して、終了コードを返す
+pub fn run<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<i32> {
+    let mut server = LanguageServer::new();
+    while let Some(message) = read_message(&mut input)? {
+        for res in server.handle(&message) {
+            write_message(&mut output, &res)?;
+        }
+        if server.is_exited() {
+            break;
+        }
+    }
+    Ok(server.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(server: &mut LanguageServer, text: &str) -> Json {
+        let msg = Json::object(vec![
+            ("jsonrpc", Json::str("2.0")),
+            ("method", Json::str("textDocument/didOpen")),
+            (
+                "params",
+                Json::object(vec![(
+                    "textDocument",
+                    Json::object(vec![
+                        ("uri", Json::str("untitled:a.mml")),
+                        ("text", Json::str(text)),
+                    ]),
+                )]),
+            ),
+        ]);
+        server.handle(&msg.to_string()).remove(0)
+    }
+
+    fn request(server: &mut LanguageServer, method: &str, line: usize, character: usize) -> Json {
+        let msg = format!(
+            r#"{{"jsonrpc":"2.0","id":7,"method":"{}","params":{{"textDocument":{{"uri":"untitled:a.mml"}},"position":{{"line":{},"character":{}}}}}}}"#,
+            method, line, character
+        );
+        let mut res = server.handle(&msg);
+        assert_eq!(res[0].get("id"), Some(&Json::Number(7.0)));
+        res.remove(0).get("result").cloned().unwrap()
+    }
+
+    #[test]
+    fn publish_diagnostics_on_open() {
+        let mut server = LanguageServer::new();
+        let res = open(&mut server, "cde\nFooBar(3)\n");
+        let list = res
+            .path(&["params", "diagnostics"])
+            .and_then(|d| d.as_array())
+            .unwrap();
+        assert!(!list.is_empty());
+        assert_eq!(
+            list[0].path(&["range", "start", "line"]),
+            Some(&Json::Number(1.0))
+        );
+        assert_eq!(list[0].get("severity"), Some(&Json::Number(1.0)));
+        // マクロの展開先の実行時エラーも、展開した位置から下線を引く
+        let res = open(&mut server, "#A={TimeSig(1)}\n  #A\n");
+        let list = res
+            .path(&["params", "diagnostics"])
+            .and_then(|d| d.as_array())
+            .unwrap();
+        assert_eq!(
+            list[0].path(&["range", "start", "line"]),
+            Some(&Json::Number(1.0))
+        );
+        assert_eq!(
+            list[0].path(&["range", "start", "character"]),
+            Some(&Json::Number(2.0))
+        );
+        let res = open(&mut server, "cde");
+        let list = res
+            .path(&["params", "diagnostics"])
+            .and_then(|d| d.as_array())
+            .unwrap();
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn hover_completion_and_definition() {
+        let mut server = LanguageServer::new();
+        open(
+            &mut server,
+            "Str Melody = {cde}\nFunction Foo() { c }\ncdeTrack(1) Melody Foo()\n",
+        );
+        let hover = request(&mut server, "textDocument/hover", 2, 5);
+        let value = hover
+            .path(&["contents", "value"])
+            .and_then(|v| v.as_str())
+            .unwrap();
+        assert!(value.starts_with("Track\n\nchange current track"));
+        assert_eq!(
+            hover.path(&["range", "start", "character"]),
+            Some(&Json::Number(3.0))
+        );
+        // 定義へジャンプ
+        let def = request(&mut server, "textDocument/definition", 2, 14);
+        assert_eq!(
+            def.path(&["range", "start", "line"]),
+            Some(&Json::Number(0.0))
+        );
+        assert_eq!(
+            def.path(&["range", "start", "character"]),
+            Some(&Json::Number(4.0))
+        );
+        let def = request(&mut server, "textDocument/definition", 2, 20);
+        assert_eq!(
+            def.path(&["range", "start", "line"]),
+            Some(&Json::Number(1.0))
+        );
+        // 補完
+        let res = request(&mut server, "textDocument/completion", 0, 0);
+        let items = res.get("items").and_then(|i| i.as_array()).unwrap();
+        let label = |i: &Json| {
+            i.get("label")
+                .and_then(|l| l.as_str())
+                .unwrap_or("")
+                .to_string()
+        };
+        for name in ["Melody", "Foo", "Track", "Random", "IF", "OctaveUnison"] {
+            assert!(items.iter().any(|i| label(i) == name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn read_and_write_framed_messages() {
+        let init = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#;
+        let input = format!("Content-Length: {}\r\n\r\n{}", init.len(), init);
+        let mut out = vec![];
+        let code = run(input.as_bytes(), &mut out).unwrap();
+        assert_eq!(code, 1);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("Content-Length: "));
+        assert!(out.contains("\"hoverProvider\":true"));
+
+        let shutdown = r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#;
+        let exit = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let input = format!(
+            "Content-Length: {}\r\n\r\n{}Content-Length: {}\r\n\r\n{}",
+            shutdown.len(),
+            shutdown,
+            exit.len(),
+            exit
+        );
+        assert_eq!(run(input.as_bytes(), &mut vec![]).unwrap(), 0);
+    }
+
+    #[test]
+    fn convert_file_uri_to_path() {
+        assert_eq!(
+            uri_to_path("file:///home/a%20b.mml").unwrap(),
+            "/home/a b.mml"
+        );
+        assert_eq!(
+            uri_to_path("file:///C:/music/a.mml").unwrap(),
+            "C:/music/a.mml"
+        );
+        assert_eq!(uri_to_path("untitled:1"), None);
+    }
+}
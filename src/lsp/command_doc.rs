@@ -0,0 +1,139 @@
+//! CommandDoc - コマンドの説明
+//!
+//! mml_defのソースに書いた注釈(command.mdの元)を埋め込んで読み、補完やホバーの説明にする。
+use std::collections::HashMap;
+
+const SYSTEM_FUNCTIONS_SRC: &str = include_str!("../mml_def/system_functions.rs");
+const RESERVED_WORDS_SRC: &str = include_str!("../mml_def/reserved_words.rs");
+const VARIABLES_SRC: &str = include_str!("../mml_def/variables.rs");
+
+/// 種類ごとのコマンド名と説明
+#[derive(Debug, Clone, Default)]
+pub struct CommandDocs {
+    /// sysfunc_add! の注釈
+    pub system_functions: HashMap<String, String>,
+    /// syscalc_add! の注釈 (書式と説明を改行でつなぐ)
+    pub calc_functions: HashMap<String, String>,
+    pub reserved_words: HashMap<String, String>,
+    pub variables: HashMap<String, String>,
+}
+
+impl CommandDocs {
+    pub fn load() -> Self {
+        let sysfunc = section(SYSTEM_FUNCTIONS_SRC, "SYSTEM_FUNCTION");
+        let calc = section(SYSTEM_FUNCTIONS_SRC, "SYSTEM_CALC_FUNCTION");
+        let calc_functions = annotated_calls(calc, "syscalc_add!(")
+            .into_iter()
+            .map(|(name, comment)| match comment.split_once("//") {
+                Some((format, desc)) => (name, format!("{}\n{}", format.trim(), desc.trim())),
+                None => (name, comment),
+            })
+            .collect();
+        Self {
+            system_functions: annotated_calls(sysfunc, "sysfunc_").into_iter().collect(),
+            calc_functions,
+            reserved_words: annotated_calls(section(RESERVED_WORDS_SRC, "RESERVED"), "var.insert(")
+                .into_iter()
+                .collect(),
+            variables: annotated_calls(section(VARIABLES_SRC, "VARIABLES"), "var.insert(")
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+/// `<MARKER>` から `</MARKER>` までを得る
+fn section<'a>(src: &'a str, marker: &str) -> &'a str {
+    let open = format!("<{}>", marker);
+    let close = format!("</{}>", marker);
+    let start = match src.find(&open) {
+        Some(i) => i + open.len(),
+        None => return "",
+    };
+    let end = src[start..]
+        .find(&close)
+        .map(|i| start + i)
+        .unwrap_or(src.len());
+    &src[start..end]
+}
+
+/// 呼び出しの最初の文字列引数(名前)と、末尾の注釈の組を得る
+fn annotated_calls(section: &str, call: &str) -> Vec<(String, String)> {
+    let mut res = vec![];
+    let mut rest = section;
+    while let Some(pos) = rest.find(call) {
+        rest = &rest[pos + call.len()..];
+        let end = match call_end(rest) {
+            Some(end) => end,
+            None => break,
+        };
+        let args = &rest[..end];
+        rest = &rest[end..];
+        let name = match first_string(args) {
+            Some(name) => name,
+            None => continue,
+        };
+        if let Some(comment) = trailing_comment(rest) {
+            let desc = comment.trim_start_matches('@').trim();
+            res.push((name, desc.to_string()));
+        }
+    }
+    res
+}
+
+/// 文字列の中を飛ばして、呼び出しの末尾 `;` の次の位置を得る
+fn call_end(s: &str) -> Option<usize> {
+    let mut in_str = false;
+    let mut escape = false;
+    for (i, c) in s.char_indices() {
+        if in_str {
+            match c {
+                _ if escape => escape = false,
+                '\\' => escape = true,
+                '"' => in_str = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_str = true,
+            ';' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn first_string(args: &str) -> Option<String> {
+    let start = args.find('"')? + 1;
+    let len = args[start..].find('"')?;
+    Some(args[start..start + len].to_string())
+}
+
+/// 同じ行か次の行の `//` 以降を得る
+fn trailing_comment(rest: &str) -> Option<&str> {
+    let mut lines = rest.lines();
+    let first = lines.next()?.trim();
+    let line = if first.is_empty() {
+        lines.next()?.trim()
+    } else {
+        first
+    };
+    line.strip_prefix("//").map(|s| s.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_annotations_from_mml_def() {
+        let docs = CommandDocs::load();
+        assert!(docs.system_functions["Track"].contains("change current track"));
+        // 複数行に分かれた呼び出しも読む
+        assert!(docs.system_functions["PortamentoTime"].contains("Portamento Time"));
+        assert!(docs.calc_functions["Random"].starts_with("Random(N, M) | Random(N)\n"));
+        assert_eq!(docs.reserved_words["IF"], "IF .. ELSE ..");
+        assert!(docs.variables.contains_key("OctaveUnison"));
+    }
+}
@@ -0,0 +1,291 @@
+//! Json - 言語サーバーで使う最小限のJSON
+//!
+//! JSON-RPCのメッセージを読み書きするための値と、パーサー・文字列化を持つ。
+use std::fmt;
+
+/// JSONの値 (オブジェクトはキーの順番を保つ)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// キーと値の組からオブジェクトを作る
+    pub fn object(items: Vec<(&str, Json)>) -> Json {
+        Json::Object(items.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+    pub fn str(s: &str) -> Json {
+        Json::Str(s.to_string())
+    }
+    /// オブジェクトのキーの値を得る
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(items) => items.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    /// `a.b.c` のようにたどって値を得る
+    pub fn path(&self, keys: &[&str]) -> Option<&Json> {
+        let mut cur = self;
+        for key in keys {
+            cur = cur.get(key)?;
+        }
+        Some(cur)
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) => Some(*n as i64),
+            _ => None,
+        }
+    }
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => {
+                if n.fract() == 0.0 && n.abs() < 1e15 {
+                    write!(f, "{}", *n as i64)
+                } else {
+                    write!(f, "{}", n)
+                }
+            }
+            Json::Str(s) => write_str(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, v) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(items) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// JSON文字列を読む
+pub fn parse(src: &str) -> Result<Json, String> {
+    let mut p = Parser {
+        chars: src.chars().collect(),
+        index: 0,
+    };
+    let v = p.value()?;
+    p.skip_space();
+    if p.index < p.chars.len() {
+        return Err(format!("unexpected data at {}", p.index));
+    }
+    Ok(v)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    index: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+    fn skip_space(&mut self) {
+        while let Some(' ' | '\t' | '\r' | '\n') = self.peek() {
+            self.index += 1;
+        }
+    }
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        for c in word.chars() {
+            if self.peek() != Some(c) {
+                return Err(format!("expected \"{}\" at {}", word, self.index));
+            }
+            self.index += 1;
+        }
+        Ok(())
+    }
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_space();
+        match self.peek() {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::Str),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some('-' | '0'..='9') => self.number(),
+            _ => Err(format!("unexpected value at {}", self.index)),
+        }
+    }
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.index;
+        while let Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9') = self.peek() {
+            self.index += 1;
+        }
+        let s: String = self.chars[start..self.index].iter().collect();
+        s.parse::<f64>()
+            .map(Json::Number)
+            .map_err(|_| format!("invalid number at {}", start))
+    }
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let d = self
+                .peek()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| format!("invalid escape at {}", self.index))?;
+            code = code * 16 + d;
+            self.index += 1;
+        }
+        Ok(code)
+    }
+    fn string(&mut self) -> Result<String, String> {
+        self.index += 1; // skip '"'
+        let mut res = String::new();
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| "unterminated string".to_string())?;
+            self.index += 1;
+            match c {
+                '"' => return Ok(res),
+                '\\' => {
+                    let e = self.peek().unwrap_or('\0');
+                    self.index += 1;
+                    match e {
+                        'n' => res.push('\n'),
+                        'r' => res.push('\r'),
+                        't' => res.push('\t'),
+                        'b' => res.push('\u{8}'),
+                        'f' => res.push('\u{c}'),
+                        'u' => {
+                            let mut code = self.hex4()?;
+                            // サロゲートペア
+                            if (0xD800..0xDC00).contains(&code) && self.peek() == Some('\\') {
+                                self.expect("\\u")?;
+                                let low = self.hex4()?;
+                                code = 0x10000
+                                    + ((code - 0xD800) << 10)
+                                    + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            res.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        c => res.push(c),
+                    }
+                }
+                c => res.push(c),
+            }
+        }
+    }
+    fn array(&mut self) -> Result<Json, String> {
+        self.index += 1; // skip '['
+        let mut items = vec![];
+        self.skip_space();
+        if self.peek() == Some(']') {
+            self.index += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_space();
+            match self.peek() {
+                Some(',') => self.index += 1,
+                Some(']') => {
+                    self.index += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(format!("expected ',' or ']' at {}", self.index)),
+            }
+        }
+    }
+    fn object(&mut self) -> Result<Json, String> {
+        self.index += 1; // skip '{'
+        let mut items = vec![];
+        self.skip_space();
+        if self.peek() == Some('}') {
+            self.index += 1;
+            return Ok(Json::Object(items));
+        }
+        loop {
+            self.skip_space();
+            if self.peek() != Some('"') {
+                return Err(format!("expected key at {}", self.index));
+            }
+            let key = self.string()?;
+            self.skip_space();
+            self.expect(":")?;
+            items.push((key, self.value()?));
+            self.skip_space();
+            match self.peek() {
+                Some(',') => self.index += 1,
+                Some('}') => {
+                    self.index += 1;
+                    return Ok(Json::Object(items));
+                }
+                _ => return Err(format!("expected ',' or '}}' at {}", self.index)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_write_round_trip() {
+        let src = r#"{"id":1,"params":{"text":"c\"d\"\nあ","list":[true,null,-2.5]}}"#;
+        let v = parse(src).unwrap();
+        assert_eq!(v.get("id").and_then(|v| v.as_i64()), Some(1));
+        assert_eq!(
+            v.path(&["params", "text"]).and_then(|v| v.as_str()),
+            Some("c\"d\"\nあ")
+        );
+        assert_eq!(parse(&v.to_string()).unwrap(), v);
+        assert!(parse("{\"a\":}").is_err());
+    }
+}
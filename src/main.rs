@@ -2,13 +2,22 @@
 
 use std::fs::{self, read_to_string, File};
 use std::io::{Read, Write};
+use std::path::PathBuf;
 
+use sakuramml::formatter::{format_mml, FormatOptions};
 use sakuramml::get_build_number;
-use sakuramml::lexer::lex;
-use sakuramml::midi::{dump_midi, generate};
+use sakuramml::include_resolver::FileIncludeResolver;
+use sakuramml::lexer::{lex_abc_source, lex_source};
+use sakuramml::lint::lint;
+use sakuramml::midi::{dump_midi, generate_with_options, source_map_to_json, MidiOutputOptions};
+use sakuramml::midi_to_mml::midi_to_mml;
+use sakuramml::musicxml::song_to_musicxml;
+use sakuramml::musicxml_to_mml::musicxml_to_mml;
 use sakuramml::runner::exec;
 use sakuramml::sakura_version::SAKURA_VERSION;
 use sakuramml::song::{Song, SAKURA_DEFAULT_MAX_EVENT_BYTES, SAKURA_DEFAULT_RANDOM_SEED};
+use sakuramml::soundfont::{self, SoundFont};
+use sakuramml::wav::{smf_to_wav, song_to_wav, RenderOptions, WAV_SAMPLE_RATE};
 
 // for randomize
 use std::collections::hash_map::DefaultHasher;
@@ -43,16 +52,29 @@ fn version_label() -> String {
 /// show usage
 fn usage() {
     println!(
-        "=== sakuramml {} ===\n{}{}{}{}{}{}{}{}{}",
+        "=== sakuramml {} ===\n{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}",
         version_label(),
         "USAGE:\n",
-        "  sakuramml (mmlfile) (midifile)\n",
+        "  sakuramml (mmlfile|abcfile) (midifile|xmlfile)\n",
+        "  sakuramml fmt mmlfile (outfile)  Format MML source (default: overwrite)\n",
         "OPTIONS:\n",
         "  -d, --debug    Debug mode\n",
         "  -e, --eval     Compile (MML)\n",
         "  -h, --help     Show help\n",
         "  -v, --version  Show version\n",
         "  -m, --dump     Dump midi file\n",
+        "      --mml      Convert midi or MusicXML file to MML (midifile|xmlfile) (mmlfile)\n",
+        "      --musicxml Write MusicXML instead of MIDI (.musicxml)\n",
+        "      --lint     Check MML source for likely mistakes\n",
+        "  -I, --include-path DIR  Add a search path for Include\n",
+        "      --source-map FILE    Write MIDI event to MML mapping as JSON\n",
+        "      --smf-format 0|1     Write SMF format 0 (single track) or 1 (default)\n",
+        "      --ppq N              Rescale output resolution to N ticks per quarter\n",
+        "      --wav FILE           Also render a preview WAV with simple oscillators\n",
+        "      --soundfont FILE     Use an SF2 file for WAV rendering\n",
+        "      --bar-lines          Insert | at measure boundaries (fmt)\n",
+        "      --from POS           Play from M:B:S or the position of Marker{\"POS\"}\n",
+        "      --to POS             Stop at M:B:S or the position of Marker{\"POS\"}\n",
         format!(
             "      --max-event-bytes N  Set MIDI event data limit (default: {})\n",
             SAKURA_DEFAULT_MAX_EVENT_BYTES,
@@ -89,6 +111,9 @@ fn main() {
     let mut mode = String::from("mml2mid");
     let mut debug = false;
     let mut max_event_bytes = SAKURA_DEFAULT_MAX_EVENT_BYTES;
+    let mut include_paths: Vec<PathBuf> = vec![];
+    let mut output = OutputSettings::default();
+    let mut format_options = FormatOptions::default();
     let mut i = 1;
     while i < args.len() {
         let arg = &args[i];
@@ -110,6 +135,16 @@ fn main() {
             outfile = String::from("eval.mid");
         } else if arg == "--dump" || arg == "dump" || arg == "-m" {
             mode = String::from("dump");
+        } else if arg == "--mml" {
+            mode = String::from("mid2mml");
+        } else if arg == "--musicxml" {
+            mode = String::from("mml2xml");
+        } else if arg == "fmt" || arg == "--fmt" {
+            mode = String::from("fmt");
+        } else if arg == "--lint" || arg == "lint" {
+            mode = String::from("lint");
+        } else if arg == "--bar-lines" {
+            format_options.bar_lines = true;
         } else if arg == "--max-event-bytes" {
             i += 1;
             if i >= args.len() {
@@ -123,6 +158,73 @@ fn main() {
                     std::process::exit(1);
                 }
             };
+        } else if arg == "--include-path" || arg == "-I" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("[ERROR](0): --include-path requires a directory");
+                std::process::exit(1);
+            }
+            include_paths.push(PathBuf::from(&args[i]));
+        } else if arg == "--source-map" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("[ERROR](0): --source-map requires a file name");
+                std::process::exit(1);
+            }
+            output.source_map_file = String::from(&args[i]);
+        } else if arg == "--wav" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("[ERROR](0): --wav requires a file name");
+                std::process::exit(1);
+            }
+            output.wav_file = String::from(&args[i]);
+        } else if arg == "--soundfont" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("[ERROR](0): --soundfont requires a file name");
+                std::process::exit(1);
+            }
+            output.soundfont = match load_soundfont(&args[i]) {
+                Ok(font) => Some(font),
+                Err(msg) => {
+                    eprintln!("[ERROR](0): {}", msg);
+                    std::process::exit(1);
+                }
+            };
+        } else if arg == "--from" || arg == "--to" {
+            i += 1;
+            let pos = match args.get(i) {
+                Some(pos) => pos.clone(),
+                None => {
+                    eprintln!("[ERROR](0): {} requires a position or a marker name", arg);
+                    std::process::exit(1);
+                }
+            };
+            if arg == "--from" {
+                output.play_from = Some(pos);
+            } else {
+                output.play_to = Some(pos);
+            }
+        } else if arg == "--smf-format" {
+            i += 1;
+            output.midi.format = match args.get(i).map(|v| v.as_str()) {
+                Some("0") => 0,
+                Some("1") => 1,
+                _ => {
+                    eprintln!("[ERROR](0): --smf-format requires 0 or 1");
+                    std::process::exit(1);
+                }
+            };
+        } else if arg == "--ppq" {
+            i += 1;
+            output.midi.ppq = match args.get(i).and_then(|v| v.parse::<u16>().ok()) {
+                Some(ppq) if (1..=0x7FFF).contains(&ppq) => Some(ppq),
+                _ => {
+                    eprintln!("[ERROR](0): --ppq requires an integer from 1 to 32767");
+                    std::process::exit(1);
+                }
+            };
         } else if filename == "" {
             filename = arg.clone();
         } else if outfile == "" {
@@ -134,10 +236,30 @@ fn main() {
         usage();
         return;
     }
+    if outfile.is_empty() && mode == "mid2mml" {
+        let stem = filename
+            .strip_suffix(".mid")
+            .or(filename.strip_suffix(".midi"))
+            .or(filename.strip_suffix(".musicxml"))
+            .or(filename.strip_suffix(".xml"))
+            .unwrap_or(&filename);
+        outfile = format!("{}.mml", stem);
+    }
+    if outfile.is_empty() && mode == "mml2xml" {
+        let stem = filename
+            .strip_suffix(".mml")
+            .or(filename.strip_suffix(".abc"))
+            .unwrap_or(&filename);
+        outfile = format!("{}.musicxml", stem);
+    }
+    if outfile.is_empty() && mode == "fmt" {
+        outfile = filename.clone();
+    }
     if outfile == "" {
         outfile.push_str(&filename);
         outfile.push_str(".mid");
         outfile = outfile.replace(".mml.mid", ".mid");
+        outfile = outfile.replace(".abc.mid", ".mid");
     }
     // dump mode
     if mode == "dump" {
@@ -154,7 +276,56 @@ fn main() {
             }
         }
     }
+    // midi (or MusicXML) to mml
+    if mode == "mid2mml" {
+        let buf = match fs::read(&filename) {
+            Ok(buf) => buf,
+            Err(_e) => {
+                println!("[ERROR](0): File not found : {}", filename);
+                std::process::exit(1);
+            }
+        };
+        let lower = filename.to_ascii_lowercase();
+        let result = if lower.ends_with(".musicxml") || lower.ends_with(".xml") {
+            musicxml_to_mml(&String::from_utf8_lossy(&buf))
+        } else {
+            midi_to_mml(&buf)
+        };
+        match result {
+            Ok(mml) => {
+                fs::write(&outfile, mml).unwrap();
+                println!("ok.");
+            }
+            Err(msg) => {
+                eprintln!("[ERROR](0): {}", msg);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // format mml
+    if mode == "fmt" {
+        let src = match read_to_string(&filename) {
+            Ok(s) => s,
+            Err(_e) => {
+                println!("[ERROR](0): File not found : {}", filename);
+                std::process::exit(1);
+            }
+        };
+        match format_mml(&src, &format_options) {
+            Ok(res) => {
+                fs::write(&outfile, res).unwrap();
+                println!("ok.");
+            }
+            Err(msg) => {
+                eprintln!("[ERROR](0): {}", msg);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     // read file
+    let mut source_name = String::new();
     let src: String;
     if eval_mml != "" {
         src = eval_mml;
@@ -166,22 +337,80 @@ fn main() {
                 std::process::exit(1);
             }
         };
+        // Include命令はこのファイルのディレクトリから探す
+        source_name = fs::canonicalize(&filename)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or(filename.clone());
     }
     // --- compile mml to midi ---
-    if !compile_to_midi(&src, &outfile, debug, max_event_bytes) {
+    let resolver = FileIncludeResolver::new(include_paths);
+    if mode == "lint" {
+        if !lint_mml(&src, &source_name, resolver) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if !compile_to_midi(
+        &src,
+        &outfile,
+        debug,
+        max_event_bytes,
+        &source_name,
+        resolver,
+        &output,
+    ) {
         std::process::exit(1);
     }
 }
 
-fn compile_to_midi(src: &str, midifile: &str, debug: bool, max_event_bytes: usize) -> bool {
+/// MIDIファイルの書き出し設定
+#[derive(Default)]
+struct OutputSettings {
+    source_map_file: String,
+    wav_file: String,
+    soundfont: Option<SoundFont>,
+    midi: MidiOutputOptions,
+    /// --from で指定した位置かマーカー
+    play_from: Option<String>,
+    /// --to で指定した位置かマーカー
+    play_to: Option<String>,
+}
+
+impl OutputSettings {
+    fn render_options(&self) -> RenderOptions<'_> {
+        RenderOptions {
+            sample_rate: WAV_SAMPLE_RATE,
+            soundfont: self.soundfont.as_ref(),
+        }
+    }
+}
+
+fn load_soundfont(path: &str) -> Result<SoundFont, String> {
+    let bin = fs::read(path).map_err(|_| format!("File not found : {}", path))?;
+    soundfont::parse(&bin).map_err(|e| format!("{} : {}", e, path))
+}
+
+fn compile_to_midi(
+    src: &str,
+    midifile: &str,
+    debug: bool,
+    max_event_bytes: usize,
+    source_name: &str,
+    resolver: FileIncludeResolver,
+    output: &OutputSettings,
+) -> bool {
     let mut song = Song::new();
     song.set_max_event_bytes(max_event_bytes);
+    song.source_names[0] = source_name.to_string();
+    song.set_include_resolver(Box::new(resolver));
     song.debug = debug;
     song.rand_seed = SAKURA_DEFAULT_RANDOM_SEED ^ (time_to_u64() ^ thread_id_to_u64()) as u32;
-    // sutoton
-    let src = sakuramml::sutoton::convert(&src);
-    // println!("{}", src);
-    let tokens = lex(&mut song, &src, 0);
+    // sutoton & lex (拡張子が .abc ならABC記譜法として読む)
+    let tokens = if source_name.to_ascii_lowercase().ends_with(".abc") {
+        lex_abc_source(&mut song, src)
+    } else {
+        lex_source(&mut song, src)
+    };
     if debug {
         let tokens_str = sakuramml::token::tokens_to_debug_str(&tokens, 0);
         println!("[PARSER]\n{}", tokens_str);
@@ -189,31 +418,84 @@ fn compile_to_midi(src: &str, midifile: &str, debug: bool, max_event_bytes: usiz
     }
     // println!("lex= {:?}", tokens);
     exec(&mut song, &tokens);
+    // 演奏範囲はMMLのPlayFrom/PlayToより優先する
+    song.set_play_range(output.play_from.as_deref(), output.play_to.as_deref());
+    if !song.check_play_range() {
+        eprintln!("{}", song.get_logs_str().trim());
+        return false;
+    }
     if song.event_limit_exceeded() {
-        save_to_file(&mut song, &midifile);
+        save_to_file(&mut song, &midifile, output);
         eprintln!("{}", song.get_logs_str().trim());
         return false;
     }
     // println!("song= {:?}", song);
-    save_to_file(&mut song, &midifile);
+    if !save_to_file(&mut song, &midifile, output) {
+        return false;
+    }
     println!("{}\nok.", song.get_logs_str().trim());
     true
 }
 
-/// save song to file
-fn save_to_file(song: &mut Song, path: &str) {
+/// 警告を表示する (警告があれば false)
+fn lint_mml(src: &str, source_name: &str, resolver: FileIncludeResolver) -> bool {
+    let mut song = Song::new();
+    song.source_names[0] = source_name.to_string();
+    song.set_include_resolver(Box::new(resolver));
+    let warnings = lint(&mut song, src);
+    for d in warnings.iter() {
+        println!("{}", d.to_log());
+    }
+    if warnings.is_empty() {
+        println!("ok.");
+    }
+    warnings.is_empty()
+}
+
+/// save song to file (拡張子が .musicxml か .xml ならMusicXML、.wav ならWAVで書く)
+fn save_to_file(song: &mut Song, path: &str, output: &OutputSettings) -> bool {
+    let lower = path.to_ascii_lowercase();
+    if lower.ends_with(".musicxml") || lower.ends_with(".xml") {
+        fs::write(path, song_to_musicxml(song)).unwrap();
+        return true;
+    }
+    if lower.ends_with(".wav") {
+        return write_wav(path, song_to_wav(song, &output.render_options()));
+    }
     let mut file = File::create(path).unwrap();
-    let buf = generate(song);
+    let (buf, source_map) = generate_with_options(song, &output.midi);
+    if !output.source_map_file.is_empty() {
+        fs::write(&output.source_map_file, source_map_to_json(&source_map)).unwrap();
+    }
     if song.debug {
         dump_midi(&buf, true);
     }
     file.write(buf.as_ref()).unwrap();
     file.flush().unwrap();
+    if !output.wav_file.is_empty() {
+        return write_wav(&output.wav_file, smf_to_wav(&buf, &output.render_options()));
+    }
+    true
+}
+
+fn write_wav(path: &str, wav: Result<Vec<u8>, String>) -> bool {
+    match wav {
+        Ok(wav) => {
+            fs::write(path, wav).unwrap();
+            true
+        }
+        Err(msg) => {
+            eprintln!("[ERROR](0): {}", msg);
+            false
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sakuramml::lexer::lex;
+    use sakuramml::midi::generate;
     use std::time::{SystemTime, UNIX_EPOCH};
 
     /// mml -> midi -> dump
@@ -238,7 +520,15 @@ mod tests {
             std::process::id(),
             unique,
         ));
-        let ok = compile_to_midi("[1000000 y1,64]", path.to_str().unwrap(), false, 64);
+        let ok = compile_to_midi(
+            "[1000000 y1,64]",
+            path.to_str().unwrap(),
+            false,
+            64,
+            "",
+            FileIncludeResolver::default(),
+            &OutputSettings::default(),
+        );
         assert!(!ok);
         assert!(fs::read(&path).unwrap().starts_with(b"MThd"));
         fs::remove_file(path).unwrap();
@@ -1,7 +1,10 @@
 //! MIDI file generator and analizer
 
+use super::key_signature::KeySignature;
 /// midi
-use super::song::{EventType, Song, Track};
+use super::song::{Event, EventType, Song, Track};
+use super::span::Span;
+use super::tempo_map::TimeSigMap;
 
 /// MIDI Event
 const MIDI_RPN_MSB: u8 = 0x65;
@@ -43,160 +46,322 @@ fn array_push_delta(res: &mut Vec<u8>, time: isize) {
     }
 }
 
-fn generate_track(track: &Track) -> Vec<u8> {
-    let mut res: Vec<u8> = vec![];
-    let mut timepos = 0;
-    for e in &track.events {
-        match e.etype {
-            EventType::NoteOn => {
-                let note_no = e.v1;
-                // note_len = e.v2 // not use
-                let note_vel = e.v3;
-                // note on
-                array_push_delta(&mut res, e.time - timepos);
-                timepos = e.time;
-                res.push(0x90 + e.channel as u8);
-                res.push(note_no as u8); // note_no
-                res.push(note_vel as u8); // velocity
-            }
-            EventType::NoteOff => {
-                let note_no = e.v1;
-                // note_len = e.v2 // not use
-                let note_vel = e.v3;
-                array_push_delta(&mut res, e.time - timepos);
-                timepos = e.time;
-                res.push(0x80 + e.channel as u8);
-                res.push(note_no as u8);
-                res.push(note_vel as u8);
-            }
-            EventType::Voice => {
-                array_push_delta(&mut res, e.time - timepos);
-                timepos = e.time;
-                res.push(0xC0 + e.channel as u8);
-                res.push(e.v1 as u8);
-            }
-            EventType::ControllChange => {
-                array_push_delta(&mut res, e.time - timepos);
-                timepos = e.time;
-                res.push(0xB0 + e.channel as u8);
-                res.push(e.v1 as u8);
-                res.push(e.v2 as u8);
+/// イベントを1つ書き込む (timeは出力する分解能での時間)
+fn write_event(res: &mut Vec<u8>, timepos: &mut isize, e: &Event, time: isize) {
+    match e.etype {
+        EventType::NoteOn => {
+            let note_no = e.v1;
+            // note_len = e.v2 // not use
+            let note_vel = e.v3;
+            // note on
+            array_push_delta(res, time - *timepos);
+            *timepos = time;
+            res.push(0x90 + e.channel as u8);
+            res.push(note_no as u8); // note_no
+            res.push(note_vel as u8); // velocity
+        }
+        EventType::NoteOff => {
+            let note_no = e.v1;
+            // note_len = e.v2 // not use
+            let note_vel = e.v3;
+            array_push_delta(res, time - *timepos);
+            *timepos = time;
+            res.push(0x80 + e.channel as u8);
+            res.push(note_no as u8);
+            res.push(note_vel as u8);
+        }
+        EventType::Voice => {
+            array_push_delta(res, time - *timepos);
+            *timepos = time;
+            res.push(0xC0 + e.channel as u8);
+            res.push(e.v1 as u8);
+        }
+        EventType::ControllChange => {
+            array_push_delta(res, time - *timepos);
+            *timepos = time;
+            res.push(0xB0 + e.channel as u8);
+            res.push(e.v1 as u8);
+            res.push(e.v2 as u8);
+        }
+        EventType::Meta => {
+            array_push_delta(res, time - *timepos);
+            *timepos = time;
+            res.push(e.v1 as u8);
+            res.push(e.v2 as u8);
+            array_push_delta(res, e.v3);
+            let data = e.data.clone().unwrap();
+            for b in data.iter() {
+                res.push(*b);
             }
-            EventType::Meta => {
-                array_push_delta(&mut res, e.time - timepos);
-                timepos = e.time;
-                res.push(e.v1 as u8);
-                res.push(e.v2 as u8);
-                array_push_delta(&mut res, e.v3);
-                let data = e.data.clone().unwrap();
-                for b in data.iter() {
-                    res.push(*b);
-                }
+        }
+        EventType::SysEx => {
+            // SysEx の書き込み処理
+            let data = e.data.clone().unwrap();
+            if data.len() == 0 {
+                return;
             }
-            EventType::SysEx => {
-                // SysEx の書き込み処理
-                let data = e.data.clone().unwrap();
-                if data.len() == 0 {
+            let delta_time = time - *timepos;
+            array_push_delta(res, delta_time);
+            *timepos = time;
+            let size = data.len() - 1;
+            // 1st byte must be 0xF0
+            res.push(0xF0); // SysEx Event
+                            // 2nd byte must be length
+            array_push_delta(res, size as isize);
+            // write data
+            for (i, b) in data.iter().enumerate() {
+                if i == 0 && *b == 0xF0 {
                     continue;
                 }
-                let delta_time = e.time - timepos;
-                array_push_delta(&mut res, delta_time);
-                timepos = e.time;
-                let size = data.len() - 1;
-                // 1st byte must be 0xF0
-                res.push(0xF0); // SysEx Event
-                                // 2nd byte must be length
-                array_push_delta(&mut res, size as isize);
-                // write data
-                for (i, b) in data.iter().enumerate() {
-                    if i == 0 && *b == 0xF0 {
-                        continue;
-                    }
-                    res.push(*b);
-                }
-            }
-            EventType::PitchBend => {
-                let v = e.v1;
-                let msb = ((v >> 7) & 0x7F) as u8;
-                let lsb = ((v >> 0) & 0x7F) as u8;
-                // println!("PB={}(0x{:02x}{:02x})", v, msb, lsb);
-                array_push_delta(&mut res, e.time - timepos);
-                timepos = e.time;
-                res.push(0xE0 + e.channel as u8);
-                res.push(lsb);
-                res.push(msb);
+                res.push(*b);
             }
-            EventType::PitchBendRange => {
-                // RPN
-                // Pitch Bend Sensitivity (3 events)
-                let range = e.v1;
-                let range = if range >= 0 && range <= 24 {
-                    range as u8
-                } else {
-                    0
-                };
-                // RPN MSB
-                array_push_delta(&mut res, e.time - timepos);
-                timepos = e.time;
-                res.push(0xB0 + e.channel as u8);
-                res.push(MIDI_RPN_MSB);
-                res.push(0);
-                // RPN LSB
-                res.push(0);
-                res.push(0xB0 + e.channel as u8);
-                res.push(MIDI_RPN_LSB);
-                res.push(0);
-                // Data Entry MSB
-                res.push(0);
-                res.push(0xB0 + e.channel as u8);
-                res.push(MIDI_DATA_ENTRY_MSB);
-                res.push(range);
+        }
+        EventType::PitchBend => {
+            let v = e.v1;
+            let msb = ((v >> 7) & 0x7F) as u8;
+            let lsb = ((v >> 0) & 0x7F) as u8;
+            // println!("PB={}(0x{:02x}{:02x})", v, msb, lsb);
+            array_push_delta(res, time - *timepos);
+            *timepos = time;
+            res.push(0xE0 + e.channel as u8);
+            res.push(lsb);
+            res.push(msb);
+        }
+        EventType::PitchBendRange => {
+            // RPN
+            // Pitch Bend Sensitivity (3 events)
+            let range = e.v1;
+            let range = if range >= 0 && range <= 24 {
+                range as u8
+            } else {
+                0
+            };
+            // RPN MSB
+            array_push_delta(res, time - *timepos);
+            *timepos = time;
+            res.push(0xB0 + e.channel as u8);
+            res.push(MIDI_RPN_MSB);
+            res.push(0);
+            // RPN LSB
+            res.push(0);
+            res.push(0xB0 + e.channel as u8);
+            res.push(MIDI_RPN_LSB);
+            res.push(0);
+            // Data Entry MSB
+            res.push(0);
+            res.push(0xB0 + e.channel as u8);
+            res.push(MIDI_DATA_ENTRY_MSB);
+            res.push(range);
+        }
+        EventType::DirectSMF => {
+            let data = e.data.clone().unwrap();
+            if data.len() == 0 {
+                return;
             }
-            EventType::DirectSMF => {
-                let data = e.data.clone().unwrap();
-                if data.len() == 0 {
-                    continue;
-                }
-                let delta_time = e.time - timepos;
-                array_push_delta(&mut res, delta_time);
-                timepos = e.time;
-                // write data
-                for b in data.iter() {
-                    res.push(*b);
-                }
+            let delta_time = time - *timepos;
+            array_push_delta(res, delta_time);
+            *timepos = time;
+            // write data
+            for b in data.iter() {
+                res.push(*b);
             }
         }
     }
-    // end of track
-    res.push(0x00);
+}
+
+fn generate_track(
+    events: &[&Event],
+    scale: &dyn Fn(isize) -> isize,
+    offsets: &mut Vec<(usize, usize)>,
+    end_time: Option<isize>,
+) -> (Vec<u8>, usize) {
+    let mut res: Vec<u8> = vec![];
+    let mut timepos = 0;
+    for (i, e) in events.iter().enumerate() {
+        let pos = res.len();
+        write_event(&mut res, &mut timepos, e, scale(e.time));
+        if res.len() > pos {
+            offsets.push((i, pos));
+        }
+    }
+    // end of track (演奏範囲の指定があれば、その長さで終える)
+    let eot_pos = res.len();
+    let end_time = end_time.map_or(timepos, |end| end.max(timepos));
+    array_push_delta(&mut res, end_time - timepos);
     res.push(0xFF);
     res.push(0x2F);
     res.push(0x00);
+    (res, eot_pos)
+}
+
+/// フォーマット0のために全トラックのイベントを時間順に1つにまとめる (トラック番号, イベント番号)
+/// トラック内の順序は変えず、同じ時間なら他のトラックのNoteOffを先にする
+fn merge_tracks(tracks: &[Track], scale: &dyn Fn(isize) -> isize) -> Vec<(usize, usize)> {
+    let mut heads = vec![0; tracks.len()];
+    let mut res = vec![];
+    loop {
+        let mut best: Option<(usize, isize, bool)> = None;
+        for (t, trk) in tracks.iter().enumerate() {
+            let e = match trk.events.get(heads[t]) {
+                Some(e) => e,
+                None => continue,
+            };
+            let time = scale(e.time);
+            let off = e.etype == EventType::NoteOff;
+            let better = match best {
+                None => true,
+                Some((_, best_time, best_off)) => {
+                    time < best_time || (time == best_time && off && !best_off)
+                }
+            };
+            if better {
+                best = Some((t, time, off));
+            }
+        }
+        match best {
+            Some((t, _, _)) => {
+                res.push((t, heads[t]));
+                heads[t] += 1;
+            }
+            None => break,
+        }
+    }
     res
 }
 
+/// SMFの出力設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MidiOutputOptions {
+    /// SMFフォーマット (0: 全トラックを1つにまとめる / 1: トラックごと)
+    /// 0と1以外は1として書く
+    pub format: u16,
+    /// 出力する分解能 (Noneなら曲のTimeBaseのまま)
+    pub ppq: Option<u16>,
+}
+
+impl Default for MidiOutputOptions {
+    fn default() -> Self {
+        Self {
+            format: 1,
+            ppq: None,
+        }
+    }
+}
+
+/// MIDIイベントとMMLの対応 (ソースマップの1項目)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceMapEntry {
+    /// トラック番号 (0始まり・MTrkの順)
+    pub track: usize,
+    /// イベントの時間 (tick)
+    pub tick: isize,
+    /// MIDIファイル先頭からのバイト位置 (デルタタイムを含む)
+    pub offset: usize,
+    /// イベントのバイト数
+    pub size: usize,
+    /// イベントを生成したMMLの範囲
+    pub span: Option<Span>,
+}
+
+impl SourceMapEntry {
+    pub fn to_json(&self) -> String {
+        let span = match &self.span {
+            Some(span) => span.to_json(),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"track\":{},\"tick\":{},\"offset\":{},\"size\":{},\"span\":{}}}",
+            self.track, self.tick, self.offset, self.size, span
+        )
+    }
+}
+
+/// ソースマップをJSONの配列にする
+pub fn source_map_to_json(list: &[SourceMapEntry]) -> String {
+    let items: Vec<String> = list.iter().map(|e| e.to_json()).collect();
+    format!("[{}]", items.join(","))
+}
+
 pub fn generate(song: &mut Song) -> Vec<u8> {
-    let midi_format = 1;
+    generate_with_source_map(song).0
+}
+
+/// MIDIを生成し、各イベントがどのMMLから生成されたかの対応も返す
+pub fn generate_with_source_map(song: &mut Song) -> (Vec<u8>, Vec<SourceMapEntry>) {
+    generate_with_options(song, &MidiOutputOptions::default())
+}
+
+/// 出力設定(フォーマット・分解能)を指定してMIDIを生成する
+/// 分解能を変えるときは時間を四捨五入するので、イベントの順序は入れ替わらない
+pub fn generate_with_options(
+    song: &mut Song,
+    options: &MidiOutputOptions,
+) -> (Vec<u8>, Vec<SourceMapEntry>) {
     let mut res: Vec<u8> = vec![];
+    let mut map: Vec<SourceMapEntry> = vec![];
+    song.assign_ports();
     song.play_from_all_track();
     song.normalize_and_sort();
+    // 切り出した範囲の長さ (ループ素材として使えるように)
+    let end_time = if song.play_to >= 0 {
+        Some(song.play_to - song.play_from.max(0))
+    } else {
+        None
+    };
+    // 書けるのはフォーマット0と1だけ
+    let format = if options.format == 0 { 0 } else { 1 };
+    let timebase = song.timebase.max(1);
+    let ppq = options.ppq.map_or(song.timebase, |ppq| ppq as isize);
+    let scale = move |time: isize| {
+        if ppq == timebase {
+            time
+        } else {
+            (time * ppq * 2 + timebase).div_euclid(timebase * 2)
+        }
+    };
+    // 出力するトラックごとのイベント (トラック番号, イベント番号)
+    let blocks: Vec<Vec<(usize, usize)>> = if format == 0 {
+        vec![merge_tracks(&song.tracks, &scale)]
+    } else {
+        song.tracks
+            .iter()
+            .enumerate()
+            .map(|(t, trk)| (0..trk.events.len()).map(|i| (t, i)).collect())
+            .collect()
+    };
     // header
     array_push_str(&mut res, "MThd");
     array_push_u32(&mut res, 6);
-    array_push_u16(&mut res, midi_format);
-    array_push_u16(&mut res, song.tracks.len() as isize);
-    array_push_u16(&mut res, song.timebase);
+    array_push_u16(&mut res, format);
+    array_push_u16(&mut res, blocks.len() as isize);
+    array_push_u16(&mut res, ppq);
     // tracks
-    for track_no in 0..song.tracks.len() {
-        let trk = &song.tracks[track_no];
-        let block = generate_track(&trk);
+    for (track_no, refs) in blocks.iter().enumerate() {
+        let events: Vec<&Event> = refs
+            .iter()
+            .map(|&(t, i)| &song.tracks[t].events[i])
+            .collect();
+        let mut offsets = vec![];
+        let (block, eot_pos) = generate_track(&events, &scale, &mut offsets, end_time.map(scale));
         array_push_str(&mut res, "MTrk");
         array_push_u32(&mut res, block.len() as isize);
+        let base = res.len();
+        for (n, &(i, pos)) in offsets.iter().enumerate() {
+            let next = offsets.get(n + 1).map_or(eot_pos, |o| o.1);
+            let e = events[i];
+            map.push(SourceMapEntry {
+                track: track_no,
+                tick: scale(e.time),
+                offset: base + pos,
+                size: next - pos,
+                span: e.span,
+            });
+        }
         for b in block {
             res.push(b);
         }
     }
-    res
+    (res, map)
 }
 
 // midi reader
@@ -204,6 +369,11 @@ pub struct MidiReaderInfo {
     frac: usize,
     deno: usize,
     is_eot: bool,
+    timebase: isize,
+    /// 読んでいるイベントの時間
+    time: isize,
+    /// 読んだ拍子の変化 (TIME(小節:拍:ステップ)の表示に使う)
+    time_signatures: TimeSigMap,
 }
 impl MidiReaderInfo {
     fn new() -> Self {
@@ -211,6 +381,9 @@ impl MidiReaderInfo {
             frac: 4,
             deno: 4,
             is_eot: false,
+            timebase: 96,
+            time: 0,
+            time_signatures: TimeSigMap::default(),
         }
     }
 }
@@ -334,8 +507,25 @@ pub fn dump_midi_event_meta(bin: &[u8], pos: &mut usize, info: &mut MidiReaderIn
                     let dd = bin[data_pos + 1] as usize;
                     info.frac = nn;
                     info.deno = (2i32.pow(dd as u32)) as usize;
+                    info.time_signatures.set(
+                        info.timebase,
+                        info.time,
+                        info.frac as isize,
+                        info.deno as isize,
+                    );
                     format!("TimeSig={}/{}", info.frac, info.deno)
                 }
+                0x21 if meta_len >= 1 => {
+                    // Port
+                    format!("Port({})", bin[data_pos])
+                }
+                0x59 if meta_len >= 2 => {
+                    // KeySig
+                    match KeySignature::from_meta_data(&bin[data_pos..data_end]) {
+                        Some(key) => format!("KeySig={}", key),
+                        None => String::from("// [ERROR] Invalid key-signature event"),
+                    }
+                }
                 _ => {
                     // text
                     let txt = array_read_str(bin, data_pos, meta_len);
@@ -572,6 +762,7 @@ pub fn dump_midi(bin: &[u8], flag_stdout: bool) -> String {
     log(&format!("/// [MThd] track_count={}", track_count));
     pos += 2;
     let timebase = array_read_u16(bin, pos) as usize;
+    info.timebase = timebase as isize;
     log(&format!("TIMEBASE={}", timebase));
     pos += 2;
     // tracks
@@ -613,15 +804,12 @@ pub fn dump_midi(bin: &[u8], flag_stdout: bool) -> String {
                 return res;
             }
             time += delta_time;
-            let beat_base = (timebase as f32 * 4.0 / info.deno as f32) as usize;
-            let beat_base = if beat_base == 0 { timebase } else { beat_base }; // for divisor of zero
-            let tick = time % beat_base;
-            let base = time / beat_base;
-            let beat = base % info.frac + 1;
-            let mes = base / info.frac + 1;
+            info.time = time as isize;
             //
             let event_channel = midi_event_channel(bin, pos);
             let desc = dump_midi_event(bin, &mut pos, &mut info);
+            // 拍子の変化を反映した位置 (拍子を変えたイベントは新しい小節の頭になる)
+            let bar = info.time_signatures.tick_to_bar(info.timebase, info.time);
             let desc = match event_channel {
                 Some(channel) if current_channel != Some(channel) => {
                     current_channel = Some(channel);
@@ -629,11 +817,7 @@ pub fn dump_midi(bin: &[u8], flag_stdout: bool) -> String {
                 }
                 _ => desc,
             };
-            // log(&format!("{:5}|TIME({:03}:{:03}:{:03}) {}", time, mes, beat, tick, desc));
-            log(&format!(
-                "TIME({:03}:{:03}:{:03}) {}",
-                mes, beat, tick, desc
-            ));
+            log(&format!("TIME({}) {}", bar, desc));
         }
         if !info.is_eot {
             log("// [ERROR] MIDI track has no end-of-track event");
@@ -718,4 +902,119 @@ mod tests {
             .windows(4)
             .any(|bytes| bytes == [0xFF, 0x01, 0x81, 0x0C]));
     }
+
+    /// MMLをコンパイルし、NoteOnの (tick, 元のソースの開始位置) を返す
+    fn note_on_spans(src: &str) -> Vec<(isize, usize)> {
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, src);
+        crate::runner::exec(&mut song, &tokens);
+        let (bin, map) = generate_with_source_map(&mut song);
+        map.iter()
+            .filter(|e| bin[e.offset + e.size - 3] & 0xF0 == 0x90)
+            .map(|e| (e.tick, e.span.unwrap().start))
+            .collect()
+    }
+
+    #[test]
+    fn source_map_points_to_the_mml() {
+        // ループ・Sub・Div
+        assert_eq!(
+            note_on_spans("l4 [2 c] Sub{d} e Div{fg}"),
+            vec![(0, 6), (96, 6), (192, 13), (192, 16), (288, 22), (336, 23)]
+        );
+        // マクロは使った位置
+        assert_eq!(
+            note_on_spans("STR A={cd}\nA 'eg'"),
+            vec![(0, 11), (96, 11), (192, 15), (192, 14)]
+        );
+        // ストトン表記・和音
+        assert_eq!(note_on_spans("ド'ミソ'"), vec![(0, 0), (96, 3), (96, 2)]);
+    }
+
+    #[test]
+    fn source_map_offsets_cover_every_event() {
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, "Tempo=120 c v100 d");
+        crate::runner::exec(&mut song, &tokens);
+        let (bin, map) = generate_with_source_map(&mut song);
+        for w in map.windows(2) {
+            if w[0].track == w[1].track {
+                assert_eq!(w[0].offset + w[0].size, w[1].offset);
+            }
+        }
+        assert!(map.iter().all(|e| e.span.is_some()));
+        assert!(map.iter().all(|e| e.offset + e.size <= bin.len()));
+    }
+
+    fn compile_with(src: &str, options: &MidiOutputOptions) -> crate::smf::Smf {
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, src);
+        crate::runner::exec(&mut song, &tokens);
+        let (bin, _) = generate_with_options(&mut song, options);
+        crate::smf::parse(&bin).unwrap()
+    }
+
+    #[test]
+    fn format0_merges_tracks_in_time_order() {
+        let options = MidiOutputOptions {
+            format: 0,
+            ppq: None,
+        };
+        let smf = compile_with("Tempo=120 TR(1) l4 c d TR(2) CH(2) r8 e4", &options);
+        assert_eq!(smf.header.format, 0);
+        assert_eq!(smf.tracks.len(), 1);
+        let ticks: Vec<usize> = smf.tracks[0].events.iter().map(|e| e.tick).collect();
+        assert!(ticks.windows(2).all(|w| w[0] <= w[1]), "{:?}", ticks);
+        // 同じ時間に同じ音が続くときはNoteOffが先
+        let smf = compile_with("TR(1) q100 c4 TR(2) r4 c4", &options);
+        let kinds: Vec<String> = smf.tracks[0]
+            .events
+            .iter()
+            .filter(|e| e.tick == 96)
+            .map(|e| format!("{:?}", e.kind))
+            .collect();
+        assert!(kinds[0].starts_with("NoteOff"), "{:?}", kinds);
+        // 書けないフォーマットはフォーマット1にする
+        let options = MidiOutputOptions {
+            format: 2,
+            ppq: None,
+        };
+        let smf = compile_with("TR(1) c TR(2) d", &options);
+        assert_eq!(smf.header.format, 1);
+        assert_eq!(smf.tracks.len(), 3);
+    }
+
+    #[test]
+    fn ppq_rescales_event_times() {
+        let options = MidiOutputOptions {
+            format: 1,
+            ppq: Some(480),
+        };
+        let smf = compile_with("TR(1) l8 c d q100 e%1 f%1", &options);
+        assert_eq!(smf.header.timing, crate::smf::Timing::Metrical(480));
+        let notes: Vec<(usize, String)> = smf.tracks[1]
+            .events
+            .iter()
+            .map(|e| (e.tick, format!("{:?}", e.kind)))
+            .filter(|e| e.1.starts_with("Note"))
+            .collect();
+        assert_eq!(notes[0].0, 0);
+        assert_eq!(notes[2].0, 240);
+        // 縮めても NoteOn と NoteOff の順序は変わらない
+        let options = MidiOutputOptions {
+            format: 0,
+            ppq: Some(24),
+        };
+        let smf = compile_with("TR(1) q100 c%1 c%1 c%1 d%3", &options);
+        let mut on = 0;
+        for e in smf.tracks[0].events.iter() {
+            match e.kind {
+                crate::smf::SmfEventKind::NoteOn { .. } => on += 1,
+                crate::smf::SmfEventKind::NoteOff { .. } => on -= 1,
+                _ => {}
+            }
+            assert!(on >= 0 && on <= 1);
+        }
+        assert_eq!(on, 0);
+    }
 }
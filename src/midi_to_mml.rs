@@ -0,0 +1,720 @@
+//! MIDI to MML - SMFをサクラのMMLに変換する
+//!
+//! トラック・チャンネルごとに音符を並べ直し、音長・オクターブ・ベロシティ・ゲートを
+//! 推測してMMLを組み立てる。同時に鳴る音符は和音か、同じチャンネルの別トラックにする。
+
+use super::key_signature::KeySignature;
+use super::midi::array_read_str;
+use super::smf::{self, Smf, SmfEventKind, SmfTrack};
+use std::collections::HashMap;
+
+/// サクラの初期値 (Track::new と同じ)
+const DEFAULT_OCTAVE: isize = 5;
+const DEFAULT_VELOCITY: isize = 100;
+const DEFAULT_QLEN: isize = 90;
+
+/// 音長の候補 (n分音符)
+const LENGTH_NAMES: [isize; 12] = [1, 2, 4, 8, 16, 32, 64, 3, 6, 12, 24, 48];
+
+/// 同時に始まり同じ長さの音符 (1音なら単音、複数なら和音)
+#[derive(Debug, Clone)]
+struct NoteGroup {
+    start: usize,
+    len: usize,
+    /// (ノート番号, ベロシティ)
+    notes: Vec<(u8, u8)>,
+}
+
+/// 出力するMMLトラック
+struct MmlTrack {
+    no: usize,
+    channel: Option<u8>,
+    groups: Vec<NoteGroup>,
+    /// 音符以外のコマンド (時間, MML)
+    commands: Vec<(usize, String)>,
+}
+
+/// ゲートの指定 (q)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Gate {
+    /// 音長に対する割合 (q90)
+    Rate(isize),
+    /// ステップ数 (q%80)
+    Step(isize),
+}
+
+/// サクラと同じ計算でゲートを求める (runner::note::calc_gate_len)
+fn gate_len(step: usize, q: isize) -> isize {
+    (step as f32 * q as f32 / 100.0) as isize
+}
+
+/// 小節線の位置を求めるための拍子の変化 (時間, 1小節のステップ数)
+struct BarMap {
+    changes: Vec<(usize, usize)>,
+}
+
+impl BarMap {
+    fn new(smf: &Smf, timebase: usize) -> Self {
+        let mut changes = vec![(0, timebase * 4)];
+        let mut sigs: Vec<(usize, usize)> = vec![];
+        for trk in smf.tracks.iter() {
+            for e in trk.events.iter() {
+                if let SmfEventKind::Meta {
+                    meta_type: 0x58,
+                    data,
+                } = &e.kind
+                {
+                    if data.len() >= 2 && data[0] > 0 && data[1] < 16 {
+                        let len = timebase * 4 * data[0] as usize / (1 << data[1]);
+                        sigs.push((e.tick, len.max(1)));
+                    }
+                }
+            }
+        }
+        sigs.sort_by_key(|s| s.0);
+        // 拍子の変化は小節の頭で起きるものとして扱う
+        for (time, len) in sigs {
+            if time == 0 {
+                changes[0].1 = len;
+            } else {
+                changes.push((time, len));
+            }
+        }
+        Self { changes }
+    }
+    /// timeより後の最初の小節線
+    fn next_bar(&self, time: usize) -> usize {
+        let i = self.changes.partition_point(|c| c.0 <= time).max(1) - 1;
+        let (start, len) = self.changes[i];
+        let bar = start + ((time - start) / len + 1) * len;
+        match self.changes.get(i + 1) {
+            Some(next) if next.0 < bar => next.0,
+            _ => bar,
+        }
+    }
+}
+
+/// MMLを組み立てる
+struct MmlWriter<'a> {
+    timebase: usize,
+    bars: &'a BarMap,
+    res: String,
+    line: Vec<String>,
+    time: usize,
+    next_bar: usize,
+    length: usize,
+    octave: isize,
+    velocity: isize,
+    gate: Gate,
+}
+
+impl<'a> MmlWriter<'a> {
+    fn new(timebase: usize, bars: &'a BarMap) -> Self {
+        Self {
+            timebase,
+            bars,
+            res: String::new(),
+            line: vec![],
+            time: 0,
+            next_bar: bars.next_bar(0),
+            length: timebase,
+            octave: DEFAULT_OCTAVE,
+            velocity: DEFAULT_VELOCITY,
+            gate: Gate::Rate(DEFAULT_QLEN),
+        }
+    }
+    fn push(&mut self, s: String) {
+        self.line.push(s);
+    }
+    /// 時間を進め、小節線を越えたら改行する
+    fn advance(&mut self, len: usize) {
+        self.time += len;
+        if self.time >= self.next_bar {
+            while self.time >= self.next_bar {
+                self.next_bar = self.bars.next_bar(self.next_bar);
+            }
+            self.flush();
+        }
+    }
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            self.res.push_str(&self.line.join(" "));
+            self.res.push('\n');
+            self.line.clear();
+        }
+    }
+    /// 音長をMMLにする (基本の音長なら省略)
+    fn length_str(&self, len: usize) -> String {
+        if len == self.length {
+            return String::new();
+        }
+        if let Some(name) = length_name(len, self.timebase) {
+            return name;
+        }
+        // 全音符より長ければ全音符をつなぐ (ex) 1^1^4
+        let whole = self.timebase * 4;
+        if len > whole {
+            let rest = len % whole;
+            let rest_name = if rest == 0 {
+                Some("1".to_string())
+            } else {
+                length_name(rest, self.timebase)
+            };
+            if let Some(rest_name) = rest_name {
+                let count = (len - rest) / whole - if rest == 0 { 1 } else { 0 };
+                return format!("{}{}", "1^".repeat(count), rest_name);
+            }
+        }
+        format!("%{}", len)
+    }
+    /// timeまで休符を入れる
+    fn rest_to(&mut self, time: usize) {
+        let whole = self.timebase * 4;
+        while self.time < time {
+            let bar_rest = self.next_bar - self.time;
+            let len = (time - self.time).min(bar_rest).min(whole);
+            let s = format!("r{}", self.length_str(len));
+            self.push(s);
+            self.advance(len);
+        }
+    }
+    fn note_str(&mut self, no: u8, vel: u8) -> String {
+        let mut s = String::new();
+        let vel = vel as isize;
+        if vel != self.velocity {
+            s.push_str(&format!("v{} ", vel));
+            self.velocity = vel;
+        }
+        let octave = (no / 12) as isize;
+        match octave - self.octave {
+            0 => {}
+            1 => s.push('>'),
+            -1 => s.push('<'),
+            _ => s.push_str(&format!("o{}", octave)),
+        }
+        self.octave = octave;
+        s.push_str(NOTE_NAMES[(no % 12) as usize]);
+        s
+    }
+    fn note(&mut self, g: &NoteGroup, step: usize) {
+        // ゲート
+        let gate = choose_gate(self.gate, g.len, step);
+        if gate != self.gate {
+            let s = match gate {
+                Gate::Rate(q) => format!("q{}", q),
+                Gate::Step(n) => format!("q%{}", n),
+            };
+            self.push(s);
+            self.gate = gate;
+        }
+        let len = self.length_str(step);
+        let s = if g.notes.len() == 1 {
+            let (no, vel) = g.notes[0];
+            format!("{}{}", self.note_str(no, vel), len)
+        } else {
+            let notes: Vec<String> = g
+                .notes
+                .iter()
+                .map(|&(no, vel)| self.note_str(no, vel))
+                .collect();
+            format!("'{}'{}", notes.join(""), len)
+        };
+        self.push(s);
+        self.advance(step);
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = [
+    "c", "c#", "d", "d#", "e", "f", "f#", "g", "g#", "a", "a#", "b",
+];
+
+/// ステップ数を音長の名前にする (ex) 96 => "4" / 144 => "4."
+pub(crate) fn length_name(len: usize, timebase: usize) -> Option<String> {
+    let whole = timebase * 4;
+    for n in LENGTH_NAMES {
+        let n = n as usize;
+        if whole / n * n != whole {
+            continue;
+        }
+        let base = whole / n;
+        if base == len {
+            return Some(n.to_string());
+        }
+        if n.is_power_of_two() && base & 1 == 0 && base * 3 / 2 == len {
+            return Some(format!("{}.", n));
+        }
+    }
+    None
+}
+
+/// 音長の候補を短い順に返す
+pub(crate) fn length_candidates(timebase: usize) -> Vec<usize> {
+    let whole = timebase * 4;
+    let mut res = vec![];
+    for n in LENGTH_NAMES {
+        let n = n as usize;
+        if whole / n * n != whole {
+            continue;
+        }
+        let base = whole / n;
+        res.push(base);
+        if n.is_power_of_two() && base & 1 == 0 {
+            res.push(base * 3 / 2);
+        }
+    }
+    res.sort();
+    res.dedup();
+    res
+}
+
+/// 音符のステップ数を決める。
+/// 次の音符までが音長の候補でゲートが短すぎなければその長さ、
+/// そうでなければ発音時間が収まる最も短い音長を選ぶ (残りは休符になる)。
+fn choose_step(len: usize, gap: Option<usize>, candidates: &[usize]) -> usize {
+    if let Some(g) = gap {
+        if g >= len && len * 4 >= g * 3 && candidates.contains(&g) {
+            return g;
+        }
+    }
+    for &c in candidates {
+        if c >= len.max(1) && !matches!(gap, Some(g) if c > g) {
+            return c;
+        }
+    }
+    match gap {
+        Some(g) => g.max(1),
+        None => len.max(1),
+    }
+}
+
+/// ゲートの指定を決める。今の指定で同じ長さになるなら変えない
+fn choose_gate(cur: Gate, len: usize, step: usize) -> Gate {
+    let len = len as isize;
+    match cur {
+        Gate::Rate(q) if gate_len(step, q) == len => return cur,
+        Gate::Step(n) if n == len => return cur,
+        _ => {}
+    }
+    let q = (len * 100 + step as isize / 2) / step as isize;
+    for q in [q, q + 1, q - 1] {
+        if (1..=100).contains(&q) && gate_len(step, q) == len {
+            return Gate::Rate(q);
+        }
+    }
+    Gate::Step(len)
+}
+
+fn direct_smf(bytes: &[u8]) -> String {
+    let args: Vec<String> = bytes.iter().map(|b| format!("${:02X}", b)).collect();
+    format!("DirectSMF({})", args.join(","))
+}
+
+fn push_delta(res: &mut Vec<u8>, v: usize) {
+    let mut buf = vec![(v & 0x7F) as u8];
+    let mut v = v >> 7;
+    while v > 0 {
+        buf.push(0x80 | (v & 0x7F) as u8);
+        v >>= 7;
+    }
+    buf.reverse();
+    res.extend(buf);
+}
+
+/// メタイベントをMMLにする
+fn meta_to_mml(mtype: u8, data: &[u8]) -> String {
+    match mtype {
+        0x51 if data.len() == 3 => {
+            let mpq = (data[0] as usize) << 16 | (data[1] as usize) << 8 | data[2] as usize;
+            if let Some(tempo) = (60_000_000 + mpq / 2).checked_div(mpq) {
+                if 60_000_000usize.checked_div(tempo) == Some(mpq) {
+                    return format!("Tempo({})", tempo);
+                }
+            }
+        }
+        0x58 if data.len() >= 2 && data[1] < 16 => {
+            return format!("TimeSignature({},{})", data[0], 1 << data[1]);
+        }
+        0x59 if data.len() == 2 => {
+            if let Some(key) = KeySignature::from_meta_data(data) {
+                let mode = if key.minor { "minor" } else { "major" };
+                return format!("KeySignature({},{})", key.fifths, mode);
+            }
+        }
+        0x01..=0x07 => {
+            let name = match mtype {
+                0x01 => "Text",
+                0x02 => "Copyright",
+                0x03 => "TrackName",
+                0x04 => "InstrumentName",
+                0x05 => "Lyric",
+                0x06 => "Marker",
+                _ => "CuePoint",
+            };
+            // ストトン表記に変換されない文字だけなら文字列で書く
+            let is_plain = data.iter().all(|&b| (0x20..0x7F).contains(&b) && b != b'"');
+            if is_plain {
+                return format!("{}(\"{}\")", name, String::from_utf8_lossy(data));
+            }
+            let text = array_read_str(data, 0, data.len())
+                .replace("*/", "* /")
+                .replace(['\r', '\n'], " ");
+            let mut bytes = vec![0xFF, mtype];
+            push_delta(&mut bytes, data.len());
+            bytes.extend_from_slice(data);
+            return format!("{} /* {}{{{}}} */", direct_smf(&bytes), name, text);
+        }
+        _ => {}
+    }
+    let mut bytes = vec![0xFF, mtype];
+    push_delta(&mut bytes, data.len());
+    bytes.extend_from_slice(data);
+    direct_smf(&bytes)
+}
+
+/// チャンネルメッセージ(音符以外)をMMLにする
+fn channel_to_mml(kind: &SmfEventKind) -> String {
+    match *kind {
+        // 後ろに続く < や > が式として読まれないよう、括弧で書く
+        SmfEventKind::ControlChange {
+            controller, value, ..
+        } => format!("CC({},{})", controller, value),
+        SmfEventKind::ProgramChange { program, .. } => format!("Voice({})", program as usize + 1),
+        SmfEventKind::PitchBend { value, .. } => format!("PitchBend({})", value),
+        SmfEventKind::ChannelPressure { channel, pressure } => {
+            direct_smf(&[0xD0 | channel, pressure])
+        }
+        SmfEventKind::PolyPressure {
+            channel,
+            key,
+            pressure,
+        } => direct_smf(&[0xA0 | channel, key, pressure]),
+        _ => String::new(),
+    }
+}
+
+/// 重ならないように音符を声部に分ける (先頭の声部ほど多くの音符を持つ)
+fn split_voices(groups: Vec<NoteGroup>) -> Vec<Vec<NoteGroup>> {
+    let mut voices: Vec<(usize, Vec<NoteGroup>)> = vec![];
+    for g in groups {
+        let end = g.start + g.len;
+        match voices.iter_mut().find(|v| v.0 <= g.start) {
+            Some(v) => {
+                v.0 = end;
+                v.1.push(g);
+            }
+            None => voices.push((end, vec![g])),
+        }
+    }
+    voices.into_iter().map(|v| v.1).collect()
+}
+
+/// 音符を和音ごとにまとめる
+fn group_notes(mut notes: Vec<(usize, usize, u8, u8)>) -> Vec<NoteGroup> {
+    notes.sort_by_key(|n| (n.0, n.1, n.2));
+    let mut groups: Vec<NoteGroup> = vec![];
+    for (start, len, no, vel) in notes {
+        match groups.last_mut() {
+            Some(g) if g.start == start && g.len == len => g.notes.push((no, vel)),
+            _ => groups.push(NoteGroup {
+                start,
+                len,
+                notes: vec![(no, vel)],
+            }),
+        }
+    }
+    groups
+}
+
+/// 1つのSMFトラックをチャンネル・声部ごとのMMLトラックに分ける
+fn split_track(no: usize, track: &SmfTrack, extra_no: &mut usize) -> Vec<MmlTrack> {
+    let mut channels: Vec<u8> = vec![];
+    let mut notes: HashMap<u8, Vec<(usize, usize, u8, u8)>> = HashMap::new();
+    let mut commands: HashMap<u8, Vec<(usize, String)>> = HashMap::new();
+    let mut meta_commands: Vec<(usize, String)> = vec![];
+    let mut note_on: HashMap<(u8, u8), Vec<(usize, u8)>> = HashMap::new();
+    let end_time = track.end_tick;
+    for e in track.events.iter() {
+        let time = e.tick;
+        if let Some(ch) = e.kind.channel() {
+            if !channels.contains(&ch) {
+                channels.push(ch);
+            }
+        }
+        match &e.kind {
+            SmfEventKind::NoteOn {
+                channel,
+                key,
+                velocity,
+            } if *velocity > 0 => {
+                note_on
+                    .entry((*channel, *key))
+                    .or_default()
+                    .push((time, *velocity));
+            }
+            SmfEventKind::NoteOn { channel, key, .. }
+            | SmfEventKind::NoteOff { channel, key, .. } => {
+                if let Some(list) = note_on.get_mut(&(*channel, *key)) {
+                    if !list.is_empty() {
+                        let (start, vel) = list.remove(0);
+                        let list = notes.entry(*channel).or_default();
+                        list.push((start, time - start, *key, vel));
+                    }
+                }
+            }
+            SmfEventKind::Meta { meta_type, data } => {
+                meta_commands.push((time, meta_to_mml(*meta_type, data)))
+            }
+            SmfEventKind::SysEx { data } => {
+                let bytes: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+                meta_commands.push((time, format!("SysEx$=F0,{}", bytes.join(","))));
+            }
+            SmfEventKind::Escape { data } => {
+                let mut bytes = vec![0xF7];
+                push_delta(&mut bytes, data.len());
+                bytes.extend_from_slice(data);
+                meta_commands.push((time, direct_smf(&bytes)));
+            }
+            kind => {
+                let ch = kind.channel().unwrap_or(0);
+                commands
+                    .entry(ch)
+                    .or_default()
+                    .push((time, channel_to_mml(kind)));
+            }
+        }
+    }
+    // 終わりのない音符はトラックの最後で止める
+    for ((ch, no), list) in note_on.into_iter() {
+        for (start, vel) in list {
+            notes
+                .entry(ch)
+                .or_default()
+                .push((start, end_time - start, no, vel));
+        }
+    }
+    let mut res = vec![];
+    if channels.is_empty() {
+        res.push(MmlTrack {
+            no,
+            channel: None,
+            groups: vec![],
+            commands: meta_commands,
+        });
+        return res;
+    }
+    for (i, ch) in channels.iter().enumerate() {
+        let groups = group_notes(notes.remove(ch).unwrap_or_default());
+        let mut cmds = commands.remove(ch).unwrap_or_default();
+        if i == 0 {
+            cmds.append(&mut meta_commands);
+        }
+        cmds.sort_by_key(|c| c.0);
+        let mut voices = split_voices(groups).into_iter();
+        let track_no = if i == 0 { no } else { next_no(extra_no) };
+        res.push(MmlTrack {
+            no: track_no,
+            channel: Some(*ch),
+            groups: voices.next().unwrap_or_default(),
+            commands: cmds,
+        });
+        for voice in voices {
+            res.push(MmlTrack {
+                no: next_no(extra_no),
+                channel: Some(*ch),
+                groups: voice,
+                commands: vec![],
+            });
+        }
+    }
+    res
+}
+
+fn next_no(extra_no: &mut usize) -> usize {
+    let no = *extra_no;
+    *extra_no += 1;
+    no
+}
+
+/// MMLトラックの本体を書き出す
+fn write_track(trk: &MmlTrack, timebase: usize, bars: &BarMap) -> String {
+    let candidates = length_candidates(timebase);
+    // 各音符のステップ数
+    let steps: Vec<usize> = trk
+        .groups
+        .iter()
+        .enumerate()
+        .map(|(i, g)| {
+            let gap = trk.groups.get(i + 1).map(|n| n.start - g.start);
+            choose_step(g.len, gap, &candidates)
+        })
+        .collect();
+    let mut w = MmlWriter::new(timebase, bars);
+    // 最も多い音長を基本の音長にする
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    for s in steps.iter() {
+        *counts.entry(*s).or_default() += 1;
+    }
+    let best = counts
+        .iter()
+        .filter(|(len, _)| length_name(**len, timebase).is_some())
+        .max_by_key(|(len, count)| (**count, **len))
+        .map(|(len, _)| *len);
+    if let Some(best) = best {
+        if best != w.length {
+            w.push(format!("l{}", length_name(best, timebase).unwrap()));
+            w.length = best;
+        }
+    }
+    let mut ci = 0;
+    let cmds = &trk.commands;
+    for (g, &step) in trk.groups.iter().zip(steps.iter()) {
+        while ci < cmds.len() && cmds[ci].0 <= g.start {
+            w.rest_to(cmds[ci].0);
+            w.push(cmds[ci].1.clone());
+            ci += 1;
+        }
+        w.rest_to(g.start);
+        // 音符の途中で変わる値は Sub{} で書く
+        let mut sub: Vec<String> = vec![];
+        let mut t = g.start;
+        while ci < cmds.len() && cmds[ci].0 < g.start + step {
+            if cmds[ci].0 > t {
+                sub.push(format!("r%{}", cmds[ci].0 - t));
+                t = cmds[ci].0;
+            }
+            sub.push(cmds[ci].1.clone());
+            ci += 1;
+        }
+        if !sub.is_empty() {
+            w.push(format!("Sub{{{}}}", sub.join(" ")));
+        }
+        w.note(g, step);
+    }
+    while ci < cmds.len() {
+        w.rest_to(cmds[ci].0);
+        w.push(cmds[ci].1.clone());
+        ci += 1;
+    }
+    w.flush();
+    w.res
+}
+
+/// SMFをサクラのMMLに変換する
+pub fn midi_to_mml(bin: &[u8]) -> Result<String, String> {
+    let smf = smf::parse(bin).map_err(|e| e.to_string())?;
+    let timebase = match smf.header.timing.timebase() {
+        Some(0) => return Err("Timebase is zero".to_string()),
+        Some(tb) => tb as usize,
+        None => return Err("SMPTE time division is not supported".to_string()),
+    };
+    let bars = BarMap::new(&smf, timebase);
+    let mut res = format!(
+        "// Converted from SMF (format={} tracks={})\nTimeBase({})\n",
+        smf.header.format,
+        smf.tracks.len(),
+        timebase
+    );
+    let mut extra_no = smf.tracks.len();
+    for (no, track) in smf.tracks.iter().enumerate() {
+        for trk in split_track(no, track, &mut extra_no) {
+            if trk.groups.is_empty() && trk.commands.is_empty() {
+                continue;
+            }
+            res.push('\n');
+            match trk.channel {
+                Some(ch) => res.push_str(&format!("TR({}) CH({})\n", trk.no, ch + 1)),
+                None => res.push_str(&format!("TR({})\n", trk.no)),
+            }
+            res.push_str(&write_track(&trk, timebase, &bars));
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::generate;
+    use crate::song::Song;
+
+    fn compile(src: &str) -> Vec<u8> {
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, src);
+        crate::runner::exec(&mut song, &tokens);
+        generate(&mut song)
+    }
+
+    /// 全トラックのイベントを時間順に並べる (声部の分け方の違いは無視する)
+    fn all_events(bin: &[u8]) -> Vec<(usize, String)> {
+        let smf = smf::parse(bin).unwrap();
+        let mut res: Vec<(usize, String)> = smf
+            .tracks
+            .iter()
+            .flat_map(|t| t.events.iter())
+            .map(|e| (e.tick, format!("{:?}", e.kind)))
+            .collect();
+        res.sort();
+        res
+    }
+
+    fn round_trip(src: &str) {
+        let bin = compile(src);
+        let mml = midi_to_mml(&bin).unwrap();
+        assert_eq!(all_events(&bin), all_events(&compile(&mml)), "{}", mml);
+    }
+
+    #[test]
+    fn converts_notes_to_mml() {
+        let bin = compile("TR(1) l8 o4 c d 'ceg'4 r4 v80 e");
+        let mml = midi_to_mml(&bin).unwrap();
+        assert_eq!(
+            mml,
+            "// Converted from SMF (format=1 tracks=2)\nTimeBase(96)\n\nTR(1) CH(1)\nl8 <c d 'ceg'4 r4 v80 e\n"
+        );
+    }
+
+    #[test]
+    fn converted_mml_compiles_to_the_same_events() {
+        round_trip("Tempo(150) TimeSignature(3,4) TR(2) @5 l4 o4 c d8. e16 'ceg'2 c1^4 r2 >c");
+        round_trip("KeySignature(Bb, minor) TR(1) c KeySignature(E) d");
+        round_trip("TR(1) y7,100 c Sub{r8 y1,64 r16 PitchBend(100)} d q50 e f%30 q%10 g");
+        // 重なる音符は同じチャンネルの別トラックにする
+        round_trip("TR(1) c1 Sub{r8 e4 g2} Sub{'ceg'1} TR(3) CH(10) n36,8 n38,8");
+        round_trip("TrackName=\"piano\" Text{テスト} TR(1) o5 Text(\"a\") <c SysEx$=F0,41,10,42,12,40,00,7F,00,41,F7");
+    }
+
+    #[test]
+    fn long_notes_and_rests() {
+        let w = BarMap {
+            changes: vec![(0, 384)],
+        };
+        let writer = MmlWriter::new(96, &w);
+        assert_eq!(writer.length_str(96 * 5), "1^4");
+        assert_eq!(writer.length_str(96 * 8), "1^1");
+        assert_eq!(writer.length_str(144), "4.");
+        assert_eq!(writer.length_str(100), "%100");
+    }
+
+    #[test]
+    fn reads_running_status() {
+        let mut bin = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x0D".to_vec();
+        // NoteOn c, ランニングステータスで NoteOn(vel=0)
+        bin.extend([0x00, 0x90, 60, 100, 0x60, 60, 0, 0x00, 0xFF, 0x2F, 0x00]);
+        bin[21] = (bin.len() - 22) as u8;
+        let mml = midi_to_mml(&bin).unwrap();
+        assert!(mml.ends_with("TR(0) CH(1)\nq100 c\n"), "{}", mml);
+    }
+
+    #[test]
+    fn rejects_broken_data() {
+        assert!(midi_to_mml(b"abc").is_err());
+        let smpte = b"MThd\0\0\0\x06\0\0\0\0\xE7\x28".to_vec();
+        assert_eq!(
+            midi_to_mml(&smpte).unwrap_err(),
+            "SMPTE time division is not supported"
+        );
+        let mut bin = compile("cde");
+        bin.truncate(bin.len() - 6);
+        assert!(midi_to_mml(&bin).is_err());
+    }
+}
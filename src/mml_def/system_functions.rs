@@ -62,9 +62,9 @@ pub fn init_system_functions() -> HashMap<String, SystemFunction> {
     sysfunc_add!(sf, "Track", TokenType::Track, 'I'); // change current track [range:0 to 999] (ex) Track(1)
     sysfunc_add!(sf, "TRACK", TokenType::Track, 'I'); // change current track [range:0 to 999] (ex) TRACK(1)
     sysfunc_add!(sf, "TR", TokenType::Track, 'I'); // change current track [range:0 to 999] (ex) TR(1)
-    sysfunc_add!(sf, "Channel", TokenType::Channel, 'I'); // change channel no [range:1 to 16] (ex) Channel(1)
-    sysfunc_add!(sf, "CHANNEL", TokenType::Channel, 'I'); // change channel no [range:1 to 16] (ex) CHANNEL(1)
-    sysfunc_add!(sf, "CH", TokenType::Channel, 'I'); // change channel no [range:1 to 16] (ex) CH(1)
+    sysfunc_add!(sf, "Channel", TokenType::Channel, 'I'); // change channel no [range:1 to 256, 17 or more selects port] (ex) Channel(1)
+    sysfunc_add!(sf, "CHANNEL", TokenType::Channel, 'I'); // change channel no [range:1 to 256, 17 or more selects port] (ex) CHANNEL(1)
+    sysfunc_add!(sf, "CH", TokenType::Channel, 'I'); // change channel no [range:1 to 256, 17 or more selects port] (ex) CH(1)
     sysfunc_add!(sf, "Time", TokenType::Time, 'A'); // change time position, Time(measure:beat:step) (ex) Time(1:1:0) Time(0)
     sysfunc_add!(sf, "TIME", TokenType::Time, 'A'); // change time position, TIME(measure:beat:step) (ex) Time(1:1:0) Time(0)
     sysfunc_add!(sf, "System.TimeBase", TokenType::TimeBase, '*'); // set system time base (ex) TimeBase(96)
@@ -81,6 +81,15 @@ pub fn init_system_functions() -> HashMap<String, SystemFunction> {
     sysfunc_add!(sf, "Sub", TokenType::Sub, '*'); // sub track / rewind time position (ex) Sub{ceg} egb
     sysfunc_add!(sf, "SUB", TokenType::Sub, '*'); // sub track / rewind time position (ex) Sub{ceg} egb
     sysfunc_add!(sf, "S", TokenType::Sub, '*'); // sub track / rewind time position (ex) Sub{ceg} egb
+    sysfunc_add!(sf, "Chord", TokenType::Chord, 'S'); // play chord symbols with the current l/q/v / コードネームを空白で区切る (ex) Chord{C Am7 F/A G7sus4}
+    sysfunc_add!(sf, "CHORD", TokenType::Chord, 'S'); // play chord symbols with the current l/q/v (ex) CHORD{C Am7 F/A G7sus4}
+    sysfunc_cc_add!(sf, "ChordStyle", TokenType::ChordOption, 'S', 0); // set how Chord plays: harmony / root / bass / arp (ex) ChordStyle{arp}
+    sysfunc_cc_add!(sf, "ChordInversion", TokenType::ChordOption, 'I', 1); // set chord inversion (number of lower notes raised an octave) (ex) ChordInversion(1)
+    sysfunc_cc_add!(sf, "ChordOctave", TokenType::ChordOption, 'I', 2); // set octave of chord root / -1 uses the track octave (ex) ChordOctave(4)
+    sysfunc_cc_add!(sf, "ChordSpread", TokenType::ChordOption, 'I', 3); // raise every second note of chord by N octaves (open voicing) (ex) ChordSpread(1)
+    sysfunc_add!(sf, "ChordVoiceLeading", TokenType::ChordVoiceLeading, '*'); // choose chord inversion closest to the previous chord (ex) ChordVoiceLeading(on)
+    sysfunc_add!(sf, "Strum", TokenType::Strum, '*'); // strum harmony notes: up (low to high) / down / off, step, 1=end together / {...} limits it to the block (ex) Strum(up, 3) 'ceg'
+    sysfunc_add!(sf, "Arp", TokenType::Arp, '*'); // arpeggiate harmony notes: up / down / updown / random / off, rate, gate / {...} limits it to the block (ex) Arp(updown, 16, 80){'ceg'1}
     sysfunc_add!(sf, "System.KeyFlag", TokenType::KeyFlag, '*'); // set key flag to note / 音名は区切らず並べる (ex) KeyFlag+(cf) / 数値指定は a,b,c,d,e,f,g の順 (ex) KeyFlag=(0,0,1,0,0,1,0)
     sysfunc_add!(sf, "KeyFlag", TokenType::KeyFlag, '*'); // set key flag to note / 音名は区切らず並べる (ex) KeyFlag+(cf) / 数値指定は a,b,c,d,e,f,g の順 (ex) KeyFlag=(0,0,1,0,0,1,0)
     sysfunc_add!(sf, "KF", TokenType::KeyFlag, '*'); // set key flag to note / 音名は区切らず並べる (ex) KeyFlag+(cf) / 数値指定は a,b,c,d,e,f,g の順 (ex) KeyFlag=(0,0,1,0,0,1,0)
@@ -88,6 +97,7 @@ pub fn init_system_functions() -> HashMap<String, SystemFunction> {
     sysfunc_add!(sf, "Key", TokenType::KeyShift, 'I'); // set key-shift (ex) Key(3)
     sysfunc_add!(sf, "KEY", TokenType::KeyShift, 'I'); // set key-shift (ex) KEY(3)
     sysfunc_add!(sf, "UseKeyShift", TokenType::UseKeyShift, '*'); // set key shift mode value=on|off (ex) UseKeyShift(on)
+    sysfunc_add!(sf, "BarCheck", TokenType::UseBarCheck, '*'); // check that each bar line is at the start of a measure value=on|off / 小節の頭でなければ小節番号とずれたステップ数を警告する (ex) BarCheck(on)
     sysfunc_add!(sf, "TrackKey", TokenType::TrackKey, 'I'); // set key-shift for track (ex) TrackKey(3)
     sysfunc_add!(sf, "TR_KEY", TokenType::TrackKey, 'I'); // set key-shift for track (ex) TR_KEY(3)
     sysfunc_add!(sf, "Play", TokenType::Play, '*'); // play multi track (ex) Play(AA,BB,CC)
@@ -95,8 +105,10 @@ pub fn init_system_functions() -> HashMap<String, SystemFunction> {
     sysfunc_add!(sf, "SysEx", TokenType::SysEx, '*'); // System Exclusive (ex) SysEx$=f0,43,10,4c,00,{00,00,30,f0},f7
     sysfunc_add!(sf, "PlayFrom.SysEx", TokenType::SysEx, '*'); // =SysEx
     sysfunc_add!(sf, "PlayFrom.CtrlChg", TokenType::ControlChange, 'A'); // =CONTROL_CHANGE
-    sysfunc_add!(sf, "PlayFrom", TokenType::PlayFrom, 'A'); // play from time position (ex) PlayFrom(5:1:0)
-    sysfunc_add!(sf, "PLAY_FROM", TokenType::PlayFrom, 'A'); // play from time position (ex) PLAY_FROM(5:1:0)
+    sysfunc_add!(sf, "PlayFrom", TokenType::PlayFrom, 'A'); // play from time position or marker (ex) PlayFrom(5:1:0) / PlayFrom(Marker("Chorus"))
+    sysfunc_add!(sf, "PLAY_FROM", TokenType::PlayFrom, 'A'); // play from time position or marker (ex) PLAY_FROM(5:1:0)
+    sysfunc_add!(sf, "PlayTo", TokenType::PlayTo, 'A'); // stop playing at time position or marker (ex) PlayTo(9:1:0) / PlayTo(Marker("Bridge"))
+    sysfunc_add!(sf, "PLAY_TO", TokenType::PlayTo, 'A'); // stop playing at time position or marker (ex) PLAY_TO(9:1:0)
     sysfunc_add!(sf, "PlayFromHere", TokenType::PlayFromHere, '_'); // play from current time pos (ex) PlayFromHere
     sysfunc_add!(sf, "PLAY_FROM_HRER", TokenType::PlayFromHere, '_'); // play from current time pos / 綴りミスだが互換性のため維持 (ex) PLAY_FROM_HRER
     sysfunc_add!(sf, "System.MeasureShift", TokenType::MeasureShift, 'I'); // set measure shift for time pointer (ex) System.MeasureShift(1)
@@ -190,6 +202,10 @@ pub fn init_system_functions() -> HashMap<String, SystemFunction> {
     sysfunc_add!(sf, "System.TimeSignature", TokenType::TimeSignature, 'A'); // set time signature (ex) TimeSignature(4, 4)
     sysfunc_add!(sf, "TimeSig", TokenType::TimeSignature, 'A'); // set time signature (ex) TimeSignature(4, 4)
     sysfunc_add!(sf, "TIMESIG", TokenType::TimeSignature, 'A'); // set time signature (ex) TimeSignature(4, 4)
+    sysfunc_add!(sf, "KeySignature", TokenType::KeySignature, '*'); // write key signature (FF 59) key=tonic or number of sharps(-:flats), mode=major|minor, on=also set KeyFlag / 引数がなければKeyFlagとKeyShiftから求める (ex) KeySignature(D, major) / KeySignature(Bb, minor, on) / KeySignature(-2)
+    sysfunc_add!(sf, "System.KeySignature", TokenType::KeySignature, '*'); // write key signature (FF 59) (ex) KeySignature(D, major)
+    sysfunc_add!(sf, "KeySig", TokenType::KeySignature, '*'); // write key signature (FF 59) (ex) KeySig(D, major)
+    sysfunc_add!(sf, "KEYSIG", TokenType::KeySignature, '*'); // write key signature (FF 59) (ex) KEYSIG(D, major)
     sysfunc_add!(sf, "Port", TokenType::Port, 'I'); // set Port No (ex) Port(0)
     sysfunc_add!(sf, "PORT", TokenType::Port, 'I'); // set Port No (ex) Port(0)
     sysfunc_cc_add!(sf, "MetaText", TokenType::MetaText, 'S', 1); // write meta text (ex) MetaText{"hello"}
@@ -202,8 +218,10 @@ pub fn init_system_functions() -> HashMap<String, SystemFunction> {
     sysfunc_cc_add!(sf, "InstrumentName", TokenType::MetaText, 'S', 4); // write InstrumentName text (ex) InstrumentName{"hello"}
     sysfunc_cc_add!(sf, "Lyric", TokenType::MetaText, 'S', 5); // write Lyric text (ex) Lyric{"hello"}
     sysfunc_cc_add!(sf, "LYRIC", TokenType::MetaText, 'S', 5); // write Lyric text (ex) LYRIC{"hello"}
-    sysfunc_cc_add!(sf, "MAKER", TokenType::MetaText, 'S', 6); // write MAKER text (ex) MAKER{"hello"}
-    sysfunc_cc_add!(sf, "Maker", TokenType::MetaText, 'S', 6); // write Maker text (ex) Maker{"hello"}
+    sysfunc_add!(sf, "Marker", TokenType::Marker, 'S'); // write Marker text and name the position (ex) Marker{"Chorus"}
+    sysfunc_add!(sf, "MARKER", TokenType::Marker, 'S'); // write MARKER text and name the position (ex) MARKER{"Chorus"}
+    sysfunc_add!(sf, "MAKER", TokenType::Marker, 'S'); // =Marker / 綴りミスだが互換性のため維持 (ex) MAKER{"hello"}
+    sysfunc_add!(sf, "Maker", TokenType::Marker, 'S'); // =Marker / 綴りミスだが互換性のため維持 (ex) Maker{"hello"}
     sysfunc_cc_add!(sf, "CuePoint", TokenType::MetaText, 'S', 7); // write CuePoint text (ex) CuePoint{"hello"}
                                                                   //@ GS System Exclusive
     sysfunc_cc_add!(sf, "GSEffect", TokenType::GSEffect, 'A', 0); // GSEffect(num, val) (ex) GSEffect($30, 0)
@@ -234,9 +252,9 @@ pub fn init_system_functions() -> HashMap<String, SystemFunction> {
     sysfunc_add!(sf, "ARRAY", TokenType::DefArray, '*'); // define array variables (ex) ARRAY A = (1,2,3)
     sysfunc_add!(sf, "Print", TokenType::Print, 'S'); // print value (ex) Print({hello})
     sysfunc_add!(sf, "PRINT", TokenType::Print, 'S'); // print value (ex) PRINT({hello})
-    sysfunc_add!(sf, "System.Include", TokenType::Include, '*'); // Unimplemented
-    sysfunc_add!(sf, "Include", TokenType::Include, '*'); // Unimplemented
-    sysfunc_add!(sf, "INCLUDE", TokenType::Include, '*'); // Unimplemented
+    sysfunc_add!(sf, "System.Include", TokenType::Include, '*'); // include MML file (ex) System.Include(drums.mml)
+    sysfunc_add!(sf, "Include", TokenType::Include, '*'); // include MML file (ex) Include(drums.mml)
+    sysfunc_add!(sf, "INCLUDE", TokenType::Include, '*'); // include MML file (ex) INCLUDE(drums.mml)
     sysfunc_add!(sf, "IF", TokenType::If, '*'); // IF(cond){ true }ELSE{ false }
     sysfunc_add!(sf, "If", TokenType::If, '*'); // IF(cond){ true }ELSE{ false }
     sysfunc_add!(sf, "FOR", TokenType::For, '*'); // FOR(INT I = 0; I < 10; I++){ ... }
@@ -300,6 +318,9 @@ pub fn init_system_calc_functions() -> HashMap<String, sakura_functions::Callbac
     syscalc_add!(sf, "HEX", sakura_functions::calc_hex); // HEX(V) // return Hex value (ex) Hex(255) // => FF
     syscalc_add!(sf, "Pos", sakura_functions::calc_pos); // Pos(N, M) // Return the 1-based index of substring N in M (ex) Pos({b}, {abc}) // => 2
     syscalc_add!(sf, "POS", sakura_functions::calc_pos); // POS(N, M) // Return the 1-based index of substring N in M (ex) Pos({b}, {abc}) // => 2
-                                                         // </SYSTEM_CALC_FUNCTION>
+    syscalc_add!(sf, "Marker", sakura_functions::calc_marker); // Marker(S) // return the position of marker S (ex) PlayFrom(Marker({Chorus}))
+    syscalc_add!(sf, "MARKER", sakura_functions::calc_marker); // MARKER(S) // return the position of marker S (ex) PLAY_FROM(MARKER({Chorus}))
+    syscalc_add!(sf, "ChordMML", sakura_functions::calc_chord_mml); // ChordMML(S) // return MML of chord symbols S with the Chord settings (ex) ChordMML({C G7})
+                                                                    // </SYSTEM_CALC_FUNCTION>
     sf
 }
@@ -0,0 +1,1116 @@
+//! MusicXML - コンパイルした曲をMusicXML(partwise)の楽譜にする
+//!
+//! 実行後のトラックの音符を四分音符=48の格子に合わせて小節に並べる。
+//! 小節をまたぐ音や1つの音符で書けない長さはタイでつなぐ。
+//! 等間隔に並んだ音の出だしから連符(Div{})を見つけ、音数から比(5音なら5:4)を求めて書く。
+
+use crate::key_signature::KeySignature;
+use crate::sakura_version::SAKURA_VERSION;
+use crate::song::{EventType, Song, Track};
+
+/// 四分音符の分解能 (MusicXMLのdivisions) --- 5連符などがあればこの倍数にする
+pub const MUSICXML_DIVISIONS: usize = 48;
+
+/// 分解能を何倍まで細かくするか
+const MAX_GRID_SCALE: usize = 64;
+
+/// 連符として探す音数の上限
+const MAX_TUPLET_NOTES: usize = 16;
+
+/// サクラのゲートの初期値 (Track::new と同じ)
+const DEFAULT_QLEN: isize = 90;
+
+/// 音符の種類と長さ (四分音符=48の倍率1のとき)
+const BASE_TYPES: [(&str, usize); 7] = [
+    ("whole", 192),
+    ("half", 96),
+    ("quarter", 48),
+    ("eighth", 24),
+    ("16th", 12),
+    ("32nd", 6),
+    ("64th", 3),
+];
+
+/// 1つの音符で書ける長さ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NoteType {
+    len: usize,
+    name: &'static str,
+    dots: usize,
+    /// 連符の比 (actual, normal)
+    tuplet: Option<(usize, usize)>,
+}
+
+/// 書ける長さの一覧 (長い順) --- 連符はDiv{}の外でも使う3連符(3:2)だけ
+fn note_types(scale: usize) -> Vec<NoteType> {
+    let mut res = vec![];
+    for (name, len) in BASE_TYPES {
+        let len = len * scale;
+        let mut push = |len, dots, tuplet| {
+            res.push(NoteType {
+                len,
+                name,
+                dots,
+                tuplet,
+            })
+        };
+        push(len, 0, None);
+        if len.is_multiple_of(2) {
+            push(len * 3 / 2, 1, None);
+        }
+        if len.is_multiple_of(4) {
+            push(len * 7 / 4, 2, None);
+        }
+        if len.is_multiple_of(3) {
+            push(len * 2 / 3, 0, Some((3, 2)));
+        }
+    }
+    res.sort_by_key(|t| std::cmp::Reverse(t.len));
+    res
+}
+
+/// 長さをタイでつなぐ音符に分ける (連符の端数を先に書く)
+fn split_duration(len: usize, types: &[NoteType]) -> Vec<NoteType> {
+    let mut res = vec![];
+    let mut rest = len;
+    // 3連符の長さは3で割り切れないので、端数が同じものを1つ使う
+    let t = types
+        .iter()
+        .find(|t| t.tuplet.is_some() && t.len <= rest && t.len % 3 == rest % 3);
+    if let Some(t) = t {
+        res.push(*t);
+        rest -= t.len;
+    }
+    while rest > 0 {
+        match types.iter().find(|t| t.tuplet.is_none() && t.len <= rest) {
+            Some(t) => {
+                res.push(*t);
+                rest -= t.len;
+            }
+            None => {
+                // 格子より細かい端数
+                res.push(NoteType {
+                    len: rest,
+                    name: "128th",
+                    dots: 0,
+                    tuplet: None,
+                });
+                break;
+            }
+        }
+    }
+    res
+}
+
+/// Div{}で書いた連符の範囲 (格子の単位)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Tuplet {
+    start: usize,
+    len: usize,
+    actual: usize,
+    normal: usize,
+}
+
+impl Tuplet {
+    fn contains(&self, start: usize, end: usize) -> bool {
+        self.start <= start && end <= self.start + self.len
+    }
+}
+
+/// 一番内側の連符
+fn tuplet_at(tuplets: &[Tuplet], start: usize, end: usize) -> Option<&Tuplet> {
+    tuplets
+        .iter()
+        .filter(|t| t.contains(start, end))
+        .min_by_key(|t| t.len)
+}
+
+/// start から len の長さを音符に分ける (連符の括弧に入れる長さも返す)
+/// Div{}の中で連符の音符1つで書ければそれを使い、書けなければタイでつなぐ
+fn pieces(
+    start: usize,
+    len: usize,
+    types: &[NoteType],
+    tuplets: &[Tuplet],
+) -> Vec<(NoteType, usize)> {
+    if let Some(tp) = tuplet_at(tuplets, start, start + len) {
+        // 連符でないときの長さ (5:4なら5/4倍)
+        let written = len * tp.actual;
+        let t = types.iter().find(|t| {
+            t.tuplet.is_none() && written.is_multiple_of(tp.normal) && t.len == written / tp.normal
+        });
+        if let Some(t) = t {
+            let t = NoteType {
+                len,
+                tuplet: Some((tp.actual, tp.normal)),
+                ..*t
+            };
+            return vec![(t, tp.len)];
+        }
+    }
+    split_duration(len, types)
+        .into_iter()
+        .map(|t| (t, if t.tuplet.is_some() { t.len * 3 } else { 0 }))
+        .collect()
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Div{}で書いた連符 (開始, 長さ, 音数) を音の出だしから探す
+/// 八分音符〜全音符の枠に、枠を音数で割った位置だけに音が並んでいれば連符とみなす
+fn find_tuplets(track: &Track, timebase: isize) -> Vec<(isize, isize, usize)> {
+    let mut onsets: Vec<isize> = track
+        .events
+        .iter()
+        .filter(|e| e.etype == EventType::NoteOn && e.v2 > 0)
+        .map(|e| e.time)
+        .collect();
+    onsets.sort();
+    onsets.dedup();
+    let mut res: Vec<(isize, isize, usize)> = vec![];
+    for len in [timebase / 2, timebase, timebase * 2, timebase * 4] {
+        if len <= 0 {
+            continue;
+        }
+        for &start in onsets.iter() {
+            if start % len != 0 || res.iter().any(|&(s, l, _)| s <= start && start < s + l) {
+                continue;
+            }
+            let inside: Vec<isize> = onsets
+                .iter()
+                .filter(|&&t| start <= t && t < start + len)
+                .map(|&t| t - start)
+                .collect();
+            // 2の累乗の音符で書ける位置ばかりなら連符ではない
+            if inside.iter().all(|&t| (t * 32) % len == 0) {
+                continue;
+            }
+            let n = inside.len();
+            if !(3..=MAX_TUPLET_NOTES).contains(&n) || n.is_power_of_two() {
+                continue;
+            }
+            // Div{}は1音を len/n (切り捨て) にするので、その位置から正確な位置までを許す
+            let fits = inside.iter().enumerate().all(|(k, &t)| {
+                let k = k as isize;
+                let n = n as isize;
+                k * (len / n) <= t && t <= (k * len + n - 1) / n
+            });
+            if fits {
+                res.push((start, len, n));
+            }
+        }
+    }
+    res.sort();
+    res
+}
+
+/// Div{}の音が格子に乗るように分解能の倍率を決める (細かくなりすぎる連符は諦める)
+fn grid_scale(spans: &[Vec<(isize, isize, usize)>], timebase: isize) -> usize {
+    let mut scale = 1;
+    for &(start, len, cnt) in spans.iter().flatten() {
+        let s = to_grid(start, timebase, 1);
+        let glen = to_grid(start + len, timebase, 1).saturating_sub(s);
+        if glen == 0 || cnt == 0 {
+            continue;
+        }
+        let need = cnt / gcd(glen, cnt);
+        let next = scale / gcd(scale, need) * need;
+        if next <= MAX_GRID_SCALE {
+            scale = next;
+        }
+    }
+    scale
+}
+
+/// 見つけた連符から連符の比を求める (5音なら5:4)
+/// 1音が連符でない音符で書ける長さなら連符にしない
+fn track_tuplets(
+    spans: &[(isize, isize, usize)],
+    timebase: isize,
+    scale: usize,
+    types: &[NoteType],
+) -> Vec<Tuplet> {
+    let mut res = vec![];
+    for &(start, len, actual) in spans.iter() {
+        let s = to_grid(start, timebase, scale);
+        let glen = to_grid(start + len, timebase, scale).saturating_sub(s);
+        if actual < 2 || !glen.is_multiple_of(actual) {
+            continue;
+        }
+        let unit = glen / actual;
+        if types.iter().any(|t| t.tuplet.is_none() && t.len == unit) {
+            continue;
+        }
+        let mut normal = 1;
+        while normal * 2 <= actual {
+            normal *= 2;
+        }
+        let tp = Tuplet {
+            start: s,
+            len: glen,
+            actual,
+            normal,
+        };
+        if normal < actual && !res.contains(&tp) {
+            res.push(tp);
+        }
+    }
+    res
+}
+
+/// Div{}の中の時間を連符の1音の長さにそろえる
+fn snap(pos: usize, tuplets: &[Tuplet]) -> usize {
+    match tuplet_at(tuplets, pos, pos) {
+        Some(tp) => {
+            let unit = tp.len / tp.actual;
+            tp.start + (pos - tp.start + unit / 2) / unit * unit
+        }
+        None => pos,
+    }
+}
+
+/// 同時に始まり同じ長さの音 (和音)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Group {
+    start: usize,
+    end: usize,
+    notes: Vec<isize>,
+}
+
+/// 小節 (拍子が変わる小節はtimeを持つ)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Measure {
+    start: usize,
+    len: usize,
+    time: Option<(usize, usize)>,
+}
+
+/// 小節に書く要素
+#[derive(Debug, Clone)]
+enum Elem {
+    /// 音符か休符 (notesが空なら休符)
+    Note {
+        notes: Vec<isize>,
+        t: NoteType,
+        tie_start: bool,
+        tie_stop: bool,
+        /// 連符の括弧 (開始, 終了)
+        tuplet: (bool, bool),
+        /// 連符の括弧に入れる長さ (0なら連符でない)
+        tuplet_len: usize,
+    },
+    /// 1小節全部の休符
+    MeasureRest(usize),
+    /// 声部2以降の空白
+    Forward(usize),
+}
+
+fn to_grid(tick: isize, timebase: isize, scale: usize) -> usize {
+    let tb = timebase.max(1);
+    let div = (MUSICXML_DIVISIONS * scale) as isize;
+    ((tick.max(0) * div * 2 + tb) / (tb * 2)) as usize
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// トラックのメタイベント(テキスト)
+fn track_text(track: &Track, meta_type: isize) -> Option<String> {
+    track
+        .events
+        .iter()
+        .find(|e| e.etype == EventType::Meta && e.v2 == meta_type)
+        .and_then(|e| e.data.as_ref())
+        .map(|d| {
+            // TrackName={"Flute"} のように引用符ごと書いた名前は引用符を外す
+            let s = String::from_utf8_lossy(d);
+            let s = s.trim();
+            s.strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .unwrap_or(s)
+                .trim()
+                .to_string()
+        })
+        .filter(|s| !s.is_empty())
+}
+
+/// KeyFlagから調号(#の数、♭なら負数)を求める
+fn key_fifths(key_flag: &[isize]) -> i32 {
+    KeySignature::from_key_flag(key_flag).fifths as i32
+}
+
+/// 曲の調号 (KeySignatureで書いた最初の調号、なければ最後のKeyFlag)
+fn song_key(song: &Song) -> KeySignature {
+    song.tracks
+        .iter()
+        .flat_map(|t| t.events.iter())
+        .filter(|e| e.etype == EventType::Meta && e.v2 == 0x59)
+        .min_by_key(|e| e.time)
+        .and_then(|e| KeySignature::from_meta_data(e.data.as_deref().unwrap_or(&[])))
+        .unwrap_or(KeySignature {
+            fifths: key_fifths(&song.key_flag) as isize,
+            minor: false,
+        })
+}
+
+/// ノート番号を音名・変化記号・オクターブにする
+fn pitch_name(no: isize, flats: bool) -> (char, i32, isize) {
+    const SHARP_NAMES: [(char, i32); 12] = [
+        ('C', 0),
+        ('C', 1),
+        ('D', 0),
+        ('D', 1),
+        ('E', 0),
+        ('F', 0),
+        ('F', 1),
+        ('G', 0),
+        ('G', 1),
+        ('A', 0),
+        ('A', 1),
+        ('B', 0),
+    ];
+    const FLAT_NAMES: [(char, i32); 12] = [
+        ('C', 0),
+        ('D', -1),
+        ('D', 0),
+        ('E', -1),
+        ('E', 0),
+        ('F', 0),
+        ('G', -1),
+        ('G', 0),
+        ('A', -1),
+        ('A', 0),
+        ('B', -1),
+        ('B', 0),
+    ];
+    let names = if flats { &FLAT_NAMES } else { &SHARP_NAMES };
+    let (step, alter) = names[no.rem_euclid(12) as usize];
+    (step, alter, no.div_euclid(12) - 1)
+}
+
+/// トラックの音符を格子に合わせて和音にまとめる
+fn track_groups(
+    track: &Track,
+    timebase: isize,
+    scale: usize,
+    types: &[NoteType],
+    tuplets: &[Tuplet],
+) -> Vec<Group> {
+    let grid = |tick: isize| snap(to_grid(tick, timebase, scale), tuplets);
+    let notes: Vec<(isize, isize, isize)> = track
+        .events
+        .iter()
+        .filter(|e| e.etype == EventType::NoteOn && e.v2 > 0)
+        .map(|e| (e.time, e.v1, e.v2))
+        .collect();
+    let mut onsets: Vec<isize> = notes.iter().map(|n| n.0).collect();
+    onsets.sort();
+    onsets.dedup();
+    let gap_of = |time: isize| {
+        let i = onsets.partition_point(|&t| t <= time);
+        onsets.get(i).map(|&t| t - time)
+    };
+    // 次の音まで続く音からゲートの割合(q)を推測する (少なければサクラの初期値)
+    let mut rates: Vec<isize> = notes
+        .iter()
+        .filter_map(|&(time, _, gate)| {
+            gap_of(time)
+                .filter(|&gap| gate <= gap)
+                .map(|gap| gate * 100 / gap)
+        })
+        .collect();
+    rates.sort();
+    let rate = if rates.len() < 3 {
+        DEFAULT_QLEN
+    } else {
+        rates[rates.len() / 2].clamp(30, 100)
+    };
+    let mut groups: Vec<Group> = vec![];
+    for (time, no, gate) in notes {
+        let gap = gap_of(time);
+        let len = gate * 100 / rate;
+        let start = grid(time);
+        let end = match gap {
+            Some(gap) if gate <= gap && len * 10 >= gap * 9 => grid(time + gap),
+            _ => {
+                // 休符の前の音は、ゲートから求めた長さかゲートそのものが音符の長さに合えばそれを使う
+                // (Div{}の中ならそろえた長さのまま)
+                let est = grid(time + len).saturating_sub(start).max(1);
+                let raw = grid(time + gate).saturating_sub(start).max(1);
+                let snapped = [est, raw]
+                    .into_iter()
+                    .find(|&l| {
+                        types.iter().any(|t| t.len == l)
+                            || tuplet_at(tuplets, start, start + l).is_some()
+                    })
+                    .or_else(|| {
+                        types
+                            .iter()
+                            .map(|t| t.len)
+                            .min_by_key(|l| l.abs_diff(est))
+                            .filter(|l| l.abs_diff(est) * 8 <= est)
+                    })
+                    .unwrap_or(est);
+                start + snapped
+            }
+        };
+        match groups.iter_mut().find(|g| g.start == start && g.end == end) {
+            Some(g) => g.notes.push(no),
+            None => groups.push(Group {
+                start,
+                end,
+                notes: vec![no],
+            }),
+        }
+    }
+    for g in groups.iter_mut() {
+        g.notes.sort();
+        g.notes.dedup();
+    }
+    groups.sort_by_key(|g| (g.start, g.end));
+    groups
+}
+
+/// 重ならないように声部に分ける
+fn split_voices(groups: Vec<Group>) -> Vec<Vec<Group>> {
+    let mut voices: Vec<Vec<Group>> = vec![];
+    for g in groups {
+        match voices
+            .iter_mut()
+            .find(|v| v.last().map(|l| l.end <= g.start).unwrap_or(true))
+        {
+            Some(v) => v.push(g),
+            None => voices.push(vec![g]),
+        }
+    }
+    voices
+}
+
+/// 拍子の変化から小節を並べる
+fn measures(song: &Song, end: usize, scale: usize) -> Vec<Measure> {
+    let mut sigs: Vec<(usize, usize, usize)> = vec![];
+    for trk in song.tracks.iter() {
+        for e in trk.events.iter() {
+            if e.etype != EventType::Meta || e.v2 != 0x58 {
+                continue;
+            }
+            if let Some(d) = e
+                .data
+                .as_ref()
+                .filter(|d| d.len() >= 2 && d[0] > 0 && d[1] < 7)
+            {
+                let time = to_grid(e.time, song.timebase, scale);
+                sigs.push((time, d[0] as usize, 1 << d[1]));
+            }
+        }
+    }
+    sigs.sort_by_key(|s| s.0);
+    let mut res = vec![];
+    let mut cur = (4, 4);
+    let mut changed = true;
+    let mut pos = 0;
+    let mut si = 0;
+    while pos < end || res.is_empty() {
+        // 小節の途中の拍子の変化は次の小節から
+        while si < sigs.len() && sigs[si].0 <= pos {
+            let sig = (sigs[si].1, sigs[si].2);
+            changed |= sig != cur;
+            cur = sig;
+            si += 1;
+        }
+        let len = (MUSICXML_DIVISIONS * scale * 4 * cur.0 / cur.1).max(1);
+        res.push(Measure {
+            start: pos,
+            len,
+            time: if changed { Some(cur) } else { None },
+        });
+        changed = false;
+        pos += len;
+    }
+    res
+}
+
+/// 1小節分の声部の要素を作る
+fn voice_elems(
+    voice: &[Group],
+    m: &Measure,
+    is_first: bool,
+    types: &[NoteType],
+    tuplets: &[Tuplet],
+) -> Vec<Elem> {
+    let m_end = m.start + m.len;
+    let mut res = vec![];
+    let mut pos = m.start;
+    let gap = |res: &mut Vec<Elem>, start: usize, len: usize| {
+        if !is_first {
+            res.push(Elem::Forward(len));
+            return;
+        }
+        for (t, tuplet_len) in pieces(start, len, types, tuplets) {
+            res.push(Elem::Note {
+                notes: vec![],
+                t,
+                tie_start: false,
+                tie_stop: false,
+                tuplet: (false, false),
+                tuplet_len,
+            });
+        }
+    };
+    for g in voice.iter().filter(|g| g.start < m_end && g.end > m.start) {
+        let s = g.start.max(m.start);
+        let e = g.end.min(m_end);
+        if s > pos {
+            gap(&mut res, pos, s - pos);
+        }
+        let list = pieces(s, e - s, types, tuplets);
+        let count = list.len();
+        for (i, (t, tuplet_len)) in list.into_iter().enumerate() {
+            res.push(Elem::Note {
+                notes: g.notes.clone(),
+                t,
+                tie_start: i + 1 < count || g.end > m_end,
+                tie_stop: i > 0 || g.start < m.start,
+                tuplet: (false, false),
+                tuplet_len,
+            });
+        }
+        pos = e;
+    }
+    if pos == m.start && is_first {
+        return vec![Elem::MeasureRest(m.len)];
+    }
+    if pos < m_end && !res.is_empty() {
+        gap(&mut res, pos, m_end - pos);
+    }
+    mark_tuplets(&mut res);
+    res
+}
+
+/// 続けて並んだ連符に括弧を付ける
+fn mark_tuplets(elems: &mut [Elem]) {
+    let mut sum = 0;
+    let mut target = 0;
+    let mut last: Option<usize> = None;
+    for i in 0..elems.len() {
+        let len = match &mut elems[i] {
+            Elem::Note {
+                t,
+                tuplet,
+                tuplet_len,
+                ..
+            } if *tuplet_len > 0 => {
+                if sum == 0 {
+                    tuplet.0 = true;
+                    target = *tuplet_len;
+                }
+                t.len
+            }
+            _ => {
+                if let Some(Elem::Note { tuplet, .. }) = last.and_then(|l| elems.get_mut(l)) {
+                    tuplet.1 = true;
+                }
+                sum = 0;
+                last = None;
+                continue;
+            }
+        };
+        sum += len;
+        last = Some(i);
+        if sum >= target {
+            if let Elem::Note { tuplet, .. } = &mut elems[i] {
+                tuplet.1 = true;
+            }
+            sum = 0;
+            last = None;
+        }
+    }
+    if let Some(Elem::Note { tuplet, .. }) = last.and_then(|l| elems.get_mut(l)) {
+        tuplet.1 = true;
+    }
+}
+
+/// インデント付きでXMLを組み立てる
+struct XmlWriter {
+    res: String,
+    depth: usize,
+}
+
+impl XmlWriter {
+    fn line(&mut self, s: &str) {
+        self.res.push_str(&"  ".repeat(self.depth));
+        self.res.push_str(s);
+        self.res.push('\n');
+    }
+    fn open(&mut self, tag: &str) {
+        self.line(&format!("<{}>", tag));
+        self.depth += 1;
+    }
+    fn close(&mut self, tag: &str) {
+        self.depth -= 1;
+        self.line(&format!("</{}>", tag));
+    }
+    fn elem(&mut self, tag: &str, value: &str) {
+        self.line(&format!("<{}>{}</{}>", tag, value, tag));
+    }
+}
+
+fn write_elem(w: &mut XmlWriter, elem: &Elem, voice: usize, flats: bool) {
+    let (notes, t, tie_start, tie_stop, tuplet) = match elem {
+        Elem::Forward(len) => {
+            w.open("forward");
+            w.elem("duration", &len.to_string());
+            w.elem("voice", &voice.to_string());
+            w.close("forward");
+            return;
+        }
+        Elem::MeasureRest(len) => {
+            w.open("note");
+            w.line("<rest measure=\"yes\"/>");
+            w.elem("duration", &len.to_string());
+            w.elem("voice", &voice.to_string());
+            w.close("note");
+            return;
+        }
+        Elem::Note {
+            notes,
+            t,
+            tie_start,
+            tie_stop,
+            tuplet,
+            ..
+        } => (notes, t, *tie_start, *tie_stop, *tuplet),
+    };
+    let pitches: Vec<Option<isize>> = if notes.is_empty() {
+        vec![None]
+    } else {
+        notes.iter().map(|n| Some(*n)).collect()
+    };
+    for (i, no) in pitches.iter().enumerate() {
+        w.open("note");
+        if i > 0 {
+            w.line("<chord/>");
+        }
+        match no {
+            Some(no) => {
+                let (step, alter, octave) = pitch_name(*no, flats);
+                w.open("pitch");
+                w.elem("step", &step.to_string());
+                if alter != 0 {
+                    w.elem("alter", &alter.to_string());
+                }
+                w.elem("octave", &octave.to_string());
+                w.close("pitch");
+            }
+            None => w.line("<rest/>"),
+        }
+        w.elem("duration", &t.len.to_string());
+        let tie_stop = tie_stop && no.is_some();
+        let tie_start = tie_start && no.is_some();
+        if tie_stop {
+            w.line("<tie type=\"stop\"/>");
+        }
+        if tie_start {
+            w.line("<tie type=\"start\"/>");
+        }
+        w.elem("voice", &voice.to_string());
+        w.elem("type", t.name);
+        for _ in 0..t.dots {
+            w.line("<dot/>");
+        }
+        if let Some((actual, normal)) = t.tuplet {
+            w.open("time-modification");
+            w.elem("actual-notes", &actual.to_string());
+            w.elem("normal-notes", &normal.to_string());
+            w.close("time-modification");
+        }
+        let tuplet = if i == 0 { tuplet } else { (false, false) };
+        if tie_stop || tie_start || tuplet.0 || tuplet.1 {
+            w.open("notations");
+            if tie_stop {
+                w.line("<tied type=\"stop\"/>");
+            }
+            if tie_start {
+                w.line("<tied type=\"start\"/>");
+            }
+            if tuplet.0 {
+                w.line("<tuplet type=\"start\" bracket=\"yes\"/>");
+            }
+            if tuplet.1 {
+                w.line("<tuplet type=\"stop\"/>");
+            }
+            w.close("notations");
+        }
+        w.close("note");
+    }
+}
+
+/// 楽譜にするパート
+struct Part {
+    name: String,
+    channel: isize,
+    program: Option<isize>,
+    voices: Vec<Vec<Group>>,
+    tuplets: Vec<Tuplet>,
+}
+
+/// 実行後の曲をMusicXML(partwise)にする
+pub fn song_to_musicxml(song: &Song) -> String {
+    let spans: Vec<Vec<(isize, isize, usize)>> = song
+        .tracks
+        .iter()
+        .map(|trk| find_tuplets(trk, song.timebase))
+        .collect();
+    let scale = grid_scale(&spans, song.timebase);
+    let types = note_types(scale);
+    let mut parts: Vec<Part> = vec![];
+    let mut title = None;
+    let mut end = 0;
+    for (no, trk) in song.tracks.iter().enumerate() {
+        let tuplets = track_tuplets(&spans[no], song.timebase, scale, &types);
+        let groups = track_groups(trk, song.timebase, scale, &types, &tuplets);
+        if groups.is_empty() {
+            if no == 0 {
+                title = track_text(trk, 3);
+            }
+            continue;
+        }
+        end = end.max(groups.iter().map(|g| g.end).max().unwrap_or(0));
+        let first_note = trk.events.iter().find(|e| e.etype == EventType::NoteOn);
+        parts.push(Part {
+            name: track_text(trk, 3).unwrap_or(format!("Track {}", no)),
+            channel: first_note.map_or(trk.channel, |e| e.channel),
+            program: trk
+                .events
+                .iter()
+                .find(|e| e.etype == EventType::Voice)
+                .map(|e| e.v1),
+            voices: split_voices(groups),
+            tuplets,
+        });
+    }
+    let measures = measures(song, end, scale);
+    // テンポ (時間, BPM)
+    let mut tempos: Vec<(usize, isize)> = vec![];
+    for trk in song.tracks.iter() {
+        for e in trk.events.iter() {
+            if e.etype != EventType::Meta || e.v2 != 0x51 {
+                continue;
+            }
+            if let Some(d) = e.data.as_ref().filter(|d| d.len() == 3) {
+                let mpq = (d[0] as isize) << 16 | (d[1] as isize) << 8 | d[2] as isize;
+                if mpq > 0 {
+                    let bpm = (60_000_000 + mpq / 2) / mpq;
+                    tempos.push((to_grid(e.time, song.timebase, scale), bpm));
+                }
+            }
+        }
+    }
+    tempos.sort();
+    let key = song_key(song);
+    let fifths = key.fifths;
+    let mut w = XmlWriter {
+        res: String::new(),
+        depth: 0,
+    };
+    w.line("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>");
+    w.line("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">");
+    w.open("score-partwise version=\"4.0\"");
+    if let Some(title) = title {
+        w.elem("movement-title", &xml_escape(&title));
+    }
+    w.open("identification");
+    w.open("encoding");
+    w.elem("software", &format!("sakuramml {}", SAKURA_VERSION));
+    w.close("encoding");
+    w.close("identification");
+    w.open("part-list");
+    for (i, p) in parts.iter().enumerate() {
+        w.open(&format!("score-part id=\"P{}\"", i + 1));
+        w.elem("part-name", &xml_escape(&p.name));
+        w.open(&format!("midi-instrument id=\"P{}-I1\"", i + 1));
+        w.elem("midi-channel", &(p.channel + 1).to_string());
+        if let Some(program) = p.program {
+            w.elem("midi-program", &(program + 1).to_string());
+        }
+        w.close("midi-instrument");
+        w.close("score-part");
+    }
+    w.close("part-list");
+    for (i, p) in parts.iter().enumerate() {
+        w.open(&format!("part id=\"P{}\"", i + 1));
+        for (mi, m) in measures.iter().enumerate() {
+            w.open(&format!("measure number=\"{}\"", mi + 1));
+            if mi == 0 || m.time.is_some() {
+                w.open("attributes");
+                if mi == 0 {
+                    w.elem("divisions", &(MUSICXML_DIVISIONS * scale).to_string());
+                    w.open("key");
+                    w.elem("fifths", &fifths.to_string());
+                    if key.minor {
+                        w.elem("mode", "minor");
+                    }
+                    w.close("key");
+                }
+                if let Some((beats, beat_type)) = m.time {
+                    w.open("time");
+                    w.elem("beats", &beats.to_string());
+                    w.elem("beat-type", &beat_type.to_string());
+                    w.close("time");
+                }
+                if mi == 0 {
+                    write_clef(&mut w, p);
+                }
+                w.close("attributes");
+            }
+            if i == 0 {
+                for (time, bpm) in tempos
+                    .iter()
+                    .filter(|t| t.0 >= m.start && t.0 < m.start + m.len)
+                {
+                    write_tempo(&mut w, *time - m.start, *bpm);
+                }
+            }
+            let mut written = false;
+            for (vi, voice) in p.voices.iter().enumerate() {
+                let elems = voice_elems(voice, m, vi == 0, &types, &p.tuplets);
+                if elems.is_empty() {
+                    continue;
+                }
+                if written {
+                    w.open("backup");
+                    w.elem("duration", &m.len.to_string());
+                    w.close("backup");
+                }
+                for e in elems.iter() {
+                    write_elem(&mut w, e, vi + 1, fifths < 0);
+                }
+                written = true;
+            }
+            w.close("measure");
+        }
+        w.close("part");
+    }
+    w.close("score-partwise");
+    w.res
+}
+
+/// 音域から音部記号を決める (10チャンネルは打楽器)
+fn write_clef(w: &mut XmlWriter, p: &Part) {
+    let notes: Vec<isize> = p
+        .voices
+        .iter()
+        .flatten()
+        .flat_map(|g| g.notes.iter().copied())
+        .collect();
+    let average = notes.iter().sum::<isize>() / notes.len().max(1) as isize;
+    let (sign, line) = if p.channel == 9 {
+        ("percussion", 2)
+    } else if average < 57 {
+        ("F", 4)
+    } else {
+        ("G", 2)
+    };
+    w.open("clef");
+    w.elem("sign", sign);
+    w.elem("line", &line.to_string());
+    w.close("clef");
+}
+
+fn write_tempo(w: &mut XmlWriter, offset: usize, bpm: isize) {
+    w.open("direction placement=\"above\"");
+    w.open("direction-type");
+    w.open("metronome");
+    w.elem("beat-unit", "quarter");
+    w.elem("per-minute", &bpm.to_string());
+    w.close("metronome");
+    w.close("direction-type");
+    if offset > 0 {
+        w.elem("offset", &offset.to_string());
+    }
+    w.line(&format!("<sound tempo=\"{}\"/>", bpm));
+    w.close("direction");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_xml(src: &str) -> String {
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, src);
+        crate::runner::exec(&mut song, &tokens);
+        song_to_musicxml(&song)
+    }
+
+    /// 要素の中身だけを並べる (ex) ["C4 48 quarter", ...]
+    fn notes(xml: &str) -> Vec<String> {
+        let mut res = vec![];
+        for note in xml.split("<note>").skip(1) {
+            let note = note.split("</note>").next().unwrap();
+            let get = |tag: &str| {
+                note.split(&format!("<{}>", tag))
+                    .nth(1)
+                    .and_then(|s| s.split('<').next())
+                    .unwrap_or("")
+                    .to_string()
+            };
+            let pitch = if note.contains("<rest") {
+                "r".to_string()
+            } else {
+                let alter = match get("alter").as_str() {
+                    "1" => "#",
+                    "-1" => "b",
+                    _ => "",
+                };
+                format!("{}{}{}", get("step"), alter, get("octave"))
+            };
+            let mut s = format!("{} {} {}", pitch, get("duration"), get("type"));
+            if note.contains("<dot/>") {
+                s.push('.');
+            }
+            if note.contains("<chord/>") {
+                s.insert(0, '+');
+            }
+            if note.contains("<tie type=\"start\"/>") {
+                s.push('-');
+            }
+            res.push(s);
+        }
+        res
+    }
+
+    #[test]
+    fn exports_parts_measures_and_attributes() {
+        let xml = to_xml("TrackName(\"Song\") Tempo(100) TimeSignature(3,4) TR(1) TrackName(\"Piano\") @1 o5 l4 c d e 'ceg'2.");
+        assert!(
+            xml.contains("<movement-title>Song</movement-title>"),
+            "{}",
+            xml
+        );
+        assert!(xml.contains("<part-name>Piano</part-name>"));
+        // 引用符ごと書いた名前は引用符を外す
+        let flute = to_xml("TR(1) TrackName={\"Flute\"} c");
+        assert!(flute.contains("<part-name>Flute</part-name>"), "{}", flute);
+        assert!(xml.contains("<midi-program>1</midi-program>"));
+        assert!(xml.contains("<beats>3</beats>"));
+        assert!(xml.contains("<per-minute>100</per-minute>"));
+        assert_eq!(xml.matches("<measure ").count(), 2);
+        assert_eq!(
+            notes(&xml),
+            vec![
+                "C4 48 quarter",
+                "D4 48 quarter",
+                "E4 48 quarter",
+                "C4 144 half.",
+                "+E4 144 half.",
+                "+G4 144 half."
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_notes_across_barlines() {
+        let xml = to_xml("TR(1) o5 l2. c c");
+        assert_eq!(
+            notes(&xml),
+            vec!["C4 144 half.", "C4 48 quarter-", "C4 96 half", "r 96 half"]
+        );
+        assert!(xml.contains("<tied type=\"stop\"/>"));
+    }
+
+    #[test]
+    fn keeps_rests_after_short_gates() {
+        let xml = to_xml("TR(1) q80 o5 l8 c d e4. r8 f4");
+        assert_eq!(
+            notes(&xml),
+            vec![
+                "C4 24 eighth",
+                "D4 24 eighth",
+                "E4 72 quarter.",
+                "r 24 eighth",
+                "F4 48 quarter"
+            ]
+        );
+    }
+
+    #[test]
+    fn short_tracks_use_the_default_gate() {
+        let xml = to_xml("TR(1) o5 c2. r4 c1");
+        assert_eq!(
+            notes(&xml),
+            vec!["C4 144 half.", "r 48 quarter", "C4 192 whole"]
+        );
+    }
+
+    #[test]
+    fn writes_tuplets_and_voices() {
+        let xml = to_xml("TR(1) o5 l4 Div{cde} Div{f g a} r2");
+        assert_eq!(xml.matches("<actual-notes>3</actual-notes>").count(), 6);
+        assert_eq!(xml.matches("<tuplet type=\"start\"").count(), 2);
+        assert_eq!(xml.matches("<tuplet type=\"stop\"/>").count(), 2);
+        assert!(notes(&xml)[0] == "C4 16 eighth", "{}", xml);
+        // 5連符は5:4にして、分解能を5倍にする
+        let xml = to_xml("TR(1) o5 l4 Div{cdefg} r2.");
+        assert!(xml.contains("<divisions>240</divisions>"), "{}", xml);
+        assert_eq!(
+            notes(&xml)[..5],
+            [
+                "C4 48 16th",
+                "D4 48 16th",
+                "E4 48 16th",
+                "F4 48 16th",
+                "G4 48 16th"
+            ]
+        );
+        assert_eq!(xml.matches("<actual-notes>5</actual-notes>").count(), 5);
+        assert_eq!(xml.matches("<normal-notes>4</normal-notes>").count(), 5);
+        assert_eq!(xml.matches("<tuplet type=\"start\"").count(), 1);
+        assert_eq!(xml.matches("<tuplet type=\"stop\"/>").count(), 1);
+        assert!(!xml.contains("32nd"), "{}", xml);
+        // 連符は音の出だしの間隔から見つける (6連符は3連符2つ、休符を含む連符は見つけない)
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(
+            &mut song,
+            "TR(1) l4 c Div{cdefg} d8 e8 Div{c d e f g a} Div{c r e}",
+        );
+        crate::runner::exec(&mut song, &tokens);
+        assert_eq!(
+            find_tuplets(&song.tracks[1], song.timebase),
+            vec![(96, 96, 5), (288, 48, 3), (336, 48, 3)]
+        );
+        // 重なる音は別の声部にする
+        let xml = to_xml("TR(1) o5 Sub{c1} e4 f4 g2");
+        assert!(xml.contains("<backup>"));
+        assert!(xml.contains("<voice>2</voice>"));
+    }
+
+    #[test]
+    fn key_flag_and_spelling() {
+        assert_eq!(key_fifths(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]), 1);
+        assert_eq!(key_fifths(&[0, 0, 0, 0, -1, 0, 0, 0, 0, 0, 0, -1]), -2);
+        // KeySignatureで書いた調号を優先する
+        let xml = to_xml("KeyFlag-(b) KeySignature(E, minor) TR(1) c");
+        assert!(xml.contains("<fifths>1</fifths>"), "{}", xml);
+        assert!(xml.contains("<mode>minor</mode>"), "{}", xml);
+        assert_eq!(pitch_name(61, false), ('C', 1, 4));
+        assert_eq!(pitch_name(61, true), ('D', -1, 4));
+        assert_eq!(
+            split_duration(80, &note_types(1))
+                .iter()
+                .map(|t| t.len)
+                .collect::<Vec<_>>(),
+            vec![32, 48]
+        );
+    }
+}
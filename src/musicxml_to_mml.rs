@@ -0,0 +1,1076 @@
+//! MusicXML to MML - MusicXML(partwise)の楽譜をサクラのMMLに変換する
+//!
+//! パートごとに1つのトラックを作り、音符・休符・タイを音長に直して並べる。
+//! 調号はKeyFlag、拍子はTimeSignature、テンポはTempo、強弱記号はv、アーティキュレーションはqにする。
+//! 2つ目以降の声部は小節ごとに Sub{} で重ねる。
+
+use super::abc::dynamics_velocity;
+use super::midi_to_mml::{length_candidates, length_name};
+use std::collections::HashMap;
+
+/// サクラの初期値 (Track::new と同じ)
+const DEFAULT_OCTAVE: isize = 5;
+const DEFAULT_VELOCITY: isize = 100;
+const DEFAULT_QLEN: isize = 90;
+
+/// TimeBaseはこの値とdivisionsの最小公倍数にする (大きすぎるときは丸める)
+const BASE_TIMEBASE: usize = 96;
+const MAX_TIMEBASE: usize = 9600;
+
+/// 音名 (c d e f g a b)
+const LETTER_NAMES: [char; 7] = ['c', 'd', 'e', 'f', 'g', 'a', 'b'];
+const LETTER_SEMITONES: [isize; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// 調号が付く順 (#: f c g d a e b / ♭: b e a d g c f)
+const SHARP_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6];
+const FLAT_ORDER: [usize; 7] = [6, 2, 5, 1, 4, 0, 3];
+
+/// XMLの要素
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|a| a.0 == name)
+            .map(|a| a.1.as_str())
+    }
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+    fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|c| c.text.trim())
+    }
+    fn child_num(&self, name: &str) -> Option<f64> {
+        self.child_text(name).and_then(|s| s.parse().ok())
+    }
+}
+
+/// 文字参照を戻す (ex) &amp; => &
+fn unescape(s: &str) -> String {
+    let mut res = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        res.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let name = &rest[1..end];
+        let code = if let Some(hex) = name.strip_prefix("#x") {
+            u32::from_str_radix(hex, 16).ok()
+        } else if let Some(dec) = name.strip_prefix('#') {
+            dec.parse().ok()
+        } else {
+            None
+        };
+        let c = match name {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => code.and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                res.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                res.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// 開始タグを読む (要素, 読んだバイト数, 空要素か)
+fn parse_tag(s: &str) -> Option<(Element, usize, bool)> {
+    let b = s.as_bytes();
+    let is_name_end = |c: u8| c.is_ascii_whitespace() || c == b'/' || c == b'>' || c == b'=';
+    let skip_space = |mut i: usize| {
+        while i < b.len() && b[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        i
+    };
+    let mut i = 1;
+    while i < b.len() && !is_name_end(b[i]) {
+        i += 1;
+    }
+    let mut elem = Element {
+        name: s[1..i].to_string(),
+        ..Default::default()
+    };
+    if elem.name.is_empty() {
+        return None;
+    }
+    loop {
+        i = skip_space(i);
+        match b.get(i)? {
+            b'>' => return Some((elem, i + 1, false)),
+            b'/' => return (b.get(i + 1) == Some(&b'>')).then_some((elem, i + 2, true)),
+            _ => {}
+        }
+        let start = i;
+        while i < b.len() && !is_name_end(b[i]) {
+            i += 1;
+        }
+        let name = s[start..i].to_string();
+        i = skip_space(i);
+        if name.is_empty() || b.get(i) != Some(&b'=') {
+            return None;
+        }
+        i = skip_space(i + 1);
+        let quote = *b.get(i)?;
+        if quote != b'"' && quote != b'\'' {
+            return None;
+        }
+        let end = i + 1 + s[i + 1..].find(quote as char)?;
+        elem.attrs.push((name, unescape(&s[i + 1..end])));
+        i = end + 1;
+    }
+}
+
+/// 簡易XMLパーサ (要素・属性・テキストだけを読む)
+fn parse_xml(src: &str) -> Result<Element, String> {
+    let err = |i: usize, msg: &str| {
+        let line = src[..i].matches('\n').count() + 1;
+        format!("XML syntax error at line {}: {}", line, msg)
+    };
+    let mut stack: Vec<Element> = vec![Element::default()];
+    let mut i = 0;
+    while i < src.len() {
+        let rest = &src[i..];
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            stack
+                .last_mut()
+                .unwrap()
+                .text
+                .push_str(&unescape(&rest[..end]));
+            i += end;
+        } else if rest.starts_with("<!--") {
+            i += rest.find("-->").ok_or_else(|| err(i, "unclosed comment"))? + 3;
+        } else if let Some(body) = rest.strip_prefix("<![CDATA[") {
+            let end = body.find("]]>").ok_or_else(|| err(i, "unclosed CDATA"))?;
+            stack.last_mut().unwrap().text.push_str(&body[..end]);
+            i += 9 + end + 3;
+        } else if rest.starts_with("<?") {
+            i += rest
+                .find("?>")
+                .ok_or_else(|| err(i, "unclosed declaration"))?
+                + 2;
+        } else if rest.starts_with("<!") {
+            // DOCTYPE (内部サブセットの[]は読み飛ばす)
+            let mut depth = 0;
+            let end = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    match c {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        '>' if depth == 0 => return true,
+                        _ => {}
+                    }
+                    false
+                })
+                .ok_or_else(|| err(i, "unclosed declaration"))?
+                .0;
+            i += end + 1;
+        } else if let Some(body) = rest.strip_prefix("</") {
+            let end = body.find('>').ok_or_else(|| err(i, "unclosed tag"))?;
+            let name = body[..end].trim();
+            if stack.len() < 2 || stack.last().unwrap().name != name {
+                return Err(err(i, &format!("unexpected </{}>", name)));
+            }
+            let elem = stack.pop().unwrap();
+            stack.last_mut().unwrap().children.push(elem);
+            i += 2 + end + 1;
+        } else {
+            let (elem, len, closed) = parse_tag(rest).ok_or_else(|| err(i, "broken tag"))?;
+            i += len;
+            if closed {
+                stack.last_mut().unwrap().children.push(elem);
+            } else {
+                stack.push(elem);
+            }
+        }
+    }
+    if stack.len() > 1 {
+        let name = &stack.last().unwrap().name;
+        return Err(err(src.len(), &format!("<{}> is not closed", name)));
+    }
+    stack
+        .pop()
+        .unwrap()
+        .children
+        .into_iter()
+        .next()
+        .ok_or_else(|| "No XML element".to_string())
+}
+
+/// 音の高さ (音名の番号 c=0..b=6, 変化, サクラのオクターブ)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pitch {
+    letter: usize,
+    alter: isize,
+    octave: isize,
+}
+
+impl Pitch {
+    fn no(&self) -> isize {
+        self.octave * 12 + LETTER_SEMITONES[self.letter] + self.alter
+    }
+    /// ノート番号から音名を決める (黒鍵は#で書く)
+    fn from_no(no: isize) -> Self {
+        const SPELLING: [(usize, isize); 12] = [
+            (0, 0),
+            (0, 1),
+            (1, 0),
+            (1, 1),
+            (2, 0),
+            (3, 0),
+            (3, 1),
+            (4, 0),
+            (4, 1),
+            (5, 0),
+            (5, 1),
+            (6, 0),
+        ];
+        let (letter, alter) = SPELLING[no.rem_euclid(12) as usize];
+        Pitch {
+            letter,
+            alter,
+            octave: no.div_euclid(12),
+        }
+    }
+    /// <pitch> や <unpitched> の音名を読む (MusicXMLのオクターブ4がサクラのo5)
+    fn read(elem: &Element, step: &str, octave: &str) -> Option<Self> {
+        let step = elem.child_text(step)?.to_ascii_lowercase();
+        let letter = LETTER_NAMES.iter().position(|c| step.starts_with(*c))?;
+        let alter = elem.child_num("alter").unwrap_or(0.0).round() as isize;
+        let octave = elem.child_num(octave)? as isize + 1;
+        Some(Pitch {
+            letter,
+            alter,
+            octave,
+        })
+    }
+}
+
+/// パートの音符 (和音は1つにまとめる)
+#[derive(Debug, Clone)]
+struct PartNote {
+    start: usize,
+    step: usize,
+    voice: usize,
+    pitches: Vec<Pitch>,
+    velocity: isize,
+    qlen: isize,
+    tie_start: bool,
+    tie_stop: bool,
+}
+
+/// トラックに書くコマンド
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    /// 調号 (#の数、♭なら負数)
+    Key(i32),
+    /// 移調楽器 (TrackKey)
+    TrackKey(isize),
+    Mml(String),
+}
+
+/// <part-list> に書かれたパートの情報
+#[derive(Debug, Default)]
+struct PartInfo {
+    name: String,
+    channel: Option<usize>,
+    program: Option<usize>,
+    /// 打楽器の音色ID => ノート番号
+    unpitched: HashMap<String, isize>,
+}
+
+/// パートの内容
+#[derive(Debug, Default)]
+struct Part {
+    info: PartInfo,
+    percussion: bool,
+    commands: Vec<(usize, Command)>,
+    notes: Vec<PartNote>,
+    /// 小節の頭の時間
+    bars: Vec<usize>,
+}
+
+fn read_part_list(score: &Element) -> HashMap<String, PartInfo> {
+    let mut res = HashMap::new();
+    let list = match score.child("part-list") {
+        Some(list) => list,
+        None => return res,
+    };
+    for sp in list.children_named("score-part") {
+        let mut info = PartInfo {
+            name: sp.child_text("part-name").unwrap_or("").to_string(),
+            ..Default::default()
+        };
+        for mi in sp.children_named("midi-instrument") {
+            let num = |name| mi.child_num(name).map(|v| v.round() as usize);
+            info.channel = info.channel.or(num("midi-channel"));
+            info.program = info.program.or(num("midi-program"));
+            if let (Some(id), Some(no)) = (mi.attr("id"), num("midi-unpitched")) {
+                info.unpitched.insert(id.to_string(), no as isize - 1);
+            }
+        }
+        res.insert(sp.attr("id").unwrap_or("").to_string(), info);
+    }
+    res
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// すべてのdivisionsを割り切れるTimeBaseを選ぶ
+fn choose_timebase(parts: &[&Element]) -> usize {
+    let mut timebase = BASE_TIMEBASE;
+    for part in parts {
+        for m in part.children_named("measure") {
+            for a in m.children_named("attributes") {
+                let d = a.child_num("divisions").unwrap_or(0.0).round() as usize;
+                if d == 0 {
+                    continue;
+                }
+                let lcm = timebase / gcd(timebase, d) * d;
+                if lcm <= MAX_TIMEBASE {
+                    timebase = lcm;
+                }
+            }
+        }
+    }
+    timebase
+}
+
+/// アーティキュレーションをゲートにする
+fn articulation_qlen(note: &Element) -> Option<isize> {
+    let names: Vec<&str> = note
+        .children_named("notations")
+        .flat_map(|n| n.children_named("articulations"))
+        .flat_map(|a| a.children.iter())
+        .map(|e| e.name.as_str())
+        .collect();
+    let has = |name| names.contains(&name);
+    if has("staccatissimo") {
+        Some(25)
+    } else if has("spiccato") {
+        Some(35)
+    } else if has("detached-legato") || (has("staccato") && has("tenuto")) {
+        Some(75)
+    } else if has("staccato") {
+        Some(50)
+    } else if has("tenuto") {
+        Some(100)
+    } else {
+        None
+    }
+}
+
+/// <sound dynamics> はフォルテ(90)に対する割合
+fn sound_velocity(percent: &str) -> Option<isize> {
+    let v: f64 = percent.trim().parse().ok()?;
+    Some(((v * 0.9).round() as isize).clamp(1, 127))
+}
+
+/// 拍子を読む (ex) <beats>3+2</beats> => 5
+fn read_time(time: &Element) -> Option<(usize, usize)> {
+    let beats: usize = time
+        .child_text("beats")?
+        .split('+')
+        .map(|b| b.trim().parse::<usize>().ok())
+        .sum::<Option<usize>>()?;
+    let beat_type: usize = time.child_text("beat-type")?.parse().ok()?;
+    (beats > 0 && beat_type > 0).then_some((beats, beat_type))
+}
+
+/// パートの小節を時間順の音符にする
+fn read_part(
+    elem: &Element,
+    info: PartInfo,
+    timebase: usize,
+    global: &mut Vec<(usize, String)>,
+) -> Part {
+    let mut part = Part {
+        info,
+        ..Default::default()
+    };
+    let mut divisions = 1.0;
+    let mut velocity = DEFAULT_VELOCITY;
+    let mut bar_len = timebase * 4;
+    let mut measure_start = 0;
+    for measure in elem.children_named("measure") {
+        part.bars.push(measure_start);
+        let mut pos = measure_start;
+        let mut end = measure_start;
+        let mut last_start = measure_start;
+        for e in measure.children.iter() {
+            let ticks = |divisions: f64| {
+                let dur = e.child_num("duration").unwrap_or(0.0);
+                (dur * timebase as f64 / divisions).round().max(0.0) as usize
+            };
+            let mut sound = |s: &Element, velocity: &mut isize| {
+                if let Some(tempo) = s.attr("tempo").and_then(|t| t.trim().parse::<f64>().ok()) {
+                    global.push((pos, format!("Tempo({})", tempo.round() as isize)));
+                }
+                if let Some(v) = s.attr("dynamics").and_then(sound_velocity) {
+                    *velocity = v;
+                }
+            };
+            match e.name.as_str() {
+                "attributes" => {
+                    if let Some(d) = e.child_num("divisions").filter(|d| *d > 0.0) {
+                        divisions = d;
+                    }
+                    if let Some(f) = e.child("key").and_then(|k| k.child_num("fifths")) {
+                        part.commands
+                            .push((pos, Command::Key((f as i32).clamp(-7, 7))));
+                    }
+                    if let Some((beats, beat_type)) = e.child("time").and_then(read_time) {
+                        bar_len = timebase * 4 * beats / beat_type;
+                        global.push((pos, format!("TimeSignature({},{})", beats, beat_type)));
+                    }
+                    if let Some(t) = e.child("transpose") {
+                        let key = t.child_num("chromatic").unwrap_or(0.0)
+                            + t.child_num("octave-change").unwrap_or(0.0) * 12.0;
+                        part.commands
+                            .push((pos, Command::TrackKey(key.round() as isize)));
+                    }
+                    let clef = e.child("clef").and_then(|c| c.child_text("sign"));
+                    part.percussion |= clef == Some("percussion");
+                }
+                "direction" => {
+                    for dt in e.children_named("direction-type") {
+                        for d in dt.children_named("dynamics") {
+                            for mark in d.children.iter() {
+                                if let Some(v) = dynamics_velocity(&mark.name) {
+                                    velocity = v;
+                                }
+                            }
+                        }
+                    }
+                    if let Some(s) = e.child("sound") {
+                        sound(s, &mut velocity);
+                    }
+                }
+                "sound" => sound(e, &mut velocity),
+                "backup" => pos = pos.saturating_sub(ticks(divisions)),
+                "forward" => {
+                    pos += ticks(divisions);
+                    end = end.max(pos);
+                }
+                "note" => {
+                    // 装飾音は時間を持たないので読み飛ばす
+                    if e.child("grace").is_some() {
+                        continue;
+                    }
+                    let dur = ticks(divisions);
+                    let is_chord = e.child("chord").is_some();
+                    let start = if is_chord { last_start } else { pos };
+                    if !is_chord {
+                        last_start = pos;
+                        pos += dur;
+                        end = end.max(pos);
+                    }
+                    if e.child("rest").is_some() || e.child("cue").is_some() || dur == 0 {
+                        continue;
+                    }
+                    let pitch = if let Some(p) = e.child("pitch") {
+                        Pitch::read(p, "step", "octave")
+                    } else if let Some(u) = e.child("unpitched") {
+                        part.percussion = true;
+                        e.child("instrument")
+                            .and_then(|i| i.attr("id"))
+                            .and_then(|id| part.info.unpitched.get(id))
+                            .map(|&no| Pitch::from_no(no))
+                            .or_else(|| Pitch::read(u, "display-step", "display-octave"))
+                    } else {
+                        None
+                    };
+                    let pitch = match pitch {
+                        Some(pitch) => pitch,
+                        None => continue,
+                    };
+                    let voice = e
+                        .child_text("voice")
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(1);
+                    let ties: Vec<&str> = e
+                        .children_named("tie")
+                        .chain(
+                            e.children_named("notations")
+                                .flat_map(|n| n.children_named("tied")),
+                        )
+                        .filter_map(|t| t.attr("type"))
+                        .collect();
+                    let tie_start = ties.contains(&"start");
+                    let tie_stop = ties.contains(&"stop");
+                    if is_chord {
+                        let last = part
+                            .notes
+                            .last_mut()
+                            .filter(|n| n.start == start && n.voice == voice);
+                        if let Some(last) = last {
+                            last.pitches.push(pitch);
+                            last.tie_start &= tie_start;
+                            last.tie_stop &= tie_stop;
+                            continue;
+                        }
+                    }
+                    part.notes.push(PartNote {
+                        start,
+                        step: dur,
+                        voice,
+                        pitches: vec![pitch],
+                        velocity: e
+                            .attr("dynamics")
+                            .and_then(sound_velocity)
+                            .unwrap_or(velocity),
+                        qlen: articulation_qlen(e).unwrap_or(DEFAULT_QLEN),
+                        tie_start,
+                        tie_stop,
+                    });
+                }
+                _ => {}
+            }
+        }
+        // 空の小節は拍子の長さ、それ以外は書かれた長さ (弱起の小節など)
+        measure_start = if end > measure_start {
+            end
+        } else {
+            measure_start + bar_len
+        };
+    }
+    part.bars.push(measure_start);
+    for n in part.notes.iter_mut() {
+        n.pitches.sort_by_key(|p| p.no());
+        n.pitches.dedup_by_key(|p| p.no());
+    }
+    part.notes = merge_ties(std::mem::take(&mut part.notes));
+    part
+}
+
+/// タイでつながった音符を1つにする
+fn merge_ties(notes: Vec<PartNote>) -> Vec<PartNote> {
+    let mut res: Vec<PartNote> = vec![];
+    for n in notes {
+        if n.tie_stop {
+            let prev = res.iter_mut().rev().find(|p| {
+                p.voice == n.voice
+                    && p.tie_start
+                    && p.start + p.step == n.start
+                    && p.pitches == n.pitches
+            });
+            if let Some(prev) = prev {
+                prev.step += n.step;
+                prev.tie_start = n.tie_start;
+                continue;
+            }
+        }
+        res.push(n);
+    }
+    res
+}
+
+/// 声部ごとに重ならない音符の列に分ける (最初の列を主旋律にする)
+fn split_streams(mut notes: Vec<PartNote>) -> Vec<Vec<PartNote>> {
+    notes.sort_by_key(|n| (n.voice, n.start));
+    let mut streams: Vec<Vec<PartNote>> = vec![];
+    for n in notes {
+        let stream = streams.iter_mut().find(|s| {
+            s.last()
+                .map(|l| l.voice == n.voice && l.start + l.step <= n.start)
+                .unwrap_or(false)
+        });
+        match stream {
+            Some(s) => s.push(n),
+            None => streams.push(vec![n]),
+        }
+    }
+    streams
+}
+
+/// 調号の音名ごとの変化
+fn key_alters(fifths: i32) -> [isize; 7] {
+    let mut res = [0; 7];
+    let n = fifths.unsigned_abs() as usize;
+    if fifths > 0 {
+        for &i in SHARP_ORDER.iter().take(n) {
+            res[i] = 1;
+        }
+    } else {
+        for &i in FLAT_ORDER.iter().take(n) {
+            res[i] = -1;
+        }
+    }
+    res
+}
+
+fn key_flag_mml(fifths: i32) -> String {
+    let n = fifths.unsigned_abs() as usize;
+    let names =
+        |order: &[usize]| -> String { order.iter().take(n).map(|&i| LETTER_NAMES[i]).collect() };
+    if fifths > 0 {
+        format!("KeyFlag+({})", names(&SHARP_ORDER))
+    } else if fifths < 0 {
+        format!("KeyFlag-({})", names(&FLAT_ORDER))
+    } else {
+        "KeyFlag=(0,0,0,0,0,0,0)".to_string()
+    }
+}
+
+/// 曲名の文字列 (ダブルクォートは使えないので置き換える)
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "'"))
+}
+
+/// MMLを組み立てる。Sub{}の中も外も、書いた順に音長・オクターブなどの状態が変わる
+struct MmlWriter<'a> {
+    timebase: usize,
+    bars: &'a [usize],
+    res: String,
+    line: Vec<String>,
+    time: usize,
+    length: usize,
+    octave: isize,
+    velocity: isize,
+    qlen: isize,
+    /// KeyFlagは曲全体の設定なのでトラックをまたいで引き継ぐ
+    fifths: i32,
+}
+
+impl<'a> MmlWriter<'a> {
+    fn new(timebase: usize, bars: &'a [usize], fifths: i32) -> Self {
+        Self {
+            timebase,
+            bars,
+            res: String::new(),
+            line: vec![],
+            time: 0,
+            length: timebase,
+            octave: DEFAULT_OCTAVE,
+            velocity: DEFAULT_VELOCITY,
+            qlen: DEFAULT_QLEN,
+            fifths,
+        }
+    }
+    /// 時間を進め、小節線を越えたら改行する
+    fn advance(&mut self, len: usize) {
+        let bar = self.next_bar();
+        self.time += len;
+        if self.time >= bar {
+            self.flush();
+        }
+    }
+    fn next_bar(&self) -> usize {
+        let i = self.bars.partition_point(|&b| b <= self.time);
+        self.bars.get(i).copied().unwrap_or(usize::MAX)
+    }
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            self.res.push_str(&self.line.join(" "));
+            self.res.push('\n');
+            self.line.clear();
+        }
+    }
+    /// 名前のある音長に分ける (ex) 5拍 => [全音符, 四分音符]
+    fn split_len(&self, len: usize) -> Option<Vec<usize>> {
+        let candidates = length_candidates(self.timebase);
+        let mut res = vec![];
+        let mut rest = len;
+        while rest > 0 && res.len() < 8 {
+            let c = *candidates.iter().rev().find(|&&c| c <= rest)?;
+            res.push(c);
+            rest -= c;
+        }
+        (rest == 0).then_some(res)
+    }
+    fn len_name(&self, len: usize) -> String {
+        if len == self.length {
+            String::new()
+        } else {
+            length_name(len, self.timebase).unwrap_or_default()
+        }
+    }
+    /// 音符の音長 (ex) 4 / 4. / 1^4 / %100
+    fn note_len(&self, len: usize) -> String {
+        if len == self.length || length_name(len, self.timebase).is_some() {
+            return self.len_name(len);
+        }
+        match self.split_len(len) {
+            Some(parts) => parts
+                .iter()
+                .map(|&l| length_name(l, self.timebase).unwrap_or_default())
+                .collect::<Vec<String>>()
+                .join("^"),
+            None => format!("%{}", len),
+        }
+    }
+    fn rests(&self, len: usize) -> Vec<String> {
+        if len == 0 {
+            return vec![];
+        }
+        if len == self.length || length_name(len, self.timebase).is_some() {
+            return vec![format!("r{}", self.len_name(len))];
+        }
+        match self.split_len(len) {
+            Some(parts) => parts
+                .iter()
+                .map(|&l| format!("r{}", self.len_name(l)))
+                .collect(),
+            None => vec![format!("r%{}", len)],
+        }
+    }
+    /// 音名を調号に合わせて書く (調号と違う変化は * で打ち消してから付ける)
+    fn pitch(&mut self, p: Pitch) -> String {
+        let mut s = String::new();
+        match p.octave - self.octave {
+            0 => {}
+            1 => s.push('>'),
+            -1 => s.push('<'),
+            _ => s.push_str(&format!("o{}", p.octave)),
+        }
+        self.octave = p.octave;
+        s.push(LETTER_NAMES[p.letter]);
+        let flag = key_alters(self.fifths)[p.letter];
+        if p.alter != flag {
+            if flag != 0 {
+                s.push('*');
+            }
+            let acc = if p.alter > 0 { "+" } else { "-" };
+            s.push_str(&acc.repeat(p.alter.unsigned_abs()));
+        }
+        s
+    }
+    fn note(&mut self, n: &PartNote) -> Vec<String> {
+        let mut res = vec![];
+        if n.qlen != self.qlen {
+            res.push(format!("q{}", n.qlen));
+            self.qlen = n.qlen;
+        }
+        if n.velocity != self.velocity {
+            res.push(format!("v{}", n.velocity));
+            self.velocity = n.velocity;
+        }
+        if n.pitches.len() == 1 {
+            let len = self.note_len(n.step);
+            let pitch = self.pitch(n.pitches[0]);
+            res.push(format!("{}{}", pitch, len));
+            return res;
+        }
+        // 和音の後ろには名前のある音長しか書けないので、それ以外はlで指定する
+        if n.step != self.length && length_name(n.step, self.timebase).is_none() {
+            res.push(format!("l%{}", n.step));
+            self.length = n.step;
+        }
+        let names: Vec<String> = n.pitches.iter().map(|&p| self.pitch(p)).collect();
+        res.push(format!("'{}'{}", names.join(""), self.len_name(n.step)));
+        res
+    }
+    /// timeまで休符を入れる (小節線で区切る)
+    fn rest_to(&mut self, time: usize) {
+        while self.time < time {
+            let len = (time - self.time).min(self.next_bar() - self.time);
+            let rests = self.rests(len);
+            self.line.extend(rests);
+            self.advance(len);
+        }
+    }
+    /// 今の位置からoffset後に置くコマンドや声部を書く
+    fn place(&mut self, item: &Placed, offset: usize) {
+        let mut body = self.rests(offset);
+        match item {
+            Placed::Command(Command::Key(f)) => {
+                if *f != self.fifths {
+                    self.line.push(key_flag_mml(*f));
+                    self.fifths = *f;
+                }
+                return;
+            }
+            Placed::Command(Command::TrackKey(k)) => {
+                self.line.push(format!("TrackKey({})", k));
+                return;
+            }
+            Placed::Command(Command::Mml(s)) => body.push(s.clone()),
+            Placed::Voice(notes) => {
+                let mut t = notes[0].start;
+                for n in notes.iter() {
+                    body.extend(self.rests(n.start - t));
+                    body.extend(self.note(n));
+                    t = n.start + n.step;
+                }
+            }
+        }
+        if offset == 0 && matches!(item, Placed::Command(_)) {
+            self.line.extend(body);
+        } else {
+            self.line.push(format!("Sub{{{}}}", body.join(" ")));
+        }
+    }
+}
+
+/// 主旋律の途中に置くもの
+enum Placed<'a> {
+    Command(Command),
+    /// 1小節分の別の声部
+    Voice(&'a [PartNote]),
+}
+
+/// パートの本体を書き出す
+fn write_part(
+    part: &Part,
+    timebase: usize,
+    global: &[(usize, String)],
+    fifths: &mut i32,
+) -> String {
+    let streams = split_streams(part.notes.clone());
+    let empty = vec![];
+    let main = streams.first().unwrap_or(&empty);
+    // 主旋律以外の声部は小節ごとに分ける
+    let mut items: Vec<(usize, usize, Placed)> = vec![];
+    for (time, cmd) in part.commands.iter() {
+        items.push((*time, 0, Placed::Command(cmd.clone())));
+    }
+    for (time, cmd) in global.iter() {
+        items.push((*time, 1, Placed::Command(Command::Mml(cmd.clone()))));
+    }
+    for stream in streams.iter().skip(1) {
+        let mut i = 0;
+        while i < stream.len() {
+            let bar = part.bars.partition_point(|&b| b <= stream[i].start);
+            let bar_end = part.bars.get(bar).copied().unwrap_or(usize::MAX);
+            let len = stream[i..].iter().take_while(|n| n.start < bar_end).count();
+            items.push((stream[i].start, 2, Placed::Voice(&stream[i..i + len])));
+            i += len;
+        }
+    }
+    if let Some(program) = part.info.program {
+        items.push((0, 1, Placed::Command(Command::Mml(format!("@{}", program)))));
+    }
+    items.sort_by_key(|item| (item.0, item.1));
+    let mut items = items.into_iter().peekable();
+    let mut w = MmlWriter::new(timebase, &part.bars, *fifths);
+    // 最初の調号や音色は音長の指定より前に書く
+    while let Some((_, _, item)) = items.next_if(|item| item.0 == 0 && item.1 < 2) {
+        w.place(&item, 0);
+    }
+    // 最も多い音長を基本の音長にする
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    for n in main.iter() {
+        *counts.entry(n.step).or_default() += 1;
+    }
+    let best = counts
+        .iter()
+        .filter(|(len, _)| length_name(**len, timebase).is_some())
+        .max_by_key(|(len, count)| (**count, **len))
+        .map(|(len, _)| *len);
+    if let Some(best) = best.filter(|b| *b != w.length) {
+        w.line
+            .push(format!("l{}", length_name(best, timebase).unwrap()));
+        w.length = best;
+    }
+    for n in main.iter() {
+        // 音符の途中で始まるものは Sub{} で後ろにずらす
+        while let Some((time, _, item)) = items.next_if(|item| item.0 < n.start + n.step) {
+            w.rest_to(time.min(n.start));
+            let offset = time.saturating_sub(w.time);
+            w.place(&item, offset);
+        }
+        w.rest_to(n.start);
+        let tokens = w.note(n);
+        w.line.extend(tokens);
+        w.advance(n.step);
+    }
+    for (time, _, item) in items {
+        w.rest_to(time);
+        w.place(&item, 0);
+    }
+    w.flush();
+    *fifths = w.fifths;
+    w.res
+}
+
+/// MusicXML(partwise)をサクラのMMLに変換する
+pub fn musicxml_to_mml(src: &str) -> Result<String, String> {
+    if src.starts_with("PK") {
+        return Err("Compressed MusicXML (.mxl) is not supported".to_string());
+    }
+    let score = parse_xml(src.trim_start_matches('\u{feff}'))?;
+    match score.name.as_str() {
+        "score-partwise" => {}
+        "score-timewise" => return Err("score-timewise is not supported".to_string()),
+        _ => return Err("Not MusicXML".to_string()),
+    }
+    let mut infos = read_part_list(&score);
+    let part_elems: Vec<&Element> = score.children_named("part").collect();
+    let timebase = choose_timebase(&part_elems);
+    let mut global: Vec<(usize, String)> = vec![];
+    let mut parts: Vec<Part> = vec![];
+    for elem in part_elems.iter() {
+        let info = infos
+            .remove(elem.attr("id").unwrap_or(""))
+            .unwrap_or_default();
+        parts.push(read_part(elem, info, timebase, &mut global));
+    }
+    // テンポと拍子はパートごとに書かれるので、同じ時間のものは1つにする
+    global.sort_by_key(|g| g.0);
+    global.dedup_by(|a, b| a.0 == b.0 && a.1.split('(').next() == b.1.split('(').next());
+    let title = score
+        .child("work")
+        .and_then(|w| w.child_text("work-title"))
+        .or(score.child_text("movement-title"))
+        .filter(|t| !t.is_empty());
+    let mut res = format!("// Converted from MusicXML\nTimeBase({})\n", timebase);
+    let header: Vec<String> = title
+        .map(|t| format!("TrackName({})", quote(t)))
+        .into_iter()
+        .chain(global.iter().filter(|g| g.0 == 0).map(|g| g.1.clone()))
+        .collect();
+    if !header.is_empty() {
+        res.push_str(&format!("TR(0) {}\n", header.join(" ")));
+    }
+    let global: Vec<(usize, String)> = global.into_iter().filter(|g| g.0 > 0).collect();
+    let mut fifths = 0;
+    let mut next_channel = 1;
+    for (i, part) in parts.iter().enumerate() {
+        let channel = match part.info.channel {
+            Some(ch) => ch.clamp(1, 16),
+            None if part.percussion => 10,
+            None => {
+                let ch = next_channel;
+                next_channel = next_channel % 16 + 1;
+                if next_channel == 10 {
+                    next_channel = 11;
+                }
+                ch
+            }
+        };
+        res.push_str(&format!("\nTR({}) CH({})", i + 1, channel));
+        if !part.info.name.is_empty() {
+            res.push_str(&format!(" TrackName({})", quote(&part.info.name)));
+        }
+        res.push('\n');
+        let g: &[(usize, String)] = if i == 0 { &global } else { &[] };
+        res.push_str(&write_part(part, timebase, g, &mut fifths));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::{EventType, Song};
+
+    fn compile(src: &str) -> Song {
+        let mut song = Song::new();
+        let tokens = crate::lexer::lex_source(&mut song, src);
+        crate::runner::exec(&mut song, &tokens);
+        song
+    }
+
+    /// 全トラックの音符 (時間, ノート番号) を時間順に並べる
+    fn notes(song: &Song) -> Vec<(isize, isize)> {
+        let mut res: Vec<(isize, isize)> = song
+            .tracks
+            .iter()
+            .flat_map(|t| t.events.iter())
+            .filter(|e| e.etype == EventType::NoteOn)
+            .map(|e| (e.time * 480 / song.timebase, e.v1))
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// MML => MusicXML => MML で同じ音符になる
+    fn round_trip(src: &str) {
+        let song = compile(src);
+        let xml = crate::musicxml::song_to_musicxml(&song);
+        let mml = musicxml_to_mml(&xml).unwrap();
+        assert_eq!(notes(&song), notes(&compile(&mml)), "{}", mml);
+    }
+
+    fn score(parts: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE score-partwise [<!ENTITY x \"y\">]>\n<score-partwise version=\"4.0\">{}</score-partwise>",
+            parts
+        )
+    }
+
+    #[test]
+    fn converts_parts_to_tracks() {
+        let xml = score(
+            "<work><work-title>Tom &amp; Jerry</work-title></work>
+            <part-list><score-part id=\"P1\"><part-name>Flute</part-name>
+              <midi-instrument id=\"P1-I1\"><midi-channel>3</midi-channel><midi-program>74</midi-program></midi-instrument>
+            </score-part></part-list>
+            <part id=\"P1\"><measure number=\"1\">
+              <attributes><divisions>2</divisions><key><fifths>-1</fifths></key>
+                <time><beats>3</beats><beat-type>4</beat-type></time></attributes>
+              <direction><direction-type><dynamics><mf/></dynamics></direction-type><sound tempo=\"96\"/></direction>
+              <note><pitch><step>B</step><alter>-1</alter><octave>4</octave></pitch><duration>2</duration><voice>1</voice></note>
+              <note><pitch><step>B</step><octave>4</octave></pitch><duration>1</duration><voice>1</voice>
+                <notations><articulations><staccato/></articulations></notations></note>
+              <note><rest/><duration>1</duration><voice>1</voice></note>
+              <note><pitch><step>C</step><octave>5</octave></pitch><duration>2</duration><voice>1</voice><tie type=\"start\"/></note>
+            </measure><measure number=\"2\">
+              <note><pitch><step>C</step><octave>5</octave></pitch><duration>2</duration><voice>1</voice><tie type=\"stop\"/></note>
+              <note><pitch><step>E</step><octave>5</octave></pitch><duration>4</duration><voice>1</voice></note>
+              <note><chord/><pitch><step>G</step><octave>5</octave></pitch><duration>4</duration><voice>1</voice></note>
+            </measure></part>",
+        );
+        let mml = musicxml_to_mml(&xml).unwrap();
+        assert_eq!(
+            mml,
+            "// Converted from MusicXML\nTimeBase(96)\nTR(0) TrackName(\"Tom & Jerry\") TimeSignature(3,4) Tempo(96)\n\nTR(1) CH(3) TrackName(\"Flute\")\nKeyFlag-(b) @74 l2 v90 b4 q50 b*8 r8 q90 >c\n'eg'\n"
+        );
+        let song = compile(&mml);
+        assert_eq!(
+            notes(&song),
+            vec![(0, 70), (480, 71), (960, 72), (1920, 76), (1920, 79)]
+        );
+    }
+
+    #[test]
+    fn writes_other_voices_with_sub() {
+        round_trip("TR(1) o5 l4 c d e f Sub{c1} g2 g2 TR(2) o3 l2 c. r4 c1");
+        round_trip("TR(1) l8 o5 Div{cde} f g a b4^8 c+16 d-16 e4. r2 TimeSignature(3,4) f2. g1^2");
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(
+            musicxml_to_mml("<a><b></a>").unwrap_err(),
+            "XML syntax error at line 1: unexpected </a>"
+        );
+        assert_eq!(
+            musicxml_to_mml("<score-timewise/>").unwrap_err(),
+            "score-timewise is not supported"
+        );
+        assert!(musicxml_to_mml("PK\u{3}\u{4}").is_err());
+        assert_eq!(unescape("&lt;&#65;&#x42;&unknown;"), "<AB&unknown;");
+    }
+}
@@ -1,10 +1,13 @@
 //! runner from tokens
-use super::lexer::lex;
+use super::diagnostic::Severity;
+use super::lexer::{lex, lex_at};
 use super::note_length::calc_length;
 use super::sakura_message::MessageKind;
 use super::song::{
-    Event, NoteInfo, NoteParam, OnNoteSine, SineType, Song, Track, WaveMode, WriteCtx, WriteTarget,
+    Arp, ArpPattern, Event, NoteInfo, NoteParam, OnNoteSine, SineType, Song, Strum, Track,
+    WaveMode, WriteCtx, WriteTarget,
 };
+use super::span::{Span, SpanOrigin};
 use super::svalue::SValue;
 use super::token::{
     Token, TokenType, COMMENT_DEBUG, NOTE_PARAM_L, NOTE_PARAM_O, NOTE_PARAM_Q, NOTE_PARAM_T,
@@ -39,6 +42,7 @@ macro_rules! trk {
 }
 
 mod cc;
+pub(crate) mod chord;
 mod control;
 pub(crate) mod function;
 mod meta;
@@ -50,6 +54,7 @@ mod track_state;
 mod variable;
 
 use cc::*;
+use chord::*;
 use control::*;
 use function::*;
 use meta::*;
@@ -120,6 +125,9 @@ pub fn exec(song: &mut Song, tokens: &[Token]) -> bool {
             break;
         }
         let t = &tokens[pos];
+        if t.span.is_some() {
+            song.cur_span = t.span;
+        }
         if song.debug {
             println!(
                 "- exec({:03})(line:{}) {}",
@@ -132,7 +140,10 @@ pub fn exec(song: &mut Song, tokens: &[Token]) -> bool {
             TokenType::Unimplemented => {}
             TokenType::Empty => {}
             TokenType::Comment => exec_comment(song, t),
-            TokenType::LineNo => song.lineno = t.lineno,
+            TokenType::LineNo => {
+                song.lineno = t.lineno;
+                song.source_no = t.value_i as usize;
+            }
             TokenType::Error => {
                 if song.debug {
                     println!("[RUNTIME.ERROR]");
@@ -151,10 +162,10 @@ pub fn exec(song: &mut Song, tokens: &[Token]) -> bool {
                 // Avoid usize underflow/panic when loop count is 0.
                 // Also keep behavior predictable (treat 0 as 1 iteration).
                 if it.count == 0 {
-                    song.add_log(format!(
-                        "[WARN]({}) Loop count is 0; treated as 1.",
-                        t.lineno
-                    ));
+                    let msg = song
+                        .get_message(MessageKind::WarningLoopCountZero)
+                        .to_string();
+                    song.add_warning(MessageKind::WarningLoopCountZero, song.lineno, msg);
                     it.count = 1;
                 }
                 // println!("loop={}", it.count);
@@ -231,16 +242,24 @@ pub fn exec(song: &mut Song, tokens: &[Token]) -> bool {
             TokenType::Tempo => exec_tempo(song, t),
             TokenType::TempoChange => exec_tempo_change(song, t),
             TokenType::MetaText => exec_meta_text(song, t),
+            TokenType::Marker => exec_marker(song, t),
             TokenType::Port => exec_port(song, t),
             TokenType::TimeSignature => exec_time_signature(song, t),
+            TokenType::KeySignature => exec_key_signature(song, t),
             TokenType::SysEx => exec_sysex(song, t),
             TokenType::SysexReset => exec_sysex_reset(song, t),
             TokenType::SysExCommand => exec_sysex_command(song, t), // Universal SysEx
             TokenType::GSEffect => exec_gs_effect(song, t),
             TokenType::Time => trk!(song).timepos = exec_get_time(song, t, "TIME"),
-            TokenType::PlayFrom => song.play_from = exec_get_time(song, t, "PlayFrom"),
+            TokenType::PlayFrom => exec_play_from(song, t),
+            TokenType::PlayTo => exec_play_to(song, t),
             TokenType::HarmonyBegin => exec_harmony(song, t, true),
             TokenType::HarmonyEnd => exec_harmony(song, t, false),
+            TokenType::Chord => exec_chord(song, t),
+            TokenType::ChordOption => exec_chord_option(song, t),
+            TokenType::ChordVoiceLeading => exec_chord_voice_leading(song, t),
+            TokenType::Strum => exec_strum(song, t),
+            TokenType::Arp => exec_arp(song, t),
             TokenType::Tokens => exec_tokens(song, t),
             TokenType::Div => exec_div(song, t),
             TokenType::Sub => exec_sub(song, t),
@@ -304,6 +323,8 @@ pub fn exec(song: &mut Song, tokens: &[Token]) -> bool {
             TokenType::TrackSync => song.track_sync(),
             TokenType::TieMode => exec_tie_mode(song, t),
             TokenType::UseKeyShift => exec_use_key_shift(song, t),
+            TokenType::UseBarCheck => exec_use_bar_check(song, t),
+            TokenType::BarLine => exec_bar_line(song),
             TokenType::If => {
                 exec_if(song, t);
             }
@@ -362,12 +383,40 @@ pub fn exec(song: &mut Song, tokens: &[Token]) -> bool {
 }
 
 fn runtime_error(song: &mut Song, msg: &str) {
-    song.add_log(format!(
-        "[ERROR]({}) {}: {}",
-        song.lineno,
-        song.get_message(MessageKind::RuntimeError),
-        msg
-    ));
+    let msg = format!("{}: {}", song.get_message(MessageKind::RuntimeError), msg);
+    let mut d = song.new_diagnostic(Severity::Error, MessageKind::RuntimeError, song.lineno, msg);
+    // 位置はトークンの範囲から取る (マクロの展開やIncludeの中でも元のソースの位置になる)
+    if let Some(span) = song.cur_span {
+        d.line = span.line;
+        d.column = span.column;
+        if let Some(name) = song.source_names.get(span.source_no) {
+            d.source = name.clone();
+        }
+    }
+    song.add_diagnostic(d);
+}
+
+/// 実行時に字句解析する文字列の位置
+/// 文字列リテラルならその中身の位置、それ以外(変数やマクロなど)は`site`の範囲にする
+fn relex_origin(song: &Song, arg: &Token, site: Option<Span>) -> SpanOrigin {
+    let mut t = arg;
+    while t.ttype == TokenType::Tokens {
+        match t.children.as_deref() {
+            Some([child]) => t = child,
+            _ => break,
+        }
+    }
+    if t.ttype == TokenType::ConstStr {
+        if let Some(span) = t.span {
+            if let Some(st) = song.source_texts.get(span.source_no) {
+                if span.source_no == song.source_no && !st.is_empty() {
+                    // 先頭の '{' や '"' の次から
+                    return SpanOrigin::Offset(st.to_converted(span.start + 1));
+                }
+            }
+        }
+    }
+    SpanOrigin::Expand(site)
 }
 
 pub fn value_range(min_v: isize, value: isize, max_v: isize) -> isize {
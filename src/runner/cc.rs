@@ -21,6 +21,7 @@ where
             max_event_bytes,
             event_bytes,
             event_limit_exceeded: false,
+            span: song.cur_span,
         };
         let trk = &mut song.tracks[song.cur_track];
         f(trk, &mut ctx);
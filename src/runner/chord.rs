@@ -0,0 +1,101 @@
+//! runner: コードネーム(Chord)の実行
+use super::*;
+use crate::chord::{note_to_mml, Chord, ChordStyle};
+
+/// 空白で区切ったコードネームをMMLにする (r は休符)
+/// 読めないコードネームは警告して飛ばす。最後のコードの音符番号も返す
+pub(crate) fn chord_mml(song: &mut Song, symbols: &str) -> (String, Vec<isize>) {
+    let opt = trk!(song).chord_opt.clone();
+    let octave_org = trk!(song).octave;
+    let base = if opt.octave >= 0 {
+        opt.octave
+    } else {
+        octave_org
+    } * 12;
+    let mut octave = octave_org;
+    let mut prev = trk!(song).chord_prev.clone();
+    let mut mml: Vec<String> = vec![];
+    for name in symbols.split_whitespace() {
+        if name == "r" || name == "N.C." {
+            mml.push("r".to_string());
+            continue;
+        }
+        let chord = match Chord::parse(name) {
+            Ok(chord) => chord,
+            Err(_) => {
+                let msg = format!(
+                    "{}: {}",
+                    song.get_message(MessageKind::WarningUnknownChord),
+                    name
+                );
+                song.add_warning(MessageKind::WarningUnknownChord, song.lineno, msg);
+                continue;
+            }
+        };
+        let notes = chord.voicing(base, &opt, &prev);
+        let mut all: Vec<isize> = chord.bass_below(&notes).into_iter().collect();
+        all.extend(notes.iter());
+        let key_flag = &song.key_flag;
+        let mut to_mml = |list: &[isize]| {
+            list.iter()
+                .map(|n| note_to_mml(*n, &mut octave, key_flag))
+                .collect::<String>()
+        };
+        let s = match opt.style {
+            ChordStyle::Harmony => format!("'{}'", to_mml(&all)),
+            ChordStyle::Root => to_mml(&[base + chord.root]),
+            ChordStyle::Bass => to_mml(&[base + chord.bass.unwrap_or(chord.root)]),
+            ChordStyle::Arpeggio => format!("Div{{{}}}", to_mml(&all)),
+        };
+        mml.push(s);
+        prev = notes;
+    }
+    // トラックのオクターブを元に戻す
+    if octave != octave_org {
+        mml.push(format!("o{}", octave_org));
+    }
+    (mml.join(" "), prev)
+}
+
+/// コードネームを和音にして書き込む (ex) Chord{C Am7 F/A G7sus4}
+pub(super) fn exec_chord(song: &mut Song, t: &Token) {
+    let args = exec_args(song, t.children.as_deref().unwrap_or(&[]));
+    let symbols = args.first().map(|v| v.to_s()).unwrap_or_default();
+    let (mml, prev) = chord_mml(song, &symbols);
+    trk!(song).chord_prev = prev;
+    let tokens = lex_at(song, &mml, t.lineno, SpanOrigin::Expand(t.span));
+    exec(song, &tokens);
+}
+
+/// コードの配置の設定 (value_i: 0=出し方 1=転回 2=オクターブ 3=広げ方)
+pub(super) fn exec_chord_option(song: &mut Song, t: &Token) {
+    let args = exec_args(song, t.children.as_deref().unwrap_or(&[]));
+    let v = args.first().cloned().unwrap_or(SValue::None);
+    let opt = &mut trk!(song).chord_opt;
+    match t.value_i {
+        0 => match ChordStyle::from_name(&v.to_s()) {
+            Some(style) => opt.style = style,
+            None => {
+                let msg = format!(
+                    "ChordStyle: {}: {}",
+                    song.get_message(MessageKind::InvalidArgument),
+                    v.to_s()
+                );
+                song.add_warning(MessageKind::InvalidArgument, song.lineno, msg);
+            }
+        },
+        1 => opt.inversion = v.to_i().max(0),
+        2 => opt.octave = value_range(-1, v.to_i(), 10),
+        _ => opt.spread = value_range(0, v.to_i(), 3),
+    }
+}
+
+/// 前のコードに近い転回を選ぶか (ex) ChordVoiceLeading(on)
+pub(super) fn exec_chord_voice_leading(song: &mut Song, t: &Token) {
+    let on = t
+        .data
+        .first()
+        .map(|v| var_extract(v, song).to_b())
+        .unwrap_or(true);
+    trk!(song).chord_opt.voice_leading = on;
+}
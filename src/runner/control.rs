@@ -58,12 +58,12 @@ pub(super) fn exec_while(song: &mut Song, t: &Token) -> bool {
         // check counter
         counter += 1;
         if counter > song.flags.max_loop {
-            song.add_log(format!(
-                "[ERROR]({}) {} WHILE(>{})",
-                t.lineno,
+            let msg = format!(
+                "{} WHILE(>{})",
                 song.get_message(MessageKind::LoopTooManyTimes),
                 song.flags.max_loop
-            ));
+            );
+            song.add_error(MessageKind::LoopTooManyTimes, t.lineno, msg);
             break;
         }
         // check break flag
@@ -122,12 +122,12 @@ pub(super) fn exec_for(song: &mut Song, t: &Token) -> bool {
         // check loop counter
         counter += 1;
         if counter > song.flags.max_loop {
-            song.add_log(format!(
-                "[ERROR]({}) {} FOR(>{})",
-                t.lineno,
+            let msg = format!(
+                "{} FOR(>{})",
                 song.get_message(MessageKind::LoopTooManyTimes),
                 song.flags.max_loop
-            ));
+            );
+            song.add_error(MessageKind::LoopTooManyTimes, t.lineno, msg);
             break;
         }
         // inc
@@ -46,7 +46,7 @@ pub(super) fn exec_userfunc_or_array_or_macro(song: &mut Song, t: &Token) -> boo
                     let varname = format!("#?{}", i + 1);
                     s = s.replace(&varname, &v.to_s());
                 }
-                let tokens = lex(song, &s, t.lineno);
+                let tokens = lex_at(song, &s, t.lineno, SpanOrigin::Expand(t.span));
                 return exec(song, &tokens);
             }
             _ => {}
@@ -152,7 +152,7 @@ pub(super) fn exec_sys_function(song: &mut Song, t: &Token) -> bool {
             song.stack.push(SValue::from_s(val_s));
         } else {
             // exec macro
-            let tokens = lex(song, &val_s, t.lineno);
+            let tokens = lex_at(song, &val_s, t.lineno, SpanOrigin::Expand(t.span));
             exec(song, &tokens);
         }
     }
@@ -231,8 +231,12 @@ pub(crate) fn var_extract(val: &SValue, song: &mut Song) -> SValue {
                     None => match get_system_value(key, song) {
                         Some(v) => return v,
                         None => {
-                            let err_msg = format!("[WARN]({}) Undefined: {}", song.lineno, key);
-                            song.add_log(err_msg);
+                            let err_msg = format!(
+                                "{}: {}",
+                                song.get_message(MessageKind::WarningUndefined),
+                                key
+                            );
+                            song.add_warning(MessageKind::WarningUndefined, song.lineno, err_msg);
                             SValue::None
                         }
                     },
@@ -1,5 +1,6 @@
 //! runner: メタイベント・ログ出力・SMFへの直接出力
 use super::*;
+use crate::key_signature::{self, KeySignature};
 
 /// MetaTextに書き込める文字列は127バイトまでなので、文字境界を保ったまま切り詰める
 pub(super) fn trim_meta_text(txt_raw: &str) -> String {
@@ -43,11 +44,11 @@ pub(super) fn exec_print(song: &mut Song, t: &Token) {
         disp.push(v.to_s());
     }
     let disp_s = disp.join(" ");
-    let msg = format!("[PRINT]({}) {}", t.lineno, disp_s);
+    let d = song.new_diagnostic(Severity::Info, MessageKind::Print, t.lineno, disp_s);
     if song.debug {
-        println!("{}", msg);
+        println!("{}", d.to_log());
     }
-    song.add_log(msg);
+    song.add_diagnostic(d);
 }
 
 /// メタテキストの書き込み
@@ -64,10 +65,24 @@ pub(super) fn exec_meta_text(song: &mut Song, t: &Token) {
     song.add_event(e);
 }
 
+/// マーカー --- FF 06 を書き込み、現在位置に名前を付ける
+pub(super) fn exec_marker(song: &mut Song, t: &Token) {
+    let txt_raw = exec_args(song, t.children.as_deref().unwrap_or(&[]))[0].to_s();
+    // Marker{"Chorus"} の引用符は名前に含めない
+    let txt = trim_meta_text(txt_raw.trim().trim_matches('"'));
+    let timepos = trk!(song).timepos;
+    if song.marker_pos(&txt).is_none() {
+        song.markers.push((txt.clone(), timepos));
+    }
+    let e = Event::meta(timepos, 0xFF, 0x06, txt.len() as isize, txt.into_bytes());
+    song.add_event(e);
+}
+
 /// ポート番号の指定
 pub(super) fn exec_port(song: &mut Song, t: &Token) {
     let port = exec_args(song, t.children.as_deref().unwrap_or(&[]))[0].to_i();
     trk!(song).port = port;
+    trk!(song).port_from_channel = false;
     let e = Event::meta(trk!(song).timepos, 0xFF, 0x21, 0x01, vec![port as u8]);
     song.add_event(e);
 }
@@ -106,6 +121,42 @@ pub(super) fn exec_time_signature(song: &mut Song, t: &Token) {
         vec![song.timesig_frac as u8, deno_v as u8, 0x18, 0x08],
     );
     song.add_event(e);
+    let (timebase, timepos) = (song.timebase, trk!(song).timepos);
+    song.time_signatures
+        .set(timebase, timepos, song.timesig_frac, song.timesig_deno);
+}
+
+/// 調号の指定 (引数がなければKeyFlagとキーシフトから求める)
+pub(super) fn exec_key_signature(song: &mut Song, t: &Token) {
+    let args = exec_args(song, t.children.as_deref().unwrap_or(&[]));
+    // 未定義の変数など、値のない引数
+    if args.iter().any(|v| v.is_none()) {
+        runtime_error(song, "[KeySignature] wrong argument");
+        return;
+    }
+    let args: Vec<String> = args.iter().map(|v| v.to_s()).collect();
+    let key = match key_signature::parse_args(&args.join(",")) {
+        Ok(Some((key, set_key_flag))) => {
+            if set_key_flag {
+                song.key_flag = key.to_key_flag();
+            }
+            key
+        }
+        Ok(None) => {
+            let key = KeySignature::from_key_flag(&song.key_flag);
+            if song.use_key_shift {
+                key.transpose(song.key_shift + trk!(song).track_key)
+            } else {
+                key
+            }
+        }
+        Err(msg) => {
+            runtime_error(song, &format!("[KeySignature] {}", msg));
+            return;
+        }
+    };
+    let e = Event::meta(trk!(song).timepos, 0xFF, 0x59, 0x02, key.to_meta_data());
+    song.add_event(e);
 }
 
 /// SMFへバイト列を直接書き込む
@@ -225,13 +225,15 @@ pub(super) fn exec_note(song: &mut Song, t: &Token) {
     // check range
     let v = value_range(0, v, trk!(song).v_opt.max_or(127));
     // event
-    let event = Event::note(
+    let mut event = Event::note(
         timepos.saturating_add(t),
         trk!(song).channel,
         note.no,
         notelen_real,
         v,
     );
+    // 和音やタイは後でまとめて書き込むため、ここで音符の位置を覚えておく
+    event.span = song.cur_span;
     if !song.reserve_event(&event) {
         finish_note_after_event_limit(song, notelen);
         return;
@@ -1,5 +1,9 @@
 //! runner: サブルーチン・連符・和音・タイムポインタの実行
 use super::*;
+use crate::tempo_map::BarPos;
+
+/// CH()で使えるポートの数 (CH(1〜256))
+const MAX_PORTS: isize = 16;
 
 pub(super) fn exec_play(song: &mut Song, t: &Token) -> bool {
     let tmp_cur_track = song.cur_track;
@@ -15,7 +19,8 @@ pub(super) fn exec_play(song: &mut Song, t: &Token) -> bool {
         let src = exec_value(song, std::slice::from_ref(arg)).to_s();
         // println!("play(TR={})({}):{}", index+1, lineno, src);
         // eval tokens
-        let tokens = lex(song, &src, lineno);
+        let origin = relex_origin(song, arg, arg.span.or(t.span));
+        let tokens = lex_at(song, &src, lineno, origin);
         exec(song, &tokens);
         // check lastpos
         if trk!(song).timepos > time_ptr_last {
@@ -115,8 +120,8 @@ pub(super) fn exec_harmony(song: &mut Song, t: &Token, flag_begin: bool) {
             write_on_note_events(song, harmony_time);
         }
         // change event length
-        while song.flags.harmony_events.len() > 0 {
-            let mut e = song.flags.harmony_events.pop().unwrap();
+        let mut notes: Vec<Event> = vec![];
+        while let Some(mut e) = song.flags.harmony_events.pop() {
             e.time = song.flags.harmony_time;
             if note_qlen_is_step {
                 e.v2 = calc_gate_len(note_len, note_qlen, true);
@@ -126,11 +131,132 @@ pub(super) fn exec_harmony(song: &mut Song, t: &Token, flag_begin: bool) {
             if !note_vel.is_none() {
                 e.v3 = note_vel.to_i();
             }
-            song.add_reserved_event(e);
+            notes.push(e);
         }
-        trk!(song).timepos = song.flags.harmony_time + note_len;
+        let harmony_time = song.flags.harmony_time;
+        if let Some(arp) = trk!(song).arp.clone() {
+            // アルペジオ --- 和音の音符の分は予算を確保済み
+            let gate = if note_qlen_is_step || note_qlen == 0 {
+                100
+            } else {
+                note_qlen
+            };
+            let reserved = notes.len();
+            let events = arp.expand(&notes, harmony_time, note_len, gate, &mut || song.rand());
+            for (i, e) in events.into_iter().enumerate() {
+                if i < reserved {
+                    song.add_reserved_event(e);
+                } else if !song.add_event(e) {
+                    break;
+                }
+            }
+        } else {
+            if let Some(strum) = &trk!(song).strum {
+                strum.apply(&mut notes);
+            }
+            for e in notes {
+                song.add_reserved_event(e);
+            }
+        }
+        trk!(song).timepos = harmony_time + note_len;
+        return;
+    }
+}
+
+/// 和音のストロークの指定 (ex) Strum(up, 3) / Strum(down, 2, 1) / Strum(off)
+pub(super) fn exec_strum(song: &mut Song, t: &Token) {
+    let (args, block) = harmony_option_args(song, t);
+    if args.iter().any(|v| v.is_none()) {
+        runtime_error(song, "[Strum] wrong argument");
         return;
     }
+    let dir = args.first().map(|v| v.to_s()).unwrap_or_default();
+    let strum = match dir.to_ascii_lowercase().as_str() {
+        "" | "off" => None,
+        dir @ ("up" | "down") => {
+            let step = match args.get(1) {
+                Some(v) => v.to_i(),
+                None => song.timebase / 32,
+            };
+            let keep_end = match args.get(2).map(|v| v.to_s()) {
+                Some(v) if v.eq_ignore_ascii_case("on") => true,
+                Some(v) if v.eq_ignore_ascii_case("off") => false,
+                Some(_) => args[2].to_b(),
+                None => false,
+            };
+            Some(Strum {
+                down: dir == "down",
+                step: value_range(0, step, song.timebase * 4),
+                keep_end,
+            })
+        }
+        _ => {
+            runtime_error(song, &format!("[Strum] unknown direction: {}", dir));
+            return;
+        }
+    };
+    let org = std::mem::replace(&mut trk!(song).strum, strum);
+    if let Some(block) = block {
+        exec(song, block);
+        trk!(song).strum = org;
+    }
+}
+
+/// 和音のアルペジオの指定 (ex) Arp(up, 16) / Arp(updown, 16, 80){ 'ceg'1 } / Arp(off)
+pub(super) fn exec_arp(song: &mut Song, t: &Token) {
+    let (args, block) = harmony_option_args(song, t);
+    if args.iter().any(|v| v.is_none()) {
+        runtime_error(song, "[Arp] wrong argument");
+        return;
+    }
+    let name = args.first().map(|v| v.to_s()).unwrap_or_default();
+    let arp = if name.is_empty() || name.eq_ignore_ascii_case("off") {
+        None
+    } else {
+        let pattern = match ArpPattern::from_name(&name) {
+            Some(pattern) => pattern,
+            None => {
+                runtime_error(song, &format!("[Arp] unknown pattern: {}", name));
+                return;
+            }
+        };
+        let rate_s = args.get(1).map(|v| v.to_s()).unwrap_or("16".to_string());
+        let is_length = !rate_s.is_empty()
+            && rate_s
+                .chars()
+                .all(|c| c.is_ascii_digit() || matches!(c, '.' | '^' | '%'));
+        let rate = if is_length {
+            calc_length(&rate_s, song.timebase, trk!(song).length)
+        } else {
+            0
+        };
+        if rate <= 0 {
+            runtime_error(song, &format!("[Arp] wrong rate: {}", rate_s));
+            return;
+        }
+        let gate = args.get(2).map(|v| v.to_i()).unwrap_or(0);
+        Some(Arp {
+            pattern,
+            rate,
+            gate: value_range(0, gate, 100),
+        })
+    };
+    let org = std::mem::replace(&mut trk!(song).arp, arp);
+    if let Some(block) = block {
+        exec(song, block);
+        trk!(song).arp = org;
+    }
+}
+
+/// Strum/Arpの引数を評価する。{ } があればその中身も返す
+fn harmony_option_args<'a>(song: &mut Song, t: &'a Token) -> (Vec<SValue>, Option<&'a Vec<Token>>) {
+    let children = t.children.as_deref().unwrap_or(&[]);
+    let args = match children.first() {
+        Some(a) => exec_args(song, a.children.as_deref().unwrap_or(&[])),
+        None => vec![],
+    };
+    let block = children.get(1).and_then(|b| b.children.as_ref());
+    (args, block)
 }
 
 pub(super) fn exec_get_time(song: &mut Song, t: &Token, cmd: &str) -> isize {
@@ -148,14 +274,13 @@ pub(super) fn exec_get_time(song: &mut Song, t: &Token, cmd: &str) -> isize {
         runtime_error(song, &format!("[{}] needs 1 or 3 arguments", cmd));
         return 0;
     }
-    let mes = args[0].to_i() + song.flags.measure_shift;
-    let beat = args[1].to_i();
-    let tick = args[2].to_i();
-
-    // calc
-    let base = song.timebase * 4 / song.timesig_deno;
-    let total = (mes - 1) * (base * song.timesig_frac) + (beat - 1) * base + tick;
-    total
+    let pos = BarPos {
+        measure: args[0].to_i() + song.flags.measure_shift,
+        beat: args[1].to_i(),
+        step: args[2].to_i(),
+    };
+    // 拍子の変化を反映して計算する
+    song.time_signatures.bar_to_tick(song.timebase, pos)
 }
 
 /// トラックの切り替え
@@ -164,11 +289,23 @@ pub(super) fn exec_track(song: &mut Song, t: &Token) {
     song.change_cur_track(no);
 }
 
-/// チャンネルの指定
+/// チャンネルの指定 (17以上は次のポートのチャンネル)
 pub(super) fn exec_channel(song: &mut Song, t: &Token) {
     let no = exec_value_int_by_token(song, t);
-    let v = value_range(1, no, 16) - 1; // CH(1 to 16)
-    trk!(song).channel = v as isize;
+    let v = value_range(1, no, MAX_PORTS * 16) - 1; // CH(1 to 256)
+    trk!(song).channel = v % 16;
+    trk!(song).channel_specified = true;
+    // CH(17)以上ならポートを切り替える (CH(1〜16)ならPort()の指定のまま)
+    let port = v / 16;
+    if port == 0 && !trk!(song).port_from_channel {
+        return;
+    }
+    trk!(song).port_from_channel = port > 0;
+    if trk!(song).port != port {
+        trk!(song).port = port;
+        let e = Event::meta(trk!(song).timepos, 0xFF, 0x21, 0x01, vec![port as u8]);
+        song.add_event(e);
+    }
 }
 
 /// 子トークンをまとめて実行する
@@ -203,9 +340,78 @@ pub(super) fn exec_use_key_shift(song: &mut Song, t: &Token) {
         .unwrap_or(t.value_i != 0);
 }
 
+/// 小節線のチェックをするかどうかの指定
+pub(super) fn exec_use_bar_check(song: &mut Song, t: &Token) {
+    song.use_bar_check = t
+        .data
+        .first()
+        .map(|v| var_extract(v, song).to_b())
+        .unwrap_or(t.value_i != 0);
+}
+
+/// 小節線 `|` --- 現在位置が小節の頭でなければ警告する
+pub(super) fn exec_bar_line(song: &mut Song) {
+    if !song.use_bar_check {
+        return;
+    }
+    let pos = trk!(song).timepos;
+    let bar = song.time_signatures.tick_to_bar(song.timebase, pos);
+    let start = song
+        .time_signatures
+        .measure_to_tick(song.timebase, bar.measure);
+    if pos == start {
+        return;
+    }
+    // 近い方の小節の頭からのずれ (ex) 3小節目の頭より12ステップ後なら +12
+    let next = song
+        .time_signatures
+        .measure_to_tick(song.timebase, bar.measure + 1);
+    let (measure, off) = if (pos - start) * 2 <= next - start {
+        (bar.measure, pos - start)
+    } else {
+        (bar.measure + 1, pos - next)
+    };
+    let msg = format!(
+        "{}: TR({}) measure {} ({:+} ticks)",
+        song.get_message(MessageKind::WarningBarCheck),
+        song.cur_track,
+        measure - song.flags.measure_shift,
+        off
+    );
+    song.add_warning(MessageKind::WarningBarCheck, song.lineno, msg);
+}
+
+/// 演奏開始位置の指定 (まだ出てこないマーカーなら曲の最後に位置を求める)
+pub(super) fn exec_play_from(song: &mut Song, t: &Token) {
+    let (pos, marker) = exec_get_play_pos(song, t, "PlayFrom");
+    if marker.is_none() {
+        song.play_from = pos;
+    }
+    song.play_from_marker = marker;
+}
+
+/// 演奏終了位置の指定
+pub(super) fn exec_play_to(song: &mut Song, t: &Token) {
+    let (pos, marker) = exec_get_play_pos(song, t, "PlayTo");
+    if marker.is_none() {
+        song.play_to = pos;
+    }
+    song.play_to_marker = marker;
+}
+
+/// 演奏範囲の位置を求める (まだ出てこないマーカーなら、その名前も返す)
+fn exec_get_play_pos(song: &mut Song, t: &Token, cmd: &str) -> (isize, Option<String>) {
+    song.flags.marker_lookup = true;
+    song.flags.marker_missing = None;
+    let pos = exec_get_time(song, t, cmd);
+    song.flags.marker_lookup = false;
+    (pos, song.flags.marker_missing.take())
+}
+
 /// 現在位置を演奏開始位置にする
 pub(super) fn exec_play_from_here(song: &mut Song) {
     song.play_from = trk!(song).timepos;
+    song.play_from_marker = None;
 }
 
 /// 曲全体のベロシティ加算値
@@ -140,7 +140,7 @@ pub(super) fn exec_value_token(song: &mut Song, t: &Token) {
                 let v = var_extract(&t.data[0], song);
                 let vs = v.to_s().clone();
                 // println!("lex={:?}", vs);
-                let tokens = lex(song, &vs, t.lineno);
+                let tokens = lex_at(song, &vs, t.lineno, SpanOrigin::Expand(t.span));
                 exec(song, &tokens);
                 song.stack.pop().unwrap_or(SValue::None)
             } else {
@@ -213,7 +213,8 @@ pub(super) fn exec_calc_tree(song: &mut Song, t: &Token) {
         '/' => c = a.div(b),
         '%' => c = SValue::from_i(a.to_i() % b.to_i()),
         _ => {
-            song.add_log(String::from("[Calc] unknown flag"));
+            let msg = format!("[Calc] unknown flag: {}", flag);
+            song.add_error(MessageKind::UnknownError, song.lineno, msg);
         }
     }
     song.stack.push(c);
@@ -492,6 +492,340 @@ mod test_for_runner {
         let song = exec_easy("PRINT( (1=1) & TRUE )");
         assert_eq!(song.get_logs_str(), "[PRINT](0) 1");
     }
+
+    #[test]
+    fn test_bar_check() {
+        // BarCheck(on)のときだけ、小節の頭にない | を警告する
+        let song = exec_easy("l4 cde | f");
+        assert_eq!(song.get_logs_str(), "");
+        let song = exec_easy("BarCheck(on) l4 cdef | g1 |\nTR(2) cde | r4 c2 |");
+        let logs = song.get_logs_str();
+        assert_eq!(
+            logs,
+            "[WARN](1) Bar line is not at the start of a measure: TR(2) measure 2 (-96 ticks)\n\
+             [WARN](1) Bar line is not at the start of a measure: TR(2) measure 2 (+192 ticks)"
+        );
+        // 拍子と小節番号のシフトも反映する
+        let song =
+            exec_easy("BarCheck(on) TimeSignature(3,4) System.MeasureShift(1) l4 cde | f8 |");
+        assert_eq!(song.get_diagnostics().len(), 1);
+        assert!(song.get_logs_str().ends_with("TR(0) measure 1 (+48 ticks)"));
+        // 行末の | をはさんでもタイとスラーは続く
+        let notes = |src: &str| -> Vec<(isize, isize, isize)> {
+            let song = exec_easy(src);
+            assert_eq!(song.get_logs_str(), "", "{}", src);
+            song.tracks[0]
+                .events
+                .iter()
+                .map(|e| (e.time, e.v1, e.v2))
+                .collect()
+        };
+        assert_eq!(notes("l4 c |\n^4 d"), notes("l4 c^4 d"));
+        assert_eq!(notes("BarCheck(on) l4 c |\n  ^4 d"), notes("l4 c^4 d"));
+        assert_eq!(notes("l4 c |\n& d"), notes("l4 c & d"));
+        assert_eq!(notes("l4 c | & | d"), notes("l4 c & d"));
+    }
+
+    #[test]
+    fn test_key_signature() {
+        let key_sigs = |src: &str| -> Vec<Vec<u8>> {
+            exec_easy(src).tracks[0]
+                .events
+                .iter()
+                .filter(|e| e.etype == EventType::Meta && e.v2 == 0x59)
+                .map(|e| e.data.clone().unwrap())
+                .collect()
+        };
+        assert_eq!(
+            key_sigs("KeySignature(D, major) KeySig(-3) KEYSIG(F#, minor)"),
+            vec![vec![2, 0], vec![0xFD, 0], vec![3, 1]]
+        );
+        // 引数がなければKeyFlagとKeyShiftから求める
+        assert_eq!(
+            key_sigs("KeyFlag-(be) KeySignature KeyShift(2) KeySignature()"),
+            vec![vec![0xFE, 0], vec![0, 0]]
+        );
+        // 数値や変数は式として読む
+        assert_eq!(
+            key_sigs("Int K=2 KeySignature(K) KeySignature(K-5, minor) Str TONIC={Bb} KeySignature(TONIC)"),
+            vec![vec![2, 0], vec![0xFD, 1], vec![0xFE, 0]]
+        );
+        // on を付けるとKeyFlagも設定する
+        let song = exec_easy("KeySignature(A, on) o4c");
+        assert_eq!(song.tracks[0].events.last().unwrap().v1, 49);
+        let song = exec_easy("KeySignature(H)");
+        assert!(song.get_logs_str().contains("[KeySignature]"));
+    }
+
+    #[test]
+    fn test_time_across_time_signatures() {
+        // 4/4 → 7/8 → 3/4 と変わっても、小節の位置は拍子の変化を反映する
+        let src = "BarCheck(on) l4 c1 | TimeSignature(7,8) c2.. | TimeSignature(3,4) c2. | \
+                   TR(2) Time(4:1:0) c Time(2:2:0) d";
+        let song = exec_easy(src);
+        assert_eq!(song.get_logs_str(), "");
+        assert_eq!(song.time_signatures.points.len(), 3);
+        let notes: Vec<isize> = song.tracks[2]
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOn)
+            .map(|e| e.time)
+            .collect();
+        assert_eq!(notes, vec![96 * 4 + 48 * 7 + 96 * 3, 96 * 4 + 48]);
+        // PlayFrom も同じ
+        let song = exec_easy(&format!("{} PlayFrom(3:1:0)", src));
+        assert_eq!(song.play_from, 96 * 4 + 48 * 7);
+    }
+
+    #[test]
+    fn test_marker() {
+        // Marker は FF 06 を書き、名前で位置を参照できる
+        let mut song = exec_easy(
+            "PlayFrom(Marker(\"Chorus\")) Marker{\"Intro\"} l4 cdef \
+             Marker{\"Chorus\"} g1 Print(Marker({Chorus})) Print(Marker({Bridge}))",
+        );
+        assert_eq!(
+            song.markers,
+            vec![("Intro".to_string(), 0), ("Chorus".to_string(), 384)]
+        );
+        assert_eq!(
+            song.get_logs_str(),
+            "[PRINT](0) 384\n[WARN](0) Marker not found: Bridge\n[PRINT](0) 0"
+        );
+        // 後ろで定義したマーカーは出力するときに位置を求める
+        assert_eq!(song.play_from_marker, Some("Chorus".to_string()));
+        song.play_to = 384 + 48;
+        song.play_from_all_track();
+        assert_eq!(song.play_from, 384);
+        let notes: Vec<(isize, isize)> = song.tracks[0]
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOn)
+            .map(|e| (e.time, e.v2))
+            .collect();
+        assert_eq!(notes, vec![(0, 48)]);
+    }
+
+    #[test]
+    fn test_play_to() {
+        // PlayFromとPlayToの間だけを切り出し、終わりをまたぐ音符は終了位置で切る
+        let mut song = exec_easy(
+            "l4 cdef PlayFrom(1:3:0) PlayTo(2:2:0) g1 a PlayTo(Marker(\"E\")) Marker{\"E\"}",
+        );
+        assert_eq!((song.play_from, song.play_to), (192, 480));
+        assert_eq!(song.play_to_marker, Some("E".to_string()));
+        song.set_play_range(None, Some("2:2:0"));
+        let bin = crate::midi::generate(&mut song);
+        let notes: Vec<(isize, isize)> = song.tracks[0]
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOff)
+            .map(|e| (e.time, e.v1))
+            .collect();
+        assert_eq!(notes, vec![(86, 64), (182, 65), (288, 67)]);
+        // トラックの終わりは切り出した長さ (NoteOffと同じ位置)
+        assert!(bin.ends_with(&[0x80, 0x43, 0x64, 0x00, 0xFF, 0x2F, 0x00]));
+        // 最後が休符でも範囲の終わりまで (384 - 86 ステップ)
+        let mut song = exec_easy("l4 c r2. PlayTo(2:1:0)");
+        let bin = crate::midi::generate(&mut song);
+        assert!(bin.ends_with(&[0x82, 0x2A, 0xFF, 0x2F, 0x00]));
+        // コマンドラインの指定は小節番号かマーカー
+        song.set_play_range(Some("2"), Some("Chorus"));
+        assert_eq!(song.play_from, 384);
+        assert_eq!(song.play_to_marker, Some("Chorus".to_string()));
+        // 終わりが始まりより前ならエラーにして、範囲を取り消す
+        let mut song = exec_easy("l4 cdef Marker{\"A\"} g");
+        song.set_play_range(Some("A"), Some("1:2:0"));
+        assert!(!song.check_play_range());
+        let logs = song.get_logs_str();
+        assert!(logs.contains("Play range is empty") && logs.contains("(384 - 96)"));
+        assert_eq!((song.play_from, song.play_to), (-1, -1));
+        let mut song = exec_easy("l4 c PlayFrom(2) PlayTo(2) d");
+        crate::midi::generate(&mut song);
+        assert!(song.get_logs_str().contains("Play range is empty"));
+        assert_eq!(song.tracks[0].events.len(), 4);
+    }
+
+    #[test]
+    fn test_channel_port() {
+        // CH(17)以上はポート1以降のチャンネル。ポートを切り替えるFF 21を書き込む
+        let song = exec_easy("CH(17) c CH(34) d CH(1) e Port(3) CH(2) f");
+        let trk = &song.tracks[0];
+        let ports: Vec<u8> = trk
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::Meta && e.v2 == 0x21)
+            .map(|e| e.data.as_ref().unwrap()[0])
+            .collect();
+        assert_eq!(ports, vec![1, 2, 0, 3]);
+        let notes: Vec<isize> = trk
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOn)
+            .map(|e| e.channel)
+            .collect();
+        assert_eq!(notes, vec![0, 1, 0, 1]);
+        // Port()で決めたポートはCH(1〜16)では変わらない
+        assert_eq!(trk.port, 3);
+        // リセットはほかのポートにも送り、割り当てをログに出す
+        let mut song = exec_easy("TR(0) ResetGS; TR(1) c TR(2) CH(17) d");
+        crate::midi::generate(&mut song);
+        let resets: Vec<isize> = song.tracks[2]
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::SysEx)
+            .map(|e| e.time)
+            .collect();
+        assert_eq!(resets.len(), 1);
+        let logs = song.get_logs_str();
+        assert!(logs.contains("[PORT] 0: TR(1)=CH(1)"));
+        assert!(logs.contains("[PORT] 1: TR(2)=CH(17) (reset -> TR(2))"));
+        // GS/XGのリセットはポートごとに装置番号を変える
+        fn reset_data(song: &super::Song, no: usize) -> Vec<Vec<u8>> {
+            song.tracks[no]
+                .events
+                .iter()
+                .filter(|e| e.etype == EventType::SysEx)
+                .map(|e| e.data.clone().unwrap())
+                .collect()
+        }
+        let mut song = exec_easy("TR(0) ResetGS; ResetXG; TR(1) c TR(2) CH(17) d TR(3) CH(33) e");
+        crate::midi::generate(&mut song);
+        let port1 = reset_data(&song, 2);
+        let port2 = reset_data(&song, 3);
+        assert_eq!(&port1[0][0..4], &[0xF0, 0x41, 0x11, 0x42]);
+        assert_eq!(&port1[1][0..4], &[0xF0, 0x43, 0x11, 0x4C]);
+        assert_eq!(&port2[0][0..4], &[0xF0, 0x41, 0x12, 0x42]);
+        assert_eq!(&port2[1][0..4], &[0xF0, 0x43, 0x12, 0x4C]);
+        // GMのリセットは 7F のまま
+        let mut song = exec_easy("TR(0) ResetGM; TR(1) c TR(2) CH(17) d");
+        crate::midi::generate(&mut song);
+        assert_eq!(&reset_data(&song, 2)[0][0..4], &[0xF0, 0x7E, 0x7F, 0x09]);
+        // リセットを送る分もイベントの上限に数える
+        let src = "TR(0) ResetGS; TR(1) c TR(2) CH(17) d";
+        let used = exec_easy(src).event_bytes();
+        let mut song = super::Song::new();
+        song.set_max_event_bytes(used);
+        let t = crate::lexer::lex(&mut song, src, 0);
+        crate::runner::exec(&mut song, &t);
+        crate::midi::generate(&mut song);
+        assert!(reset_data(&song, 2).is_empty());
+        assert!(song.get_logs_str().contains("max_event_bytes"));
+    }
+
+    #[test]
+    fn test_chord() {
+        fn notes(song: &super::Song) -> Vec<(isize, isize, isize, isize)> {
+            let mut notes: Vec<(isize, isize, isize, isize)> = song.tracks[0]
+                .events
+                .iter()
+                .filter(|e| e.etype == EventType::NoteOn)
+                .map(|e| (e.time, e.v1, e.v2, e.v3))
+                .collect();
+            notes.sort();
+            notes
+        }
+        // 現在の l q v で和音にする。分数コードのベース音は和音の下
+        let song = exec_easy("l2 q50 v90 o4 Chord{C F/A} c");
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, 48, 96, 90),
+                (0, 52, 96, 90),
+                (0, 55, 96, 90),
+                (192, 45, 96, 90),
+                (192, 53, 96, 90),
+                (192, 57, 96, 90),
+                (192, 60, 96, 90),
+                (384, 48, 96, 90)
+            ]
+        );
+        // ボイスリーディング・転回・オクターブ
+        let song = exec_easy("ChordVoiceLeading(on) Chord{C F}");
+        let f: Vec<isize> = notes(&song)[3..].iter().map(|n| n.1).collect();
+        assert_eq!(f, vec![60, 65, 69]);
+        let song = exec_easy("ChordInversion(1) ChordOctave(4) Chord{C}");
+        let c: Vec<isize> = notes(&song).iter().map(|n| n.1).collect();
+        assert_eq!(c, vec![52, 55, 60]);
+        // 根音・ベース音・分散和音
+        let song =
+            exec_easy("ChordStyle{bass} ChordOctave(2) l4 Chord{C G/B} ChordStyle{arp} Chord{Am}");
+        let list: Vec<(isize, isize)> = notes(&song).iter().map(|n| (n.0, n.1)).collect();
+        assert_eq!(
+            list,
+            vec![(0, 24), (96, 35), (192, 33), (224, 36), (256, 40)]
+        );
+        // ChordMMLはMMLを返す。読めないコードネームは警告して飛ばす
+        let song = exec_easy("KeyFlag+(f) Str A = ChordMML({D F Hm7}); Print(A)");
+        assert!(song.get_logs_str().contains("'df+*a' 'f*ao6c' o5"));
+        assert!(song.get_logs_str().contains("Unknown chord symbol: Hm7"));
+    }
+
+    #[test]
+    fn test_strum_and_arp() {
+        fn notes(song: &super::Song) -> Vec<(isize, isize, isize)> {
+            song.tracks[0]
+                .events
+                .iter()
+                .filter(|e| e.etype == EventType::NoteOn)
+                .map(|e| (e.time, e.v1, e.v2))
+                .collect()
+        }
+        // 高い音から4ステップずつずらし、終わりをそろえる。{ }の後は元の指定(Subの中で指定したもの)に戻る
+        let song = exec_easy("l4 q100 Sub{ Strum(down, 4, 1) 'ceg' } Strum(up, 2){ 'ceg' } 'ce'");
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, 67, 96),
+                (4, 64, 92),
+                (8, 60, 88),
+                (0, 60, 96),
+                (2, 64, 96),
+                (4, 67, 96),
+                (96, 64, 96),
+                (100, 60, 92)
+            ]
+        );
+        // アルペジオは和音の長さの間、音型をくり返す
+        let song = exec_easy("l2 Arp(updown, 16, 50) 'ceg' Arp(off) c");
+        assert_eq!(
+            notes(&song),
+            vec![
+                (0, 60, 12),
+                (24, 64, 12),
+                (48, 67, 12),
+                (72, 64, 12),
+                (96, 60, 12),
+                (120, 64, 12),
+                (144, 67, 12),
+                (168, 64, 12),
+                (192, 60, 172)
+            ]
+        );
+        // 知らない音型は未定義の変数になる
+        let song = exec_easy("Arp(sideways) 'ceg'");
+        assert!(song.get_logs_str().contains("sideways"));
+        assert!(song.get_logs_str().contains("[Arp] wrong argument"));
+        let song = exec_easy("Str PAT={sideways} Arp(PAT) 'ceg'");
+        assert!(song
+            .get_logs_str()
+            .contains("[Arp] unknown pattern: sideways"));
+        // 引数は式として評価する。ステップは0未満にならない
+        let song = exec_easy("Int N=4 l4 q100 Strum(up, N) 'ce' Strum(down, -3) 'ce'");
+        assert_eq!(
+            notes(&song),
+            vec![(0, 60, 96), (4, 64, 96), (96, 64, 96), (96, 60, 96)]
+        );
+        let song = exec_easy("Int RATE=8 l4 q100 Arp(up, RATE, 50) 'ce' Arp(up, 8.) 'ce'");
+        assert_eq!(
+            notes(&song),
+            vec![(0, 60, 24), (48, 64, 24), (96, 60, 72), (168, 64, 24)]
+        );
+        let song = exec_easy("Arp(up, l8) 'ceg'");
+        assert!(song.get_logs_str().contains("[Arp] wrong argument"));
+        let song = exec_easy("Arp(up, 0) 'ceg'");
+        assert!(song.get_logs_str().contains("[Arp] wrong rate: 0"));
+    }
 }
 // ------------------------------------------
 
@@ -1940,3 +2274,55 @@ mod test_note_param_arg {
         assert_eq!(song.tracks.len(), 2);
     }
 }
+
+#[cfg(test)]
+mod test_include {
+    use crate::include_resolver::MemoryIncludeResolver;
+    use crate::lexer::lex;
+    use crate::runner::exec;
+    use crate::sakura_message::MessageKind;
+    use crate::song::{EventType, Song};
+
+    fn exec_with_files(src: &str, files: &[(&str, &str)]) -> Song {
+        let mut resolver = MemoryIncludeResolver::default();
+        for (name, file_src) in files {
+            resolver.add_file(name, file_src);
+        }
+        let mut song = Song::new();
+        song.set_include_resolver(Box::new(resolver));
+        let tokens = lex(&mut song, src, 0);
+        exec(&mut song, &tokens);
+        song
+    }
+
+    #[test]
+    fn test_include_function_and_macro() {
+        // 読み込んだファイルの関数やマクロを使える
+        let song = exec_with_files(
+            "Include(lib.mml) o5 BASS(2) KICK",
+            &[("lib.mml", "Str KICK={n36}\nFunction BASS(Int N){ [N c] }")],
+        );
+        let notes: Vec<isize> = song.tracks[0]
+            .events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOn)
+            .map(|e| e.v1)
+            .collect();
+        assert_eq!(notes, vec![60, 60, 36]);
+    }
+
+    #[test]
+    fn test_include_runtime_error_lineno() {
+        // 実行時エラーも読み込んだファイルの行番号で報告する
+        let song = exec_with_files(
+            "c\nInclude(a.mml)\nTimeSig(1)",
+            &[("a.mml", "\n\n  TimeSig(1)")],
+        );
+        let log = song.get_logs_str();
+        assert!(log.contains("[ERROR](a.mml:2) Runtime Error"), "{}", log);
+        assert!(log.contains("[ERROR](2) Runtime Error"), "{}", log);
+        let d = &song.get_diagnostics()[0];
+        assert_eq!(d.kind, MessageKind::RuntimeError);
+        assert_eq!((d.source.as_str(), d.line, d.column), ("a.mml", 2, 2));
+    }
+}
@@ -1,8 +1,11 @@
-use crate::lexer::lex;
+use crate::lexer::lex_at;
+use crate::runner::chord::chord_mml;
 use crate::runner::function::var_extract;
 use crate::runner::note::{get_note_info_from_token, set_note_info_with_default_value};
 use crate::runner::value_range;
+use crate::sakura_message::MessageKind;
 use crate::song::Song;
+use crate::span::SpanOrigin;
 use crate::svalue::SValue;
 use crate::token::{Token, TokenType};
 
@@ -261,11 +264,16 @@ pub fn calc_noteno(song: &mut Song, args: Vec<SValue>) -> SValue {
         return SValue::from_i(0);
     }
     let mml = args[0].to_s();
-    let tokens = lex(song, &mml, 0);
+    let tokens = lex_at(song, &mml, 0, SpanOrigin::Expand(song.cur_span));
     match find_note_no(song, &tokens) {
         Some(no) => SValue::from_i(no),
         None => {
-            song.add_log(format!("[WARN] NoteNo: 音符が見つかりません: {}", mml));
+            let msg = format!(
+                "NoteNo: {}: {}",
+                song.get_message(MessageKind::WarningNoteNotFound),
+                mml
+            );
+            song.add_warning(MessageKind::WarningNoteNotFound, song.lineno, msg);
             SValue::from_i(0)
         }
     }
@@ -294,6 +302,32 @@ pub fn calc_pos(_: &mut Song, args: Vec<SValue>) -> SValue {
     SValue::from_i(0)
 }
 
+/// Marker --- マーカーの位置を返す
+pub fn calc_marker(song: &mut Song, args: Vec<SValue>) -> SValue {
+    let name = args.first().map(|v| v.to_s()).unwrap_or_default();
+    if let Some(pos) = song.marker_pos(&name) {
+        return SValue::from_i(pos);
+    }
+    if song.flags.marker_lookup {
+        // PlayFrom/PlayToの引数なら、後ろで定義されるマーカーを曲の最後に探す
+        song.flags.marker_missing = Some(name);
+    } else {
+        let msg = format!(
+            "{}: {}",
+            song.get_message(MessageKind::WarningMarkerNotFound),
+            name
+        );
+        song.add_warning(MessageKind::WarningMarkerNotFound, song.lineno, msg);
+    }
+    SValue::from_i(0)
+}
+
+/// ChordMML --- コードネームをMMLにして返す (前のコードは覚えない)
+pub fn calc_chord_mml(song: &mut Song, args: Vec<SValue>) -> SValue {
+    let symbols = args.first().map(|v| v.to_s()).unwrap_or_default();
+    SValue::from_s(chord_mml(song, &symbols).0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,7 +1,7 @@
 /// message calalogue
 
 /// Message Kind
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageKind {
     UnknownChar,
     UnknownCommand,
@@ -22,6 +22,30 @@ pub enum MessageKind {
     InvalidArgument,
     WarningChangeTimebaseAfterNote,
     ShouldBeConstant,
+    ErrorIncludeNotFound,
+    ErrorIncludeCircular,
+    ErrorVariableName,
+    ErrorInvalidValueType,
+    ErrorRhythmMacro,
+    ErrorNotSupported,
+    ErrorEventLimit,
+    ErrorInputSize,
+    ErrorAbcSyntax,
+    ErrorPlayRange,
+    WarningUndefined,
+    WarningLoopCountZero,
+    WarningNoteNotFound,
+    WarningAbcIgnored,
+    WarningBarCheck,
+    WarningMarkerNotFound,
+    WarningUnknownChord,
+    LintUnusedVariable,
+    LintCodeAfterEnd,
+    LintSharedChannel,
+    LintNoteOutOfRange,
+    LintVoiceAfterNote,
+    LintShadowedFunction,
+    Print,
 }
 
 /// Language
@@ -138,5 +162,101 @@ pub fn get_message(lang: &MessageLang, kind: MessageKind) -> &'static str {
             MessageLang::EN => "The argument should be a constant value.",
             MessageLang::JA => "引数は定数である必要があります。",
         },
+        MessageKind::ErrorIncludeNotFound => match lang {
+            MessageLang::EN => "Include file not found",
+            MessageLang::JA => "Includeするファイルが見つかりません",
+        },
+        MessageKind::ErrorIncludeCircular => match lang {
+            MessageLang::EN => "Circular Include",
+            MessageLang::JA => "Includeが循環しています",
+        },
+        MessageKind::ErrorVariableName => match lang {
+            MessageLang::EN => "Variable's name should be Upper case like \"Test\".",
+            MessageLang::JA => "変数名は\"Test\"のように大文字で始めてください。",
+        },
+        MessageKind::ErrorInvalidValueType => match lang {
+            MessageLang::EN => "Invalid value type.",
+            MessageLang::JA => "値の型が無効です。",
+        },
+        MessageKind::ErrorRhythmMacro => match lang {
+            MessageLang::EN => "could not define Rhythm macro",
+            MessageLang::JA => "リズムマクロを定義できません",
+        },
+        MessageKind::ErrorNotSupported => match lang {
+            MessageLang::EN => "not supported",
+            MessageLang::JA => "未対応",
+        },
+        MessageKind::ErrorEventLimit => match lang {
+            MessageLang::EN => "MIDI event data exceeds max_event_bytes",
+            MessageLang::JA => "MIDIイベントデータがmax_event_bytesを超えました",
+        },
+        MessageKind::ErrorInputSize => match lang {
+            MessageLang::EN => "Input size exceeds max_input_size",
+            MessageLang::JA => "入力がmax_input_sizeを超えました",
+        },
+        MessageKind::ErrorAbcSyntax => match lang {
+            MessageLang::EN => "ABC syntax error",
+            MessageLang::JA => "ABC記譜法のエラー",
+        },
+        MessageKind::ErrorPlayRange => match lang {
+            MessageLang::EN => "Play range is empty (the end must come after the start)",
+            MessageLang::JA => "演奏範囲が空です (終わりは始まりより後にしてください)",
+        },
+        MessageKind::WarningUndefined => match lang {
+            MessageLang::EN => "Undefined",
+            MessageLang::JA => "未定義",
+        },
+        MessageKind::WarningLoopCountZero => match lang {
+            MessageLang::EN => "Loop count is 0; treated as 1.",
+            MessageLang::JA => "ループ回数が0なので1回として扱います。",
+        },
+        MessageKind::WarningNoteNotFound => match lang {
+            MessageLang::EN => "note not found",
+            MessageLang::JA => "音符が見つかりません",
+        },
+        MessageKind::WarningBarCheck => match lang {
+            MessageLang::EN => "Bar line is not at the start of a measure",
+            MessageLang::JA => "小節線が小節の頭にありません",
+        },
+        MessageKind::WarningMarkerNotFound => match lang {
+            MessageLang::EN => "Marker not found",
+            MessageLang::JA => "マーカーが見つかりません",
+        },
+        MessageKind::WarningUnknownChord => match lang {
+            MessageLang::EN => "Unknown chord symbol",
+            MessageLang::JA => "コードネームを読めません",
+        },
+        MessageKind::WarningAbcIgnored => match lang {
+            MessageLang::EN => "Ignored in ABC",
+            MessageLang::JA => "ABCの変換で無視しました",
+        },
+        MessageKind::LintUnusedVariable => match lang {
+            MessageLang::EN => "Variable is defined but never used",
+            MessageLang::JA => "変数を定義していますが使っていません",
+        },
+        MessageKind::LintCodeAfterEnd => match lang {
+            MessageLang::EN => "Code after End is not compiled",
+            MessageLang::JA => "End以降はコンパイルされません",
+        },
+        MessageKind::LintSharedChannel => match lang {
+            MessageLang::EN => "Tracks share the same channel",
+            MessageLang::JA => "複数のトラックが同じチャンネルを使っています",
+        },
+        MessageKind::LintNoteOutOfRange => match lang {
+            MessageLang::EN => "Note number is out of range (0-127)",
+            MessageLang::JA => "ノート番号が範囲(0-127)外です",
+        },
+        MessageKind::LintVoiceAfterNote => match lang {
+            MessageLang::EN => "Voice is set after notes were played",
+            MessageLang::JA => "音符を演奏した後で音色を指定しています",
+        },
+        MessageKind::LintShadowedFunction => match lang {
+            MessageLang::EN => "Function has the same name as a system function",
+            MessageLang::JA => "システム関数と同じ名前の関数です",
+        },
+        MessageKind::Print => match lang {
+            MessageLang::EN => "Print",
+            MessageLang::JA => "出力",
+        },
     }
 }
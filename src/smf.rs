@@ -0,0 +1,572 @@
+//! SMF reader - Standard MIDI File を型付きのイベント列として読み込む
+//!
+//! `midi::dump_midi` は表示用の文字列を作るが、こちらはツールや変換処理で
+//! 使えるように、ヘッダ・トラック・絶対時間付きのイベントを返す。
+
+use std::fmt;
+
+/// SMFの読み込みエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmfError {
+    /// MThdで始まっていない
+    NotSmf,
+    /// ヘッダが途中で切れている
+    TruncatedHeader,
+    /// MThdのサイズが6未満
+    HeaderSize(u32),
+    /// 未対応のフォーマット (0,1,2以外)
+    UnsupportedFormat(u16),
+    /// ヘッダのトラック数だけMTrkがない
+    MissingTrack { track: usize },
+    /// チャンクのサイズがデータの長さを超えている
+    ChunkSize { track: usize },
+    /// イベントが途中で切れている (offsetはファイル先頭からのバイト位置)
+    TruncatedEvent { track: usize, offset: usize },
+    /// ランニングステータスの前にステータスバイトがない
+    MissingStatus { track: usize, offset: usize },
+    /// 不明なステータスバイト
+    UnknownStatus {
+        track: usize,
+        offset: usize,
+        status: u8,
+    },
+    /// End of Track (FF 2F) がない
+    MissingEndOfTrack { track: usize },
+}
+
+impl fmt::Display for SmfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmfError::NotSmf => write!(f, "Not Midi file"),
+            SmfError::TruncatedHeader => write!(f, "Truncated MIDI header"),
+            SmfError::HeaderSize(size) => write!(f, "Midi MThd size error 6!={}", size),
+            SmfError::UnsupportedFormat(format) => write!(f, "Midi Format error: {}", format),
+            SmfError::MissingTrack { track } => write!(f, "MIDI track {} not found", track),
+            SmfError::ChunkSize { track } => {
+                write!(f, "MIDI track {} size exceeds input data", track)
+            }
+            SmfError::TruncatedEvent { track, offset } => {
+                write!(f, "Truncated MIDI event (track {} at ${:X})", track, offset)
+            }
+            SmfError::MissingStatus { track, offset } => write!(
+                f,
+                "Running status without status byte (track {} at ${:X})",
+                track, offset
+            ),
+            SmfError::UnknownStatus {
+                track,
+                offset,
+                status,
+            } => write!(
+                f,
+                "Unknown event ${:02X} (track {} at ${:X})",
+                status, track, offset
+            ),
+            SmfError::MissingEndOfTrack { track } => {
+                write!(f, "MIDI track {} has no end-of-track event", track)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SmfError {}
+
+/// 時間の単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    /// 四分音符あたりのtick数 (タイムベース)
+    Metrical(u16),
+    /// SMPTE (1秒あたりのフレーム数, 1フレームあたりのtick数)
+    Smpte { fps: u8, ticks_per_frame: u8 },
+}
+
+impl Timing {
+    /// タイムベース (SMPTEならNone)
+    pub fn timebase(&self) -> Option<u16> {
+        match self {
+            Timing::Metrical(tb) => Some(*tb),
+            Timing::Smpte { .. } => None,
+        }
+    }
+}
+
+/// MThd
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmfHeader {
+    /// 0:単一トラック 1:同時に演奏する複数トラック 2:独立した複数トラック
+    pub format: u16,
+    pub track_count: u16,
+    pub timing: Timing,
+}
+
+/// イベントの種類
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmfEventKind {
+    NoteOff {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    /// velocity=0 もそのまま (NoteOffとして扱うかは使う側で決める)
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        key: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// value: -8192..8191
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+    /// メタイベント (FF type len data) / End of Track は含まない
+    Meta {
+        meta_type: u8,
+        data: Vec<u8>,
+    },
+    /// F0 len data (dataはF0の後ろ。通常はF7で終わる)
+    SysEx {
+        data: Vec<u8>,
+    },
+    /// F7 len data
+    Escape {
+        data: Vec<u8>,
+    },
+}
+
+impl SmfEventKind {
+    /// チャンネルメッセージならチャンネル (0〜15)
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            SmfEventKind::NoteOff { channel, .. }
+            | SmfEventKind::NoteOn { channel, .. }
+            | SmfEventKind::PolyPressure { channel, .. }
+            | SmfEventKind::ControlChange { channel, .. }
+            | SmfEventKind::ProgramChange { channel, .. }
+            | SmfEventKind::ChannelPressure { channel, .. }
+            | SmfEventKind::PitchBend { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+    /// テンポ (FF 51) なら四分音符あたりのマイクロ秒
+    pub fn tempo_mpq(&self) -> Option<u32> {
+        match self {
+            SmfEventKind::Meta {
+                meta_type: 0x51,
+                data,
+            } if data.len() == 3 => {
+                Some((data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// 絶対時間付きのイベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmfEvent {
+    /// トラック先頭からのtick
+    pub tick: usize,
+    pub kind: SmfEventKind,
+}
+
+/// MTrk
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SmfTrack {
+    pub events: Vec<SmfEvent>,
+    /// End of Track の時間
+    pub end_tick: usize,
+}
+
+/// 読み込んだSMF
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Smf {
+    pub header: SmfHeader,
+    pub tracks: Vec<SmfTrack>,
+}
+
+fn read_u16(bin: &[u8], pos: usize) -> u16 {
+    (bin[pos] as u16) << 8 | bin[pos + 1] as u16
+}
+
+fn read_u32(bin: &[u8], pos: usize) -> u32 {
+    (read_u16(bin, pos) as u32) << 16 | read_u16(bin, pos + 2) as u32
+}
+
+/// 可変長の数値を読む。途中で切れていればNone
+fn read_var_len(bin: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut v: usize = 0;
+    for _ in 0..4 {
+        let b = *bin.get(*pos)?;
+        *pos += 1;
+        v = v << 7 | (b & 0x7F) as usize;
+        if b < 0x80 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+/// SMFを読み込む
+pub fn parse(bin: &[u8]) -> Result<Smf, SmfError> {
+    if bin.len() < 8 {
+        return Err(if bin.starts_with(b"MThd") || b"MThd".starts_with(bin) {
+            SmfError::TruncatedHeader
+        } else {
+            SmfError::NotSmf
+        });
+    }
+    if &bin[0..4] != b"MThd" {
+        return Err(SmfError::NotSmf);
+    }
+    let header_size = read_u32(bin, 4);
+    if header_size < 6 {
+        return Err(SmfError::HeaderSize(header_size));
+    }
+    let header_end = 8usize.saturating_add(header_size as usize);
+    if bin.len() < header_end {
+        return Err(SmfError::TruncatedHeader);
+    }
+    let format = read_u16(bin, 8);
+    if format > 2 {
+        return Err(SmfError::UnsupportedFormat(format));
+    }
+    let track_count = read_u16(bin, 10);
+    let division = read_u16(bin, 12);
+    let timing = if division & 0x8000 != 0 {
+        Timing::Smpte {
+            fps: (-((division >> 8) as u8 as i8)) as u8,
+            ticks_per_frame: (division & 0xFF) as u8,
+        }
+    } else {
+        Timing::Metrical(division)
+    };
+    let header = SmfHeader {
+        format,
+        track_count,
+        timing,
+    };
+    let mut tracks = vec![];
+    let mut pos = header_end;
+    while tracks.len() < track_count as usize {
+        let track = tracks.len();
+        if bin.len().saturating_sub(pos) < 8 {
+            return Err(SmfError::MissingTrack { track });
+        }
+        let size = read_u32(bin, pos + 4) as usize;
+        let start = pos + 8;
+        let end = match start.checked_add(size) {
+            Some(end) if end <= bin.len() => end,
+            _ => return Err(SmfError::ChunkSize { track }),
+        };
+        // MTrk以外のチャンクは読み飛ばす
+        if &bin[pos..pos + 4] == b"MTrk" {
+            tracks.push(parse_track(bin, start, end, track)?);
+        }
+        pos = end;
+    }
+    Ok(Smf { header, tracks })
+}
+
+fn parse_track(bin: &[u8], start: usize, end: usize, track: usize) -> Result<SmfTrack, SmfError> {
+    let data = &bin[..end];
+    let mut events = vec![];
+    let mut pos = start;
+    let mut tick = 0usize;
+    let mut running_status = 0u8;
+    while pos < end {
+        let offset = pos;
+        let truncated = SmfError::TruncatedEvent { track, offset };
+        let delta = read_var_len(data, &mut pos).ok_or(truncated.clone())?;
+        tick = tick.saturating_add(delta);
+        let mut status = *data.get(pos).ok_or(truncated.clone())?;
+        if status < 0x80 {
+            if running_status == 0 {
+                return Err(SmfError::MissingStatus { track, offset });
+            }
+            status = running_status;
+        } else {
+            pos += 1;
+        }
+        let kind = match status {
+            0x80..=0xEF => {
+                running_status = status;
+                let channel = status & 0x0F;
+                let size = if (0xC0..=0xDF).contains(&status) {
+                    1
+                } else {
+                    2
+                };
+                let d = data.get(pos..pos + size).ok_or(truncated)?;
+                pos += size;
+                if d.iter().any(|b| *b >= 0x80) {
+                    return Err(SmfError::MissingStatus { track, offset });
+                }
+                match status & 0xF0 {
+                    0x80 => SmfEventKind::NoteOff {
+                        channel,
+                        key: d[0],
+                        velocity: d[1],
+                    },
+                    0x90 => SmfEventKind::NoteOn {
+                        channel,
+                        key: d[0],
+                        velocity: d[1],
+                    },
+                    0xA0 => SmfEventKind::PolyPressure {
+                        channel,
+                        key: d[0],
+                        pressure: d[1],
+                    },
+                    0xB0 => SmfEventKind::ControlChange {
+                        channel,
+                        controller: d[0],
+                        value: d[1],
+                    },
+                    0xC0 => SmfEventKind::ProgramChange {
+                        channel,
+                        program: d[0],
+                    },
+                    0xD0 => SmfEventKind::ChannelPressure {
+                        channel,
+                        pressure: d[0],
+                    },
+                    _ => SmfEventKind::PitchBend {
+                        channel,
+                        value: ((d[1] as i16) << 7 | d[0] as i16) - 8192,
+                    },
+                }
+            }
+            0xFF => {
+                // メタイベントとSysExはランニングステータスを解除する
+                running_status = 0;
+                let meta_type = *data.get(pos).ok_or(truncated.clone())?;
+                pos += 1;
+                let len = read_var_len(data, &mut pos).ok_or(truncated.clone())?;
+                let payload = data.get(pos..pos.saturating_add(len)).ok_or(truncated)?;
+                pos += len;
+                if meta_type == 0x2F {
+                    return Ok(SmfTrack {
+                        events,
+                        end_tick: tick,
+                    });
+                }
+                SmfEventKind::Meta {
+                    meta_type,
+                    data: payload.to_vec(),
+                }
+            }
+            0xF0 | 0xF7 => {
+                running_status = 0;
+                let len = read_var_len(data, &mut pos).ok_or(truncated.clone())?;
+                let payload = data.get(pos..pos.saturating_add(len)).ok_or(truncated)?;
+                pos += len;
+                if status == 0xF0 {
+                    SmfEventKind::SysEx {
+                        data: payload.to_vec(),
+                    }
+                } else {
+                    SmfEventKind::Escape {
+                        data: payload.to_vec(),
+                    }
+                }
+            }
+            _ => {
+                return Err(SmfError::UnknownStatus {
+                    track,
+                    offset,
+                    status,
+                })
+            }
+        };
+        events.push(SmfEvent { tick, kind });
+    }
+    Err(SmfError::MissingEndOfTrack { track })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smf(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bin = b"MThd\0\0\0\x06".to_vec();
+        bin.extend(format.to_be_bytes());
+        bin.extend((tracks.len() as u16).to_be_bytes());
+        bin.extend(division.to_be_bytes());
+        for t in tracks {
+            bin.extend(b"MTrk");
+            bin.extend((t.len() as u32).to_be_bytes());
+            bin.extend(*t);
+        }
+        bin
+    }
+
+    #[test]
+    fn parses_events_with_absolute_ticks() {
+        let bin = crate::compile("Tempo(120) TR(1) @2 c y7,100 PitchBend(-1)", 0).bin;
+        let smf = parse(&bin).unwrap();
+        assert_eq!(smf.header.format, 1);
+        assert_eq!(smf.header.timing, Timing::Metrical(96));
+        assert_eq!(smf.tracks.len(), 2);
+        assert_eq!(smf.tracks[0].events[0].kind.tempo_mpq(), Some(500_000));
+        let kinds: Vec<(usize, SmfEventKind)> = smf.tracks[1]
+            .events
+            .iter()
+            .map(|e| (e.tick, e.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (
+                    0,
+                    SmfEventKind::ProgramChange {
+                        channel: 0,
+                        program: 1
+                    }
+                ),
+                (
+                    0,
+                    SmfEventKind::NoteOn {
+                        channel: 0,
+                        key: 60,
+                        velocity: 100
+                    }
+                ),
+                (
+                    86,
+                    SmfEventKind::NoteOff {
+                        channel: 0,
+                        key: 60,
+                        velocity: 100
+                    }
+                ),
+                (
+                    96,
+                    SmfEventKind::ControlChange {
+                        channel: 0,
+                        controller: 7,
+                        value: 100
+                    }
+                ),
+                (
+                    96,
+                    SmfEventKind::PitchBend {
+                        channel: 0,
+                        value: -1
+                    }
+                ),
+            ]
+        );
+        assert_eq!(smf.tracks[1].end_tick, 96);
+    }
+
+    #[test]
+    fn parses_running_status_sysex_and_smpte() {
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100, // NoteOn
+            0x10, 62, 90, // ランニングステータス
+            0x00, 0xF0, 0x03, 0x41, 0x10, 0xF7, // SysEx
+            0x20, 0xFF, 0x2F, 0x00,
+        ];
+        // -25fps, 40tick/frame
+        let smf = parse(&smf(0, 0xE728, &[track])).unwrap();
+        assert_eq!(
+            smf.header.timing,
+            Timing::Smpte {
+                fps: 25,
+                ticks_per_frame: 40
+            }
+        );
+        assert_eq!(smf.header.timing.timebase(), None);
+        let events = &smf.tracks[0].events;
+        assert_eq!(
+            events[1],
+            SmfEvent {
+                tick: 16,
+                kind: SmfEventKind::NoteOn {
+                    channel: 0,
+                    key: 62,
+                    velocity: 90
+                }
+            }
+        );
+        assert_eq!(
+            events[2].kind,
+            SmfEventKind::SysEx {
+                data: vec![0x41, 0x10, 0xF7]
+            }
+        );
+        assert_eq!(smf.tracks[0].end_tick, 48);
+    }
+
+    #[test]
+    fn skips_unknown_chunks() {
+        let mut bin = smf(2, 480, &[]);
+        bin[11] = 1;
+        bin.extend(b"XFIH\0\0\0\x02ab");
+        bin.extend(b"MTrk\0\0\0\x04\0\xFF\x2F\0");
+        let smf = parse(&bin).unwrap();
+        assert_eq!(smf.header.format, 2);
+        assert_eq!(smf.tracks.len(), 1);
+    }
+
+    #[test]
+    fn reports_malformed_data() {
+        assert_eq!(parse(b"RIFF1234"), Err(SmfError::NotSmf));
+        assert_eq!(parse(b"MTh"), Err(SmfError::TruncatedHeader));
+        assert_eq!(parse(&smf(3, 96, &[])), Err(SmfError::UnsupportedFormat(3)));
+        let mut bin = smf(1, 96, &[]);
+        bin[11] = 1;
+        assert_eq!(parse(&bin), Err(SmfError::MissingTrack { track: 0 }));
+        let mut bin = smf(1, 96, &[&[0x00, 0xFF, 0x2F, 0x00]]);
+        bin[21] = 0x10;
+        assert_eq!(parse(&bin), Err(SmfError::ChunkSize { track: 0 }));
+        assert_eq!(
+            parse(&smf(0, 96, &[&[0x00, 0x90, 60]])),
+            Err(SmfError::TruncatedEvent {
+                track: 0,
+                offset: 22
+            })
+        );
+        assert_eq!(
+            parse(&smf(0, 96, &[&[0x00, 60, 100]])),
+            Err(SmfError::MissingStatus {
+                track: 0,
+                offset: 22
+            })
+        );
+        assert_eq!(
+            parse(&smf(0, 96, &[&[0x00, 0xF4]])),
+            Err(SmfError::UnknownStatus {
+                track: 0,
+                offset: 22,
+                status: 0xF4
+            })
+        );
+        assert_eq!(
+            parse(&smf(0, 96, &[&[0x00, 0xC0, 1]])),
+            Err(SmfError::MissingEndOfTrack { track: 0 })
+        );
+        assert_eq!(
+            SmfError::MissingEndOfTrack { track: 0 }.to_string(),
+            "MIDI track 0 has no end-of-track event"
+        );
+    }
+}
@@ -1,20 +1,28 @@
 //! song & track
 
+mod arpeggio;
+mod chase;
 mod event;
 mod flags;
 mod function;
+mod ports;
 mod track;
 
+pub use arpeggio::*;
 pub use event::*;
 pub use flags::*;
 pub use function::*;
 pub use track::*;
 
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::include_resolver::IncludeResolver;
 use crate::mml_def::{self, TieMode};
 use crate::runner::value_range;
 use crate::sakura_functions;
 use crate::sakura_message::{MessageData, MessageKind, MessageLang};
+use crate::span::{SourceText, Span, SpanOrigin};
 use crate::svalue::SValue;
+use crate::tempo_map::{BarPos, TimeSigMap};
 use crate::token::Tokens;
 use std::collections::HashMap;
 
@@ -37,6 +45,8 @@ pub struct Song {
     pub cur_track: usize,
     pub timesig_frac: isize, // 分子
     pub timesig_deno: isize, // 分母
+    /// 曲中の拍子の変化 (小節:拍:ステップの位置の計算に使う)
+    pub time_signatures: TimeSigMap,
     pub flags: Flags,
     pub rhthm_macro: Vec<String>,
     pub variables_stack: Vec<HashMap<String, SValue>>,
@@ -47,17 +57,40 @@ pub struct Song {
     pub key_flag: Vec<isize>, // order: [c,c#,d,d#,e,f,f#,g,g#,a,a#,b]
     pub key_shift: isize,
     pub play_from: isize,
+    /// 演奏開始位置に指定したマーカー (曲の最後に位置を求める)
+    pub play_from_marker: Option<String>,
+    /// 演奏終了位置 (-1なら最後まで)
+    pub play_to: isize,
+    /// 演奏終了位置に指定したマーカー
+    pub play_to_marker: Option<String>,
+    /// Markerで名前を付けた位置 (名前, ステップ)
+    pub markers: Vec<(String, isize)>,
     pub v_add: isize,
     pub q_add: isize,
     pub stack: Vec<SValue>,
     pub rand_seed: u32,
     pub device_number: u8,
     pub use_key_shift: bool,
+    /// `|` で小節の頭にいるか確かめるか
+    pub use_bar_check: bool,
     pub lineno: isize,
+    /// 読み込んだソースの名前 (0:メインのソース / 1以降:Includeしたファイル)
+    pub source_names: Vec<String>,
+    /// 読み込んだソースの位置の対応表 (source_namesと同じ添字)
+    pub source_texts: Vec<SourceText>,
+    /// 字句解析・実行中のソースの番号 (source_namesの添字)
+    pub source_no: usize,
+    /// 実行中のトークンの範囲
+    pub cur_span: Option<Span>,
+    /// Include中のソースの番号 (循環参照の検出用)
+    pub include_stack: Vec<usize>,
+    /// Include命令のファイルを探す
+    pub include_resolver: Option<Box<dyn IncludeResolver>>,
     max_event_bytes: usize,
     event_bytes: usize,
     event_limit_exceeded: bool,
     logs: Vec<String>, // ログ
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Song {
@@ -79,6 +112,7 @@ impl Song {
             cur_track: 0,
             timesig_frac: 4,
             timesig_deno: 4,
+            time_signatures: TimeSigMap::default(),
             flags: Flags::new(),
             system_functions: sys_funcs,
             calc_functions: mml_def::init_system_calc_functions(),
@@ -89,14 +123,26 @@ impl Song {
             key_flag: vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
             key_shift: 0,
             play_from: -1,
+            play_from_marker: None,
+            play_to: -1,
+            play_to_marker: None,
+            markers: vec![],
             logs: vec![],
+            diagnostics: vec![],
             v_add: 8,
             q_add: 1,
             stack: vec![],
             rand_seed: SAKURA_DEFAULT_RANDOM_SEED, // Random Seed
             device_number: 0x10,                   // default device number (0x10: General MIDI)
             use_key_shift: true,
+            use_bar_check: false,
             lineno: 0,
+            source_names: vec![String::new()],
+            source_texts: vec![SourceText::default()],
+            source_no: 0,
+            cur_span: None,
+            include_stack: vec![],
+            include_resolver: None,
             max_event_bytes: SAKURA_DEFAULT_MAX_EVENT_BYTES,
             event_bytes: 0,
             event_limit_exceeded: false,
@@ -125,6 +171,55 @@ impl Song {
         } // check max logs
         self.logs.push(msg);
     }
+    /// エラー・警告を作る。ソースの名前は字句解析・実行中のソースになる
+    pub fn new_diagnostic(
+        &self,
+        severity: Severity,
+        kind: MessageKind,
+        lineno: isize,
+        message: String,
+    ) -> Diagnostic {
+        let source = self
+            .source_names
+            .get(self.source_no)
+            .map(String::as_str)
+            .unwrap_or("");
+        Diagnostic::new(severity, kind, lineno, source, message)
+    }
+    /// エラー・警告を記録する。ログにも同じ内容を書く
+    pub fn add_diagnostic(&mut self, d: Diagnostic) {
+        self.add_log(d.to_log());
+        if SAKURA_MAX_LOGS <= self.diagnostics.len() {
+            return;
+        } // check max logs
+        self.diagnostics.push(d);
+    }
+    pub fn add_error(&mut self, kind: MessageKind, lineno: isize, message: String) {
+        let d = self.new_diagnostic(Severity::Error, kind, lineno, message);
+        self.add_diagnostic(d);
+    }
+    pub fn add_warning(&mut self, kind: MessageKind, lineno: isize, message: String) {
+        let d = self.new_diagnostic(Severity::Warning, kind, lineno, message);
+        self.add_diagnostic(d);
+    }
+    pub fn get_diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+    /// 字句解析中の範囲を元のソースでの範囲にする
+    pub fn span_of(&self, origin: SpanOrigin, start: usize, end: usize) -> Option<Span> {
+        match origin {
+            SpanOrigin::Offset(base) => self
+                .source_texts
+                .get(self.source_no)
+                .filter(|st| !st.is_empty())
+                .map(|st| st.span(self.source_no, base + start, base + end)),
+            SpanOrigin::Expand(span) => span,
+        }
+    }
+    /// Include命令のファイルを探す方法を設定する
+    pub fn set_include_resolver(&mut self, resolver: Box<dyn IncludeResolver>) {
+        self.include_resolver = Some(resolver);
+    }
     pub fn get_logs_len(&self) -> usize {
         self.logs.len()
     }
@@ -146,10 +241,12 @@ impl Song {
             return;
         }
         self.event_limit_exceeded = true;
-        self.add_log(format!(
-            "[ERROR]({}) MIDI event data exceeds max_event_bytes ({})",
-            self.lineno, self.max_event_bytes,
-        ));
+        let msg = format!(
+            "{} ({})",
+            self.get_message(MessageKind::ErrorEventLimit),
+            self.max_event_bytes
+        );
+        self.add_error(MessageKind::ErrorEventLimit, self.lineno, msg);
     }
     /// イベント用の予算を確保する。和音やタイなど、一時領域へ置く場合にも使う。
     pub fn reserve_event(&mut self, e: &Event) -> bool {
@@ -165,15 +262,21 @@ impl Song {
         self.event_bytes = next;
         true
     }
-    pub fn add_event(&mut self, e: Event) -> bool {
+    pub fn add_event(&mut self, mut e: Event) -> bool {
         if !self.reserve_event(&e) {
             return false;
         }
+        if e.span.is_none() {
+            e.span = self.cur_span;
+        }
         self.tracks[self.cur_track].events.push(e);
         true
     }
     /// すでに予算を確保した一時イベントをトラックへ移す。
-    pub fn add_reserved_event(&mut self, e: Event) {
+    pub fn add_reserved_event(&mut self, mut e: Event) {
+        if e.span.is_none() {
+            e.span = self.cur_span;
+        }
         self.tracks[self.cur_track].events.push(e);
     }
     /// Track内の連続書き込みから予算超過を通知する。
@@ -189,7 +292,102 @@ impl Song {
             trk.events_sort();
         }
     }
+    /// マーカーの位置 (同じ名前なら最初のもの)
+    pub fn marker_pos(&self, name: &str) -> Option<isize> {
+        self.markers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, pos)| *pos)
+    }
+    /// 演奏範囲を文字列で指定する (ex) "5:1:0" / "5" / "Chorus"
+    /// 数字なら小節:拍:ステップ、それ以外はマーカーの名前として扱う
+    pub fn set_play_range(&mut self, from: Option<&str>, to: Option<&str>) {
+        if let Some(s) = from {
+            match self.parse_bar_pos(s) {
+                Some(pos) => {
+                    self.play_from = pos;
+                    self.play_from_marker = None;
+                }
+                None => self.play_from_marker = Some(s.to_string()),
+            }
+        }
+        if let Some(s) = to {
+            match self.parse_bar_pos(s) {
+                Some(pos) => {
+                    self.play_to = pos;
+                    self.play_to_marker = None;
+                }
+                None => self.play_to_marker = Some(s.to_string()),
+            }
+        }
+    }
+    /// "小節:拍:ステップ" をステップに直す (拍とステップは省略できる)
+    fn parse_bar_pos(&self, s: &str) -> Option<isize> {
+        let nums: Vec<isize> = s
+            .split(':')
+            .map(|v| v.trim().parse::<isize>())
+            .collect::<Result<_, _>>()
+            .ok()?;
+        if nums.len() > 3 {
+            return None;
+        }
+        let pos = BarPos {
+            measure: nums[0] + self.flags.measure_shift,
+            beat: nums.get(1).copied().unwrap_or(1),
+            step: nums.get(2).copied().unwrap_or(0),
+        };
+        Some(self.time_signatures.bar_to_tick(self.timebase, pos))
+    }
+    /// 名前で指定した演奏範囲をステップに直す
+    fn resolve_play_markers(&mut self) {
+        if let Some(name) = self.play_from_marker.take() {
+            match self.marker_pos(&name) {
+                Some(pos) => self.play_from = pos,
+                None => self.warn_marker_not_found(&name),
+            }
+        }
+        if let Some(name) = self.play_to_marker.take() {
+            match self.marker_pos(&name) {
+                Some(pos) => self.play_to = pos,
+                None => self.warn_marker_not_found(&name),
+            }
+        }
+    }
+    fn warn_marker_not_found(&mut self, name: &str) {
+        let msg = format!(
+            "{}: {}",
+            self.get_message(MessageKind::WarningMarkerNotFound),
+            name
+        );
+        self.add_warning(MessageKind::WarningMarkerNotFound, 0, msg);
+    }
+    /// 演奏範囲を確かめる。終わりが始まりより前なら、エラーにして範囲を取り消す
+    pub fn check_play_range(&mut self) -> bool {
+        self.resolve_play_markers();
+        if self.play_to < 0 || self.play_to > self.play_from.max(0) {
+            return true;
+        }
+        let msg = format!(
+            "{} ({} - {})",
+            self.get_message(MessageKind::ErrorPlayRange),
+            self.play_from.max(0),
+            self.play_to
+        );
+        self.add_error(MessageKind::ErrorPlayRange, 0, msg);
+        self.play_from = -1;
+        self.play_to = -1;
+        false
+    }
     pub fn play_from_all_track(&mut self) {
+        self.check_play_range();
+        if self.play_to >= 0 {
+            if self.debug {
+                println!("PLAY_TO={}", self.play_to);
+            }
+            for trk in self.tracks.iter_mut() {
+                trk.play_to(self.play_to);
+            }
+        }
         if self.play_from < 0 {
             return;
         }
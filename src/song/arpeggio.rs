@@ -0,0 +1,169 @@
+//! arpeggio - 和音のストロークとアルペジオ
+//!
+//! 和音(' ')の音符を少しずつずらして書き込む(Strum)か、音型をくり返して
+//! 分散和音にする(Arp)。
+use super::*;
+
+/// 和音の音符を1音ずつずらす (ギターのストローク)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Strum {
+    /// 高い音から鳴らすか (down) --- false なら低い音から (up)
+    pub down: bool,
+    /// ずらすステップ数
+    pub step: isize,
+    /// 遅れた分だけ音長を短くして、和音の終わりをそろえるか
+    pub keep_end: bool,
+}
+
+impl Strum {
+    /// 音符を鳴らす順に並べ、時間と音長をずらす
+    pub fn apply(&self, notes: &mut [Event]) {
+        notes.sort_by_key(|e| e.v1);
+        if self.down {
+            notes.reverse();
+        }
+        for (i, e) in notes.iter_mut().enumerate() {
+            let delay = self.step * i as isize;
+            e.time += delay;
+            if self.keep_end {
+                e.v2 = (e.v2 - delay).max(1);
+            }
+        }
+    }
+}
+
+/// アルペジオの音型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpPattern {
+    Up,
+    Down,
+    UpDown,
+    Random,
+}
+
+impl ArpPattern {
+    /// 名前から作る (up / down / updown / random)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "up" => Some(ArpPattern::Up),
+            "down" => Some(ArpPattern::Down),
+            "updown" => Some(ArpPattern::UpDown),
+            "random" => Some(ArpPattern::Random),
+            _ => None,
+        }
+    }
+}
+
+/// 和音の音を音型どおりにくり返す
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arp {
+    pub pattern: ArpPattern,
+    /// 1音の長さ (ステップ)
+    pub rate: isize,
+    /// ゲート (%) --- 0以下なら和音のゲートを使う
+    pub gate: isize,
+}
+
+impl Arp {
+    /// n音の和音で、k番目に鳴らす音の番号 (低い順)
+    fn index(&self, k: usize, n: usize, rand: &mut dyn FnMut() -> u32) -> usize {
+        match self.pattern {
+            ArpPattern::Up => k % n,
+            ArpPattern::Down => n - 1 - k % n,
+            ArpPattern::UpDown => {
+                if n == 1 {
+                    return 0;
+                }
+                let k = k % (n * 2 - 2);
+                if k < n {
+                    k
+                } else {
+                    n * 2 - 2 - k
+                }
+            }
+            ArpPattern::Random => rand() as usize % n,
+        }
+    }
+
+    /// 和音の音符から、start から len ステップの間に鳴らす音符を作る
+    pub fn expand(
+        &self,
+        notes: &[Event],
+        start: isize,
+        len: isize,
+        gate: isize,
+        rand: &mut dyn FnMut() -> u32,
+    ) -> Vec<Event> {
+        let mut sorted: Vec<&Event> = notes.iter().collect();
+        sorted.sort_by_key(|e| e.v1);
+        if sorted.is_empty() || self.rate <= 0 {
+            return vec![];
+        }
+        let gate = if self.gate > 0 { self.gate } else { gate };
+        let mut result = vec![];
+        let mut k = 0;
+        while self.rate * (k as isize) < len {
+            let time = start + self.rate * k as isize;
+            let step_len = self.rate.min(start + len - time);
+            let mut e = sorted[self.index(k, sorted.len(), rand)].clone();
+            e.time = time;
+            e.v2 = (step_len * gate / 100).max(1);
+            result.push(e);
+            k += 1;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord() -> Vec<Event> {
+        [64, 60, 67]
+            .iter()
+            .map(|no| Event::note(0, 0, *no, 96, 100))
+            .collect()
+    }
+
+    #[test]
+    fn strum_delays_each_note() {
+        let mut notes = chord();
+        let strum = Strum {
+            down: true,
+            step: 4,
+            keep_end: true,
+        };
+        strum.apply(&mut notes);
+        let list: Vec<(isize, isize, isize)> = notes.iter().map(|e| (e.time, e.v1, e.v2)).collect();
+        assert_eq!(list, vec![(0, 67, 96), (4, 64, 92), (8, 60, 88)]);
+    }
+
+    #[test]
+    fn arp_repeats_pattern_over_length() {
+        let mut arp = Arp {
+            pattern: ArpPattern::UpDown,
+            rate: 24,
+            gate: 50,
+        };
+        let mut rand = || 0;
+        let notes = arp.expand(&chord(), 96, 120, 80, &mut rand);
+        let list: Vec<(isize, isize, isize)> = notes.iter().map(|e| (e.time, e.v1, e.v2)).collect();
+        assert_eq!(
+            list,
+            vec![
+                (96, 60, 12),
+                (120, 64, 12),
+                (144, 67, 12),
+                (168, 64, 12),
+                (192, 60, 12)
+            ]
+        );
+        // ゲートの指定がなければ和音のゲート
+        arp.pattern = ArpPattern::Down;
+        arp.gate = 0;
+        let notes = arp.expand(&chord(), 0, 36, 80, &mut rand);
+        let list: Vec<(isize, isize, isize)> = notes.iter().map(|e| (e.time, e.v1, e.v2)).collect();
+        assert_eq!(list, vec![(0, 67, 19), (24, 64, 9)]);
+    }
+}
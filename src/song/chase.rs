@@ -0,0 +1,243 @@
+//! chase - 途中から演奏するときの状態の復元
+//!
+//! 開始位置より前のイベントから、チャンネルごとの音色・CC・RPN/NRPN・ピッチベンドと
+//! テンポ・拍子・調号を求め、時間0のイベントとして並べ直す。
+use super::*;
+use std::collections::BTreeMap;
+
+const CC_BANK_MSB: isize = 0;
+const CC_DATA_ENTRY_MSB: isize = 6;
+const CC_BANK_LSB: isize = 32;
+const CC_DATA_ENTRY_LSB: isize = 38;
+const CC_DATA_INCREMENT: isize = 96;
+const CC_DATA_DECREMENT: isize = 97;
+const CC_NRPN_LSB: isize = 98;
+const CC_NRPN_MSB: isize = 99;
+const CC_RPN_LSB: isize = 100;
+const CC_RPN_MSB: isize = 101;
+const CC_ALL_SOUND_OFF: isize = 120;
+const CC_RESET_ALL_CONTROLLERS: isize = 121;
+const CC_POLY_MODE_ON: isize = 127;
+/// RPN/NRPNの未選択 (RPN NULL)
+const PARAM_NULL: (isize, isize) = (127, 127);
+
+/// RPN/NRPNの番号 (NRPNか, MSB, LSB)
+type ParamKey = (bool, isize, isize);
+
+/// 1チャンネル分の状態
+struct ChannelState {
+    bank_msb: Option<isize>,
+    bank_lsb: Option<isize>,
+    program: Option<isize>,
+    cc: BTreeMap<isize, isize>,
+    rpn: (isize, isize),
+    nrpn: (isize, isize),
+    /// 最後に選んだのがNRPNか
+    nrpn_selected: bool,
+    /// 値を送ったRPN/NRPN (最初に送った順, データMSB, データLSB)
+    params: Vec<(ParamKey, Option<isize>, Option<isize>)>,
+    pitch_bend: Option<isize>,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        Self {
+            bank_msb: None,
+            bank_lsb: None,
+            program: None,
+            cc: BTreeMap::new(),
+            rpn: PARAM_NULL,
+            nrpn: PARAM_NULL,
+            nrpn_selected: false,
+            params: vec![],
+            pitch_bend: None,
+        }
+    }
+
+    fn selected(&self) -> Option<ParamKey> {
+        let (msb, lsb) = if self.nrpn_selected {
+            self.nrpn
+        } else {
+            self.rpn
+        };
+        if (msb, lsb) == PARAM_NULL {
+            return None;
+        }
+        Some((self.nrpn_selected, msb, lsb))
+    }
+
+    /// 選択中のRPN/NRPNに値を送る
+    fn data_entry(&mut self, msb: Option<isize>, lsb: Option<isize>) {
+        let key = match self.selected() {
+            Some(key) => key,
+            None => return,
+        };
+        let i = match self.params.iter().position(|(k, _, _)| *k == key) {
+            Some(i) => i,
+            None => {
+                self.params.push((key, None, None));
+                self.params.len() - 1
+            }
+        };
+        let param = &mut self.params[i];
+        if msb.is_some() {
+            param.1 = msb;
+            // データMSBを送るとLSBは受け直しになる
+            param.2 = None;
+        }
+        if lsb.is_some() {
+            param.2 = lsb;
+        }
+    }
+
+    fn control_change(&mut self, no: isize, value: isize) {
+        match no {
+            CC_BANK_MSB => self.bank_msb = Some(value),
+            CC_BANK_LSB => self.bank_lsb = Some(value),
+            CC_RPN_MSB | CC_RPN_LSB => {
+                if no == CC_RPN_MSB {
+                    self.rpn.0 = value;
+                } else {
+                    self.rpn.1 = value;
+                }
+                self.nrpn_selected = false;
+            }
+            CC_NRPN_MSB | CC_NRPN_LSB => {
+                if no == CC_NRPN_MSB {
+                    self.nrpn.0 = value;
+                } else {
+                    self.nrpn.1 = value;
+                }
+                self.nrpn_selected = true;
+            }
+            CC_DATA_ENTRY_MSB => self.data_entry(Some(value), None),
+            CC_DATA_ENTRY_LSB => self.data_entry(None, Some(value)),
+            CC_DATA_INCREMENT | CC_DATA_DECREMENT => {}
+            CC_RESET_ALL_CONTROLLERS => self.reset_controllers(),
+            // チャンネルモードメッセージはその時だけの意味なので戻さない
+            CC_ALL_SOUND_OFF..=CC_POLY_MODE_ON => {}
+            _ => {
+                self.cc.insert(no, value);
+            }
+        }
+    }
+
+    /// リセットオールコントローラー (RP-015)
+    /// 音量・パン・音色の設定・エフェクトとRPN/NRPNの値は残し、ほかのCCとピッチベンドを戻す
+    fn reset_controllers(&mut self) {
+        self.cc
+            .retain(|no, _| matches!(*no, 7 | 10 | 70..=79 | 91..=95));
+        self.pitch_bend = None;
+        self.rpn = PARAM_NULL;
+        self.nrpn = PARAM_NULL;
+    }
+
+    /// 時間0のイベントにする (音色→CC→RPN/NRPN→ピッチベンドの順)
+    fn to_events(&self, ch: isize, events: &mut Vec<Event>) {
+        if let Some(v) = self.bank_msb {
+            events.push(Event::cc(0, ch, CC_BANK_MSB, v));
+        }
+        if let Some(v) = self.bank_lsb {
+            events.push(Event::cc(0, ch, CC_BANK_LSB, v));
+        }
+        if let Some(v) = self.program {
+            events.push(Event::voice(0, ch, v));
+        }
+        for (no, v) in self.cc.iter() {
+            events.push(Event::cc(0, ch, *no, *v));
+        }
+        let select = |events: &mut Vec<Event>, key: ParamKey| {
+            let (cc_msb, cc_lsb) = if key.0 {
+                (CC_NRPN_MSB, CC_NRPN_LSB)
+            } else {
+                (CC_RPN_MSB, CC_RPN_LSB)
+            };
+            events.push(Event::cc(0, ch, cc_msb, key.1));
+            events.push(Event::cc(0, ch, cc_lsb, key.2));
+        };
+        for (key, msb, lsb) in self.params.iter() {
+            select(events, *key);
+            if let Some(v) = msb {
+                events.push(Event::cc(0, ch, CC_DATA_ENTRY_MSB, *v));
+            }
+            if let Some(v) = lsb {
+                events.push(Event::cc(0, ch, CC_DATA_ENTRY_LSB, *v));
+            }
+        }
+        // 選択中のRPN/NRPNを元に戻す
+        if let Some(key) = self.selected() {
+            if self.params.last().map(|(k, _, _)| *k) != Some(key) {
+                select(events, key);
+            }
+        }
+        if let Some(v) = self.pitch_bend {
+            events.push(Event::pitch_bend(0, ch, v));
+        }
+    }
+}
+
+/// 開始位置より前のイベントから、時間0に置くイベントを作る
+pub(super) fn chase_events(events: &[Event], timepos: isize) -> Vec<Event> {
+    let mut before: Vec<&Event> = events.iter().filter(|e| e.time < timepos).collect();
+    before.sort_by_key(|e| e.time);
+    let mut result: Vec<Event> = vec![];
+    let mut channels: BTreeMap<isize, ChannelState> = BTreeMap::new();
+    for e in before {
+        match e.etype {
+            EventType::Meta => match e.v2 {
+                // テンポ・拍子・調号は最後の値だけを残す
+                0x51 | 0x58 | 0x59 => {
+                    result.retain(|r| r.etype != EventType::Meta || r.v2 != e.v2);
+                    result.push(Event {
+                        time: 0,
+                        ..e.clone()
+                    });
+                }
+                // 歌詞・マーカー・キューポイントはその時間だけの意味なので捨てる
+                0x05..=0x07 => {}
+                _ => result.push(Event {
+                    time: 0,
+                    ..e.clone()
+                }),
+            },
+            EventType::SysEx => result.push(Event {
+                time: 0,
+                ..e.clone()
+            }),
+            EventType::DirectSMF => {
+                let status = e.data.as_ref().and_then(|d| d.first()).copied();
+                // 鳴らしっぱなしにならないようノートオン・オフは捨てる
+                if !matches!(status, Some(0x80..=0x9F)) {
+                    result.push(Event {
+                        time: 0,
+                        ..e.clone()
+                    });
+                }
+            }
+            EventType::NoteOn | EventType::NoteOff => {}
+            EventType::Voice => {
+                let state = channels.entry(e.channel).or_insert_with(ChannelState::new);
+                state.program = Some(e.v1);
+            }
+            EventType::ControllChange => {
+                let state = channels.entry(e.channel).or_insert_with(ChannelState::new);
+                state.control_change(e.v1, e.v2);
+            }
+            EventType::PitchBend => {
+                let state = channels.entry(e.channel).or_insert_with(ChannelState::new);
+                state.pitch_bend = Some(e.v1);
+            }
+            EventType::PitchBendRange => {
+                // RPN 0,0 を選んで値を送るのと同じ
+                let state = channels.entry(e.channel).or_insert_with(ChannelState::new);
+                state.control_change(CC_RPN_MSB, 0);
+                state.control_change(CC_RPN_LSB, 0);
+                state.control_change(CC_DATA_ENTRY_MSB, e.v1);
+            }
+        }
+    }
+    for (ch, state) in channels.iter() {
+        state.to_events(*ch, &mut result);
+    }
+    result
+}
@@ -25,6 +25,8 @@ pub struct Event {
     pub v2: isize,
     pub v3: isize,
     pub data: Option<Vec<u8>>,
+    /// このイベントを生成したMMLの範囲
+    pub span: Option<Span>,
 }
 
 impl Event {
@@ -59,6 +61,7 @@ impl Event {
             v2: len,
             v3: vel,
             data: None,
+            span: None,
         }
     }
     pub fn voice(time: isize, channel: isize, value: isize) -> Self {
@@ -70,6 +73,7 @@ impl Event {
             v2: 0,
             v3: 0,
             data: None,
+            span: None,
         }
     }
     pub fn meta(time: isize, v1: isize, v2: isize, v3: isize, data_v: Vec<u8>) -> Self {
@@ -81,6 +85,7 @@ impl Event {
             v2,
             v3,
             data: Some(data_v),
+            span: None,
         }
     }
     /// generate SMF event type
@@ -93,6 +98,7 @@ impl Event {
             v2: 0,
             v3: 0,
             data: Some(data_v),
+            span: None,
         }
     }
     pub fn sysex(time: isize, data_v: &[SValue], checksum_mode: bool) -> Self {
@@ -110,6 +116,7 @@ impl Event {
                 v2: 0,
                 v3: 0,
                 data: Some(a),
+                span: None,
             };
         }
         // calc checksum
@@ -142,6 +149,7 @@ impl Event {
             v2: 0,
             v3: 0,
             data: Some(a),
+            span: None,
         }
     }
 
@@ -154,6 +162,7 @@ impl Event {
             v2: 0,
             v3: 0,
             data: Some(data_v),
+            span: None,
         }
     }
     /// ControllChange
@@ -166,6 +175,7 @@ impl Event {
             v2: value,
             v3: 0,
             data: None,
+            span: None,
         }
     }
     /// pitch_bend : 0..16383 (-8192 .. 0 .. 8191)
@@ -178,6 +188,7 @@ impl Event {
             v2: 0,
             v3: 0,
             data: None,
+            span: None,
         }
     }
     pub fn pitch_bend_range(time: isize, channel: isize, value: isize) -> Self {
@@ -189,6 +200,7 @@ impl Event {
             v2: 0,
             v3: 0,
             data: None,
+            span: None,
         }
     }
     /// dump data
@@ -16,6 +16,10 @@ pub struct Flags {
     pub end_flag: bool,
     pub max_loop: isize,
     pub function_needs_return_value: bool,
+    /// PlayFrom/PlayToの引数を評価中か (未定義のマーカーを後で探す)
+    pub marker_lookup: bool,
+    /// PlayFrom/PlayToの引数で見つからなかったマーカーの名前
+    pub marker_missing: Option<String>,
 }
 
 impl Flags {
@@ -31,6 +35,8 @@ impl Flags {
             end_flag: false,
             max_loop: 10000,
             function_needs_return_value: false,
+            marker_lookup: false,
+            marker_missing: None,
         }
     }
 }
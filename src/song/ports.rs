@@ -0,0 +1,151 @@
+//! ports - 複数ポートの割り当て
+//!
+//! CH(17)以上で使ったポートごとに音源のリセット(ResetGM/GS/XG)を届け、
+//! チャンネルとポートの割り当てをログに出す。
+use super::*;
+use std::collections::BTreeMap;
+
+/// 音源をリセットするSysExか (GM/GM2・GS・XG)
+fn is_reset_sysex(data: &[u8]) -> bool {
+    let gm = data.len() >= 5
+        && data[0..2] == [0xF0, 0x7E]
+        && data[3] == 0x09
+        && matches!(data[4], 0x01 | 0x03);
+    let gs = data.len() >= 8
+        && data[0..2] == [0xF0, 0x41]
+        && data[3..8] == [0x42, 0x12, 0x40, 0x00, 0x7F];
+    let xg =
+        data.len() >= 7 && data[0..2] == [0xF0, 0x43] && data[3..7] == [0x4C, 0x00, 0x00, 0x7E];
+    gm || gs || xg
+}
+
+/// GS/XGのリセットに、ポートに合わせた装置番号 (0x10 + ポート) を書く
+/// GMのユニバーサルなリセット (7F) はそのまま
+fn with_device_id(data: &[u8], port: isize) -> Vec<u8> {
+    let mut data = data.to_vec();
+    if data.len() > 2 && matches!(data[1], 0x41 | 0x43) {
+        data[2] = 0x10 + (port % 16) as u8;
+    }
+    data
+}
+
+/// チャンネルを使うイベントか
+fn is_channel_event(e: &Event) -> bool {
+    !matches!(
+        e.etype,
+        EventType::Meta | EventType::SysEx | EventType::DirectSMF
+    )
+}
+
+/// ポートを切り替えるメタイベント(FF 21)のポート番号
+fn port_of_meta(e: &Event) -> Option<isize> {
+    match (&e.etype, &e.data) {
+        (EventType::Meta, Some(data)) if e.v2 == 0x21 && !data.is_empty() => Some(data[0] as isize),
+        _ => None,
+    }
+}
+
+/// トラックでのポートの使い方
+struct PortUse {
+    /// 使ったチャンネル (0〜15)
+    channels: Vec<isize>,
+    /// ポートを切り替えた直後のイベントの位置と時間 (リセットを置く)
+    pos: usize,
+    time: isize,
+}
+
+/// トラックで使ったポート (ポート番号 → 使い方)
+fn track_ports(trk: &Track) -> BTreeMap<isize, PortUse> {
+    let mut ports: BTreeMap<isize, PortUse> = BTreeMap::new();
+    let mut port = 0;
+    // ポートを切り替えた直後の位置
+    let mut switched: Option<(usize, isize)> = None;
+    for (i, e) in trk.events.iter().enumerate() {
+        if let Some(p) = port_of_meta(e) {
+            port = p;
+            switched = Some((i + 1, e.time));
+            continue;
+        }
+        if !is_channel_event(e) {
+            continue;
+        }
+        let entry = ports.entry(port).or_insert_with(|| PortUse {
+            channels: vec![],
+            pos: switched.map_or(i, |s| s.0),
+            time: switched.map_or(e.time, |s| s.1),
+        });
+        if !entry.channels.contains(&e.channel) {
+            entry.channels.push(e.channel);
+        }
+    }
+    ports
+}
+
+impl Song {
+    /// ポートごとに音源のリセットを届け、割り当てをログに出す (ポートが1つなら何もしない)
+    pub fn assign_ports(&mut self) {
+        // ポートごとの、チャンネルを使うトラック
+        let mut ports: BTreeMap<isize, Vec<(usize, PortUse)>> = BTreeMap::new();
+        for (no, trk) in self.tracks.iter().enumerate() {
+            for (port, used) in track_ports(trk) {
+                ports.entry(port).or_default().push((no, used));
+            }
+        }
+        if ports.len() < 2 {
+            return;
+        }
+        // リセットを送ったポートと、ほかのポートにも送るリセット
+        let mut reset_ports: Vec<isize> = vec![];
+        let mut resets: Vec<Event> = vec![];
+        for trk in self.tracks.iter() {
+            let mut port = 0;
+            for e in trk.events.iter() {
+                if let Some(p) = port_of_meta(e) {
+                    port = p;
+                }
+                if e.etype != EventType::SysEx || !is_reset_sysex(e.data.as_deref().unwrap_or(&[]))
+                {
+                    continue;
+                }
+                if !reset_ports.contains(&port) {
+                    reset_ports.push(port);
+                }
+                if reset_ports[0] == port {
+                    resets.push(e.clone());
+                }
+            }
+        }
+        let mut inserts: Vec<(usize, usize, isize, isize)> = vec![];
+        for (port, tracks) in ports.iter() {
+            let list: Vec<String> = tracks
+                .iter()
+                .flat_map(|(no, used)| {
+                    used.channels
+                        .iter()
+                        .map(move |ch| format!("TR({})=CH({})", no, port * 16 + ch + 1))
+                })
+                .collect();
+            let mut msg = format!("[PORT] {}: {}", port, list.join(", "));
+            if !resets.is_empty() && !reset_ports.contains(port) {
+                let (no, used) = &tracks[0];
+                inserts.push((*no, used.pos, used.time, *port));
+                msg.push_str(&format!(" (reset -> TR({}))", no));
+            }
+            self.add_log(msg);
+        }
+        // ポートを切り替えた直後 (チャンネルのイベントより前) に置く。後ろの位置から入れる
+        // ほかのイベントと同じく予算を確保し、超えたら入れない
+        inserts.sort();
+        for (no, pos, time, port) in inserts.into_iter().rev() {
+            for (i, r) in resets.iter().enumerate() {
+                let mut e = r.clone();
+                e.time = e.time.max(time);
+                e.data = Some(with_device_id(r.data.as_deref().unwrap_or(&[]), port));
+                if !self.reserve_event(&e) {
+                    return;
+                }
+                self.tracks[no].events.insert(pos + i, e);
+            }
+        }
+    }
+}
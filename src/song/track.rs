@@ -1,5 +1,6 @@
 //! song: トラックと演奏パラメータの管理
 use super::*;
+use crate::chord::ChordOptions;
 use std::collections::HashMap;
 
 const CC_MAIN_VOLUME: isize = 7;
@@ -120,6 +121,8 @@ pub struct WriteCtx<'a> {
     pub max_event_bytes: usize,
     pub event_bytes: usize,
     pub event_limit_exceeded: bool,
+    /// 書き込むイベントの元になったMMLの範囲
+    pub span: Option<Span>,
 }
 
 impl<'a> WriteCtx<'a> {
@@ -412,6 +415,8 @@ impl NoteParam {
 pub struct Track {
     pub timepos: isize,
     pub channel: isize,
+    /// CH() でチャンネルを指定したか (lintで使う)
+    pub channel_specified: bool,
     pub length: isize,
     pub octave: isize,
     pub velocity: isize,
@@ -425,12 +430,22 @@ pub struct Track {
     pub qlen_is_step: bool,
     pub timing: isize,
     pub port: isize,
+    /// ポート番号を CH(17以上) で決めたか (CH(1〜16)に戻すとポート0に戻す)
+    pub port_from_channel: bool,
     pub track_key: isize,
     pub tie_mode: TieMode, // Slur(#7)
     pub tie_value: isize,
     pub bend_range: isize,
     pub pitch_bend: isize,
     pub program_change: isize,
+    /// コードネーム(Chord)の配置の設定
+    pub chord_opt: ChordOptions,
+    /// 前に書いたコードの音符番号 (ボイスリーディングに使う)
+    pub chord_prev: Vec<isize>,
+    /// 和音のストローク (Strum)
+    pub strum: Option<Strum>,
+    /// 和音のアルペジオ (Arp) --- Strumより優先する
+    pub arp: Option<Arp>,
     /// 音符属性の先行指定 (v/q/t/o/l)
     pub v_opt: NoteParam,
     pub q_opt: NoteParam,
@@ -474,6 +489,7 @@ impl Track {
             timing: 0,
             track_key: 0,
             port: 0,
+            port_from_channel: false,
             tie_mode: TieMode::Port,
             tie_value: 0,
             v_sub: vec![0],
@@ -482,6 +498,10 @@ impl Track {
             v_sub_on_note: vec![None],
             v_sub_on_cycle: vec![None],
             program_change: 0,
+            chord_opt: ChordOptions::default(),
+            chord_prev: vec![],
+            strum: None,
+            arp: None,
             cc_on_time_freq: 4,
             pb_on_time_freq: 0,
             v_opt: NoteParam::new(),
@@ -490,6 +510,7 @@ impl Track {
             o_opt: NoteParam::new(),
             l_opt: NoteParam::new(),
             channel,
+            channel_specified: false,
             events: vec![],
             tie_notes: vec![],
             bend_range: -1,
@@ -545,68 +566,25 @@ impl Track {
         // sort_byなら要素の順序は保持される
         self.events.sort_by(|a, b| a.time.cmp(&b.time));
     }
-    pub fn play_from(&mut self, timepos: isize) {
-        let mut events: Vec<Event> = vec![];
-        let mut cc_values: Vec<isize> = vec![];
-        let mut voice: isize = -1;
-        let mut ch: isize = 0;
-        for _ in 0..128 {
-            cc_values.push(-1);
-        }
-        for e in self.events.iter() {
-            match e.etype {
-                EventType::Meta | EventType::SysEx => {
-                    let mut e2 = e.clone();
-                    e2.time -= timepos;
-                    if e2.time < 0 {
-                        e2.time = 0;
-                    }
-                    events.push(e2);
-                }
-                EventType::NoteOn => {
-                    let mut e2 = e.clone();
-                    e2.time -= timepos;
-                    if e2.time < 0 {
-                        continue;
-                    }
-                    events.push(e2);
-                }
-                EventType::Voice => {
-                    let mut e2 = e.clone();
-                    e2.time -= timepos;
-                    if e2.time < 0 {
-                        voice = e2.v1;
-                        ch = e2.channel;
-                        continue;
-                    }
-                    events.push(e2);
-                }
-                EventType::ControllChange => {
-                    let mut e2 = e.clone();
-                    e2.time -= timepos;
-                    if e2.time < 0 {
-                        cc_values[e2.v1 as usize] = e2.v2;
-                        ch = e2.channel;
-                        continue;
-                    }
-                    events.push(e2);
-                }
-                EventType::NoteOff => {}
-                EventType::PitchBend => {}      // TODO: #8
-                EventType::PitchBendRange => {} // TODO: #8
-                EventType::DirectSMF => {}
+    /// 指定位置より後のイベントを取り除く (またがる音符は指定位置で切る)
+    pub fn play_to(&mut self, timepos: isize) {
+        self.events.retain(|e| e.time < timepos);
+        for e in self.events.iter_mut() {
+            if e.etype == EventType::NoteOn && e.time + e.v2 > timepos {
+                e.v2 = timepos - e.time;
             }
         }
-        // add cc
-        for no in 0..128 {
-            if cc_values[no] < 0 {
+    }
+    /// 指定位置から演奏する (それより前の音色やCCなどの状態は時間0に置き直す)
+    pub fn play_from(&mut self, timepos: isize) {
+        let mut events = chase::chase_events(&self.events, timepos);
+        for e in self.events.iter() {
+            if e.time < timepos {
                 continue;
             }
-            events.push(Event::cc(0, ch, no as isize, cc_values[no as usize]));
-        }
-        // voice
-        if voice >= 0 {
-            events.push(Event::voice(0, ch, voice));
+            let mut e2 = e.clone();
+            e2.time -= timepos;
+            events.push(e2);
         }
         self.events = events;
     }
@@ -885,7 +863,7 @@ impl Track {
         }
         let time = time.saturating_add(opt.delay);
         let ch = self.channel;
-        let event = match target {
+        let mut event = match target {
             WriteTarget::CC(no) => {
                 let v = value_range(0, v, 127);
                 Event::cc(time, ch, no, v)
@@ -900,6 +878,7 @@ impl Track {
                 Event::pitch_bend(time, ch, v)
             }
         };
+        event.span = ctx.span;
         if !ctx.reserve_event(&event) {
             return false;
         }
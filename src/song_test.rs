@@ -166,6 +166,106 @@ mod note_tests {
         assert_eq!(notes[0].v1, 62);
     }
 
+    #[test]
+    fn play_from_chases_state_per_channel() {
+        let mut song = test_mml(
+            "Tempo(100) TimeSignature(3,4) KeySignature(D) RPN(0,0,12) Tempo(140) \
+             CH(1) Voice(49,8,0) v100 y1,10 y11,90 PitchBend(2000) r4 y1,20 c \
+             CH(2) y99,1 y98,8 y6,70 BR(2) PitchBend(-500) y7,80 d Time(2:1:0) CH(1) e",
+        );
+        song.play_from = 96 * 3;
+        song.play_from_all_track();
+        let cc = |e: &Event| -> (isize, isize, isize) { (e.channel, e.v1, e.v2) };
+        let events = &song.tracks[0].events;
+        let at_zero: Vec<&Event> = events.iter().filter(|e| e.time == 0).collect();
+        // テンポ・拍子・調号は最後の値だけ (元の順番のまま)
+        let metas: Vec<(isize, Vec<u8>)> = at_zero
+            .iter()
+            .filter(|e| e.etype == EventType::Meta)
+            .map(|e| (e.v2, e.data.clone().unwrap()))
+            .collect();
+        assert_eq!(
+            metas,
+            vec![
+                (0x58, vec![3, 2, 24, 8]),
+                (0x59, vec![2, 0]),
+                (0x51, vec![0x06, 0x8A, 0x1B])
+            ]
+        );
+        // CH1: バンク→音色→CC→RPN→ピッチベンド
+        let ch1: Vec<(EventType, (isize, isize, isize))> = at_zero
+            .iter()
+            .filter(|e| {
+                e.channel == 0 && e.etype != EventType::Meta && e.etype != EventType::NoteOn
+            })
+            .map(|e| (e.etype.clone(), cc(e)))
+            .collect();
+        assert_eq!(
+            ch1,
+            vec![
+                (EventType::ControllChange, (0, 0, 8)),
+                (EventType::ControllChange, (0, 32, 0)),
+                (EventType::Voice, (0, 48, 0)),
+                (EventType::ControllChange, (0, 1, 20)),
+                (EventType::ControllChange, (0, 11, 90)),
+                (EventType::ControllChange, (0, 101, 0)),
+                (EventType::ControllChange, (0, 100, 0)),
+                (EventType::ControllChange, (0, 6, 12)),
+                (EventType::PitchBend, (0, 2000 + 8192, 0)),
+            ]
+        );
+        // CH2: NRPNとベンド幅を送った順に戻し、最後に選んでいたRPNで終わる
+        let ch2: Vec<(isize, isize, isize)> = at_zero
+            .iter()
+            .filter(|e| e.channel == 1 && e.etype == EventType::ControllChange)
+            .map(|e| cc(e))
+            .collect();
+        assert_eq!(
+            ch2,
+            vec![
+                (1, 7, 80),
+                (1, 99, 1),
+                (1, 98, 8),
+                (1, 6, 70),
+                (1, 101, 0),
+                (1, 100, 0),
+                (1, 6, 2),
+            ]
+        );
+        assert!(at_zero
+            .iter()
+            .any(|e| e.etype == EventType::PitchBend && e.channel == 1 && e.v1 == 8192 - 500));
+        // 開始位置より前の音符は出力しない
+        let notes: Vec<(isize, isize)> = events
+            .iter()
+            .filter(|e| e.etype == EventType::NoteOn)
+            .map(|e| (e.time, e.v1))
+            .collect();
+        assert_eq!(notes, vec![(0, 64)]);
+    }
+
+    #[test]
+    fn play_from_applies_reset_all_controllers() {
+        let mut song =
+            test_mml("y1,64 y7,90 r4 y121,0 r4 y11,80 PitchBend(100) r1 PlayFrom(2:1:0) c");
+        song.play_from_all_track();
+        let at_zero: Vec<(EventType, isize, isize)> = song.tracks[0]
+            .events
+            .iter()
+            .filter(|e| e.time == 0 && e.etype != EventType::NoteOn)
+            .map(|e| (e.etype.clone(), e.v1, e.v2))
+            .collect();
+        // CC121より前のCC1は消え、音量は残る。チャンネルモードメッセージは書かない
+        assert_eq!(
+            at_zero,
+            vec![
+                (EventType::ControllChange, 7, 90),
+                (EventType::ControllChange, 11, 80),
+                (EventType::PitchBend, 100 + 8192, 0),
+            ]
+        );
+    }
+
     #[test]
     fn on_note_values_stop_or_cycle_as_configured() {
         let mut track = Track::new(96, 0);
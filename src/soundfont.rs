@@ -0,0 +1,718 @@
+//! SoundFont - SF2ファイルを読み込み、音色(プリセット)から鳴らすサンプルを選ぶ
+//!
+//! RIFF(sfbk)のサンプルデータ(smpl)と、プリセット・インストゥルメント・サンプルの
+//! ヘッダ(pdta)を読む。モジュレーター(pmod/imod)は読み飛ばし、既定の動作だけを使う。
+
+use std::fmt;
+
+/// SF2の読み込みエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SoundFontError {
+    /// RIFF(sfbk)で始まっていない
+    NotSoundFont,
+    /// チャンクのサイズがデータの長さを超えている
+    ChunkSize(String),
+    /// 必要なチャンクがない
+    MissingChunk(&'static str),
+    /// レコードの番号が範囲外
+    BrokenIndex(&'static str),
+}
+
+impl fmt::Display for SoundFontError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SoundFontError::NotSoundFont => write!(f, "Not SoundFont (SF2) file"),
+            SoundFontError::ChunkSize(id) => {
+                write!(f, "SoundFont chunk '{}' size exceeds input data", id)
+            }
+            SoundFontError::MissingChunk(id) => write!(f, "SoundFont chunk '{}' not found", id),
+            SoundFontError::BrokenIndex(id) => write!(f, "SoundFont '{}' has a broken index", id),
+        }
+    }
+}
+
+impl std::error::Error for SoundFontError {}
+
+/// ジェネレーターの番号 (SF2.04 8.1.2)
+mod generator {
+    pub const START_ADDRS_OFFSET: u16 = 0;
+    pub const END_ADDRS_OFFSET: u16 = 1;
+    pub const STARTLOOP_ADDRS_OFFSET: u16 = 2;
+    pub const ENDLOOP_ADDRS_OFFSET: u16 = 3;
+    pub const START_ADDRS_COARSE_OFFSET: u16 = 4;
+    pub const END_ADDRS_COARSE_OFFSET: u16 = 12;
+    pub const CHORUS_EFFECTS_SEND: u16 = 15;
+    pub const REVERB_EFFECTS_SEND: u16 = 16;
+    pub const PAN: u16 = 17;
+    pub const DELAY_VOL_ENV: u16 = 33;
+    pub const ATTACK_VOL_ENV: u16 = 34;
+    pub const HOLD_VOL_ENV: u16 = 35;
+    pub const DECAY_VOL_ENV: u16 = 36;
+    pub const SUSTAIN_VOL_ENV: u16 = 37;
+    pub const RELEASE_VOL_ENV: u16 = 38;
+    pub const INSTRUMENT: u16 = 41;
+    pub const KEY_RANGE: u16 = 43;
+    pub const VEL_RANGE: u16 = 44;
+    pub const STARTLOOP_ADDRS_COARSE_OFFSET: u16 = 45;
+    pub const KEYNUM: u16 = 46;
+    pub const VELOCITY: u16 = 47;
+    pub const INITIAL_ATTENUATION: u16 = 48;
+    pub const ENDLOOP_ADDRS_COARSE_OFFSET: u16 = 50;
+    pub const COARSE_TUNE: u16 = 51;
+    pub const FINE_TUNE: u16 = 52;
+    pub const SAMPLE_ID: u16 = 53;
+    pub const SAMPLE_MODES: u16 = 54;
+    pub const SCALE_TUNING: u16 = 56;
+    pub const EXCLUSIVE_CLASS: u16 = 57;
+    pub const OVERRIDING_ROOT_KEY: u16 = 58;
+    pub const COUNT: usize = 61;
+}
+
+/// ゾーン (鍵盤・ベロシティの範囲ごとのジェネレーターの組)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Zone {
+    gens: Vec<(u16, u16)>,
+}
+
+impl Zone {
+    fn get(&self, oper: u16) -> Option<u16> {
+        self.gens.iter().rev().find(|g| g.0 == oper).map(|g| g.1)
+    }
+    /// 鍵盤・ベロシティの範囲に入っているか (指定がなければ globalの指定、それもなければ全体)
+    fn matches(&self, global: Option<&Zone>, key: u8, velocity: u8) -> bool {
+        let in_range = |oper: u16, v: u8| {
+            let amount = self
+                .get(oper)
+                .or_else(|| global.and_then(|g| g.get(oper)))
+                .unwrap_or(0x7F00);
+            let (lo, hi) = ((amount & 0xFF) as u8, (amount >> 8) as u8);
+            lo <= v && v <= hi
+        };
+        in_range(generator::KEY_RANGE, key) && in_range(generator::VEL_RANGE, velocity)
+    }
+}
+
+/// 音色
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    global: Option<Zone>,
+    zones: Vec<Zone>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Instrument {
+    global: Option<Zone>,
+    zones: Vec<Zone>,
+}
+
+/// サンプルのヘッダ (位置はsmplの先頭からのサンプル数)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleHeader {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub sample_rate: u32,
+    pub original_pitch: u8,
+    pub pitch_correction: i8,
+}
+
+/// 音量の包絡線 (各段の長さは秒)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VolumeEnvelope {
+    pub delay: f64,
+    pub attack: f64,
+    pub hold: f64,
+    pub decay: f64,
+    /// サステインでの減衰量(センチベル)
+    pub sustain_cb: f64,
+    pub release: f64,
+}
+
+/// ノートを鳴らすためにまとめた1つのサンプルの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub sample_rate: u32,
+    /// 0:ループなし 1:ループ 3:リリースまでループ
+    pub loop_mode: u16,
+    pub root_key: i32,
+    /// ルートキーからの音程の補正(セント)
+    pub tune: i32,
+    /// 1半音あたりのセント
+    pub scale_tuning: i32,
+    /// 音量の減衰(センチベル)
+    pub attenuation: f64,
+    /// -500(左)〜500(右)
+    pub pan: f64,
+    /// エフェクトへの送り 0〜1
+    pub reverb: f64,
+    pub chorus: f64,
+    pub exclusive_class: u16,
+    /// 固定のキー・ベロシティ (keynum / velocity)
+    pub key: Option<u8>,
+    pub velocity: Option<u8>,
+    pub envelope: VolumeEnvelope,
+}
+
+/// 読み込んだSF2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoundFont {
+    pub presets: Vec<Preset>,
+    instruments: Vec<Instrument>,
+    pub samples: Vec<SampleHeader>,
+    /// 16bitのサンプルデータ
+    pub data: Vec<i16>,
+}
+
+struct Chunk<'a> {
+    id: &'a [u8],
+    data: &'a [u8],
+}
+
+/// RIFFのチャンクを並べる
+fn chunks(bin: &[u8]) -> Result<Vec<Chunk<'_>>, SoundFontError> {
+    let mut res = vec![];
+    let mut pos = 0;
+    while pos + 8 <= bin.len() {
+        let id = &bin[pos..pos + 4];
+        let size = read_u32(bin, pos + 4) as usize;
+        let start = pos + 8;
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end <= bin.len())
+            .ok_or_else(|| SoundFontError::ChunkSize(String::from_utf8_lossy(id).to_string()))?;
+        res.push(Chunk {
+            id,
+            data: &bin[start..end],
+        });
+        // 奇数のサイズは1バイト詰める
+        pos = end + (size & 1);
+    }
+    Ok(res)
+}
+
+/// LIST チャンクの中身 (種類が一致するもの)
+fn list<'a>(all: &[Chunk<'a>], kind: &[u8]) -> Option<Vec<Chunk<'a>>> {
+    all.iter()
+        .find(|c| c.id == b"LIST" && c.data.len() >= 4 && &c.data[0..4] == kind)
+        .and_then(|c| chunks(&c.data[4..]).ok())
+}
+
+fn find<'a>(all: &[Chunk<'a>], id: &'static str) -> Result<&'a [u8], SoundFontError> {
+    all.iter()
+        .find(|c| c.id == id.as_bytes())
+        .map(|c| c.data)
+        .ok_or(SoundFontError::MissingChunk(id))
+}
+
+fn read_u16(bin: &[u8], pos: usize) -> u16 {
+    bin[pos] as u16 | (bin[pos + 1] as u16) << 8
+}
+
+fn read_u32(bin: &[u8], pos: usize) -> u32 {
+    read_u16(bin, pos) as u32 | (read_u16(bin, pos + 2) as u32) << 16
+}
+
+fn read_name(bin: &[u8]) -> String {
+    let end = bin.iter().position(|&b| b == 0).unwrap_or(bin.len());
+    String::from_utf8_lossy(&bin[..end]).trim_end().to_string()
+}
+
+/// 固定長のレコードに分ける
+fn records(data: &[u8], size: usize) -> Vec<&[u8]> {
+    data.chunks_exact(size).collect()
+}
+
+/// bag(ゾーンの一覧)とgen(ジェネレーター)から、headerの i 番目のゾーンを作る
+/// 最初のゾーンに終端のジェネレーター(instrument/sampleID)がなければglobalゾーン
+fn read_zones(
+    bags: &[&[u8]],
+    gens: &[&[u8]],
+    bag_start: usize,
+    bag_end: usize,
+    terminal: u16,
+    name: &'static str,
+) -> Result<(Option<Zone>, Vec<Zone>), SoundFontError> {
+    if bag_start > bag_end || bag_end >= bags.len() {
+        return Err(SoundFontError::BrokenIndex(name));
+    }
+    let mut global = None;
+    let mut zones = vec![];
+    for b in bag_start..bag_end {
+        let gen_start = read_u16(bags[b], 0) as usize;
+        let gen_end = read_u16(bags[b + 1], 0) as usize;
+        if gen_start > gen_end || gen_end > gens.len() {
+            return Err(SoundFontError::BrokenIndex(name));
+        }
+        let zone = Zone {
+            gens: gens[gen_start..gen_end]
+                .iter()
+                .map(|g| (read_u16(g, 0), read_u16(g, 2)))
+                .collect(),
+        };
+        if zone.get(terminal).is_some() {
+            zones.push(zone);
+        } else if b == bag_start {
+            global = Some(zone);
+        }
+    }
+    Ok((global, zones))
+}
+
+/// SF2を読み込む
+pub fn parse(bin: &[u8]) -> Result<SoundFont, SoundFontError> {
+    if bin.len() < 12 || &bin[0..4] != b"RIFF" || &bin[8..12] != b"sfbk" {
+        return Err(SoundFontError::NotSoundFont);
+    }
+    let size = (read_u32(bin, 4) as usize).min(bin.len() - 8);
+    let top = chunks(&bin[12..8 + size])?;
+    let sdta = list(&top, b"sdta").ok_or(SoundFontError::MissingChunk("sdta"))?;
+    let pdta = list(&top, b"pdta").ok_or(SoundFontError::MissingChunk("pdta"))?;
+    let data: Vec<i16> = find(&sdta, "smpl")?
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect();
+
+    let phdr = records(find(&pdta, "phdr")?, 38);
+    let pbag = records(find(&pdta, "pbag")?, 4);
+    let pgen = records(find(&pdta, "pgen")?, 4);
+    let inst = records(find(&pdta, "inst")?, 22);
+    let ibag = records(find(&pdta, "ibag")?, 4);
+    let igen = records(find(&pdta, "igen")?, 4);
+    let shdr = records(find(&pdta, "shdr")?, 46);
+
+    // 最後のレコードは終端 (EOP/EOI/EOS)
+    let mut presets = vec![];
+    for w in phdr.windows(2) {
+        let (global, zones) = read_zones(
+            &pbag,
+            &pgen,
+            read_u16(w[0], 24) as usize,
+            read_u16(w[1], 24) as usize,
+            generator::INSTRUMENT,
+            "pbag",
+        )?;
+        presets.push(Preset {
+            name: read_name(&w[0][0..20]),
+            program: read_u16(w[0], 20),
+            bank: read_u16(w[0], 22),
+            global,
+            zones,
+        });
+    }
+    let mut instruments = vec![];
+    for w in inst.windows(2) {
+        let (global, zones) = read_zones(
+            &ibag,
+            &igen,
+            read_u16(w[0], 20) as usize,
+            read_u16(w[1], 20) as usize,
+            generator::SAMPLE_ID,
+            "ibag",
+        )?;
+        instruments.push(Instrument { global, zones });
+    }
+    let samples = shdr[..shdr.len().saturating_sub(1)]
+        .iter()
+        .map(|s| SampleHeader {
+            name: read_name(&s[0..20]),
+            start: read_u32(s, 20) as usize,
+            end: read_u32(s, 24) as usize,
+            loop_start: read_u32(s, 28) as usize,
+            loop_end: read_u32(s, 32) as usize,
+            sample_rate: read_u32(s, 36),
+            original_pitch: s[40],
+            pitch_correction: s[41] as i8,
+        })
+        .collect();
+    Ok(SoundFont {
+        presets,
+        instruments,
+        samples,
+        data,
+    })
+}
+
+/// タイムセント → 秒
+fn timecents(tc: i32) -> f64 {
+    2f64.powf(tc as f64 / 1200.0)
+}
+
+impl SoundFont {
+    /// バンク・プログラムの音色を探す (なければバンク0、ドラムはバンク128の0番)
+    pub fn find_preset(&self, bank: u16, program: u16) -> Option<&Preset> {
+        let find = |bank: u16, program: u16| {
+            self.presets
+                .iter()
+                .find(|p| p.bank == bank && p.program == program)
+        };
+        find(bank, program)
+            .or_else(|| {
+                if bank >= 128 {
+                    find(128, 0)
+                } else {
+                    find(0, program)
+                }
+            })
+            .or_else(|| {
+                self.presets
+                    .iter()
+                    .find(|p| (p.bank >= 128) == (bank >= 128))
+            })
+    }
+
+    /// 音色のキー・ベロシティで鳴らすサンプルの一覧
+    pub fn regions(&self, preset: &Preset, key: u8, velocity: u8) -> Vec<Region> {
+        let mut res = vec![];
+        for pz in preset.zones.iter() {
+            if !pz.matches(preset.global.as_ref(), key, velocity) {
+                continue;
+            }
+            let inst = match pz
+                .get(generator::INSTRUMENT)
+                .and_then(|i| self.instruments.get(i as usize))
+            {
+                Some(inst) => inst,
+                None => continue,
+            };
+            for iz in inst.zones.iter() {
+                if !iz.matches(inst.global.as_ref(), key, velocity) {
+                    continue;
+                }
+                if let Some(r) = self.region(preset, pz, inst, iz) {
+                    res.push(r);
+                }
+            }
+        }
+        res
+    }
+
+    fn region(&self, preset: &Preset, pz: &Zone, inst: &Instrument, iz: &Zone) -> Option<Region> {
+        // インストゥルメントの値は既定値を置き換え、プリセットの値はそれに加える
+        let mut g = [0i32; generator::COUNT];
+        for oper in [
+            generator::DELAY_VOL_ENV,
+            generator::ATTACK_VOL_ENV,
+            generator::HOLD_VOL_ENV,
+            generator::DECAY_VOL_ENV,
+            generator::RELEASE_VOL_ENV,
+        ] {
+            g[oper as usize] = -12000;
+        }
+        g[generator::SCALE_TUNING as usize] = 100;
+        g[generator::KEYNUM as usize] = -1;
+        g[generator::VELOCITY as usize] = -1;
+        g[generator::OVERRIDING_ROOT_KEY as usize] = -1;
+        let zones = [inst.global.as_ref(), Some(iz)];
+        for z in zones.iter().flatten() {
+            for &(oper, amount) in z.gens.iter() {
+                if (oper as usize) < generator::COUNT {
+                    g[oper as usize] = amount as i16 as i32;
+                }
+            }
+        }
+        let mut offset = [None; generator::COUNT];
+        let zones = [preset.global.as_ref(), Some(pz)];
+        for z in zones.iter().flatten() {
+            for &(oper, amount) in z.gens.iter() {
+                if (oper as usize) < generator::COUNT {
+                    offset[oper as usize] = Some(amount as i16 as i32);
+                }
+            }
+        }
+        for (oper, v) in offset.iter().enumerate() {
+            let oper = oper as u16;
+            // 範囲・番号・サンプルの位置はプリセットでは指定できない
+            let additive = !matches!(
+                oper,
+                generator::START_ADDRS_OFFSET
+                    | generator::END_ADDRS_OFFSET
+                    | generator::STARTLOOP_ADDRS_OFFSET
+                    | generator::ENDLOOP_ADDRS_OFFSET
+                    | generator::START_ADDRS_COARSE_OFFSET
+                    | generator::END_ADDRS_COARSE_OFFSET
+                    | generator::STARTLOOP_ADDRS_COARSE_OFFSET
+                    | generator::ENDLOOP_ADDRS_COARSE_OFFSET
+                    | generator::INSTRUMENT
+                    | generator::KEY_RANGE
+                    | generator::VEL_RANGE
+                    | generator::KEYNUM
+                    | generator::VELOCITY
+                    | generator::SAMPLE_ID
+                    | generator::SAMPLE_MODES
+                    | generator::EXCLUSIVE_CLASS
+                    | generator::OVERRIDING_ROOT_KEY
+            );
+            if let (true, Some(v)) = (additive, v) {
+                g[oper as usize] += v;
+            }
+        }
+        let get = |oper: u16| g[oper as usize];
+        let sample = self.samples.get(get(generator::SAMPLE_ID) as usize)?;
+        let addr = |base: usize, fine: u16, coarse: u16| {
+            (base as i64 + get(fine) as i64 + get(coarse) as i64 * 32768).max(0) as usize
+        };
+        let end = addr(
+            sample.end,
+            generator::END_ADDRS_OFFSET,
+            generator::END_ADDRS_COARSE_OFFSET,
+        )
+        .min(self.data.len());
+        let start = addr(
+            sample.start,
+            generator::START_ADDRS_OFFSET,
+            generator::START_ADDRS_COARSE_OFFSET,
+        )
+        .min(end);
+        let loop_start = addr(
+            sample.loop_start,
+            generator::STARTLOOP_ADDRS_OFFSET,
+            generator::STARTLOOP_ADDRS_COARSE_OFFSET,
+        );
+        let loop_end = addr(
+            sample.loop_end,
+            generator::ENDLOOP_ADDRS_OFFSET,
+            generator::ENDLOOP_ADDRS_COARSE_OFFSET,
+        )
+        .min(end);
+        let mut loop_mode = (get(generator::SAMPLE_MODES) & 3) as u16;
+        if loop_mode == 2 || loop_start >= loop_end || loop_start < start {
+            loop_mode = 0;
+        }
+        let root_key = match get(generator::OVERRIDING_ROOT_KEY) {
+            k @ 0..=127 => k,
+            _ => sample.original_pitch.min(127) as i32,
+        };
+        let fixed = |oper: u16| match get(oper) {
+            v @ 0..=127 => Some(v as u8),
+            _ => None,
+        };
+        Some(Region {
+            start,
+            end,
+            loop_start,
+            loop_end,
+            sample_rate: sample.sample_rate.max(1),
+            loop_mode,
+            root_key,
+            tune: get(generator::COARSE_TUNE) * 100
+                + get(generator::FINE_TUNE)
+                + sample.pitch_correction as i32,
+            scale_tuning: get(generator::SCALE_TUNING),
+            attenuation: get(generator::INITIAL_ATTENUATION).clamp(0, 1440) as f64,
+            pan: get(generator::PAN).clamp(-500, 500) as f64,
+            reverb: get(generator::REVERB_EFFECTS_SEND).clamp(0, 1000) as f64 / 1000.0,
+            chorus: get(generator::CHORUS_EFFECTS_SEND).clamp(0, 1000) as f64 / 1000.0,
+            exclusive_class: get(generator::EXCLUSIVE_CLASS).max(0) as u16,
+            key: fixed(generator::KEYNUM),
+            velocity: fixed(generator::VELOCITY),
+            envelope: VolumeEnvelope {
+                delay: timecents(get(generator::DELAY_VOL_ENV)),
+                attack: timecents(get(generator::ATTACK_VOL_ENV)),
+                hold: timecents(get(generator::HOLD_VOL_ENV)),
+                decay: timecents(get(generator::DECAY_VOL_ENV)),
+                sustain_cb: get(generator::SUSTAIN_VOL_ENV).clamp(0, 1440) as f64,
+                release: timecents(get(generator::RELEASE_VOL_ENV)),
+            },
+        })
+    }
+}
+
+/// テスト用の小さなSF2を作る
+#[cfg(test)]
+pub(crate) mod test_font {
+    /// ジェネレーター (番号, 値)
+    pub type Gens = Vec<(u16, u16)>;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut res = id.to_vec();
+        res.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        res.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            res.push(0);
+        }
+        res
+    }
+
+    fn list(kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = kind.to_vec();
+        for c in chunks {
+            data.extend_from_slice(c);
+        }
+        chunk(b"LIST", &data)
+    }
+
+    fn name(s: &str) -> Vec<u8> {
+        let mut res = s.as_bytes().to_vec();
+        res.resize(20, 0);
+        res
+    }
+
+    fn u16le(v: usize) -> [u8; 2] {
+        (v as u16).to_le_bytes()
+    }
+
+    /// (bag, gen) を作る。ゾーンごとのジェネレーターの一覧を受け取る
+    fn bags(zones: &[&Gens]) -> (Vec<u8>, Vec<u8>) {
+        let (mut bag, mut gens) = (vec![], vec![]);
+        let mut n = 0;
+        for z in zones {
+            bag.extend_from_slice(&u16le(n));
+            bag.extend_from_slice(&u16le(0));
+            for &(oper, amount) in z.iter() {
+                gens.extend_from_slice(&oper.to_le_bytes());
+                gens.extend_from_slice(&amount.to_le_bytes());
+                n += 1;
+            }
+        }
+        bag.extend_from_slice(&u16le(n));
+        bag.extend_from_slice(&u16le(0));
+        gens.extend_from_slice(&[0; 4]);
+        (bag, gens)
+    }
+
+    /// presets: (名前, バンク, プログラム, ゾーン), instruments: ゾーンの一覧
+    /// サンプルは1周期32サンプルのサイン波 (ルートキー69, 32*440Hz) を1つだけ持つ
+    pub fn build(presets: &[(&str, u16, u16, Vec<Gens>)], instruments: &[Vec<Gens>]) -> Vec<u8> {
+        let period = 32;
+        let mut smpl = vec![];
+        for i in 0..period * 8 {
+            let v = (i as f64 / period as f64 * 2.0 * std::f64::consts::PI).sin();
+            smpl.extend_from_slice(&((v * 16000.0) as i16).to_le_bytes());
+        }
+        smpl.extend_from_slice(&[0; 92]);
+
+        let mut phdr = vec![];
+        let mut zones: Vec<&Gens> = vec![];
+        for (n, bank, program, z) in presets {
+            phdr.extend_from_slice(&name(n));
+            phdr.extend_from_slice(&program.to_le_bytes());
+            phdr.extend_from_slice(&bank.to_le_bytes());
+            phdr.extend_from_slice(&u16le(zones.len()));
+            phdr.extend_from_slice(&[0; 12]);
+            zones.extend(z.iter());
+        }
+        phdr.extend_from_slice(&name("EOP"));
+        phdr.extend_from_slice(&[0; 4]);
+        phdr.extend_from_slice(&u16le(zones.len()));
+        phdr.extend_from_slice(&[0; 12]);
+        let (pbag, pgen) = bags(&zones);
+
+        let mut inst = vec![];
+        let mut zones: Vec<&Gens> = vec![];
+        for (i, z) in instruments.iter().enumerate() {
+            inst.extend_from_slice(&name(&format!("inst{}", i)));
+            inst.extend_from_slice(&u16le(zones.len()));
+            zones.extend(z.iter());
+        }
+        inst.extend_from_slice(&name("EOI"));
+        inst.extend_from_slice(&u16le(zones.len()));
+        let (ibag, igen) = bags(&zones);
+
+        let mut shdr = name("sine");
+        for v in [0u32, period as u32 * 8, 0, period as u32 * 8, 32 * 440] {
+            shdr.extend_from_slice(&v.to_le_bytes());
+        }
+        shdr.extend_from_slice(&[69, 0, 0, 0, 1, 0]);
+        shdr.extend_from_slice(&name("EOS"));
+        shdr.extend_from_slice(&[0; 26]);
+
+        let body = [
+            b"sfbk".to_vec(),
+            list(b"INFO", &[chunk(b"ifil", &[2, 0, 1, 0])]),
+            list(b"sdta", &[chunk(b"smpl", &smpl)]),
+            list(
+                b"pdta",
+                &[
+                    chunk(b"phdr", &phdr),
+                    chunk(b"pbag", &pbag),
+                    chunk(b"pmod", &[0; 10]),
+                    chunk(b"pgen", &pgen),
+                    chunk(b"inst", &inst),
+                    chunk(b"ibag", &ibag),
+                    chunk(b"imod", &[0; 10]),
+                    chunk(b"igen", &igen),
+                    chunk(b"shdr", &shdr),
+                ],
+            ),
+        ]
+        .concat();
+        chunk(b"RIFF", &body)
+    }
+
+    /// バンク0の0番(ループする音)と1番(1オクターブ上)、バンク128の0番(ドラム)を持つSF2
+    pub fn simple() -> Vec<u8> {
+        build(
+            &[
+                ("Sine", 0, 0, vec![vec![(41, 0)]]),
+                ("High", 0, 1, vec![vec![(51, 12), (41, 0)]]),
+                ("Drums", 128, 0, vec![vec![(41, 1)]]),
+            ],
+            &[
+                vec![vec![(54, 1), (38, 0xF448u16), (53, 0)]],
+                vec![vec![(57, 1), (53, 0)]],
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_presets_and_samples() {
+        let font = parse(&test_font::simple()).unwrap();
+        let names: Vec<&str> = font.presets.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Sine", "High", "Drums"]);
+        assert_eq!(font.samples.len(), 1);
+        assert_eq!(font.samples[0].original_pitch, 69);
+        assert_eq!(font.data.len(), 256 + 46);
+        assert_eq!(
+            parse(b"RIFF\0\0\0\0WAVE"),
+            Err(SoundFontError::NotSoundFont)
+        );
+    }
+
+    #[test]
+    fn preset_generators_are_added_to_the_instrument() {
+        let font = parse(&test_font::simple()).unwrap();
+        let preset = font.find_preset(0, 1).unwrap();
+        let r = font.regions(preset, 60, 100);
+        assert_eq!(r.len(), 1);
+        assert_eq!((r[0].root_key, r[0].tune, r[0].loop_mode), (69, 1200, 1));
+        // releaseVolEnv -3000 timecents
+        assert!((r[0].envelope.release - 2f64.powf(-2.5)).abs() < 1e-9);
+        // ないバンクはバンク0、ドラムはバンク128の0番
+        assert_eq!(font.find_preset(8, 1).unwrap().name, "High");
+        assert_eq!(font.find_preset(128, 25).unwrap().name, "Drums");
+        let drums = font.find_preset(128, 0).unwrap();
+        assert_eq!(font.regions(drums, 38, 100)[0].exclusive_class, 1);
+    }
+
+    #[test]
+    fn zones_are_chosen_by_key_and_velocity() {
+        let data = test_font::build(
+            &[("Split", 0, 0, vec![vec![(41, 0)]])],
+            &[vec![
+                // globalゾーン: ベロシティ64以上
+                vec![(44, 0x7F40)],
+                vec![(43, 0x3B00), (53, 0)],
+                vec![(43, 0x7F3C), (51, 0xFFF4), (53, 0)],
+            ]],
+        );
+        let font = parse(&data).unwrap();
+        let preset = &font.presets[0];
+        assert_eq!(font.regions(preset, 59, 100)[0].tune, 0);
+        assert_eq!(font.regions(preset, 60, 100)[0].tune, -1200);
+        assert!(font.regions(preset, 60, 10).is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 //! Source reader
+use crate::span::SpanOrigin;
 
 #[derive(Debug)]
 pub struct SourceCursor {
@@ -7,6 +8,8 @@ pub struct SourceCursor {
     src: Vec<char>,
     /// line number
     pub line: isize,
+    /// 読んでいる文字列の元のソースでの位置
+    pub origin: SpanOrigin,
 }
 
 impl SourceCursor {
@@ -16,8 +19,16 @@ impl SourceCursor {
             index: 0,
             src: source.chars().collect(),
             line: 0,
+            origin: SpanOrigin::Offset(0),
         }
     }
+    /// get_token_nest で読む中身の元のソースでの位置
+    pub fn origin_of_nest(&self, open_ch: char) -> SpanOrigin {
+        if self.peek_n(0) == open_ch {
+            return self.origin.at(self.index + 1);
+        }
+        self.origin.at(self.index)
+    }
     /// is eos
     pub fn is_eos(&self) -> bool {
         self.src.len() <= self.index
@@ -264,8 +275,24 @@ impl SourceCursor {
                     self.index += 1;
                     continue;
                 }
-                ' ' | '|' | '\t' => {
+                ' ' | '\t' => {
+                    self.next();
+                }
+                '|' => {
+                    // 小節線の後(改行も飛ばす)が"^"ならタイとして続行、"&"ならスラーとして読ませる
+                    // どちらでもなければ小節線として残す
+                    let tmp_index = self.index;
+                    let tmp_line = self.line;
                     self.next();
+                    self.skip_space_ret();
+                    match self.peek_n(0) {
+                        '^' => continue,
+                        '&' => break,
+                        _ => {}
+                    }
+                    self.index = tmp_index;
+                    self.line = tmp_line;
+                    break;
                 }
                 '\n' => {
                     // 改行があっても続く部分が"^"で始まれば続行
@@ -0,0 +1,221 @@
+//! Span - トークンの元のソースでの位置
+//!
+//! 字句解析はストトン表記を変換した後の文字列で行うため、
+//! 変換後の位置から元のソースの位置へ戻す対応表(SourceText)を持つ。
+
+/// 元のソースでの範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// ソースの番号 (Song::source_namesの添字)
+    pub source_no: usize,
+    /// 先頭の文字位置 (0始まり・文字単位)
+    pub start: usize,
+    /// 末尾の文字位置 (この位置は含まない)
+    pub end: usize,
+    /// 先頭の行番号 (0始まり)
+    pub line: isize,
+    /// 先頭の列番号 (0始まり・文字単位)
+    pub column: isize,
+}
+
+impl Span {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"source\":{},\"start\":{},\"end\":{},\"line\":{},\"column\":{}}}",
+            self.source_no, self.start, self.end, self.line, self.column
+        )
+    }
+}
+
+/// 字句解析する文字列が、元のソースのどこから来たか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanOrigin {
+    /// ソースの一部をそのまま読む。値は変換後のソースでの先頭位置
+    Offset(usize),
+    /// マクロの展開など実行時に組み立てた文字列。全てのトークンを展開元の範囲にする
+    Expand(Option<Span>),
+}
+
+impl SpanOrigin {
+    /// 文字列の`index`文字目から読む場合の位置
+    pub fn at(&self, index: usize) -> SpanOrigin {
+        match self {
+            SpanOrigin::Offset(base) => SpanOrigin::Offset(base + index),
+            SpanOrigin::Expand(span) => SpanOrigin::Expand(*span),
+        }
+    }
+}
+
+/// 変換後の位置と元の位置の対応 (1区間分)
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    /// 変換後のソースでの先頭位置
+    conv: usize,
+    /// 元のソースでの先頭位置
+    orig: usize,
+    /// ストトン表記を置換した区間なら元の文字数 (Noneなら1文字ずつ対応)
+    replaced: Option<usize>,
+}
+
+/// 変換後のソースの位置を元のソースの行・列へ戻す対応表
+#[derive(Debug, Clone, Default)]
+pub struct SourceText {
+    segments: Vec<Segment>,
+    /// 元のソースの各行の先頭位置
+    line_starts: Vec<usize>,
+}
+
+impl SourceText {
+    /// 変換していないソース (全ての位置が1文字ずつ対応する)
+    pub fn new(original: &str) -> Self {
+        let mut st = Self::with_original(original);
+        st.push_same(0, 0);
+        st
+    }
+    /// 対応表が空のSourceTextを作る。push_same/push_replaceで対応を追加する
+    pub fn with_original(original: &str) -> Self {
+        let mut line_starts = vec![0];
+        for (i, c) in original.chars().enumerate() {
+            if c == '\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        Self {
+            segments: vec![],
+            line_starts,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+    /// 変換後の`conv`以降が、元の`orig`以降と1文字ずつ対応する
+    pub fn push_same(&mut self, conv: usize, orig: usize) {
+        if let Some(last) = self.segments.last() {
+            if last.replaced.is_none() && last.conv + orig == last.orig + conv {
+                return; // 直前の区間の続き
+            }
+        }
+        self.segments.push(Segment {
+            conv,
+            orig,
+            replaced: None,
+        });
+    }
+    /// 変換後の`conv`以降が、元の`orig`から`len`文字を置換したもの
+    pub fn push_replace(&mut self, conv: usize, orig: usize, len: usize) {
+        self.segments.push(Segment {
+            conv,
+            orig,
+            replaced: Some(len),
+        });
+    }
+    /// 変換後の先頭`n`文字を取り除いたことを反映する (ex) trim
+    pub fn remove_head(&mut self, n: usize) {
+        for seg in self.segments.iter_mut() {
+            if seg.conv >= n {
+                seg.conv -= n;
+            } else {
+                if seg.replaced.is_none() {
+                    seg.orig += n - seg.conv;
+                }
+                seg.conv = 0;
+            }
+        }
+    }
+    fn segment(&self, conv: usize) -> Option<&Segment> {
+        let i = self.segments.partition_point(|s| s.conv <= conv);
+        if i == 0 {
+            return None;
+        }
+        self.segments.get(i - 1)
+    }
+    /// 変換後の位置を元の位置にする
+    pub fn to_original(&self, conv: usize) -> usize {
+        match self.segment(conv) {
+            Some(seg) => match seg.replaced {
+                Some(_) => seg.orig,
+                None => seg.orig + (conv - seg.conv),
+            },
+            None => conv,
+        }
+    }
+    /// 変換後の末尾位置(含まない)を元の末尾位置にする
+    pub fn to_original_end(&self, conv_end: usize) -> usize {
+        if conv_end == 0 {
+            return self.to_original(0);
+        }
+        match self.segment(conv_end - 1) {
+            Some(seg) => match seg.replaced {
+                Some(len) => seg.orig + len,
+                None => seg.orig + (conv_end - seg.conv),
+            },
+            None => conv_end,
+        }
+    }
+    /// 元の位置を変換後の位置にする
+    pub fn to_converted(&self, orig: usize) -> usize {
+        let i = self.segments.partition_point(|s| s.orig <= orig);
+        if i == 0 {
+            return orig;
+        }
+        let seg = &self.segments[i - 1];
+        match seg.replaced {
+            Some(_) => seg.conv,
+            None => seg.conv + (orig - seg.orig),
+        }
+    }
+    /// 元の位置の行と列 (0始まり)
+    pub fn line_column(&self, orig: usize) -> (isize, isize) {
+        let line = self.line_starts.partition_point(|&s| s <= orig).max(1) - 1;
+        let column = orig - self.line_starts.get(line).copied().unwrap_or(0);
+        (line as isize, column as isize)
+    }
+    /// 変換後の範囲から元のソースでの範囲を作る
+    pub fn span(&self, source_no: usize, conv_start: usize, conv_end: usize) -> Span {
+        let start = self.to_original(conv_start);
+        let end = self.to_original_end(conv_end.max(conv_start)).max(start);
+        let (line, column) = self.line_column(start);
+        Span {
+            source_no,
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_source_text() {
+        let st = SourceText::new("cde\n  fg");
+        let span = st.span(0, 6, 8);
+        assert_eq!((span.start, span.end, span.line, span.column), (6, 8, 1, 2));
+        assert_eq!(st.to_converted(6), 6);
+    }
+
+    #[test]
+    fn replaced_segments_map_back_to_the_original() {
+        // "ドレミ\nc" => "cde\nc"
+        let mut st = SourceText::with_original("ドレミ\nc");
+        st.push_replace(0, 0, 1);
+        st.push_replace(1, 1, 1);
+        st.push_replace(2, 2, 1);
+        st.push_same(3, 3);
+        assert_eq!(st.span(0, 1, 2).start, 1);
+        assert_eq!(st.span(0, 0, 3).end, 3);
+        let span = st.span(0, 4, 5);
+        assert_eq!((span.start, span.line, span.column), (4, 1, 0));
+        assert_eq!(st.to_converted(4), 4);
+    }
+
+    #[test]
+    fn origin_moves_only_offsets() {
+        assert_eq!(SpanOrigin::Offset(3).at(2), SpanOrigin::Offset(5));
+        let e = SpanOrigin::Expand(Some(Span::default()));
+        assert_eq!(e.at(4), e);
+    }
+}
@@ -1,5 +1,6 @@
 /// Sutoton Mode Converter
 use super::source_cursor::SourceCursor;
+use super::span::SourceText;
 use super::token::zen2han;
 
 /// Sutoton Item for converter
@@ -81,9 +82,10 @@ fn init_items() -> SutotonList {
     items.set_item("−", "-"); // @ マイナス
     items.set_item("‘", "`"); // @ 次の音符をオクターブ1つ上げる
     items.set_item("調", "System.KeyFlag"); // @ 調#(音符)//臨時記号を設定する。（例）調＃（ドファ）
+    items.set_item("調号", "KeySignature"); // @ 調号(主音,major|minor)//MIDIファイルに調号を書き込む。（例）調号(D,major)
     items.set_item("音階", "o"); // @ 音階(数値)//音階を数値で指定する。初期値は５。範囲は、0～10（例）音階５
     items.set_item("時間", "Time"); // @ 時間(小節数:拍数:ステップ数)//指定時間にポインタを移動する。範囲は、小節数・拍数が、１～。ステップ数は、０～。（例）時間（４：１：０）
-    items.set_item("読む", "Include"); // @ 読む(ファイル名)//外部定義ファイルを読み込む。※Includeは未実装。（例）読む(chord2.h)
+    items.set_item("読む", "Include"); // @ 読む(ファイル名)//外部定義ファイルを読み込む。（例）読む(chord2.h)
     items.set_item("予約", ".onNote="); // @ (コマンド)予約(v1,v2,v3...)//コマンドの値を予約しておく（例）音量予約120,50【ドレミファ】
     items.set_item("拍子", "System.TimeSignature="); // @ 拍子 分子,分母//拍子を設定する。（例）拍子4,4
     items.set_item("音色", "@"); // @ 音色（番号）//音色を設定する。
@@ -95,6 +97,7 @@ fn init_items() -> SutotonList {
     items.set_item("曖昧さ", ".Random="); // @ (コマンド)曖昧さ（数値）//各属性の曖昧さを設定する。範囲は、0～。初期値は、0。（例）音量曖昧さ80 【ドレミソ】
     items.set_item("トラック", "Track="); // @ トラック（番号）//トラック番号を指定する。初期値は、０。範囲は、0～。（例）トラック３
     items.set_item("チャンネル", "Channel="); // @ チャンネル（番号）//現在のトラックにチャンネルを設定する。初期値は、トラック番号と同じ。範囲は、１～１６（例）トラック３チャンネル１０
+    items.set_item("マーカー", "Marker="); // @ マーカー{"名前"}//現在位置に名前を付けてマーカーを埋め込む。演奏位置(Marker("名前"))で参照できる。（例）マーカー{"サビ"}
     items.set_item("曲名", "TrackName="); // @ 曲名{"文字列"}//生成するMIDIファイルに曲名を埋め込む。（例）曲名{"テスト"}
     items.set_item("作者", "Copyright="); // @ 作者{"文字列"}//生成するMIDIファイルに著作権情報を埋め込む。（例）作者{"クジラ飛行机"}
     items.set_item("コメント", "MetaText="); // @ コメント{"文字列"}//生成するMIDIファイルにコメントを埋め込む。（例）コメント{"テスト"}
@@ -165,21 +168,27 @@ fn init_items() -> SutotonList {
 
 /// Sutoton Converter
 pub fn convert(src: &str) -> String {
+    convert_with_map(src).0
+}
+
+/// Sutoton Converter - 変換後の位置を元のソースの位置へ戻す対応表も返す
+pub fn convert_with_map(src: &str) -> (String, SourceText) {
     let mut items = init_items();
-    let mut res = String::new();
+    let mut out = Output::new(src);
     let mut cur = SourceCursor::from(src);
     while !cur.is_eos() {
         let ch = zen2han(cur.peek_n(0));
+        let start = cur.index;
         // string ?
         match ch {
             '{' => {
                 if cur.eq("{\"") {
                     let s = cur.get_token_s("\"}");
-                    res.push_str(&s);
-                    res.push_str("\"}");
+                    out.push_same(start, &s);
+                    out.push_same(start + s.chars().count(), "\"}");
                     continue;
                 }
-                res.push(ch);
+                out.push_same(start, &ch.to_string());
                 cur.next();
                 continue;
             }
@@ -187,18 +196,18 @@ pub fn convert(src: &str) -> String {
                 // line comment
                 if cur.eq("//") {
                     let s = cur.get_token_s("\n");
-                    res.push_str(&s);
-                    res.push('\n');
+                    out.push_same(start, &s);
+                    out.push_same(start + s.chars().count(), "\n");
                     continue;
                 }
                 // range comment
                 if cur.eq("/*") {
                     let s = cur.get_token_s("*/");
-                    res.push_str(&s);
-                    res.push_str("*/");
+                    out.push_same(start, &s);
+                    out.push_same(start + s.chars().count(), "*/");
                     continue;
                 }
-                res.push(ch);
+                out.push_same(start, &ch.to_string());
                 cur.next();
                 continue;
             }
@@ -236,18 +245,48 @@ pub fn convert(src: &str) -> String {
         let mut found = false;
         for cmd in items.items.iter() {
             if cur.eq(&cmd.name) {
-                res.push_str(&cmd.value);
+                out.push_replace(start, cmd.length, &cmd.value);
                 cur.index += cmd.length;
                 found = true;
                 break;
             }
         }
         if !found {
-            res.push(ch);
+            out.push_same(start, &ch.to_string());
             cur.index += 1;
         }
     }
-    res.trim().to_string()
+    let head = out.len - out.res.trim_start().chars().count();
+    out.map.remove_head(head);
+    (out.res.trim().to_string(), out.map)
+}
+
+/// 変換結果と対応表
+struct Output {
+    res: String,
+    len: usize, // resの文字数
+    map: SourceText,
+}
+impl Output {
+    fn new(src: &str) -> Self {
+        Self {
+            res: String::new(),
+            len: 0,
+            map: SourceText::with_original(src),
+        }
+    }
+    /// 元のソースの`orig`以降をそのまま書き写す
+    fn push_same(&mut self, orig: usize, s: &str) {
+        self.map.push_same(self.len, orig);
+        self.res.push_str(s);
+        self.len += s.chars().count();
+    }
+    /// 元のソースの`orig`から`orig_len`文字を`s`に置換する
+    fn push_replace(&mut self, orig: usize, orig_len: usize, s: &str) {
+        self.map.push_replace(self.len, orig, orig_len);
+        self.res.push_str(s);
+        self.len += s.chars().count();
+    }
 }
 
 #[cfg(test)]
@@ -259,6 +298,24 @@ mod tests {
         assert_eq!(convert("トラック3ドレミ"), String::from("Track=3cde"));
     }
     #[test]
+    fn test_convert_with_map() {
+        let (res, map) = convert_with_map("\n トラック3\nドレミ");
+        assert_eq!(res, "Track=3\ncde");
+        // "3" は元のソースの1行目5文字目
+        let span = map.span(0, 6, 7);
+        assert_eq!((span.start, span.line, span.column), (6, 1, 5));
+        // "Track=" は「トラック」
+        let span = map.span(0, 0, 6);
+        assert_eq!((span.start, span.end), (2, 6));
+        // "e" は「ミ」
+        let span = map.span(0, 10, 11);
+        assert_eq!(
+            (span.start, span.end, span.line, span.column),
+            (10, 11, 2, 2)
+        );
+        assert_eq!(map.to_converted(10), 10);
+    }
+    #[test]
     fn test_ex() {
         assert_eq!(convert("~{ど}={c}ドレミどレミ"), String::from("cdecde"));
         assert_eq!(
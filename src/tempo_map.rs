@@ -0,0 +1,409 @@
+//! TempoMap - テンポ・拍子の変化から、tickと実時間・小節位置を相互に変換する
+//!
+//! 曲中のテンポ(FF 51)と拍子(FF 58)のメタイベントを全トラックから集めて作る。
+//! tickは曲のTimeBaseを単位とし、小節・拍は dump_midi の TIME(001:001:000) と同じく1始まり。
+//! 拍子の変化だけを持つ TimeSigMap は、実行中の Song でも小節の位置の計算に使う。
+
+use crate::song::{EventType, Song};
+use std::fmt;
+
+/// SMFのテンポの既定値 (120BPM, μ秒/四分音符)
+const DEFAULT_MPQ: isize = 500_000;
+
+/// テンポの変化点
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoPoint {
+    pub tick: isize,
+    /// 四分音符の長さ(μ秒)
+    pub mpq: isize,
+    /// この変化点までの経過秒数
+    pub seconds: f64,
+}
+
+impl TempoPoint {
+    pub fn bpm(&self) -> f64 {
+        60_000_000.0 / self.mpq as f64
+    }
+}
+
+/// 拍子の変化点 (小節の頭で変わるものとして扱う)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSigPoint {
+    pub tick: isize,
+    /// この変化点から始まる小節の番号 (1始まり)
+    pub measure: isize,
+    pub numerator: isize,
+    pub denominator: isize,
+}
+
+impl TimeSigPoint {
+    fn beat_len(&self, timebase: isize) -> isize {
+        (timebase * 4 / self.denominator).max(1)
+    }
+    fn measure_len(&self, timebase: isize) -> isize {
+        self.beat_len(timebase) * self.numerator
+    }
+}
+
+/// 小節:拍:ステップ の位置 (小節と拍は1始まり)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarPos {
+    pub measure: isize,
+    pub beat: isize,
+    pub step: isize,
+}
+
+impl fmt::Display for BarPos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03}:{:03}:{:03}", self.measure, self.beat, self.step)
+    }
+}
+
+/// 拍子マップ (tickの順に並んだ拍子の変化点。先頭は必ずtick 0)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeSigMap {
+    pub points: Vec<TimeSigPoint>,
+}
+
+impl Default for TimeSigMap {
+    fn default() -> Self {
+        Self::from_changes(96, &[])
+    }
+}
+
+impl TimeSigMap {
+    /// 拍子 (tick, 分子, 分母) の変化から作る
+    /// 同じtickに複数の変化があるときは後のものを使う
+    pub fn from_changes(timebase: isize, sigs: &[(isize, isize, isize)]) -> Self {
+        let timebase = timebase.max(1);
+        let mut sig_changes: Vec<(isize, isize, isize)> = sigs
+            .iter()
+            .filter(|s| s.1 > 0 && s.2 > 0)
+            .map(|&(tick, n, d)| (tick.max(0), n, d))
+            .collect();
+        sig_changes.sort_by_key(|s| s.0);
+        let mut points = vec![TimeSigPoint {
+            tick: 0,
+            measure: 1,
+            numerator: 4,
+            denominator: 4,
+        }];
+        for (tick, numerator, denominator) in sig_changes {
+            let last = points[points.len() - 1];
+            if last.tick == tick {
+                let p = points.last_mut().unwrap();
+                p.numerator = numerator;
+                p.denominator = denominator;
+                continue;
+            }
+            // 小節の途中で変わったときは、そこから新しい小節が始まる
+            let len = last.measure_len(timebase);
+            let measure = last.measure + (tick - last.tick + len - 1) / len;
+            points.push(TimeSigPoint {
+                tick,
+                measure,
+                numerator,
+                denominator,
+            });
+        }
+        Self { points }
+    }
+
+    /// tickの位置から拍子を変える (後ろの変化点の小節番号も付け直す)
+    pub fn set(&mut self, timebase: isize, tick: isize, numerator: isize, denominator: isize) {
+        let mut sigs: Vec<(isize, isize, isize)> = self
+            .points
+            .iter()
+            .map(|s| (s.tick, s.numerator, s.denominator))
+            .collect();
+        sigs.push((tick, numerator, denominator));
+        *self = Self::from_changes(timebase, &sigs);
+    }
+
+    /// tickの位置の拍子
+    pub fn at(&self, tick: isize) -> &TimeSigPoint {
+        let i = self.points.partition_point(|s| s.tick <= tick).max(1) - 1;
+        &self.points[i]
+    }
+
+    /// tick → 小節:拍:ステップ
+    pub fn tick_to_bar(&self, timebase: isize, tick: isize) -> BarPos {
+        let tick = tick.max(0);
+        let sig = self.at(tick);
+        let beat_len = sig.beat_len(timebase);
+        let beats = (tick - sig.tick) / beat_len;
+        BarPos {
+            measure: sig.measure + beats / sig.numerator,
+            beat: beats % sig.numerator + 1,
+            step: (tick - sig.tick) % beat_len,
+        }
+    }
+
+    /// 小節:拍:ステップ → tick
+    pub fn bar_to_tick(&self, timebase: isize, pos: BarPos) -> isize {
+        let i = self
+            .points
+            .partition_point(|s| s.measure <= pos.measure)
+            .max(1)
+            - 1;
+        let sig = &self.points[i];
+        let beat_len = sig.beat_len(timebase);
+        sig.tick
+            + (pos.measure - sig.measure) * sig.measure_len(timebase)
+            + (pos.beat - 1) * beat_len
+            + pos.step
+    }
+
+    /// 小節の頭のtick
+    pub fn measure_to_tick(&self, timebase: isize, measure: isize) -> isize {
+        self.bar_to_tick(
+            timebase,
+            BarPos {
+                measure,
+                beat: 1,
+                step: 0,
+            },
+        )
+    }
+}
+
+/// テンポマップ
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    pub timebase: isize,
+    pub tempos: Vec<TempoPoint>,
+    pub time_signatures: TimeSigMap,
+}
+
+impl Default for TempoMap {
+    fn default() -> Self {
+        Self::from_changes(96, &[], &[])
+    }
+}
+
+impl TempoMap {
+    /// 曲のメタイベントから作る (PlayFromなどを反映させるなら midi::generate の後に呼ぶ)
+    pub fn new(song: &Song) -> Self {
+        let mut tempos = vec![];
+        let mut sigs = vec![];
+        for trk in song.tracks.iter() {
+            for e in trk.events.iter() {
+                if e.etype != EventType::Meta {
+                    continue;
+                }
+                let data = e.data.as_deref().unwrap_or(&[]);
+                match e.v2 {
+                    0x51 if data.len() >= 3 => {
+                        let mpq =
+                            (data[0] as isize) << 16 | (data[1] as isize) << 8 | data[2] as isize;
+                        tempos.push((e.time, mpq));
+                    }
+                    0x58 if data.len() >= 2 && data[1] < 8 => {
+                        sigs.push((e.time, data[0] as isize, 1 << data[1]));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Self::from_changes(song.timebase, &tempos, &sigs)
+    }
+
+    /// テンポ (tick, μ秒/四分音符) と拍子 (tick, 分子, 分母) の変化から作る
+    /// 同じtickに複数の変化があるときは後のものを使う
+    pub fn from_changes(
+        timebase: isize,
+        tempos: &[(isize, isize)],
+        sigs: &[(isize, isize, isize)],
+    ) -> Self {
+        let timebase = timebase.max(1);
+        let mut tempo_changes: Vec<(isize, isize)> = tempos
+            .iter()
+            .filter(|t| t.1 > 0)
+            .map(|&(tick, mpq)| (tick.max(0), mpq))
+            .collect();
+        tempo_changes.sort_by_key(|t| t.0);
+        let mut points = vec![TempoPoint {
+            tick: 0,
+            mpq: DEFAULT_MPQ,
+            seconds: 0.0,
+        }];
+        for (tick, mpq) in tempo_changes {
+            let last = points[points.len() - 1];
+            if last.tick == tick {
+                points.last_mut().unwrap().mpq = mpq;
+                continue;
+            }
+            let seconds = last.seconds + seconds_of(tick - last.tick, last.mpq, timebase);
+            points.push(TempoPoint { tick, mpq, seconds });
+        }
+
+        let time_signatures = TimeSigMap::from_changes(timebase, sigs);
+        Self {
+            timebase,
+            tempos: points,
+            time_signatures,
+        }
+    }
+
+    /// 分解能を変えたテンポマップ (midi::generate_with_options で PPQ を指定したときと同じ丸め)
+    pub fn rescale(&self, timebase: isize) -> Self {
+        let from = self.timebase;
+        let scale = |tick: isize| (tick * timebase * 2 + from).div_euclid(from * 2);
+        let tempos: Vec<(isize, isize)> =
+            self.tempos.iter().map(|p| (scale(p.tick), p.mpq)).collect();
+        let sigs: Vec<(isize, isize, isize)> = self
+            .time_signatures
+            .points
+            .iter()
+            .map(|s| (scale(s.tick), s.numerator, s.denominator))
+            .collect();
+        Self::from_changes(timebase, &tempos, &sigs)
+    }
+
+    fn tempo_index(&self, tick: isize) -> usize {
+        self.tempos.partition_point(|p| p.tick <= tick).max(1) - 1
+    }
+
+    /// tickの位置のテンポ(BPM)
+    pub fn bpm_at(&self, tick: isize) -> f64 {
+        self.tempos[self.tempo_index(tick)].bpm()
+    }
+
+    /// tick → 曲の先頭からの秒数
+    pub fn tick_to_seconds(&self, tick: isize) -> f64 {
+        let p = &self.tempos[self.tempo_index(tick)];
+        p.seconds + seconds_of(tick - p.tick, p.mpq, self.timebase)
+    }
+
+    /// 秒数 → その時刻に鳴っているtick (端数は切り捨て)
+    pub fn seconds_to_tick(&self, seconds: f64) -> isize {
+        let i = self.tempos.partition_point(|p| p.seconds <= seconds).max(1) - 1;
+        let p = &self.tempos[i];
+        let ticks = (seconds - p.seconds) * 1_000_000.0 * self.timebase as f64 / p.mpq as f64;
+        // 浮動小数の誤差で1つ手前のtickにならないよう、わずかに丸める
+        p.tick + (ticks + 1e-6).floor() as isize
+    }
+
+    /// tick → 小節:拍:ステップ
+    pub fn tick_to_bar(&self, tick: isize) -> BarPos {
+        self.time_signatures.tick_to_bar(self.timebase, tick)
+    }
+
+    /// 小節:拍:ステップ → tick
+    pub fn bar_to_tick(&self, pos: BarPos) -> isize {
+        self.time_signatures.bar_to_tick(self.timebase, pos)
+    }
+
+    /// JSONにする
+    /// (ex) {"timebase":96,"tempos":[{"tick":0,"bpm":120,"seconds":0}],"timeSignatures":[{"tick":0,"measure":1,"numerator":4,"denominator":4}]}
+    pub fn to_json(&self) -> String {
+        let tempos: Vec<String> = self
+            .tempos
+            .iter()
+            .map(|p| {
+                format!(
+                    "{{\"tick\":{},\"bpm\":{},\"seconds\":{}}}",
+                    p.tick,
+                    p.bpm(),
+                    p.seconds
+                )
+            })
+            .collect();
+        let sigs: Vec<String> = self
+            .time_signatures
+            .points
+            .iter()
+            .map(|s| {
+                format!(
+                    "{{\"tick\":{},\"measure\":{},\"numerator\":{},\"denominator\":{}}}",
+                    s.tick, s.measure, s.numerator, s.denominator
+                )
+            })
+            .collect();
+        format!(
+            "{{\"timebase\":{},\"tempos\":[{}],\"timeSignatures\":[{}]}}",
+            self.timebase,
+            tempos.join(","),
+            sigs.join(",")
+        )
+    }
+}
+
+/// テンポが一定の区間で、ticks分の秒数
+fn seconds_of(ticks: isize, mpq: isize, timebase: isize) -> f64 {
+    ticks as f64 * mpq as f64 / (timebase as f64 * 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_source;
+    use crate::runner::exec;
+
+    fn tempo_map(src: &str) -> TempoMap {
+        let mut song = Song::new();
+        let tokens = lex_source(&mut song, src);
+        exec(&mut song, &tokens);
+        TempoMap::new(&song)
+    }
+
+    #[test]
+    fn converts_ticks_and_seconds_across_tempo_changes() {
+        let map = tempo_map("Tempo(120) c1 Tempo(60) c1");
+        assert_eq!(map.tempos.len(), 2);
+        assert_eq!(map.tick_to_seconds(0), 0.0);
+        assert_eq!(map.tick_to_seconds(96 * 4), 2.0);
+        assert_eq!(map.tick_to_seconds(96 * 5), 3.0);
+        assert_eq!(map.seconds_to_tick(2.0), 96 * 4);
+        assert_eq!(map.seconds_to_tick(3.5), 96 * 5 + 48);
+        assert_eq!(map.bpm_at(96 * 4), 60.0);
+        // テンポ指定がなければ120
+        let map = tempo_map("cde");
+        assert_eq!(map.tick_to_seconds(96), 0.5);
+    }
+
+    #[test]
+    fn converts_ticks_and_bars_across_time_signatures() {
+        let map = tempo_map("TimeSignature(3,4) l2. c c TimeSignature(6,8) c c");
+        assert_eq!(map.time_signatures.points.len(), 2);
+        assert_eq!(map.time_signatures.points[1].measure, 3);
+        let pos = map.tick_to_bar(96 * 3 + 48);
+        assert_eq!(pos.to_string(), "002:001:048");
+        assert_eq!(map.bar_to_tick(pos), 96 * 3 + 48);
+        // 6/8 は8分音符が1拍
+        let pos = map.tick_to_bar(96 * 6 + 48 * 7);
+        assert_eq!((pos.measure, pos.beat, pos.step), (4, 2, 0));
+        assert_eq!(map.bar_to_tick(pos), 96 * 6 + 48 * 7);
+    }
+
+    #[test]
+    fn rescaled_map_keeps_seconds_and_bars() {
+        let map = tempo_map("TimeSignature(3,4) Tempo(75) c2. Tempo(150) c2.").rescale(480);
+        assert_eq!(map.timebase, 480);
+        assert_eq!(map.tempos[1].tick, 480 * 3);
+        assert_eq!(map.tick_to_seconds(480 * 3), 2.4);
+        assert_eq!(map.tick_to_bar(480 * 3).to_string(), "002:001:000");
+    }
+
+    #[test]
+    fn time_signature_in_the_middle_of_a_bar_starts_a_new_bar() {
+        let map = TempoMap::from_changes(96, &[], &[(96 * 2, 3, 4)]);
+        assert_eq!(map.time_signatures.points[1].measure, 2);
+        assert_eq!(map.tick_to_bar(96 * 5).to_string(), "003:001:000");
+        assert!(map
+            .to_json()
+            .contains("{\"tick\":192,\"measure\":2,\"numerator\":3,\"denominator\":4}"));
+    }
+
+    #[test]
+    fn setting_a_time_signature_renumbers_later_measures() {
+        let mut map = TimeSigMap::default();
+        map.set(96, 96 * 8, 3, 4);
+        assert_eq!(map.points[1].measure, 3);
+        // 先に置いた変化点より前を変えると、後ろの小節番号も変わる
+        map.set(96, 0, 2, 4);
+        assert_eq!(map.points[0].numerator, 2);
+        assert_eq!(map.points[1].measure, 5);
+        assert_eq!(map.measure_to_tick(96, 6), 96 * 8 + 96 * 3);
+    }
+}
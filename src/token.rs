@@ -1,5 +1,6 @@
 use std::vec;
 
+use super::span::Span;
 use super::svalue::SValue;
 
 /// TokenType::Comment の value_i - 通常のコメント (MIDIには何も出力しない)
@@ -61,10 +62,12 @@ pub enum TokenType {
     Tempo,
     TempoChange,
     MetaText,
+    Marker,
     GSEffect,
     Port,
     SysEx,
     TimeSignature,
+    KeySignature,
     PitchBend,
     PBonTime,
     PBonNoteWave,
@@ -77,12 +80,23 @@ pub enum TokenType {
     Rhythm,
     HarmonyBegin,
     HarmonyEnd,
+    /// コードネームを和音にする (ex) Chord{C Am7}
+    Chord,
+    /// コードの配置の設定 (value_i=項目)
+    ChordOption,
+    ChordVoiceLeading,
+    /// 和音のストローク (ex) Strum(up, 3)
+    Strum,
+    /// 和音のアルペジオ (ex) Arp(updown, 16, 80)
+    Arp,
     Tokens, // should run children toknes
     Div,
     Sub,
     KeyFlag,
     KeyShift,
     UseKeyShift,
+    UseBarCheck,
+    BarLine,
     TrackKey,
     DefInt,
     DefStr,
@@ -90,6 +104,7 @@ pub enum TokenType {
     LetVar,
     PlayFrom,
     PlayFromHere,
+    PlayTo,
     OctaveRandom,
     OctaveOnNote,
     OctaveOnCycle,
@@ -201,6 +216,8 @@ pub struct Token {
     pub data: Vec<SValue>,
     pub children: Option<Vec<Token>>,
     pub lineno: isize,
+    /// 元のソースでの範囲
+    pub span: Option<Span>,
 }
 
 impl Token {
@@ -215,6 +232,7 @@ impl Token {
             data: vec![],
             children: None,
             lineno,
+            span: None,
         }
     }
     pub fn new(ttype: TokenType, value: isize, data: Vec<SValue>) -> Self {
@@ -228,6 +246,7 @@ impl Token {
             data,
             children: None,
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_const0() -> Self {
@@ -241,6 +260,7 @@ impl Token {
             data: vec![],
             children: None,
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_const(
@@ -259,6 +279,7 @@ impl Token {
             data: vec![],
             children: None,
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_variable(
@@ -276,6 +297,7 @@ impl Token {
             data: vec![],
             children: init_tokens,
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_value(ttype: TokenType, value: isize) -> Self {
@@ -289,6 +311,7 @@ impl Token {
             data: vec![],
             children: None,
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_value_tag(ttype: TokenType, value: isize, tag: isize) -> Self {
@@ -302,6 +325,7 @@ impl Token {
             data: vec![],
             children: None,
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_tokens(ttype: TokenType, value_i: isize, tokens: Vec<Token>) -> Self {
@@ -315,6 +339,7 @@ impl Token {
             data: vec![],
             children: Some(tokens),
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_calc_token(operator_ch: char, priority: isize, children: Vec<Token>) -> Self {
@@ -328,6 +353,7 @@ impl Token {
             data: vec![],
             children: Some(children),
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_tokens_lineno(
@@ -346,6 +372,7 @@ impl Token {
             data: vec![],
             children: Some(tokens),
             lineno,
+            span: None,
         }
     }
     pub fn new_data_tokens(
@@ -364,6 +391,7 @@ impl Token {
             data,
             children: Some(tokens),
             lineno: 0,
+            span: None,
         }
     }
     pub fn new_empty(cmd: &str, lineno: isize) -> Self {
@@ -377,6 +405,7 @@ impl Token {
             data: vec![],
             children: None,
             lineno,
+            span: None,
         }
     }
     pub fn new_comment(commet: &str, lineno: isize) -> Self {
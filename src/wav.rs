@@ -0,0 +1,941 @@
+//! WAV renderer - SMFを簡易な音源で鳴らしてPCM(WAV)にする
+//!
+//! 外部の音源なしで試聴や音の比較ができるよう、チャンネルごとにサイン波・矩形波・のこぎり波、
+//! ドラム(CH10)はノイズで鳴らす。SoundFont(SF2)を渡したときは、その音色のサンプルで鳴らす。
+//! ベロシティ、CC7/CC11(音量)、CC10(パン)、CC64(サステイン)、CC91/CC93(リバーブ・コーラスの送り)、
+//! RPNのピッチベンド幅とピッチベンド、テンポの変化を反映する。
+
+use crate::midi;
+use crate::smf::{self, SmfEventKind};
+use crate::song::Song;
+use crate::soundfont::{Region, SoundFont, VolumeEnvelope};
+use crate::tempo_map::TempoMap;
+use std::f64::consts::PI;
+
+/// サンプリング周波数の既定値
+pub const WAV_SAMPLE_RATE: u32 = 44100;
+
+/// 書き出せる長さの上限(秒)
+const MAX_SECONDS: f64 = 600.0;
+/// 同時発音数
+const MAX_VOICES: usize = 128;
+/// アタックとリリースの長さ(秒)
+const ATTACK: f64 = 0.005;
+const RELEASE: f64 = 0.08;
+/// 重ねても割れにくいよう、全体の音量を下げておく
+const MASTER_GAIN: f64 = 0.25;
+/// ドラムのチャンネル (CH10)
+const DRUM_CHANNEL: usize = 9;
+/// エフェクトの余韻を書く長さの上限(秒)
+const MAX_TAIL: f64 = 5.0;
+
+/// 描画の設定
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions<'a> {
+    pub sample_rate: u32,
+    /// 音色に使うSF2 (Noneなら発振器で鳴らす)
+    pub soundfont: Option<&'a SoundFont>,
+}
+
+impl Default for RenderOptions<'_> {
+    fn default() -> Self {
+        Self {
+            sample_rate: WAV_SAMPLE_RATE,
+            soundfont: None,
+        }
+    }
+}
+
+/// 波形
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wave {
+    Sine,
+    Square,
+    Saw,
+    /// バスドラム (周波数が下がるサイン波)
+    Kick,
+    Noise,
+}
+
+impl Wave {
+    /// GMの音色の分類から波形を選ぶ
+    fn from_program(program: u8) -> Self {
+        match program / 8 {
+            // オルガン, リード, パイプ
+            2 | 8 | 9 => Wave::Square,
+            // ギター, ベース, ストリングス, アンサンブル, ブラス, シンセリード
+            3..=7 | 10 => Wave::Saw,
+            _ => Wave::Sine,
+        }
+    }
+    fn from_drum_key(key: u8) -> Self {
+        match key {
+            35 | 36 => Wave::Kick,
+            _ => Wave::Noise,
+        }
+    }
+    fn is_drum(&self) -> bool {
+        matches!(self, Wave::Kick | Wave::Noise)
+    }
+}
+
+/// チャンネルの状態
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    program: u8,
+    bank_msb: u8,
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    sustain: bool,
+    reverb: u8,
+    chorus: u8,
+    bend: i16,
+    bend_range: u8,
+    rpn: (u8, u8),
+    /// ピッチベンドによる周波数の倍率
+    bend_ratio: f64,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            program: 0,
+            bank_msb: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            sustain: false,
+            reverb: 0,
+            chorus: 0,
+            bend: 0,
+            bend_range: 2,
+            rpn: (0x7F, 0x7F),
+            bend_ratio: 1.0,
+        }
+    }
+}
+
+impl Channel {
+    fn update_bend(&mut self) {
+        let semitones = self.bend as f64 / 8192.0 * self.bend_range as f64;
+        self.bend_ratio = 2f64.powf(semitones / 12.0);
+    }
+    /// 左右のゲイン (panは音色ごとの位置 -1〜1 を加える。等パワーのパン)
+    fn gains(&self, pan: f64) -> (f64, f64) {
+        let level = self.volume as f64 / 127.0 * self.expression as f64 / 127.0;
+        let pos = ((self.pan as f64 - 64.0) / 63.0 + pan).clamp(-1.0, 1.0);
+        let angle = (pos + 1.0) / 2.0 * PI / 2.0;
+        (level * angle.cos(), level * angle.sin())
+    }
+}
+
+/// 音の元
+enum Source {
+    Wave {
+        wave: Wave,
+        freq: f64,
+        phase: f64,
+    },
+    /// SF2のサンプル (stepは1サンプルで進む位置)
+    Sample {
+        region: Region,
+        pos: f64,
+        step: f64,
+    },
+}
+
+/// 発音中の音
+struct Voice {
+    channel: usize,
+    key: u8,
+    source: Source,
+    gain: f64,
+    /// 音色ごとのパン (-1〜1)
+    pan: f64,
+    reverb: f64,
+    chorus: f64,
+    exclusive_class: u16,
+    /// 発音してからのサンプル数
+    age: usize,
+    /// NoteOffからのサンプル数と、その時の音量
+    release: Option<(usize, f64)>,
+    /// サステインペダルでNoteOffを待っている
+    sustained: bool,
+}
+
+impl Voice {
+    /// 音量の包絡線 (0〜1)
+    fn envelope(&self, rate: f64) -> f64 {
+        let t = self.age as f64 / rate;
+        match &self.source {
+            Source::Wave { wave, .. } if wave.is_drum() => drum_level(*wave, self.key, t),
+            Source::Wave { .. } => match self.release {
+                Some((age, from)) => from * (1.0 - age as f64 / rate / RELEASE).max(0.0),
+                None => (t / ATTACK).min(1.0),
+            },
+            Source::Sample { region, .. } => match self.release {
+                Some((age, from)) => {
+                    let cb = 1000.0 * age as f64 / rate / region.envelope.release.max(0.001);
+                    from * centibel(cb)
+                }
+                None => sf2_level(&region.envelope, t),
+            },
+        }
+    }
+
+    fn is_finished(&self, rate: f64) -> bool {
+        match &self.source {
+            Source::Wave {
+                wave: Wave::Kick, ..
+            } => self.age as f64 / rate > 1.0,
+            Source::Wave {
+                wave: Wave::Noise, ..
+            } => self.age as f64 / rate > drum_decay(self.key) * 7.0,
+            Source::Wave { .. } => self
+                .release
+                .map(|(age, _)| age as f64 / rate >= RELEASE)
+                .unwrap_or(false),
+            Source::Sample { region, pos, .. } => {
+                *pos >= region.end as f64
+                    || self
+                        .release
+                        .map(|(age, _)| age as f64 / rate >= region.envelope.release)
+                        .unwrap_or(false)
+            }
+        }
+    }
+
+    fn release(&mut self, rate: f64) {
+        if self.release.is_none() {
+            self.release = Some((0, self.envelope(rate)));
+        }
+        self.sustained = false;
+    }
+}
+
+/// センチベルの減衰 → 音量の倍率
+fn centibel(cb: f64) -> f64 {
+    10f64.powf(-cb / 200.0)
+}
+
+/// SF2の音量の包絡線 (ディケイは100dB下がるまでの時間で、サステインで止まる)
+fn sf2_level(e: &VolumeEnvelope, t: f64) -> f64 {
+    let t = t - e.delay;
+    if t < 0.0 {
+        return 0.0;
+    }
+    if t < e.attack {
+        return t / e.attack;
+    }
+    let t = t - e.attack - e.hold;
+    if t < 0.0 {
+        return 1.0;
+    }
+    let cb = (1000.0 * t / e.decay.max(0.001)).min(e.sustain_cb);
+    centibel(cb)
+}
+
+fn drum_level(wave: Wave, key: u8, t: f64) -> f64 {
+    match wave {
+        Wave::Kick => (-t / 0.15).exp(),
+        _ => (-t / drum_decay(key)).exp(),
+    }
+}
+
+/// ドラムのノイズが減衰する速さ(秒) シンバル類は長めにする
+fn drum_decay(key: u8) -> f64 {
+    match key {
+        49 | 51 | 52 | 55 | 57 | 59 => 0.4,
+        46 => 0.2,
+        _ => 0.06,
+    }
+}
+
+/// ノイズ (結果を比較できるよう、毎回同じ系列にする)
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f64 / u32::MAX as f64 * 2.0 - 1.0
+    }
+}
+
+/// 遅延線
+struct Delay {
+    buf: Vec<f64>,
+    pos: usize,
+}
+
+impl Delay {
+    fn new(len: usize) -> Self {
+        Self {
+            buf: vec![0.0; len.max(2)],
+            pos: 0,
+        }
+    }
+    /// delayサンプル前の値 (小数は線形補間)
+    fn tap(&self, delay: f64) -> f64 {
+        let len = self.buf.len();
+        let d = delay.clamp(0.0, (len - 2) as f64);
+        let i = d.floor() as usize;
+        let frac = d - i as f64;
+        let a = self.buf[(self.pos + len - i) % len];
+        let b = self.buf[(self.pos + len - i - 1) % len];
+        a + (b - a) * frac
+    }
+    /// 一番古い値
+    fn oldest(&self) -> f64 {
+        self.buf[(self.pos + 1) % self.buf.len()]
+    }
+    fn push(&mut self, v: f64) {
+        self.pos = (self.pos + 1) % self.buf.len();
+        self.buf[self.pos] = v;
+    }
+}
+
+/// Freeverbを小さくしたリバーブ (コムフィルター4つとオールパス2つを左右に持つ)
+struct Reverb {
+    combs: Vec<(Delay, f64)>,
+    allpasses: Vec<Delay>,
+}
+
+impl Reverb {
+    fn new(rate: f64) -> Self {
+        let scale = |n: usize| (n as f64 * rate / 44100.0) as usize;
+        let mut combs = vec![];
+        let mut allpasses = vec![];
+        // 右は少しずらして広がりを出す
+        for spread in [0, 23] {
+            for n in [1116, 1188, 1277, 1356] {
+                combs.push((Delay::new(scale(n + spread)), 0.0));
+            }
+            for n in [556, 441] {
+                allpasses.push(Delay::new(scale(n + spread)));
+            }
+        }
+        Self { combs, allpasses }
+    }
+    fn process(&mut self, input: f64) -> (f64, f64) {
+        let mut out = [0.0; 2];
+        for (side, v) in out.iter_mut().enumerate() {
+            let mut sum = 0.0;
+            for (delay, store) in self.combs[side * 4..side * 4 + 4].iter_mut() {
+                let y = delay.oldest();
+                *store = y * 0.8 + *store * 0.2;
+                delay.push(input * 0.05 + *store * 0.84);
+                sum += y;
+            }
+            for delay in self.allpasses[side * 2..side * 2 + 2].iter_mut() {
+                let b = delay.oldest();
+                delay.push(sum + b * 0.5);
+                sum = b - sum;
+            }
+            *v = sum;
+        }
+        (out[0], out[1])
+    }
+}
+
+/// 揺らした遅延で音を重ねるコーラス (左右で揺れの位相を変える)
+struct Chorus {
+    delay: Delay,
+    rate: f64,
+    time: usize,
+}
+
+impl Chorus {
+    fn new(rate: f64) -> Self {
+        Self {
+            delay: Delay::new((rate * 0.05) as usize),
+            rate,
+            time: 0,
+        }
+    }
+    fn process(&mut self, input: f64) -> (f64, f64) {
+        self.delay.push(input);
+        self.time += 1;
+        let lfo = self.time as f64 / self.rate * 0.5 * 2.0 * PI;
+        let ms = self.rate / 1000.0;
+        let left = self.delay.tap((15.0 + 5.0 * lfo.sin()) * ms);
+        let right = self.delay.tap((15.0 + 5.0 * lfo.cos()) * ms);
+        (left, right)
+    }
+}
+
+struct Renderer<'a> {
+    rate: f64,
+    font: Option<&'a SoundFont>,
+    channels: [Channel; 16],
+    voices: Vec<Voice>,
+    noise: Noise,
+    reverb: Reverb,
+    chorus: Chorus,
+    /// エフェクトに音を送ったか
+    effects_used: bool,
+    /// ステレオで交互に並べたサンプル
+    out: Vec<f32>,
+    samples: usize,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(options: &RenderOptions<'a>) -> Self {
+        let rate = options.sample_rate as f64;
+        Self {
+            rate,
+            font: options.soundfont,
+            channels: [Channel::default(); 16],
+            voices: vec![],
+            noise: Noise(0x2545_F491),
+            reverb: Reverb::new(rate),
+            chorus: Chorus::new(rate),
+            effects_used: false,
+            out: vec![],
+            samples: 0,
+        }
+    }
+
+    /// 1サンプル分を合成して書き出す
+    fn render_sample(&mut self) {
+        let (mut left, mut right) = (0.0, 0.0);
+        let (mut reverb, mut chorus) = (0.0, 0.0);
+        let data = self.font.map(|f| f.data.as_slice()).unwrap_or(&[]);
+        for v in self.voices.iter_mut() {
+            let env = v.envelope(self.rate);
+            let ch = &self.channels[v.channel];
+            let s = match &mut v.source {
+                Source::Wave { wave, freq, phase } => {
+                    let s = match wave {
+                        Wave::Sine | Wave::Kick => (*phase * 2.0 * PI).sin(),
+                        Wave::Square => {
+                            if *phase < 0.5 {
+                                0.5
+                            } else {
+                                -0.5
+                            }
+                        }
+                        Wave::Saw => (*phase * 2.0 - 1.0) * 0.6,
+                        Wave::Noise => self.noise.next() * 0.5,
+                    };
+                    let f = match wave {
+                        Wave::Kick => 50.0 + 70.0 * (-(v.age as f64) / self.rate / 0.03).exp(),
+                        _ => *freq * ch.bend_ratio,
+                    };
+                    *phase = (*phase + f / self.rate).fract();
+                    s
+                }
+                Source::Sample { region, pos, step } => {
+                    let s = sample_at(data, region, *pos);
+                    *pos += *step * ch.bend_ratio;
+                    let looping =
+                        region.loop_mode == 1 || (region.loop_mode == 3 && v.release.is_none());
+                    if looping {
+                        let (start, end) = (region.loop_start as f64, region.loop_end as f64);
+                        while *pos >= end {
+                            *pos -= end - start;
+                        }
+                    }
+                    s
+                }
+            };
+            v.age += 1;
+            if let Some((age, _)) = v.release.as_mut() {
+                *age += 1;
+            }
+            let s = s * env * v.gain * MASTER_GAIN;
+            let (gl, gr) = ch.gains(v.pan);
+            left += s * gl;
+            right += s * gr;
+            let level = (gl + gr) / 2.0;
+            reverb += s * level * (ch.reverb as f64 / 127.0 + v.reverb).min(1.0);
+            chorus += s * level * (ch.chorus as f64 / 127.0 + v.chorus).min(1.0);
+        }
+        if reverb != 0.0 || chorus != 0.0 {
+            self.effects_used = true;
+        }
+        if self.effects_used {
+            let (rl, rr) = self.reverb.process(reverb);
+            let (cl, cr) = self.chorus.process(chorus);
+            left += rl + cl;
+            right += rr + cr;
+        }
+        self.out.push(left as f32);
+        self.out.push(right as f32);
+        self.samples += 1;
+        let rate = self.rate;
+        self.voices.retain(|v| !v.is_finished(rate));
+    }
+
+    fn render_until(&mut self, sample: usize) {
+        while self.samples < sample {
+            self.render_sample();
+        }
+    }
+
+    /// 最後の音が消えた後、エフェクトの余韻が小さくなるまで書く
+    fn render_tail(&mut self) {
+        while !self.voices.is_empty() {
+            self.render_sample();
+        }
+        if !self.effects_used {
+            return;
+        }
+        let quiet_len = (self.rate * 0.1) as usize;
+        let mut quiet = 0;
+        let limit = self.samples + (self.rate * MAX_TAIL) as usize;
+        while quiet < quiet_len && self.samples < limit {
+            self.render_sample();
+            let n = self.out.len();
+            if self.out[n - 2].abs() < 1e-4 && self.out[n - 1].abs() < 1e-4 {
+                quiet += 1;
+            } else {
+                quiet = 0;
+            }
+        }
+    }
+
+    fn note_off(&mut self, channel: usize, key: u8) {
+        let sustain = self.channels[channel].sustain;
+        let rate = self.rate;
+        for v in self.voices.iter_mut() {
+            if v.channel == channel && v.key == key && v.release.is_none() {
+                if sustain {
+                    v.sustained = true;
+                } else {
+                    v.release(rate);
+                }
+            }
+        }
+    }
+
+    /// channelがNoneなら全チャンネル (サステイン中の音も止める)
+    fn all_notes_off(&mut self, channel: Option<usize>) {
+        let rate = self.rate;
+        for v in self.voices.iter_mut() {
+            if channel.map(|c| c == v.channel).unwrap_or(true) {
+                v.release(rate);
+            }
+        }
+    }
+
+    /// SF2の音色で鳴らす音 (音色がなければNone)
+    fn sample_voices(&self, channel: usize, key: u8, velocity: u8) -> Option<Vec<Voice>> {
+        let font = self.font?;
+        let ch = &self.channels[channel];
+        let bank = if channel == DRUM_CHANNEL {
+            128
+        } else {
+            ch.bank_msb as u16
+        };
+        let preset = font.find_preset(bank, ch.program as u16)?;
+        let mut voices = vec![];
+        for region in font.regions(preset, key, velocity) {
+            let vel = region.velocity.unwrap_or(velocity) as f64 / 127.0;
+            let cents = (region.key.unwrap_or(key) as i32 - region.root_key) * region.scale_tuning
+                + region.tune;
+            let step = 2f64.powf(cents as f64 / 1200.0) * region.sample_rate as f64 / self.rate;
+            voices.push(Voice {
+                channel,
+                key,
+                gain: vel * vel * centibel(region.attenuation),
+                pan: region.pan / 500.0,
+                reverb: region.reverb,
+                chorus: region.chorus,
+                exclusive_class: region.exclusive_class,
+                source: Source::Sample {
+                    region,
+                    pos: region.start as f64,
+                    step,
+                },
+                age: 0,
+                release: None,
+                sustained: false,
+            });
+        }
+        Some(voices)
+    }
+
+    fn note_on(&mut self, channel: usize, key: u8, velocity: u8) {
+        let rate = self.rate;
+        for v in self.voices.iter_mut() {
+            if v.channel == channel && v.key == key {
+                v.release(rate);
+            }
+        }
+        let voices = self
+            .sample_voices(channel, key, velocity)
+            .unwrap_or_else(|| {
+                let wave = if channel == DRUM_CHANNEL {
+                    Wave::from_drum_key(key)
+                } else {
+                    Wave::from_program(self.channels[channel].program)
+                };
+                vec![Voice {
+                    channel,
+                    key,
+                    source: Source::Wave {
+                        wave,
+                        freq: 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0),
+                        phase: 0.0,
+                    },
+                    gain: velocity as f64 / 127.0,
+                    pan: 0.0,
+                    reverb: 0.0,
+                    chorus: 0.0,
+                    exclusive_class: 0,
+                    age: 0,
+                    release: None,
+                    sustained: false,
+                }]
+            });
+        // 同じ排他グループ(オープン・クローズのハイハットなど)の音は止める
+        for class in voices.iter().map(|v| v.exclusive_class) {
+            if class != 0 {
+                self.voices
+                    .retain(|v| v.channel != channel || v.exclusive_class != class);
+            }
+        }
+        for v in voices {
+            if self.voices.len() >= MAX_VOICES {
+                self.voices.remove(0);
+            }
+            self.voices.push(v);
+        }
+    }
+
+    fn control_change(&mut self, channel: usize, controller: u8, value: u8) {
+        let ch = &mut self.channels[channel];
+        match controller {
+            0 => ch.bank_msb = value,
+            6 if ch.rpn == (0, 0) => {
+                ch.bend_range = value;
+                ch.update_bend();
+            }
+            7 => ch.volume = value,
+            10 => ch.pan = value,
+            11 => ch.expression = value,
+            64 => {
+                ch.sustain = value >= 64;
+                if !ch.sustain {
+                    let rate = self.rate;
+                    for v in self.voices.iter_mut() {
+                        if v.channel == channel && v.sustained {
+                            v.release(rate);
+                        }
+                    }
+                }
+            }
+            91 => ch.reverb = value,
+            93 => ch.chorus = value,
+            100 => ch.rpn.1 = value,
+            101 => ch.rpn.0 = value,
+            120 => self.voices.retain(|v| v.channel != channel),
+            123 => self.all_notes_off(Some(channel)),
+            121 => {
+                *ch = Channel {
+                    program: ch.program,
+                    bank_msb: ch.bank_msb,
+                    volume: ch.volume,
+                    pan: ch.pan,
+                    reverb: ch.reverb,
+                    chorus: ch.chorus,
+                    ..Channel::default()
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn event(&mut self, kind: &SmfEventKind) {
+        match *kind {
+            SmfEventKind::NoteOn {
+                channel,
+                key,
+                velocity,
+            } if velocity > 0 => self.note_on(channel as usize, key, velocity),
+            SmfEventKind::NoteOn { channel, key, .. }
+            | SmfEventKind::NoteOff { channel, key, .. } => self.note_off(channel as usize, key),
+            SmfEventKind::ControlChange {
+                channel,
+                controller,
+                value,
+            } => self.control_change(channel as usize, controller, value),
+            SmfEventKind::ProgramChange { channel, program } => {
+                self.channels[channel as usize].program = program
+            }
+            SmfEventKind::PitchBend { channel, value } => {
+                let ch = &mut self.channels[channel as usize];
+                ch.bend = value;
+                ch.update_bend();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// SF2のサンプルを線形補間で読む (-1〜1)
+fn sample_at(data: &[i16], region: &Region, pos: f64) -> f64 {
+    let i = pos.floor() as usize;
+    if i >= region.end || i >= data.len() {
+        return 0.0;
+    }
+    let next = if region.loop_mode != 0 && i + 1 >= region.loop_end {
+        region.loop_start
+    } else {
+        i + 1
+    };
+    let a = data[i] as f64;
+    let b = if next < region.end && next < data.len() {
+        data[next] as f64
+    } else {
+        a
+    };
+    (a + (b - a) * (pos - i as f64)) / 32768.0
+}
+
+/// SMFを鳴らして、左右交互に並べたサンプル(-1〜1)を返す
+pub fn render_smf(bin: &[u8], options: &RenderOptions) -> Result<Vec<f32>, String> {
+    let smf = smf::parse(bin).map_err(|e| e.to_string())?;
+    let timebase = match smf.header.timing.timebase() {
+        Some(tb) if tb > 0 => tb as isize,
+        _ => return Err("SMPTE time division is not supported".to_string()),
+    };
+    if options.sample_rate == 0 {
+        return Err("Sample rate must be greater than 0".to_string());
+    }
+    // 全トラックのイベントを時間順に並べる (同じ時間ならトラック順)
+    let mut events: Vec<(usize, &SmfEventKind)> = smf
+        .tracks
+        .iter()
+        .flat_map(|trk| trk.events.iter().map(|e| (e.tick, &e.kind)))
+        .collect();
+    events.sort_by_key(|e| e.0);
+    let tempos: Vec<(isize, isize)> = events
+        .iter()
+        .filter_map(|(tick, kind)| kind.tempo_mpq().map(|mpq| (*tick as isize, mpq as isize)))
+        .collect();
+    let tempo_map = TempoMap::from_changes(timebase, &tempos, &[]);
+    let end_tick = smf.tracks.iter().map(|t| t.end_tick).max().unwrap_or(0);
+    let end_secs = tempo_map.tick_to_seconds(end_tick as isize);
+    if end_secs > MAX_SECONDS {
+        return Err(format!(
+            "WAV length exceeds {} seconds ({:.0} seconds)",
+            MAX_SECONDS, end_secs
+        ));
+    }
+    let rate = options.sample_rate as f64;
+    let to_sample =
+        |tick: usize| (tempo_map.tick_to_seconds(tick as isize) * rate).round() as usize;
+    let mut r = Renderer::new(options);
+    for (tick, kind) in events {
+        let pos = to_sample(tick);
+        r.render_until(pos);
+        r.event(kind);
+    }
+    r.render_until(to_sample(end_tick));
+    // 鳴りっぱなしの音を止めて、余韻が消えるまで書く
+    r.all_notes_off(None);
+    r.render_tail();
+    Ok(r.out)
+}
+
+/// SMFをWAV(16bitステレオ)にする
+pub fn smf_to_wav(bin: &[u8], options: &RenderOptions) -> Result<Vec<u8>, String> {
+    let pcm = render_smf(bin, options)?;
+    Ok(wav_file(&pcm, options.sample_rate))
+}
+
+/// コンパイルした曲をWAVにする
+pub fn song_to_wav(song: &mut Song, options: &RenderOptions) -> Result<Vec<u8>, String> {
+    smf_to_wav(&midi::generate(song), options)
+}
+
+/// 左右交互のサンプルを16bitにして、WAVのヘッダを付ける
+pub fn wav_file(pcm: &[f32], sample_rate: u32) -> Vec<u8> {
+    let size = pcm.len() * 2;
+    let mut res = Vec::with_capacity(size + 44);
+    let channels: u16 = 2;
+    let bits: u16 = 16;
+    let block = channels * bits / 8;
+    res.extend_from_slice(b"RIFF");
+    res.extend_from_slice(&(36 + size as u32).to_le_bytes());
+    res.extend_from_slice(b"WAVEfmt ");
+    res.extend_from_slice(&16u32.to_le_bytes());
+    res.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    res.extend_from_slice(&channels.to_le_bytes());
+    res.extend_from_slice(&sample_rate.to_le_bytes());
+    res.extend_from_slice(&(sample_rate * block as u32).to_le_bytes());
+    res.extend_from_slice(&block.to_le_bytes());
+    res.extend_from_slice(&bits.to_le_bytes());
+    res.extend_from_slice(b"data");
+    res.extend_from_slice(&(size as u32).to_le_bytes());
+    for s in pcm {
+        let s = (s.clamp(-1.0, 1.0) * 32767.0) as i16;
+        res.extend_from_slice(&s.to_le_bytes());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_source;
+    use crate::runner::exec;
+    use crate::soundfont::{self, test_font};
+
+    const RATE: u32 = 8000;
+
+    fn render_with(src: &str, font: Option<&SoundFont>) -> (Vec<u8>, Vec<(i16, i16)>) {
+        let mut song = Song::new();
+        let tokens = lex_source(&mut song, src);
+        exec(&mut song, &tokens);
+        let options = RenderOptions {
+            sample_rate: RATE,
+            soundfont: font,
+        };
+        let wav = song_to_wav(&mut song, &options).unwrap();
+        let samples = wav[44..]
+            .chunks(4)
+            .map(|c| {
+                (
+                    i16::from_le_bytes([c[0], c[1]]),
+                    i16::from_le_bytes([c[2], c[3]]),
+                )
+            })
+            .collect();
+        (wav, samples)
+    }
+
+    /// MMLをWAVにして、左右のサンプル列を返す
+    fn render(src: &str) -> (Vec<u8>, Vec<(i16, i16)>) {
+        render_with(src, None)
+    }
+
+    fn is_silent(samples: &[(i16, i16)]) -> bool {
+        samples.iter().all(|s| s.0 == 0 && s.1 == 0)
+    }
+
+    /// 左チャンネルがマイナスからプラスになる回数 (サイン波の周波数)
+    fn crossings(samples: &[(i16, i16)]) -> usize {
+        samples
+            .windows(2)
+            .filter(|w| w[0].0 < 0 && w[1].0 >= 0)
+            .count()
+    }
+
+    #[test]
+    fn writes_a_pcm_wav_header() {
+        let (wav, samples) = render("Tempo(120) c4");
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(
+            u32::from_le_bytes([wav[24], wav[25], wav[26], wav[27]]),
+            RATE
+        );
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(wav.len(), 44 + samples.len() * 4);
+        // 四分音符(0.5秒)とリリース
+        let len = samples.len() as f64 / RATE as f64;
+        assert!((0.5..0.5 + RELEASE).contains(&len), "{}", len);
+        assert!(!is_silent(&samples));
+    }
+
+    #[test]
+    fn tempo_changes_move_the_notes() {
+        let (_, samples) = render("Tempo(60) r4 o5a4");
+        let rate = RATE as usize;
+        assert!(is_silent(&samples[..rate]));
+        assert!(!is_silent(&samples[rate..rate * 3 / 2]));
+    }
+
+    #[test]
+    fn pan_and_volume_change_the_levels() {
+        let (_, samples) = render("P(0) c");
+        assert!(samples.iter().any(|s| s.0 != 0));
+        assert!(samples.iter().all(|s| s.1 == 0));
+        let (_, samples) = render("V(0) c");
+        assert!(is_silent(&samples));
+    }
+
+    #[test]
+    fn pitch_bend_uses_the_bend_range() {
+        // o5a = 440Hz を1秒
+        let rate = RATE as usize;
+        let (_, samples) = render("Tempo(60) q100 o5a1");
+        assert!((crossings(&samples[..rate * 4]) as isize - 1760).abs() <= 2);
+        // 1オクターブ上
+        let (_, samples) = render("Tempo(60) q100 BR(12) PitchBend(8191) o5a1");
+        assert!((crossings(&samples[..rate * 4]) as isize - 3520).abs() <= 8);
+    }
+
+    #[test]
+    fn drums_use_noise_and_stop_without_note_off() {
+        let (_, samples) = render("CH(10) Tempo(60) o3d1");
+        assert!(!is_silent(&samples));
+        // スネアは1秒を待たずに消える
+        let rate = RATE as usize;
+        assert!(is_silent(&samples[rate / 2..rate]));
+    }
+
+    #[test]
+    fn sustain_pedal_holds_notes_until_released() {
+        let rate = RATE as usize;
+        let (_, samples) = render("Tempo(60) CC(64,127) c8 r2 CC(64,0)");
+        assert!(!is_silent(&samples[rate * 3 / 2..rate * 2]));
+        // ペダルを離した2.5秒からリリースして終わる
+        let len = samples.len() as f64 / RATE as f64;
+        assert!((2.5..2.5 + RELEASE + 0.01).contains(&len), "{}", len);
+    }
+
+    #[test]
+    fn soundfont_presets_follow_the_program_and_bank() {
+        let font = soundfont::parse(&test_font::simple()).unwrap();
+        let rate = RATE as usize;
+        // 440Hzのサンプルをルートキーで鳴らす
+        let (_, samples) = render_with("Tempo(60) q100 o5a1", Some(&font));
+        assert!((crossings(&samples[..rate * 4]) as isize - 1760).abs() <= 4);
+        // @2 はプリセットで1オクターブ上 / Voice(2,8,0) はないバンクなのでバンク0
+        for src in ["@2 Tempo(60) q100 o5a1", "Voice(2,8,0) Tempo(60) q100 o5a1"] {
+            let (_, samples) = render_with(src, Some(&font));
+            assert!((crossings(&samples[..rate * 4]) as isize - 3520).abs() <= 8);
+        }
+        // リリース(約0.18秒)の間だけ鳴り続ける
+        let (_, samples) = render_with("Tempo(60) q100 o5a4", Some(&font));
+        let len = samples.len() as f64 / RATE as f64;
+        assert!(len > 1.1 && len < 1.3, "{}", len);
+    }
+
+    #[test]
+    fn reverb_send_adds_a_tail() {
+        let (_, dry) = render("Tempo(120) c8");
+        let (_, wet) = render("Tempo(120) REV(127) c8");
+        assert!(wet.len() > dry.len() + RATE as usize / 10);
+        assert!(!is_silent(&wet[dry.len()..]));
+        let (_, chorus) = render("Tempo(120) CHO(127) c8");
+        assert!(chorus.len() > dry.len());
+    }
+
+    #[test]
+    fn float_buffer_is_interleaved_stereo() {
+        let mut song = Song::new();
+        let tokens = lex_source(&mut song, "P(127) c");
+        exec(&mut song, &tokens);
+        let options = RenderOptions {
+            sample_rate: RATE,
+            soundfont: None,
+        };
+        let pcm = render_smf(&midi::generate(&mut song), &options).unwrap();
+        assert_eq!(pcm.len() % 2, 0);
+        assert!(pcm.iter().step_by(2).all(|s| s.abs() < 1e-6));
+        assert!(pcm.iter().skip(1).step_by(2).any(|s| s.abs() > 0.01));
+    }
+
+    #[test]
+    fn rejects_data_that_is_not_midi() {
+        let options = RenderOptions::default();
+        assert_eq!(smf_to_wav(b"RIFF", &options).unwrap_err(), "Not Midi file");
+    }
+}
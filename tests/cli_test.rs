@@ -69,6 +69,106 @@ fn eval_creates_a_midi_file() {
         .starts_with(b"MThd"));
 }
 
+#[test]
+fn source_map_option_writes_json() {
+    let dir = TestDir::new("source-map");
+    let output = run(&["--eval", "c\n d", "--source-map", "map.json"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let json = fs::read_to_string(dir.0.join("map.json")).unwrap();
+    assert!(json.starts_with("[{\"track\":"), "{json}");
+    assert!(
+        json.contains("\"span\":{\"source\":0,\"start\":3,\"end\":4,\"line\":1,\"column\":1}"),
+        "{json}"
+    );
+}
+
+#[test]
+fn smf_format_and_ppq_options_change_the_header() {
+    let dir = TestDir::new("smf-format");
+    let output = run(
+        &[
+            "--eval",
+            "TR(1) c TR(2) e",
+            "--smf-format",
+            "0",
+            "--ppq",
+            "480",
+        ],
+        &dir,
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let bin = fs::read(dir.0.join("eval.mid")).unwrap();
+    // format=0, tracks=1, timebase=480
+    assert_eq!(&bin[8..14], &[0, 0, 0, 1, 0x01, 0xE0]);
+
+    let output = run(&["--eval", "c", "--smf-format", "2"], &dir);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--smf-format"));
+}
+
+#[test]
+fn wav_option_renders_a_preview() {
+    let dir = TestDir::new("wav");
+    let output = run(&["--eval", "Tempo(120) cde", "--wav", "preview.wav"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(dir.0.join("eval.mid").exists());
+    let wav = fs::read(dir.0.join("preview.wav")).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[8..12], b"WAVE");
+
+    // 出力名が .wav ならWAVだけを書く
+    fs::write(dir.0.join("song.mml"), "c").unwrap();
+    let output = run(&["song.mml", "song.wav"], &dir);
+    assert!(output.status.success());
+    assert!(fs::read(dir.0.join("song.wav"))
+        .unwrap()
+        .starts_with(b"RIFF"));
+    assert!(!dir.0.join("song.mid").exists());
+}
+
+#[test]
+fn soundfont_option_rejects_files_that_are_not_sf2() {
+    let dir = TestDir::new("soundfont");
+    let output = run(&["--eval", "c", "--soundfont", "missing.sf2"], &dir);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("File not found : missing.sf2"));
+
+    fs::write(dir.0.join("bad.sf2"), b"RIFF\0\0\0\0WAVE").unwrap();
+    let output = run(&["--eval", "c", "--soundfont", "bad.sf2"], &dir);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Not SoundFont (SF2) file"));
+}
+
+#[test]
+fn mml_option_converts_midi_to_mml() {
+    let dir = TestDir::new("mid2mml");
+    let output = run(&["--eval", "o4 c d8 e8 'ceg'2"], &dir);
+    assert!(output.status.success());
+    let output = run(&["--mml", "eval.mid"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let mml = fs::read_to_string(dir.0.join("eval.mml")).unwrap();
+    assert!(mml.contains("l8 <c4 d e 'ceg'2"), "{mml}");
+
+    let output = run(&["--mml", "none.mid"], &dir);
+    assert!(!output.status.success());
+}
+
 #[test]
 fn dump_outputs_midi_channels() {
     let dir = TestDir::new("dump-channel");
@@ -143,6 +243,70 @@ fn input_file_uses_mid_as_the_default_extension() {
         .starts_with(b"MThd"));
 }
 
+#[test]
+fn abc_file_is_compiled_by_extension() {
+    let dir = TestDir::new("abc");
+    fs::write(
+        dir.0.join("tune.abc"),
+        "X:1\nT:Tune\nK:G\nL:1/4\nG A B F|]\n",
+    )
+    .unwrap();
+
+    let output = run(&["tune.abc"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let dump = run(&["--dump", "tune.mid"], &dir);
+    let stdout = String::from_utf8_lossy(&dump.stdout);
+    assert!(stdout.contains("TRACK_NAME{Tune}"), "{stdout}");
+    assert!(stdout.contains("NoteOn($42"), "{stdout}");
+}
+
+#[test]
+fn musicxml_option_writes_a_score() {
+    let dir = TestDir::new("musicxml");
+    fs::write(
+        dir.0.join("song.mml"),
+        "TR(1) TrackName(\"Flute\") o5 l4 cdef g1",
+    )
+    .unwrap();
+
+    let output = run(&["--musicxml", "song.mml"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let xml = fs::read_to_string(dir.0.join("song.musicxml")).unwrap();
+    assert!(xml.contains("<part-name>Flute</part-name>"), "{xml}");
+    assert_eq!(xml.matches("<measure ").count(), 2);
+    assert!(!dir.0.join("song.mid").exists());
+}
+
+#[test]
+fn musicxml_file_is_converted_to_mml() {
+    let dir = TestDir::new("musicxml-import");
+    fs::write(
+        dir.0.join("song.mml"),
+        "TR(1) TrackName(\"Oboe\") KeyFlag-(b) o5 l4 f b a2",
+    )
+    .unwrap();
+    let output = run(&["--musicxml", "song.mml"], &dir);
+    assert!(output.status.success());
+
+    let output = run(&["--mml", "song.musicxml", "back.mml"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let mml = fs::read_to_string(dir.0.join("back.mml")).unwrap();
+    assert!(mml.contains("TrackName(\"Oboe\")"), "{mml}");
+    assert!(mml.contains("KeyFlag-(b) f b a2"), "{mml}");
+}
+
 #[test]
 fn missing_input_file_reports_an_error() {
     let dir = TestDir::new("missing");
@@ -189,3 +353,113 @@ fn max_event_bytes_accepts_a_custom_cli_limit() {
         .unwrap()
         .starts_with(b"MThd"));
 }
+
+#[test]
+fn include_searches_the_source_directory_and_include_paths() {
+    let dir = TestDir::new("include");
+    fs::create_dir(dir.0.join("songs")).unwrap();
+    fs::create_dir(dir.0.join("lib")).unwrap();
+    fs::write(
+        dir.0.join("songs/song.mml"),
+        "Include(part.mml)\nInclude(drums.mml)\nPART DRUMS",
+    )
+    .unwrap();
+    fs::write(dir.0.join("songs/part.mml"), "Str PART={o4cde}").unwrap();
+    fs::write(dir.0.join("lib/drums.mml"), "Str DRUMS={n36}").unwrap();
+
+    let output = run(&["-I", "lib", "songs/song.mml"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(dir.0.join("songs/song.mid").exists());
+
+    let output = run(&["songs/song.mml"], &dir);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Include file not found"));
+}
+
+#[test]
+fn fmt_rewrites_the_source_in_place() {
+    let dir = TestDir::new("fmt");
+    fs::write(
+        dir.0.join("song.mml"),
+        "TR(1)   l4 cdef  g1\nSub{\nr8 cde\n}\n",
+    )
+    .unwrap();
+
+    let output = run(&["fmt", "--bar-lines", "song.mml"], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(dir.0.join("song.mml")).unwrap(),
+        "TR(1) l4 cdef | g1\nSub{\n  r8 cde\n}\n"
+    );
+    assert!(!dir.0.join("song.mid").exists());
+}
+
+#[test]
+fn lint_reports_warnings_with_rule_ids() {
+    let dir = TestDir::new("lint");
+    fs::write(
+        dir.0.join("song.mml"),
+        "Int A = 1\nInt B = 2 // lint-ignore\nTR(1) cde\n",
+    )
+    .unwrap();
+
+    let output = run(&["--lint", "song.mml"], &dir);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("song.mml:0)"), "{}", stdout);
+    assert!(stdout.contains("\"A\" [unused-variable]"), "{}", stdout);
+    assert!(!stdout.contains("\"B\""), "{}", stdout);
+    assert!(!dir.0.join("song.mid").exists());
+}
+
+#[test]
+fn from_and_to_options_play_between_markers() {
+    let dir = TestDir::new("markers");
+    let src = "Marker{\"Intro\"} l4 cdef Marker{\"Chorus\"} gab Marker{\"Bridge\"} >c";
+    let output = run(&["--from", "Chorus", "--to", "Bridge", "--eval", src], &dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let dump = run(&["--dump", "eval.mid"], &dir);
+    let stdout = String::from_utf8_lossy(&dump.stdout);
+    assert!(
+        stdout.contains("TIME(001:001:000) NoteOn($43,$64)"),
+        "{stdout}"
+    );
+    assert_eq!(stdout.matches("NoteOn").count(), 3, "{stdout}");
+
+    // 位置は 小節:拍:ステップ (拍とステップは省略できる)
+    let output = run(&["--from", "1:3:0", "--to", "2", "--eval", src], &dir);
+    assert!(output.status.success());
+    let dump = run(&["--dump", "eval.mid"], &dir);
+    let stdout = String::from_utf8_lossy(&dump.stdout);
+    assert!(
+        stdout.contains("TIME(001:001:000) NoteOn($40,$64)"),
+        "{stdout}"
+    );
+    assert_eq!(stdout.matches("NoteOn").count(), 2, "{stdout}");
+
+    let output = run(&["--from", "Outro", "--eval", src], &dir);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Marker not found: Outro"), "{stdout}");
+
+    // 終わりが始まりより前ならエラーにして書き出さない
+    fs::remove_file(dir.0.join("eval.mid")).unwrap();
+    let output = run(&["--from", "9:1:0", "--to", "5:1:0", "--eval", src], &dir);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Play range is empty"), "{stderr}");
+    assert!(!dir.0.join("eval.mid").exists());
+    let output = run(&["--from", "Chorus", "--to", "Intro", "--eval", src], &dir);
+    assert!(!output.status.success());
+}